@@ -0,0 +1,79 @@
+use std::f32::consts::PI;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::ct::{replay_samples, CT};
+use crate::{MAX_MV_ATTEN_11, MEASUREMENT_CROSSINGS};
+
+// Samples per mains cycle of the synthetic waveform, about what the one-shot reads reach at 50Hz.
+const SAMPLES_PER_CYCLE: usize = 80;
+// Amplitudes in mV around mid-scale, and how far the current lags the voltage.
+const VOLTAGE_AMPLITUDE: f32 = 800.0;
+const CURRENT_AMPLITUDE: f32 = 400.0;
+const CURRENT_LAG: f32 = PI / 8.0;
+// Measurements timed by run_benchmark, each over MEASUREMENT_CROSSINGS crossings.
+const RUNS: u32 = 20;
+
+/// Throughput of the measurement math, see run_benchmark.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BenchResult {
+    pub samples: u64,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    pub(crate) fn samples_per_second(&self) -> f64 {
+        self.samples as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Time the math takes per (current, voltage) sample, in µs.
+    pub(crate) fn micros_per_sample(&self) -> f64 {
+        self.elapsed.as_secs_f64() * 1e6 / self.samples as f64
+    }
+}
+
+// A clean sine of MEASUREMENT_CROSSINGS crossings, as (current, voltage) pairs in mV.
+fn synthetic_waveform() -> Vec<(u16, u16)> {
+    let mid_scale = MAX_MV_ATTEN_11 as f32 / 2.0;
+    let samples = SAMPLES_PER_CYCLE * (MEASUREMENT_CROSSINGS as usize / 2 + 1);
+    (0..samples)
+        .map(|n| {
+            let angle = 2.0 * PI * n as f32 / SAMPLES_PER_CYCLE as f32;
+            let voltage = mid_scale + VOLTAGE_AMPLITUDE * f32::sin(angle);
+            let current = mid_scale + CURRENT_AMPLITUDE * f32::sin(angle - CURRENT_LAG);
+            (current as u16, voltage as u16)
+        })
+        .collect()
+}
+
+/// Time the filter, rms and power math of `ct` on a synthetic waveform, without the ADC.
+///
+/// The samples are played back from RAM like replay_waveform, so the result is the CPU budget of
+/// the signal processing alone: the rate the ADC could at most be read at before the math falls
+/// behind. Compare it before and after a change to the sampling or oversampling. Moves the dc
+/// offsets of `ct`, so they are reset afterwards.
+pub(crate) fn run_benchmark(ct: &mut CT) -> anyhow::Result<BenchResult> {
+    let waveform = synthetic_waveform();
+    let mut result = BenchResult {
+        samples: 0,
+        elapsed: Duration::ZERO,
+    };
+    for _ in 0..RUNS {
+        // Copying the waveform is not part of the math.
+        let samples = waveform.clone();
+        let start = Instant::now();
+        replay_samples(samples, ct)?;
+        result.elapsed += start.elapsed();
+        result.samples += waveform.len() as u64;
+    }
+    ct.reset_offsets();
+    info!(
+        "Benchmark: {} samples in {:?}, {:.0} samples/s, {:.2} µs per sample",
+        result.samples,
+        result.elapsed,
+        result.samples_per_second(),
+        result.micros_per_sample()
+    );
+    Ok(result)
+}
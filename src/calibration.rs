@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::ct::{Calibration, CalibrationError, CT};
+use crate::sampling::Sampler;
+
+// Measurements of the offsets stage, enough for the dc offsets to converge from the defaults.
+const NO_LOAD_MEASUREMENTS: u32 = 5;
+// phase_cal values tried by the phase stage.
+const PHASE_CAL_MIN: f32 = 0.0;
+const PHASE_CAL_MAX: f32 = 2.0;
+const PHASE_CAL_STEP: f32 = 0.1;
+// Per measurement of the wizard, as in the main loop.
+const CROSSINGS: u32 = 200;
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What the installer did since the last step of a CalibrationWizard.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WizardInput {
+    /// Followed the instruction of the current state.
+    Continue,
+    /// Read these off a reference meter on the known load, for the KnownLoad state.
+    Reference { watts: f32, volts: f32 },
+    /// Gave up, the calibration from before the wizard is put back.
+    Cancel,
+}
+
+/// Where a CalibrationWizard stands, see instruction for what the installer does next.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum WizardState {
+    /// The dc offsets are measured without a load.
+    NoLoad,
+    /// vcal and ical are fitted to a reference meter on a known resistive load.
+    KnownLoad,
+    /// phase_cal is fitted to the power factor of 1 of the resistive load.
+    PhaseCal,
+    /// The complete calibration, already applied to the CT and ready for save_calibration.
+    Done(Calibration),
+    /// The calibration from before the wizard has been put back.
+    Cancelled,
+}
+
+impl WizardState {
+    /// What to tell the installer in this state.
+    pub(crate) fn instruction(&self) -> &'static str {
+        match self {
+            WizardState::NoLoad => {
+                "Switch off every load on the CT, keep the mains voltage connected, then continue."
+            }
+            WizardState::KnownLoad => {
+                "Switch on a steady resistive load, e.g. a kettle or heater, and enter the power \
+                 and voltage a reference meter shows for it."
+            }
+            WizardState::PhaseCal => {
+                "Keep the resistive load on and continue. This takes about a minute."
+            }
+            WizardState::Done(_) => "Calibration finished, save it to keep it across restarts.",
+            WizardState::Cancelled => "Calibration cancelled, the previous calibration is kept.",
+        }
+    }
+}
+
+/// Guides an installer through the calibration of one CT, one step at a time.
+///
+/// The stages are the ones of a manual calibration, in order: the dc offsets without a load, vcal
+/// and ical against a reference meter on a known resistive load (see CT::compare_to_reference),
+/// and last phase_cal, which is swept for the highest power factor of the same load. Each stage is
+/// applied to the CT right away, so the later ones measure with it. Cancelling puts the
+/// calibration from before the wizard back.
+pub(crate) struct CalibrationWizard {
+    state: WizardState,
+    previous: Calibration,
+}
+
+impl CalibrationWizard {
+    pub(crate) fn new(ct: &CT) -> Self {
+        CalibrationWizard {
+            state: WizardState::NoLoad,
+            previous: ct.calibration(),
+        }
+    }
+
+    pub(crate) fn state(&self) -> WizardState {
+        self.state
+    }
+
+    /// Act on `input` in the current state, measuring `ct` as needed, and return the new state.
+    ///
+    /// Input that doesn't fit the current state leaves it unchanged. The CT's reading of the save
+    /// period is not touched.
+    pub(crate) fn step(
+        &mut self,
+        ct: &mut CT,
+        sampler: &mut Sampler,
+        input: WizardInput,
+    ) -> anyhow::Result<WizardState> {
+        if input == WizardInput::Cancel {
+            if !matches!(self.state, WizardState::Done(_)) {
+                ct.set_calibration(self.previous);
+                self.state = WizardState::Cancelled;
+            }
+            return Ok(self.state);
+        }
+        self.state = match (self.state, input) {
+            (WizardState::NoLoad, WizardInput::Continue) => {
+                ct.reset_offsets();
+                for _ in 0..NO_LOAD_MEASUREMENTS {
+                    ct.measure_once(sampler, CROSSINGS, TIMEOUT)?;
+                }
+                info!("Calibration: dc offsets {:?}", ct.current_offsets());
+                WizardState::KnownLoad
+            }
+            (WizardState::KnownLoad, WizardInput::Reference { watts, volts }) => {
+                let reading = ct.measure_once(sampler, CROSSINGS, TIMEOUT)?;
+                let error = CalibrationError::between(&reading, watts, volts)?;
+                info!("Calibration: {:?}", error);
+                ct.set_calibration(error.corrected(ct.calibration()));
+                WizardState::PhaseCal
+            }
+            (WizardState::PhaseCal, WizardInput::Continue) => {
+                let mut cal = ct.calibration();
+                let mut best = (cal.phase_cal, 0.0);
+                let steps = ((PHASE_CAL_MAX - PHASE_CAL_MIN) / PHASE_CAL_STEP).round() as u32;
+                for n in 0..=steps {
+                    cal.phase_cal = PHASE_CAL_MIN + n as f32 * PHASE_CAL_STEP;
+                    ct.set_calibration(cal);
+                    let reading = ct.measure_once(sampler, CROSSINGS, TIMEOUT)?;
+                    let power_factor = f32::abs(reading.period_power_factor());
+                    if power_factor > best.1 {
+                        best = (cal.phase_cal, power_factor);
+                    }
+                }
+                info!(
+                    "Calibration: phase_cal {} at power factor {}",
+                    best.0, best.1
+                );
+                cal.phase_cal = best.0;
+                ct.set_calibration(cal);
+                WizardState::Done(cal)
+            }
+            (state, input) => {
+                warn!("Calibration: {:?} doesn't fit {:?}, ignored.", input, state);
+                state
+            }
+        };
+        Ok(self.state)
+    }
+}
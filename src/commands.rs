@@ -0,0 +1,461 @@
+use std::fmt::Write;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+
+use crate::calibration::{CalibrationWizard, WizardInput, WizardState};
+use crate::ct::{to_prometheus, CT};
+#[cfg(feature = "fault-injection")]
+use crate::fault::FaultInjector;
+use crate::sampling::Sampler;
+use crate::settings::{self, Setting};
+use crate::storage::CTStorage;
+use crate::{AC_PHASE, COMMAND_TIMEOUT, ENERGY_RATE, JSON_SIZE};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+/// What a web handler asks of the main loop, which owns the CTs and the sampler.
+#[derive(Debug)]
+pub(crate) enum Request {
+    /// The readings of the save period so far as a JSON array, see CTReading::write_json.
+    Live,
+    /// The readings of the save period so far for Prometheus, see ct::to_prometheus.
+    Metrics,
+    /// What each CT saw in its last measurement, as "key=value" lines.
+    Status,
+    /// The calibration of a CT, see CT::calibration_str.
+    Calibration(u16),
+    /// Apply a calibration to a CT and store it, see CT::apply_calibration_str.
+    SetCalibration(u16, String),
+    /// Step the calibration wizard of a CT: "start", "continue", "cancel" or the "<W> <V>" of a
+    /// reference meter, see CalibrationWizard.
+    Calibrate(u16, String),
+    /// Compare the reading of a CT to the W and V of a reference meter, see
+    /// CT::compare_to_reference.
+    Compare(u16, f32, f32),
+    /// Keep the measurements of every CT out of the stored data, see CT::pause.
+    Pause,
+    Resume,
+    /// The state of the device, see CTStorage::export_state.
+    ExportState,
+    ImportState(Vec<u8>),
+    /// Update the stored settings with these lines and apply them, see settings::parse.
+    Settings(String),
+    /// Capture a waveform of this many samples of a CT, see CTStorage::capture_waveform.
+    Capture(u16, usize),
+    /// Replay the waveform in a file of the storage through a CT, see
+    /// CTStorage::replay_waveform.
+    Replay(u16, String),
+    /// Persist everything and restart, see CTStorage::shutdown.
+    Shutdown,
+}
+
+/// A Request with the channel its reply goes back on.
+pub(crate) struct Command {
+    request: Request,
+    reply: Sender<anyhow::Result<Vec<u8>>>,
+}
+
+/// The side of the command channel the web handlers ask on, shared between them.
+pub(crate) struct Commands {
+    sender: Mutex<Sender<Command>>,
+}
+
+impl Commands {
+    /// Send `request` to the main loop and wait for its reply, at most COMMAND_TIMEOUT.
+    pub(crate) fn ask(&self, request: Request) -> anyhow::Result<Vec<u8>> {
+        let (reply, replies) = mpsc::channel();
+        {
+            let sender = match self.sender.lock() {
+                Ok(guard) => guard,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            sender
+                .send(Command { request, reply })
+                .map_err(|_| anyhow!("The main loop is gone"))?;
+        }
+        replies
+            .recv_timeout(COMMAND_TIMEOUT)
+            .map_err(|_| anyhow!("The main loop didn't reply in {:?}", COMMAND_TIMEOUT))?
+    }
+}
+
+/// The command channel, the main loop serves the commands of the receiver between measurements.
+pub(crate) fn channel() -> (Commands, Receiver<Command>) {
+    let (sender, receiver) = mpsc::channel();
+    (
+        Commands {
+            sender: Mutex::new(sender),
+        },
+        receiver,
+    )
+}
+
+/// Serves the commands in the main loop, keeping what lasts across them.
+pub(crate) struct CommandHandler {
+    // The wizard running and the index of its CT, one at a time.
+    wizard: Option<(usize, CalibrationWizard)>,
+    #[cfg(feature = "fault-injection")]
+    faults: FaultInjector,
+}
+
+impl CommandHandler {
+    pub(crate) fn new(#[cfg(feature = "fault-injection")] faults: FaultInjector) -> Self {
+        CommandHandler {
+            wizard: None,
+            #[cfg(feature = "fault-injection")]
+            faults,
+        }
+    }
+
+    /// Handle `command` and send the reply. Restarts the device after a Shutdown that succeeded.
+    pub(crate) fn serve(
+        &mut self,
+        command: Command,
+        cts: &mut [CT; AC_PHASE],
+        sampler: &mut Sampler,
+        storage_lock: &Mutex<CTStorage>,
+    ) {
+        let restart = matches!(command.request, Request::Shutdown);
+        let reply = self.handle(command.request, cts, sampler, storage_lock);
+        let restart = restart && reply.is_ok();
+        // A handler that timed out no longer waits for the reply.
+        let _ = command.reply.send(reply);
+        if restart {
+            info!("Restarting after shutdown.");
+            unsafe { esp_idf_sys::esp_restart() };
+        }
+    }
+
+    fn handle(
+        &mut self,
+        request: Request,
+        cts: &mut [CT; AC_PHASE],
+        sampler: &mut Sampler,
+        storage_lock: &Mutex<CTStorage>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let lock = || match storage_lock.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        debug!("Command: {:?}", request);
+        Ok(match request {
+            Request::Live => {
+                let mut json = b"[".to_vec();
+                let mut buf = [0_u8; JSON_SIZE];
+                for (n, ct) in cts.iter().enumerate() {
+                    if n > 0 {
+                        json.push(b',');
+                    }
+                    let len = ct.reading.write_json(ct.id, &mut buf)?;
+                    json.extend_from_slice(&buf[..len]);
+                }
+                json.push(b']');
+                json
+            }
+            Request::Metrics => to_prometheus(cts).into_bytes(),
+            Request::Status => status(cts)?.into_bytes(),
+            Request::Calibration(id) => cts[index(cts, id)?].calibration_str().into_bytes(),
+            Request::SetCalibration(id, calibration) => {
+                cts[index(cts, id)?].apply_calibration_str(&calibration)?;
+                lock().save_calibration(cts)?;
+                Vec::new()
+            }
+            Request::Calibrate(id, input) => {
+                self.calibrate(id, &input, cts, sampler, storage_lock)?
+            }
+            Request::Compare(id, watts, volts) => {
+                let error = cts[index(cts, id)?].compare_to_reference(watts, volts)?;
+                format!(
+                    "power_error_percent={}\nvoltage_error_percent={}\nvcal_factor={}\n\
+                     ical_factor={}\n",
+                    error.power_error_percent,
+                    error.voltage_error_percent,
+                    error.vcal_factor,
+                    error.ical_factor
+                )
+                .into_bytes()
+            }
+            Request::Pause => {
+                cts.iter_mut().for_each(CT::pause);
+                Vec::new()
+            }
+            Request::Resume => {
+                cts.iter_mut().for_each(CT::resume);
+                Vec::new()
+            }
+            Request::ExportState => lock().export_state(cts)?,
+            Request::ImportState(bytes) => {
+                lock().import_state(cts, &bytes)?;
+                Vec::new()
+            }
+            Request::Settings(update) => {
+                let update_settings = settings::parse(&update)?;
+                let mut storage = lock();
+                let mut stored = storage.settings()?;
+                // The device runs on the defaults then, see main, so they are replaced.
+                if let Err(err) = settings::parse(&stored) {
+                    warn!("Replacing the stored settings, they don't parse: {}", err);
+                    stored.clear();
+                }
+                storage.store_settings(&settings::merge(&stored, &update))?;
+                let (boot_only, now): (Vec<&Setting>, Vec<&Setting>) = update_settings
+                    .iter()
+                    .partition(|setting| setting.boot_only());
+                for setting in &now {
+                    cts.iter_mut().for_each(|ct| setting.apply_to_ct(ct));
+                    setting.apply_to_sampler(sampler);
+                    setting.apply_to_storage(&mut storage);
+                    #[cfg(feature = "fault-injection")]
+                    setting.apply_to_faults(&self.faults);
+                }
+                format!("applied={}\nat_next_boot={}\n", now.len(), boot_only.len()).into_bytes()
+            }
+            Request::Capture(id, samples) => {
+                let n = index(cts, id)?;
+                lock()
+                    .capture_waveform(&mut cts[n], sampler, samples)?
+                    .into_bytes()
+            }
+            Request::Replay(id, name) => {
+                let n = index(cts, id)?;
+                let reading = lock().replay_waveform(&mut cts[n], &name)?;
+                let mut buf = [0_u8; JSON_SIZE];
+                let len = reading.write_json(id, &mut buf)?;
+                buf[..len].to_vec()
+            }
+            Request::Shutdown => {
+                lock().shutdown(cts)?;
+                Vec::new()
+            }
+        })
+    }
+
+    // Step the wizard of CT `id` with `input`, see Request::Calibrate, and reply with what the
+    // installer does next. A finished calibration is stored.
+    fn calibrate(
+        &mut self,
+        id: u16,
+        input: &str,
+        cts: &mut [CT; AC_PHASE],
+        sampler: &mut Sampler,
+        storage_lock: &Mutex<CTStorage>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let n = index(cts, id)?;
+        if input == "start" {
+            if let Some((running, _)) = &self.wizard {
+                anyhow::bail!(
+                    "CT {} is being calibrated, cancel it first",
+                    cts[*running].id
+                );
+            }
+            let wizard = CalibrationWizard::new(&cts[n]);
+            let instruction = wizard.state().instruction();
+            self.wizard = Some((n, wizard));
+            return Ok(instruction.as_bytes().to_vec());
+        }
+        let input = match (input, input.split_once(' ')) {
+            ("continue", _) => WizardInput::Continue,
+            ("cancel", _) => WizardInput::Cancel,
+            (_, Some((watts, volts))) => WizardInput::Reference {
+                watts: watts.trim().parse()?,
+                volts: volts.trim().parse()?,
+            },
+            _ => anyhow::bail!(
+                "Expected start, continue, cancel or \"<W> <V>\", got {:?}",
+                input
+            ),
+        };
+        let wizard = match &mut self.wizard {
+            Some((running, wizard)) if *running == n => wizard,
+            _ => anyhow::bail!("CT {} isn't being calibrated, start it first", id),
+        };
+        let state = wizard.step(&mut cts[n], sampler, input)?;
+        match state {
+            WizardState::Done(calibration) => {
+                info!("CT {}: calibrated to {:?}", id, calibration);
+                let mut storage = match storage_lock.lock() {
+                    Ok(guard) => guard,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                storage.save_calibration(cts)?;
+                self.wizard = None;
+            }
+            WizardState::Cancelled => self.wizard = None,
+            _ => {}
+        }
+        Ok(state.instruction().as_bytes().to_vec())
+    }
+}
+
+// Index of the CT with `id` in `cts`.
+fn index(cts: &[CT; AC_PHASE], id: u16) -> anyhow::Result<usize> {
+    cts.iter()
+        .position(|ct| ct.id == id)
+        .ok_or_else(|| anyhow!("No CT {}", id))
+}
+
+// The "key=value" lines of Request::Status, each key prefixed with the id of its CT.
+fn status(cts: &[CT; AC_PHASE]) -> anyhow::Result<String> {
+    let mut out = String::new();
+    for ct in cts {
+        let reading = &ct.reading;
+        let (crossings, requested) = ct.last_crossings();
+        let (offset_i, offset_v) = ct.current_offsets();
+        let values: [(&str, String); 25] = [
+            ("mode", settings::mode_name(ct.mode()).to_string()),
+            ("paused", ct.is_paused().to_string()),
+            ("exporting", ct.is_exporting().to_string()),
+            ("lifetime_kwh", ct.lifetime_kwh().to_string()),
+            ("exported_kwh", ct.exported_kwh().to_string()),
+            ("quality", reading.quality().to_string()),
+            (
+                "reduced_precision",
+                reading.is_reduced_precision().to_string(),
+            ),
+            ("clipped", reading.is_clipped().to_string()),
+            ("under_sampled", reading.is_under_sampled().to_string()),
+            (
+                "implausible_voltage",
+                reading.is_implausible_voltage().to_string(),
+            ),
+            ("apparent_only", reading.is_apparent_only().to_string()),
+            ("v_crest_factor", reading.v_crest_factor().to_string()),
+            ("i_crest_factor", reading.i_crest_factor().to_string()),
+            (
+                "phase_angle_degrees",
+                reading.phase_angle_degrees().to_string(),
+            ),
+            ("leading", reading.is_leading().to_string()),
+            (
+                "estimated_cost",
+                reading.estimated_cost(ENERGY_RATE).to_string(),
+            ),
+            ("uptime", reading.uptime().to_string()),
+            ("crossings", crossings.to_string()),
+            ("requested_crossings", requested.to_string()),
+            ("offset_i", offset_i.to_string()),
+            ("offset_v", offset_v.to_string()),
+            (
+                "offset_convergence",
+                ct.last_offset_convergence().to_string(),
+            ),
+            ("channel_stuck", ct.last_channel_stuck().to_string()),
+            ("pins_likely_swapped", ct.pins_likely_swapped().to_string()),
+            ("calibration", ct.calibration_str()),
+        ];
+        for (key, value) in &values {
+            writeln!(out, "ct{}_{}={}", ct.id, key, value)?;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ct::tests::{reading, test_cts};
+    use crate::sampling::tests::test_sampler;
+    use crate::storage::tests::{storage, MemFs};
+
+    // Handle `request` on `cts` with a storage on `fs`.
+    fn handle(request: Request, cts: &mut [CT; AC_PHASE], fs: &MemFs) -> anyhow::Result<Vec<u8>> {
+        let storage_lock = Mutex::new(storage(fs));
+        let mut handler = CommandHandler::new(
+            #[cfg(feature = "fault-injection")]
+            FaultInjector::new(Default::default()),
+        );
+        handler.handle(request, cts, &mut test_sampler(), &storage_lock)
+    }
+
+    #[test]
+    fn live_is_a_json_array_of_the_cts() {
+        let mut cts = test_cts();
+        for ct in cts.iter_mut() {
+            ct.reading = reading(100.0, 1_000);
+        }
+        let fs = MemFs::new();
+        let json = String::from_utf8(handle(Request::Live, &mut cts, &fs).unwrap()).unwrap();
+        assert!(json.starts_with("[{\"id\":1,") && json.ends_with("}]"));
+        assert_eq!(json.matches("\"id\":").count(), AC_PHASE);
+    }
+
+    #[test]
+    fn set_calibration_is_stored() {
+        let mut cts = test_cts();
+        let fs = MemFs::new();
+        let request = Request::SetCalibration(cts[0].id, "vcal=200".to_string());
+        handle(request, &mut cts, &fs).unwrap();
+        assert_eq!(cts[0].calibration().vcal, 200.0);
+
+        let mut loaded = test_cts();
+        storage(&fs).load_calibration(&mut loaded).unwrap();
+        assert_eq!(loaded[0].calibration().vcal, 200.0);
+
+        let request = Request::SetCalibration(99, "vcal=200".to_string());
+        assert!(handle(request, &mut cts, &fs).is_err());
+    }
+
+    #[test]
+    fn settings_are_merged_stored_and_applied() {
+        let mut cts = test_cts();
+        let fs = MemFs::new();
+        let update = "mode=current_only\nshard_recovery=roll".to_string();
+        let reply = handle(Request::Settings(update), &mut cts, &fs).unwrap();
+        assert_eq!(reply, b"applied=1\nat_next_boot=1\n");
+        assert!(cts
+            .iter()
+            .all(|ct| ct.mode() == crate::ct::MeasurementMode::CurrentOnly));
+
+        let update = "mode=standard".to_string();
+        handle(Request::Settings(update), &mut cts, &fs).unwrap();
+        assert_eq!(
+            storage(&fs).settings().unwrap(),
+            "shard_recovery=roll\nmode=standard\n"
+        );
+
+        // Nothing is stored or applied from a text that doesn't parse.
+        let update = "mode=current_only\nshard_cache=lots".to_string();
+        assert!(handle(Request::Settings(update), &mut cts, &fs).is_err());
+        assert_eq!(cts[0].mode(), crate::ct::MeasurementMode::Standard);
+    }
+
+    #[test]
+    fn calibrate_needs_a_started_wizard() {
+        let mut cts = test_cts();
+        let fs = MemFs::new();
+        let storage_lock = Mutex::new(storage(&fs));
+        let mut handler = CommandHandler::new(
+            #[cfg(feature = "fault-injection")]
+            FaultInjector::new(Default::default()),
+        );
+        let mut sampler = test_sampler();
+        let id = cts[0].id;
+        let mut calibrate = |input: &str| {
+            let request = Request::Calibrate(id, input.to_string());
+            handler.handle(request, &mut cts, &mut sampler, &storage_lock)
+        };
+        assert!(calibrate("continue").is_err());
+        let reply = calibrate("start").unwrap();
+        assert_eq!(reply, WizardState::NoLoad.instruction().as_bytes());
+        assert!(calibrate("start").is_err());
+        assert!(calibrate("faster").is_err());
+        let reply = calibrate("cancel").unwrap();
+        assert_eq!(reply, WizardState::Cancelled.instruction().as_bytes());
+        assert!(calibrate("cancel").is_err());
+    }
+
+    #[test]
+    fn pause_and_resume_every_ct() {
+        let mut cts = test_cts();
+        let fs = MemFs::new();
+        handle(Request::Pause, &mut cts, &fs).unwrap();
+        assert!(cts.iter().all(CT::is_paused));
+        let status = String::from_utf8(handle(Request::Status, &mut cts, &fs).unwrap()).unwrap();
+        assert!(status.contains(&format!("ct{}_paused=true\n", cts[0].id)));
+        handle(Request::Resume, &mut cts, &fs).unwrap();
+        assert!(!cts.iter().any(CT::is_paused));
+    }
+}
@@ -1,593 +1,5318 @@
-use crate::{now, set_system_time, ACCESS_TOKEN_SIZE, MAX_TIME_STORAGE_SIZE};
-use std::collections::HashSet;
-use std::io::{Read, Seek, SeekFrom, Write};
+use crate::{clock_set, now, uptime};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
 
-use std::{fs, ops};
+use std::ops;
 
-use embedded_hal_0_2_7::adc::OneShot;
-
-use embedded_svc::io::Write as SvcWrite;
-use esp_idf_hal::adc::{Atten11dB, PoweredAdc, ADC1};
-use esp_idf_hal::gpio::{Gpio34, Gpio35, Pins};
-use esp_idf_svc::http::server::EspHttpResponseWrite;
+use esp_idf_hal::gpio::Pins;
 
+#[cfg(feature = "fault-injection")]
+use crate::fault::{inject_channel_faults, FaultInjector};
+use crate::sampling::{
+    Adc1Pin, Adc2Pin, AdcChannel, AdcUnit, Adcs, ContinuousAdc, OneShotSource, ReplaySource,
+    SampleSource, Sampler,
+};
+use crate::serial::encode_frame;
+use crate::storage::{CTStorage, Filesystem};
+#[cfg(feature = "async")]
+use crate::ASYNC_BATCH_CROSSINGS;
 use crate::{
-    utils::*, AC_PHASE, CT_READING_SIZE, MAX_MV_ATTEN_11, MAX_SHARD_SIZE, NOISE_THRESHOLD,
-    SAVE_PERIOD_TIMEOUT, SUPPLY_VOLTAGE,
+    AC_PHASE, CLIP_MARGIN, CT_ON_ADC2, CT_READING_SIZE, DMA_FRAME_SIZE, MAX_FAILED_READS,
+    MAX_MV_ATTEN_11, MAX_NOISE_FLOOR, MAX_OFFSET_DRIFT, MAX_POWER_FACTOR, MAX_VOLTAGE_DEVIATION,
+    MEASUREMENT_CROSSINGS, MIN_SAMPLES_PER_CROSSING, NOISE_THRESHOLD, NOMINAL_VOLTAGE,
+    PLAUSIBLE_VOLTAGE, STUCK_CHANNEL_VARIANCE, SUPPLY_VOLTAGE, SWAPPED_SWING_RATIO,
+    WARMUP_READINGS, ZERO_CROSS_BAND,
 };
+#[cfg(feature = "three-phase")]
+use crate::{PHASE_CHECK_HYSTERESIS, PHASE_CHECK_TIMEOUT, PHASE_TOLERANCE_DEG};
 
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
+
+// Starting points of the adaptive dc offsets, in mV.
+const DEFAULT_OFFSET_I: f32 = 1066.0;
+const DEFAULT_OFFSET_V: f32 = 1288.0;
 struct VoltagePin {
-    pin: Gpio34<Atten11dB<ADC1>>,
+    pin: Box<dyn AdcChannel>,
     vcal: f32,
+    // Of an external voltage transformer, see CT::set_vt_ratio.
+    vt_ratio: f32,
     phase_cal: f32,
     offset_v: f32,
 }
 
 struct CurrentPin {
-    pin: Gpio35<Atten11dB<ADC1>>,
+    pin: Box<dyn AdcChannel>,
     ical: f32,
     offset_i: f32,
 }
 
+/// Receives the CT id and reading of every new measurement, see CT::on_reading.
+pub type ReadingCallback = Box<dyn FnMut(u16, &CTReading)>;
+
 pub struct CT {
-    id: u16,
+    pub(crate) id: u16,
     current_pin: CurrentPin,
     voltage_pin: VoltagePin,
+    config: MeasurementConfig,
+    diagnostics: MeasurementDiagnostics,
+    // The interval reading: the measurements of the running save period, as combined by
+    // `accumulator`. Its kwh is the energy of this period only.
     pub reading: CTReading,
+    pub(crate) accumulator: Accumulator,
+    // Lifetime energy: kWh of all the finished save periods since the device was first set up.
+    pub(crate) energy_total_kwh: f64,
+    // kWh taken out of the measurements by NegativeEnergy::Export since the device was first set
+    // up, stored with the energy total.
+    pub(crate) exported_kwh: f64,
+    // Called with every new measurement, see on_reading.
+    reading_callback: Option<ReadingCallback>,
+    // Readings still to be dropped before the offsets are settled, see set_warmup_readings.
+    warmup_remaining: u32,
+    // Smoothed direction of the power flow and the readings in a row against it, see
+    // is_exporting.
+    exporting: bool,
+    opposite_readings: u32,
+    // Whether new measurements are kept out of the reading of the period, see pause.
+    pub(crate) paused: bool,
 }
 
-#[derive(Debug)]
-pub struct CTReading {
-    real_power: f32,
-    apparent_power: f32,
-    i_rms: f32,
-    v_rms: f32,
-    kwh: f32,
-    timestamp: u64,
+/// Calibration constants of a CT channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub vcal: f32,
+    pub ical: f32,
+    pub phase_cal: f32,
+}
+
+impl Calibration {
+    // Whether the constants can be measured with: finite, and vcal and ical positive.
+    pub(crate) fn is_valid(&self) -> bool {
+        let positive = |value: f32| value.is_finite() && value > 0.0;
+        positive(self.vcal) && positive(self.ical) && self.phase_cal.is_finite()
+    }
+}
+
+/// What CTStorage::load_calibration found at boot, see BootReport::calibration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CalibrationLoad {
+    /// No stored calibration, the CTs run on the compiled defaults.
+    #[default]
+    Missing,
+    /// Loaded as stored.
+    Loaded,
+    /// Loaded from the format of before CALIBRATION_VERSION and stored again in the current one.
+    Migrated,
+    /// Unreadable, of an unknown version, with a bad crc or invalid values. The CTs run on the
+    /// compiled defaults and the file was moved to "<root>/calibration.bad".
+    Corrupt,
 }
 
-pub struct CTStorage {
-    pub readings_shard_counter: i32,
-    pub readings_shards: HashSet<i32>,
+/// Knobs that change how calculate_energy samples and processes the signal.
+struct MeasurementConfig {
+    /// Number of back to back ADC reads averaged into one logical sample.
+    ///
+    /// Averaging N reads lowers the quantization and white noise of each sample by roughly
+    /// sqrt(N), but every logical sample now takes N times as long to acquire. So with the same
+    /// timeout, fewer logical samples fit in each mains cycle and the waveform is resolved more
+    /// coarsely. 1 disables oversampling.
+    oversampling: u8,
+    /// Log the per measurement diagnostics at info instead of debug.
+    verbose: bool,
+    /// Log the diagnostics of every Nth measurement at info even when not verbose, None for never.
+    log_every: Option<u32>,
+    /// Cutoff in Hz of a first order low-pass filter on the sampled signal, None to disable it.
+    ///
+    /// The ADC has no analog anti-aliasing filter, so content above half the sample rate (e.g.
+    /// switching noise of power supplies) folds back and inflates the RMS values. Filtering helps
+    /// against that, but the filter also attenuates the mains frequency itself by
+    /// 1 / sqrt(1 + (f / cutoff)^2). Keep the cutoff at least 10 times the mains frequency, where
+    /// the error at 50Hz is below 0.5%. Voltage and current are filtered alike so their phase
+    /// relation, and with it the real power, is kept.
+    lowpass_cutoff: Option<f32>,
+    /// Sign of the reported real power and kWh.
+    power_convention: PowerConvention,
+    /// Whether the CT is clamped on the wrong way round, so it measures import as export.
+    reversed: bool,
+    /// Which of the two one-shot reads of a sample comes first.
+    read_order: ReadOrder,
+    /// How often a measurement that looks wrong is repeated before it is kept anyway.
+    measurement_retries: u8,
+    /// (measured, actual) rms current points, sorted by measured, empty to disable.
+    ///
+    /// CTs are not linear at the ends of their range, mostly at low currents, which a single ical
+    /// can't follow. The measured i_rms is mapped through the piecewise linear curve of these
+    /// points, and the power scaled along with it.
+    current_correction: Vec<(f32, f32)>,
+    /// (current, gain) points of the ratio error of the CT, sorted by current, empty to disable.
+    /// The gain at the measured i_rms multiplies the corrected i_rms, see CT::set_current_gain.
+    current_gain: Vec<(f32, f32)>,
+    /// Number of readings dropped after boot and after reset_offsets, see set_warmup_readings.
+    warmup_readings: u32,
+    /// In W around zero, readings within it don't count towards a change of direction.
+    export_dead_zone: f32,
+    /// Readings in a row beyond the dead zone it takes to change the direction.
+    export_hysteresis: u32,
+    /// Cut the sums at the exact crossing instead of at the sample after it.
+    ///
+    /// A slice starts at the voltage of its first sample and ends at the first sample past the
+    /// last crossing of that voltage, which is up to a sample period late, and every sample counts
+    /// fully, also the first one whose period only half lies in the slice. At around 40 samples
+    /// per half cycle that adds up to 2.5% of a half cycle of the wrong phase to the sums, and the
+    /// error doesn't average out over the crossings of a slice, only over many slices. The
+    /// correction integrates the samples of a slice with the trapezoidal rule from its first
+    /// sample, so the leading edge counts half, to where between the last two samples the voltage
+    /// crossed, interpolated. With an even number of crossings the sums then cover whole cycles on
+    /// both edges, which brings the rms of a measurement of a few cycles of a sine to within 0.3%
+    /// instead of about 1%.
+    cycle_correction: bool,
+    /// Sample the current only, for an install without a voltage transformer. See
+    /// MeasurementMode::CurrentOnly.
+    current_only: bool,
+    /// Mains frequency and tolerance in Hz the measured frequency is checked against, None to
+    /// not check it.
+    expected_frequency: Option<(f32, f32)>,
+    /// What a one-shot measurement does when the voltage never comes near zero to start on.
+    zero_cross_timeout: ZeroCrossTimeout,
+    /// Distance in mV from the dc offset of the voltage within which a measurement starts.
+    zero_cross_band: f32,
+    /// Fewest crossings a measurement must reach and what to do when it falls short, None to
+    /// accept any.
+    min_crossings: Option<(u32, ShortCrossings)>,
+    /// Largest distance in mV of a dc offset from mid-scale before it is warned about, None to not
+    /// check it.
+    max_offset_drift: Option<f32>,
+    /// Raw samples kept around a fault and the peak current in A that counts as one besides
+    /// clipping, None to keep none. See CT::set_fault_capture.
+    fault_capture: Option<(usize, Option<f32>)>,
+    /// What happens to the negative kWh of a measurement before it is added up.
+    negative_energy: NegativeEnergy,
+    /// Lowest and highest plausible v_rms and what to do with a reading outside, None to not
+    /// check it.
+    plausible_voltage: Option<((f32, f32), ImplausibleVoltage)>,
+    /// rms mains voltage in V, see CT::set_nominal_voltage.
+    nominal_voltage: f32,
 }
 
-impl CTStorage {
-    pub(crate) fn new() -> Self {
-        CTStorage {
-            readings_shard_counter: 1,
-            readings_shards: HashSet::new(),
+// Map `x` through the piecewise linear curve of `points`, which are sorted by their first value.
+// Beyond the first and last point the outer segments are extended. A single point scales through
+// the origin and no points leave `x` unchanged.
+fn interpolate(points: &[(f32, f32)], x: f32) -> f32 {
+    match points {
+        [] => x,
+        [(x0, y0)] => {
+            if *x0 != 0.0 {
+                x * y0 / x0
+            } else {
+                x
+            }
+        }
+        _ => {
+            let i = points[1..points.len() - 1]
+                .iter()
+                .take_while(|(xi, _)| *xi < x)
+                .count();
+            let ((x0, y0), (x1, y1)) = (points[i], points[i + 1]);
+            if x1 == x0 {
+                return y0;
+            }
+            y0 + (x - x0) * (y1 - y0) / (x1 - x0)
         }
     }
+}
 
-    //Reset everything and clear all files
-    pub(crate) fn reset_storage(&mut self) -> anyhow::Result<()> {
-        std::fs::remove_file("/littlefs/powerloss_log")?;
-        std::fs::remove_dir_all("/littlefs/ct_readings")?;
-        info!("Deleted Everything.");
-        fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open("/littlefs/powerloss_log")?;
-        self.readings_shards = HashSet::new();
-        self.readings_shard_counter = 1;
-        self.find_newest_readings_shard_num()?;
-        Ok(())
+// The gain of `points` at `x`, interpolated linearly between the points, which are sorted by their
+// first value. Beyond the first and last point their gain holds, no points are a gain of 1.
+fn gain_at(points: &[(f32, f32)], x: f32) -> f32 {
+    match points {
+        [] => 1.0,
+        [(x0, g0), ..] if x <= *x0 => *g0,
+        [.., (xn, gn)] if x >= *xn => *gn,
+        _ => {
+            let i = points.iter().take_while(|(xi, _)| *xi < x).count();
+            let ((x0, g0), (x1, g1)) = (points[i - 1], points[i]);
+            if x1 == x0 {
+                return g1;
+            }
+            g0 + (x - x0) * (g1 - g0) / (x1 - x0)
+        }
     }
+}
 
-    // Whenever the esp boots, it restores the previously set RTC and stores that RTC in a log.
-    pub(crate) fn log_powerloss(&mut self) -> anyhow::Result<()> {
-        if let Ok(mut file) = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open("/littlefs/powerloss_log")
-        {
-            file.seek(SeekFrom::End(0))?;
-            file.write_all(&now().as_millis().to_le_bytes())?;
-            info!("logged powerloss at {}", now().as_millis());
+/// Order of the current and voltage read of each one-shot sample.
+///
+/// phase_cal interpolates between the previous and the current voltage sample to line the voltage
+/// up with the current sample. The two reads of a sample are about half a sample period apart, so
+/// reading the voltage first instead of second moves it a whole sample period earlier relative to
+/// the current. The phase_cal of the calibration always refers to CurrentFirst, the other order
+/// adds the one sample period back so the same calibration stays valid. Fine tune phase_cal after
+/// switching if the reads of your hardware are not evenly spaced.
+/// The continuous backend always converts the current first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOrder {
+    CurrentFirst,
+    VoltageFirst,
+}
+
+impl ReadOrder {
+    // What to add to the CurrentFirst phase_cal for this order, in sample periods.
+    fn phase_cal_offset(self) -> f32 {
+        match self {
+            ReadOrder::CurrentFirst => 0.0,
+            ReadOrder::VoltageFirst => 1.0,
         }
-        Ok(())
     }
+}
 
-    // Dump the powerloss log into the given writer and after that delete the log.
-    pub(crate) fn send_powerloss_log(
-        &mut self,
-        writer: &mut EspHttpResponseWrite,
-    ) -> anyhow::Result<()> {
-        // open the log file and send data. If no log is available an empty response is sent.
-        if let Ok(mut file) = fs::OpenOptions::new()
-            .write(true)
-            .read(true)
-            .open("/littlefs/powerloss_log")
-        {
-            let mut buf = [0_u8; std::mem::size_of::<u128>() * 5];
-            loop {
-                let n = file.read(&mut buf)?;
-                if n == 0 {
-                    break;
-                }
-                writer.write_all(&buf)?;
+/// How calculate_energy measures a CT, see CT::set_mode and available_modes.
+///
+/// The mode is independent of the other choices of how to sample: the backend of the Sampler
+/// (one-shot reads or DMA), measuring several CTs taking turns (calculate_energy_round_robin) and
+/// the async calculate_energy_async all measure in the mode of each CT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeasurementMode {
+    /// Sample current and voltage over whole voltage crossings. The default.
+    Standard,
+    /// Standard, cutting the sums at the exact last crossing, see CT::set_cycle_correction. More
+    /// accurate over few crossings, at a little math per slice.
+    CycleCorrected,
+    /// Sample the current only, for installs without a voltage transformer. There are no
+    /// crossings to count, so every measurement lasts its whole timeout. Readings have
+    /// the nominal voltage as v_rms, apparent_power from it and no real power or energy, see
+    /// CT::set_nominal_voltage and CTReading::is_apparent_only.
+    CurrentOnly,
+}
+
+impl MeasurementMode {
+    /// What the mode is for, in a sentence.
+    pub(crate) fn description(&self) -> &'static str {
+        match self {
+            MeasurementMode::Standard => "Current and voltage over whole crossings.",
+            MeasurementMode::CycleCorrected => {
+                "Current and voltage over whole crossings, cut at the exact crossing."
+            }
+            MeasurementMode::CurrentOnly => {
+                "Current only, apparent power at the nominal voltage, no real power or energy."
             }
-            writer.flush()?;
         }
-        Ok(())
     }
+}
 
-    /// Find the newest readings shard id
-    ///
-    /// under "/littlefs/ct_readings" files are saved with a number as their filename.
-    /// here we iterate through all of them and find the newest file (the one with higher number as
-    /// its filename). This is the file that we will be appending new data to.
-    pub(crate) fn find_newest_readings_shard_num(&mut self) -> anyhow::Result<()> {
-        let mut max_num = 1;
-        if let Ok(paths) = fs::read_dir("/littlefs/ct_readings") {
-            for path in paths {
-                info!("Shard: {:?}", path);
-                let num = path?.file_name().to_str().unwrap().parse()?;
-                max_num = i32::max(max_num, num);
-                self.readings_shards.insert(num);
-            }
+/// Every MeasurementMode, for CT::set_mode.
+pub fn available_modes() -> &'static [MeasurementMode] {
+    &[
+        MeasurementMode::Standard,
+        MeasurementMode::CycleCorrected,
+        MeasurementMode::CurrentOnly,
+    ]
+}
+
+/// What a one-shot measurement does when the voltage doesn't come near zero within the timeout.
+///
+/// The measurement starts on a voltage near mid-scale, so it counts whole half cycles from there.
+/// A voltage that never gets there is flat, usually a dead voltage channel or an unplugged
+/// transformer, and the sums of such a measurement are garbage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroCrossTimeout {
+    /// Fail the measurement.
+    Abort,
+    /// Measure the current anyway and report the nominal voltage as v_rms, apparent_power from
+    /// it, and no real power or energy, see CTReading::is_apparent_only.
+    ApparentOnly,
+    /// Wait for another timeout, and if the voltage still doesn't come near zero leave the
+    /// measurement out of the period. Its reading keeps the values it had and is flagged, see
+    /// CTReading::is_apparent_only, so a dead channel shows as a gap rather than as no energy.
+    Skip,
+}
+
+/// What calculate_energy does when a measurement reaches fewer crossings than the minimum, see
+/// CT::set_min_crossings.
+///
+/// A weak or noisy voltage can cross slower than the mains frequency, so the timeout ends the
+/// measurement early. Its reading covers fewer cycles than asked for and averages out less noise
+/// than its crossing count promises.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShortCrossings {
+    /// Keep the reading, flagged by CTReading::is_under_sampled.
+    Flag,
+    /// Measure again from scratch, once. Flag the second reading if it falls short too.
+    Retry,
+    /// Sample the missing crossings with another timeout, once, and add them to the same
+    /// measurement. Flag the reading if it still falls short.
+    ExtendTimeout,
+}
+
+/// What happens to a measurement with negative kWh, energy fed back to the grid, before its kWh
+/// are added to the reading of the period and with it to the energy total.
+///
+/// Non-finite kWh, from a measurement whose math went wrong, are always dropped, whatever this
+/// says. Negative means fed back to the grid here, whatever the PowerConvention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegativeEnergy {
+    /// Keep them, the energy total is net of the export.
+    Keep,
+    /// Drop them, the energy total counts the import only.
+    Clamp,
+    /// Move them to the export counter of the CT, see CT::exported_kwh.
+    Export,
+}
+
+/// What happens to a measurement whose v_rms is outside the plausible band, see
+/// CT::set_plausible_voltage.
+///
+/// Mains voltage doesn't swing that far, so such a v_rms is almost always a fault of the voltage
+/// channel, e.g. a loose connector, and its real power is off by the same factor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImplausibleVoltage {
+    /// Keep it as measured, flagged by CTReading::is_implausible_voltage.
+    Flag,
+    /// Flag it and report the last plausible v_rms of the CT instead, with the real power, energy
+    /// and apparent power scaled to it. Readings before the first plausible one are only flagged.
+    Substitute,
+}
+
+/// What CTStorage::save_to_storage does with a clipped reading, see CTReading::is_clipped.
+///
+/// A clipped reading still has a usable voltage, and its real power and energy are off by less
+/// than its i_rms, since the power is summed over the whole cycle while the clipping only cuts the
+/// peaks. Whether that partial data is better than none depends on what the records are used for.
+/// Whatever the policy, the lifetime statistics leave out the i_rms and apparent power of clipped
+/// readings, so neither the cut values nor the sentinels end up in them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClippedPolicy {
+    /// Store it like any other reading, with ReadingFlags::CLIPPED set in the record.
+    Store,
+    /// Store it with CLIPPED_SENTINEL as i_rms and apparent_power, which can't be measured. The
+    /// other metrics are kept.
+    Sentinel,
+    /// Don't store it. Its energy is missing from the records, the time of use totals and, if the
+    /// period is started over with CT::discard_interval, the energy total of the CT.
+    Skip,
+}
+
+/// Which direction of power flow is reported as positive real power and kWh.
+///
+/// The measurement itself is always import positive, this only changes the sign of the reported
+/// real_power and kwh so each integration can get the sign it expects. Everything built from the
+/// kWh follows: the energy total of the CT, the time of use totals and the stored records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerConvention {
+    /// Power drawn from the grid is positive, power fed back is negative.
+    ImportPositive,
+    /// Power fed back to the grid is positive, power drawn from it is negative.
+    ExportPositive,
+}
+
+impl PowerConvention {
+    // Turn the import positive measured power into the reported power.
+    fn apply(self, real_power: f32) -> f32 {
+        match self {
+            PowerConvention::ImportPositive => real_power,
+            PowerConvention::ExportPositive => -real_power,
+        }
+    }
+}
+
+impl Default for MeasurementConfig {
+    fn default() -> Self {
+        MeasurementConfig {
+            oversampling: 1,
+            verbose: false,
+            log_every: None,
+            lowpass_cutoff: None,
+            power_convention: PowerConvention::ImportPositive,
+            reversed: false,
+            read_order: ReadOrder::CurrentFirst,
+            current_correction: Vec::new(),
+            current_gain: Vec::new(),
+            measurement_retries: 0,
+            warmup_readings: WARMUP_READINGS,
+            export_dead_zone: 10.0,
+            export_hysteresis: 3,
+            cycle_correction: false,
+            current_only: false,
+            expected_frequency: None,
+            zero_cross_timeout: ZeroCrossTimeout::Skip,
+            zero_cross_band: ZERO_CROSS_BAND,
+            min_crossings: None,
+            max_offset_drift: Some(MAX_OFFSET_DRIFT),
+            fault_capture: None,
+            negative_energy: NegativeEnergy::Keep,
+            plausible_voltage: Some((PLAUSIBLE_VOLTAGE, ImplausibleVoltage::Flag)),
+            nominal_voltage: NOMINAL_VOLTAGE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CTReading {
+    pub(crate) real_power: f32,
+    pub(crate) apparent_power: f32,
+    pub(crate) i_rms: f32,
+    pub(crate) v_rms: f32,
+    pub(crate) kwh: f32,
+    pub(crate) timestamp: u64,
+    // Time since boot in ms when the timestamp was taken. Unlike the timestamp it is right before
+    // the clock is synced, so it orders the readings of a boot and dates them once the offset of
+    // the clock is known, see reconcile_time.
+    pub(crate) uptime: u64,
+    // Sequence number of the save this reading was stored with, 0 until it is stored.
+    pub(crate) sequence: u32,
+    // Lowest quality of the measurements in this reading, None before the first one.
+    pub(crate) quality: Option<u8>,
+    // Peak voltage and current, from the extreme samples of the measurements. Not stored.
+    v_peak: f32,
+    i_peak: f32,
+    // The flags of the measurements, stored together as ReadingFlags.
+    // Whether a measurement had fewer crossings than MEASUREMENT_CROSSINGS.
+    reduced_precision: bool,
+    // Whether a measurement had samples at the ends of the ADC range.
+    pub(crate) clipped: bool,
+    // Whether a pin read a constant value during a measurement.
+    stuck: bool,
+    // Whether a measurement had no voltage to measure, see ZeroCrossTimeout.
+    apparent_only: bool,
+    // Whether a measurement fell short of the minimum crossings, see ShortCrossings.
+    under_sampled: bool,
+    // Whether a measurement was timestamped before the clock was set, see reconcile_time.
+    unsynced_time: bool,
+    // Whether a measurement had a v_rms outside the plausible band, see ImplausibleVoltage.
+    implausible_voltage: bool,
+    // Whether the current leads the voltage, see is_leading.
+    leading: bool,
+}
+
+/// The flags of a reading, stored in one byte of its record. Bits not listed here are reserved
+/// and read back as unset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReadingFlags(u8);
+
+impl ReadingFlags {
+    /// See CTReading::is_clipped.
+    pub const CLIPPED: ReadingFlags = ReadingFlags(1);
+    /// See CTReading::is_under_sampled.
+    pub const UNDER_SAMPLED: ReadingFlags = ReadingFlags(1 << 1);
+    /// See CTReading::is_unsynced_time.
+    pub const UNSYNCED_TIME: ReadingFlags = ReadingFlags(1 << 2);
+    /// See CTReading::is_apparent_only.
+    pub const APPARENT_ONLY: ReadingFlags = ReadingFlags(1 << 3);
+    /// The voltage pin of the CT read a constant value, see CT::last_channel_stuck.
+    pub const STUCK: ReadingFlags = ReadingFlags(1 << 4);
+    /// See CTReading::is_reduced_precision.
+    pub const REDUCED_PRECISION: ReadingFlags = ReadingFlags(1 << 5);
+    /// See CTReading::is_implausible_voltage.
+    pub const IMPLAUSIBLE_VOLTAGE: ReadingFlags = ReadingFlags(1 << 6);
+    /// See CTReading::is_leading.
+    pub const LEADING: ReadingFlags = ReadingFlags(1 << 7);
+
+    pub(crate) fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub(crate) fn from_bits(bits: u8) -> Self {
+        ReadingFlags(bits)
+    }
+
+    /// Whether every flag of `other` is set.
+    pub(crate) fn contains(self, other: ReadingFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Set the flags of `other` if `set`.
+    pub(crate) fn set(&mut self, other: ReadingFlags, set: bool) {
+        if set {
+            self.0 |= other.0;
         } else {
-            fs::create_dir("/littlefs/ct_readings")?;
-        }
-        self.readings_shard_counter = max_num;
-
-        // if this the first ever shard, we must create it
-        if self.readings_shard_counter == 1 {
-            fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(format!(
-                    "/littlefs/ct_readings/{}",
-                    self.readings_shard_counter
-                ))?;
-            info!("Made sure the first shard is created.");
-        }
-        info!("Next shard will be: {:?}", self.readings_shard_counter);
-        Ok(())
+            self.0 &= !other.0;
+        }
     }
+}
 
-    /// Save sensor readings to storage.
-    ///
-    /// this function does not do any synchronization. If something like mutex is needed, you must deal
-    /// with it before calling this function.
-    /// under "/littlefs/ct_readings" files are saved with a number as their filename.
-    /// newer files have a higher number as their filename.
-    pub(crate) fn save_to_storage(&mut self, cts: &[CT; AC_PHASE]) -> anyhow::Result<()> {
-        // check whether the selected shard has enough size. if it doesn't create a new shard
-        println!(
-            "shard size {}",
-            fs::metadata(format!(
-                "/littlefs/ct_readings/{}",
-                self.readings_shard_counter
-            ))?
-            .len()
-        );
-        if (MAX_SHARD_SIZE as i64
-            - fs::metadata(format!(
-                "/littlefs/ct_readings/{}",
-                self.readings_shard_counter
-            ))?
-            .len() as i64)
-            < CT_READING_SIZE as i64
-        {
-            self.readings_shard_counter += 1;
-            self.readings_shards.insert(self.readings_shard_counter);
-        }
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(format!(
-                "/littlefs/ct_readings/{}",
-                self.readings_shard_counter
-            ))?;
-        info!(
-            "Opened {} for writing.",
-            format!("/littlefs/ct_readings/{}", self.readings_shard_counter)
-        );
+/// How the values of a metric over the measurements of a save period are combined, see
+/// Accumulator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// The mean of the values.
+    Mean,
+    /// The largest value, e.g. the peak current of the period.
+    Max,
+    /// The value of the last measurement.
+    Last,
+    /// The root of the mean of the squared values. For rms values this is the rms of the whole
+    /// period, over measurements of equal length. The mean of rms values is never larger, and falls
+    /// short the more the load varies: a heater switching between 0 and 10 A averages to 5 A, while
+    /// the period really had an rms of 7.1 A.
+    RmsOfSquares,
+}
+
+/// The Aggregation of every metric of a reading. The kWh are always summed.
+#[derive(Debug, Clone, Copy)]
+pub struct Aggregations {
+    pub real_power: Aggregation,
+    pub apparent_power: Aggregation,
+    pub i_rms: Aggregation,
+    pub v_rms: Aggregation,
+    pub v_peak: Aggregation,
+    pub i_peak: Aggregation,
+}
 
-        // Append the readings for each CT at the end of the file
-        for ct in cts {
-            let buf = CTStorage::ct_reading_to_le_bytes(ct)?;
-            file.seek(SeekFrom::End(0))?;
-            file.write_all(&buf)?;
-            info!("Wrote reading: {:?}", ct.reading);
+impl Default for Aggregations {
+    /// RmsOfSquares for the rms values and Mean for the rest.
+    fn default() -> Self {
+        Aggregations {
+            real_power: Aggregation::Mean,
+            apparent_power: Aggregation::Mean,
+            i_rms: Aggregation::RmsOfSquares,
+            v_rms: Aggregation::RmsOfSquares,
+            v_peak: Aggregation::Mean,
+            i_peak: Aggregation::Mean,
         }
-        file.flush()?;
-        info!(
-            "Flushed readings to storage and shard size is {}",
-            file.metadata()?.len()
-        );
-        Ok(())
     }
+}
 
-    // Retrieve the latest time from storage and update RTC
-    pub(crate) fn update_system_time(&mut self) -> anyhow::Result<()> {
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("/littlefs/time")?;
-        if file
-            .seek(std::io::SeekFrom::End(-(std::mem::size_of::<u64>() as i64)))
-            .is_ok()
-        {
-            let mut time_buf = [12_u8; 8];
-            if file.read_exact(&mut time_buf).is_ok() {
-                let time = u64::from_le_bytes(time_buf);
-                println!("Found time from storage: {}", time);
-                set_system_time(time)?;
-            }
+impl Aggregations {
+    // In the order of CTReading::aggregated_values.
+    fn as_array(&self) -> [Aggregation; 6] {
+        [
+            self.real_power,
+            self.apparent_power,
+            self.i_rms,
+            self.v_rms,
+            self.v_peak,
+            self.i_peak,
+        ]
+    }
+}
+
+// Running state of one metric in an Accumulator, enough for every Aggregation.
+#[derive(Debug, Clone, Copy, Default)]
+struct MetricAccumulator {
+    sum: f64,
+    sum_sq: f64,
+    max: Option<f32>,
+    last: f32,
+}
+
+impl MetricAccumulator {
+    fn push(&mut self, value: f32) {
+        self.sum += value as f64;
+        self.sum_sq += value as f64 * value as f64;
+        self.max = Some(self.max.map_or(value, |max| f32::max(max, value)));
+        self.last = value;
+    }
+
+    // The value by `aggregation` over `count` pushed values.
+    fn get(&self, aggregation: Aggregation, count: u32) -> f32 {
+        let n = count as f64;
+        match aggregation {
+            Aggregation::Mean => (self.sum / n) as f32,
+            Aggregation::Max => self.max.unwrap_or(0.0),
+            Aggregation::Last => self.last,
+            Aggregation::RmsOfSquares => f64::sqrt(self.sum_sq / n) as f32,
         }
-        Ok(())
+    }
+}
+
+/// Combines the readings of the measurements of a save period into the reading of the period.
+///
+/// Every measurement counts the same, whatever order they come in. Each metric is combined by
+/// its Aggregation in `aggregations`, and the kWh are summed. The quality is the lowest one, the
+/// flags are set if any measurement set them, and the timestamp is that of the last measurement.
+/// Reset it when the period is saved.
+#[derive(Debug, Clone, Default)]
+pub struct Accumulator {
+    pub aggregations: Aggregations,
+    count: u32,
+    metrics: [MetricAccumulator; 6],
+    kwh: f32,
+    // Sum of the reactive power, whose sign makes the period is_leading.
+    reactive_power: f32,
+    // The quality, flags and timestamp of the readings so far, combined as they come.
+    last: CTReading,
+}
+
+impl Accumulator {
+    /// Add the reading of a measurement.
+    pub(crate) fn push(&mut self, reading: &CTReading) {
+        self.count += 1;
+        for (metric, value) in self.metrics.iter_mut().zip(reading.aggregated_values()) {
+            metric.push(value);
+        }
+        self.kwh += reading.kwh;
+        self.reactive_power += reading.reactive_power();
+        self.push_flags(reading);
     }
 
-    // Store the given time to storage
-    pub(crate) fn store_time(&mut self, time: u64) -> anyhow::Result<()> {
-        let mut file = if (MAX_TIME_STORAGE_SIZE as i64
-            - fs::metadata("/littlefs/time")?.len() as i64)
-            < std::mem::size_of::<u64>() as i64
+    /// Add the quality, flags and timestamp of a measurement left out of the period, see
+    /// ZeroCrossTimeout::Skip.
+    pub(crate) fn push_flags(&mut self, reading: &CTReading) {
+        self.last.quality = match (self.last.quality, reading.quality) {
+            (Some(a), Some(b)) => Some(u8::min(a, b)),
+            (a, b) => a.or(b),
+        };
+        self.last.reduced_precision |= reading.reduced_precision;
+        self.last.clipped |= reading.clipped;
+        self.last.apparent_only |= reading.apparent_only;
+        self.last.under_sampled |= reading.under_sampled;
+        self.last.unsynced_time |= reading.unsynced_time;
+        self.last.implausible_voltage |= reading.implausible_voltage;
+        self.last.stuck |= reading.stuck;
+        self.last.timestamp = reading.timestamp;
+        self.last.uptime = reading.uptime;
+    }
+
+    /// The reading of the period so far, a zero reading with any flags of push_flags before the
+    /// first push.
+    pub(crate) fn finalize(&self) -> CTReading {
+        if self.count == 0 {
+            return self.last.clone();
+        }
+        let mut values = [0.0; 6];
+        for ((value, metric), aggregation) in values
+            .iter_mut()
+            .zip(&self.metrics)
+            .zip(self.aggregations.as_array())
         {
-            // If the file is full, create a new one overwriting the previous file.
-            fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open("/littlefs/time")?
-        } else {
-            fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open("/littlefs/time")?
-        };
-
-        file.seek(SeekFrom::End(0))?;
-        file.write_all(&time.to_le_bytes())?;
-        file.flush()?;
-        info!("Wrote time {} to storage.", time);
-        info!("Time file size {}", file.metadata()?.len());
-        Ok(())
+            *value = metric.get(aggregation, self.count);
+        }
+        let [real_power, apparent_power, i_rms, v_rms, v_peak, i_peak] = values;
+        CTReading {
+            real_power,
+            apparent_power,
+            i_rms,
+            v_rms,
+            kwh: self.kwh,
+            v_peak,
+            i_peak,
+            leading: self.reactive_power < 0.0,
+            ..self.last.clone()
+        }
     }
 
-    // Retrieve the latest token from storage
-    pub(crate) fn retrieve_token(&mut self) -> anyhow::Result<[u8; ACCESS_TOKEN_SIZE]> {
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("/littlefs/token")?;
-        let mut token = [0_u8; ACCESS_TOKEN_SIZE];
-        file.read_exact(&mut token)?;
-        Ok(token)
-    }
-
-    // Store the given token to storage
-    pub(crate) fn store_token(&mut self, token: &[u8]) -> anyhow::Result<()> {
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open("/littlefs/token")?;
-        file.write_all(token)?;
-        log::info!(
-            "Stored toke: {} to storage.",
-            String::from_utf8(token.to_vec())?
-        );
-        Ok(())
+    /// Start over for the next period, keeping the aggregations.
+    pub(crate) fn reset(&mut self) {
+        *self = Accumulator {
+            aggregations: self.aggregations,
+            ..Default::default()
+        };
     }
+}
 
-    // Send reading shards one by one into this writer.
-    // before deleting a shard, we make sure that he have flushed thr writer.
-    pub(crate) fn send_readings_shards(
-        &mut self,
-        writer: &mut EspHttpResponseWrite,
-    ) -> anyhow::Result<()> {
-        let mut sorted_shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
-        sorted_shard_ids.sort();
-        // a fixed size buffer to avoid stack overflow
-        let mut buf = [0_u8; CT_READING_SIZE];
-        for shard_id in sorted_shard_ids {
-            if let Ok(mut file) = fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(format!("/littlefs/ct_readings/{}", shard_id))
-            {
-                while file.read_exact(&mut buf).is_ok() {
-                    writer.write(&buf)?;
-                }
-                writer.flush()?;
-                info!(
-                    "Sent shard {}",
-                    format!("/littlefs/ct_readings/{}", shard_id)
-                );
-            }
+/// Header of the rows of CTReading::write_csv.
+pub(crate) const CSV_HEADER: &str =
+    "id,timestamp,sequence,real_power,apparent_power,i_rms,v_rms,kwh\n";
+
+/// Price of energy per kWh by when it was used, see CTReading::cost.
+///
+/// A plain f32 is a flat rate. Time of use tariffs implement it with a schedule.
+pub trait Tariff {
+    /// Rate at `timestamp`, in ms since the epoch like CTReading timestamps.
+    fn rate_per_kwh(&self, timestamp: u64) -> f32;
+}
+
+impl Tariff for f32 {
+    fn rate_per_kwh(&self, _timestamp: u64) -> f32 {
+        *self
+    }
+}
+
+/// Time of use period of a tariff, see TouSchedule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TouPeriod {
+    Peak,
+    Shoulder,
+    OffPeak,
+}
+
+/// Which time of use period each hour of the day belongs to.
+#[derive(Debug, Clone, Copy)]
+pub struct TouSchedule {
+    /// Period of each local hour, from 0:00 to 23:00.
+    pub hours: [TouPeriod; 24],
+    /// Offset of the local time from the UTC of the timestamps, in minutes.
+    pub utc_offset_minutes: i32,
+}
+
+impl Default for TouSchedule {
+    /// Peak from 17:00 to 21:00, shoulder from 7:00 to 17:00 and 21:00 to 22:00, off-peak the rest,
+    /// in UTC.
+    fn default() -> Self {
+        let mut hours = [TouPeriod::OffPeak; 24];
+        for (hour, period) in hours.iter_mut().enumerate() {
+            *period = match hour {
+                17..=20 => TouPeriod::Peak,
+                7..=16 | 21 => TouPeriod::Shoulder,
+                _ => TouPeriod::OffPeak,
+            };
+        }
+        TouSchedule {
+            hours,
+            utc_offset_minutes: 0,
         }
-        Ok(())
     }
+}
 
-    fn ct_reading_to_le_bytes(ct: &CT) -> anyhow::Result<[u8; CT_READING_SIZE]> {
-        let mut buf = [0_u8; CT_READING_SIZE];
-        let mut pos = 0;
-        pos += add_u16_to_buf(&ct.id, &mut buf, &pos)?;
-        pos += add_f32_to_buf(&ct.reading.real_power, &mut buf, &pos)?;
-        pos += add_f32_to_buf(&ct.reading.apparent_power, &mut buf, &pos)?;
-        pos += add_f32_to_buf(&ct.reading.i_rms, &mut buf, &pos)?;
-        pos += add_f32_to_buf(&ct.reading.v_rms, &mut buf, &pos)?;
-        pos += add_f32_to_buf(&ct.reading.kwh, &mut buf, &pos)?;
-        add_u64_to_buf(&ct.reading.timestamp, &mut buf, &pos)?;
-        Ok(buf)
+impl TouSchedule {
+    /// Period of the local hour of `timestamp`, in ms since the epoch.
+    pub(crate) fn period_at(&self, timestamp: u64) -> TouPeriod {
+        let local_minutes = (timestamp / 60_000) as i64 + self.utc_offset_minutes as i64;
+        let hour = (local_minutes / 60).rem_euclid(24) as usize;
+        self.hours[hour]
     }
 }
 
-impl CT {
-    pub(crate) fn calculate_energy(
-        &mut self,
-        powered_adc1: &mut PoweredAdc<ADC1>,
-        crossing: u32,
-        timeout: std::time::Duration,
-    ) -> anyhow::Result<()> {
-        // Variables
-        let mut cross_count = 0;
-        let mut n_samples: u32 = 0;
+/// kWh of all CTs per time of use period, see CTStorage::tou_totals.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TouTotals {
+    pub peak: f64,
+    pub shoulder: f64,
+    pub off_peak: f64,
+}
 
-        // Used for delay/phase compensation
-        let mut filtered_v;
-        let mut last_filtered_v = 0.0;
-        let mut filtered_i;
-        let mut last_filtered_i = 0.0;
+impl TouTotals {
+    pub(crate) fn add(&mut self, reading: &CTReading, schedule: &TouSchedule) {
+        let kwh = reading.kwh as f64;
+        match schedule.period_at(reading.timestamp) {
+            TouPeriod::Peak => self.peak += kwh,
+            TouPeriod::Shoulder => self.shoulder += kwh,
+            TouPeriod::OffPeak => self.off_peak += kwh,
+        }
+    }
 
-        let mut sample_v: u16 = 0;
-        let mut sample_i: u16 = 0;
-        let mut offset_v: f32 = self.voltage_pin.offset_v as f32;
-        let mut offset_i: f32 = self.current_pin.offset_i as f32;
+    pub(crate) fn totals(&self) -> [&f64; 3] {
+        [&self.peak, &self.shoulder, &self.off_peak]
+    }
 
-        let mut min_sample_i: u16 = MAX_MV_ATTEN_11;
-        let mut min_sample_v: u16 = MAX_MV_ATTEN_11;
-        let mut max_sample_i: u16 = 0;
-        let mut max_sample_v: u16 = 0;
+    pub(crate) fn totals_mut(&mut self) -> [&mut f64; 3] {
+        [&mut self.peak, &mut self.shoulder, &mut self.off_peak]
+    }
+}
 
-        let (mut sum_v, mut sum_i, mut sum_p) = (0.0, 0.0, 0.0);
-        let mut check_v_cross = false;
-        let mut last_v_cross;
+/// Smallest absolute change of each metric that counts as significant, see significant_change.
+#[derive(Debug, Clone, Copy)]
+pub struct ChangeThresholds {
+    /// In W.
+    pub real_power: f32,
+    /// In VA.
+    pub apparent_power: f32,
+    /// In A.
+    pub i_rms: f32,
+    /// In V.
+    pub v_rms: f32,
+    /// In kWh.
+    pub kwh: f32,
+}
 
-        let mut start = std::time::Instant::now(); // start.elapsed() makes sure it doesnt get stuck in the loop if there is an error.
-        let mut start_v = 0;
+impl Default for ChangeThresholds {
+    fn default() -> Self {
+        ChangeThresholds {
+            real_power: 10.0,
+            apparent_power: 10.0,
+            i_rms: 0.05,
+            v_rms: 2.0,
+            kwh: 0.01,
+        }
+    }
+}
 
-        // 1) Waits for the waveform to be close to 'zero' (mid-scale adc) part in sin curve.
-        loop {
-            start_v = powered_adc1
-                .read(&mut self.voltage_pin.pin)
-                .unwrap_or(start_v);
+/// Whether any metric of `curr` is more than its threshold away from `prev`.
+///
+/// For report by exception: publish a reading only if it changed significantly since the last
+/// published one, and keep `prev` at that one, so that slow drifts still get reported once they
+/// add up. A metric that turns into or out of NaN always counts as changed.
+pub(crate) fn significant_change(
+    prev: &CTReading,
+    curr: &CTReading,
+    thresholds: &ChangeThresholds,
+) -> bool {
+    let metrics = [
+        (prev.real_power, curr.real_power, thresholds.real_power),
+        (
+            prev.apparent_power,
+            curr.apparent_power,
+            thresholds.apparent_power,
+        ),
+        (prev.i_rms, curr.i_rms, thresholds.i_rms),
+        (prev.v_rms, curr.v_rms, thresholds.v_rms),
+        (prev.kwh, curr.kwh, thresholds.kwh),
+    ];
+    metrics.iter().any(|&(prev, curr, threshold)| {
+        if prev.is_nan() || curr.is_nan() {
+            prev.is_nan() != curr.is_nan()
+        } else {
+            f32::abs(curr - prev) > threshold
+        }
+    })
+}
 
-            if ((start_v as f32) < MAX_MV_ATTEN_11 as f32 * 0.55)
-                && ((start_v as f32) > MAX_MV_ATTEN_11 as f32 * 0.45)
-            {
-                break;
-            }
-            if start.elapsed() > timeout {
-                break;
-            }
+/// Min, max and mean of one metric over the records of a shard.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricSummary {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+}
+
+impl MetricSummary {
+    // Fold the value of the `n`th record (counting from 1) into the summary.
+    pub(crate) fn add(&mut self, value: f32, n: usize) {
+        if n == 1 {
+            *self = MetricSummary {
+                min: value,
+                max: value,
+                mean: value,
+            };
+        } else {
+            self.min = f32::min(self.min, value);
+            self.max = f32::max(self.max, value);
+            self.mean += (value - self.mean) / n as f32;
         }
-        // 2) Main measurement loop
-        start = std::time::Instant::now();
-        while (cross_count < crossing) && (start.elapsed() < timeout) {
-            // A) Read in raw voltage and current samples
-            sample_i = powered_adc1
-                .read(&mut self.current_pin.pin)
-                .unwrap_or(sample_i);
-            sample_v = powered_adc1
-                .read(&mut self.voltage_pin.pin)
-                .unwrap_or(sample_v);
-
-            // B) Apply digital low pass filters to extract the 2.5 V or 1.65 V dc offset,
-            //     then subtract this - signal is now centred on 0 counts.
-            offset_i = offset_i + ((sample_i as f32 - offset_i) / 512.0);
-            filtered_i = sample_i as f32 - offset_i;
-
-            offset_v = offset_v + ((sample_v as f32 - offset_v) / 512.0);
-            filtered_v = sample_v as f32 - offset_v;
-
-            // Ignore noise
-            if f32::abs(last_filtered_v - filtered_v) < NOISE_THRESHOLD {
-                min_sample_v = u16::min(min_sample_v, sample_v);
-                max_sample_v = u16::max(max_sample_v, sample_v);
-            }
-            if f32::abs(last_filtered_i - filtered_i) < NOISE_THRESHOLD {
-                min_sample_i = u16::min(min_sample_i, sample_i);
-                max_sample_i = u16::max(max_sample_i, sample_i);
-            }
-
-            // C) RMS
-            sum_v += filtered_v * filtered_v;
-            sum_i += filtered_i * filtered_i;
-
-            // E) Phase calibration
-            let phase_shift_v =
-                last_filtered_v + self.voltage_pin.phase_cal * (filtered_v - last_filtered_v);
-
-            // F) Instantaneous power calc
-            sum_p += phase_shift_v * filtered_i;
-
-            // G) Find the number of times the voltage has crossed the initial voltage
-            //    - every 2 crosses we will have sampled 1 wavelength
-            //    - so this method allows us to sample an integer number of half wavelengths which increases accuracy
-            last_v_cross = check_v_cross;
-            if sample_v > start_v {
-                check_v_cross = true;
-            } else {
-                check_v_cross = false;
-            }
-            if n_samples == 0 {
-                last_v_cross = check_v_cross;
-            }
+    }
+}
 
-            if last_v_cross != check_v_cross {
-                cross_count += 1;
-            }
+/// Running count, sum and sum of squares of one metric.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricStats {
+    pub count: u64,
+    pub sum: f64,
+    pub sum_sq: f64,
+}
+
+impl MetricStats {
+    fn add(&mut self, value: f32) {
+        let value = value as f64;
+        self.count += 1;
+        self.sum += value;
+        self.sum_sq += value * value;
+    }
 
-            n_samples += 1;
-            last_filtered_v = filtered_v;
-            last_filtered_i = filtered_i;
+    /// Mean of all values, 0 if there are none.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
         }
+        self.sum / self.count as f64
+    }
 
-        // Improve the approximation for mid point (dc offset)
-        offset_i = (offset_i + ((max_sample_i + min_sample_i) as f32 / 2.0)) / 2.0;
-        offset_v = (offset_v + ((max_sample_v + min_sample_v) as f32 / 2.0)) / 2.0;
+    /// Population standard deviation of all values, 0 if there are none.
+    pub fn std_dev(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        f64::sqrt(f64::max(self.sum_sq / self.count as f64 - mean * mean, 0.0))
+    }
+}
 
-        self.current_pin.offset_i = offset_i;
-        self.voltage_pin.offset_v = offset_v;
+/// Statistics over every record ever saved, of all CTs, see CTStorage::lifetime_stats.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LifetimeStats {
+    pub real_power: MetricStats,
+    pub apparent_power: MetricStats,
+    pub i_rms: MetricStats,
+    pub v_rms: MetricStats,
+    pub kwh: MetricStats,
+    /// Highest real power in W of any record, the import with the default power convention.
+    pub peak_demand: f32,
+    /// Timestamp in ms of the record with the peak demand.
+    pub peak_demand_at: u64,
+}
+
+impl LifetimeStats {
+    pub(crate) fn add(&mut self, reading: &CTReading) {
+        self.real_power.add(reading.real_power);
+        // Clipping cuts the i_rms and the apparent power, see ClippedPolicy.
+        if !reading.clipped {
+            self.apparent_power.add(reading.apparent_power);
+            self.i_rms.add(reading.i_rms);
+        }
+        self.v_rms.add(reading.v_rms);
+        self.kwh.add(reading.kwh);
+        if reading.real_power > self.peak_demand {
+            self.peak_demand = reading.real_power;
+            self.peak_demand_at = reading.timestamp;
+        }
+    }
 
-        let v_ratio = self.voltage_pin.vcal * (SUPPLY_VOLTAGE / (MAX_MV_ATTEN_11 as f32));
-        let v_rms = v_ratio * f32::sqrt(sum_v / n_samples as f32);
+    pub(crate) fn metrics(&self) -> [&MetricStats; 5] {
+        [
+            &self.real_power,
+            &self.apparent_power,
+            &self.i_rms,
+            &self.v_rms,
+            &self.kwh,
+        ]
+    }
 
-        let i_ratio = self.current_pin.ical * (SUPPLY_VOLTAGE / (MAX_MV_ATTEN_11 as f32));
-        let i_rms = i_ratio * f32::sqrt(sum_i / n_samples as f32);
+    pub(crate) fn metrics_mut(&mut self) -> [&mut MetricStats; 5] {
+        [
+            &mut self.real_power,
+            &mut self.apparent_power,
+            &mut self.i_rms,
+            &mut self.v_rms,
+            &mut self.kwh,
+        ]
+    }
+}
 
-        // Calculate power values
-        let real_power = f32::abs(v_ratio * i_ratio * (sum_p / n_samples as f32));
-        let apparent_power = v_rms * i_rms;
-        let kwh =
-            (real_power / 1000.0) * start.elapsed().as_secs_f32() / SAVE_PERIOD_TIMEOUT as f32;
-        let new_reading = CTReading {
-            real_power,
-            apparent_power,
-            kwh,
-            i_rms,
-            v_rms,
-            timestamp: now().as_millis() as u64,
+/// Overview of the records stored in a shard, see CTStorage::shard_summary.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShardSummary {
+    /// Number of complete records. The metrics are all 0 if there are none.
+    pub records: usize,
+    /// Bytes after the last complete record. Anything but 0 means the shard is cut off or corrupt.
+    pub trailing_bytes: usize,
+    pub real_power: MetricSummary,
+    pub i_rms: MetricSummary,
+    pub v_rms: MetricSummary,
+    pub total_kwh: f64,
+}
+
+/// A shard in the readings directory, see CTStorage::list_shards.
+#[derive(Debug, Clone, Copy)]
+pub struct ShardInfo {
+    pub id: i32,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Number of complete records.
+    pub records: usize,
+    /// Whether the next saves are appended to this shard.
+    pub active: bool,
+}
+
+/// State of the storage after boot, see CTStorage::boot_report.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BootReport {
+    /// Whether the readings directory could be opened. If not, nothing else was loaded.
+    pub storage_available: bool,
+    /// Shard the next saves are appended to.
+    pub newest_shard: i32,
+    pub shards: usize,
+    pub records: usize,
+    /// Shards that end with an incomplete record, see ShardSummary::trailing_bytes.
+    pub damaged_shards: usize,
+    /// Bytes of an incomplete record cut off the newest shard, see ShardRecovery::Truncate.
+    pub truncated_bytes: usize,
+    /// Whether the newest shard ended with an incomplete record and was left for a new one.
+    pub rolled_shard: bool,
+    /// Complete records that don't make sense, see CTStorage::set_integrity_scan. None unless the
+    /// scan is on.
+    pub corrupt_records: Option<usize>,
+    /// Shards that failed to read, left out of records and corrupt_records.
+    pub unreadable_shards: usize,
+    /// Sequence number of the last save.
+    pub sequence: u32,
+    /// Whether the system time was restored from the stored one.
+    pub time_restored: bool,
+    /// CTs with a stored calibration, the others run on the compiled defaults.
+    pub calibrations_loaded: usize,
+    /// What became of the stored calibration.
+    pub calibration: CalibrationLoad,
+    /// CTs with a stored energy total, the others start from 0 kWh.
+    pub energy_totals_loaded: usize,
+    /// Whether the lifetime statistics were missing or damaged and rebuilt from the shards.
+    pub lifetime_stats_rebuilt: bool,
+    /// Whether the time of use totals were missing or damaged and rebuilt from the shards.
+    pub tou_totals_rebuilt: bool,
+}
+
+/// Deviation of a CT reading from a reference meter.
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrationError {
+    /// (measured - reference) / reference of the real power, in percent.
+    pub power_error_percent: f32,
+    /// (measured - reference) / reference of the rms voltage, in percent.
+    pub voltage_error_percent: f32,
+    /// Multiply vcal by this to match the reference voltage.
+    pub vcal_factor: f32,
+    /// Multiply ical by this, together with vcal_factor, to match the reference power.
+    pub ical_factor: f32,
+}
+
+impl CalibrationError {
+    /// Deviation of `reading` from the reference, see CT::compare_to_reference. Fails for a
+    /// reference that is not a positive number, the errors are relative to it.
+    pub(crate) fn between(
+        reading: &CTReading,
+        ref_watts: f32,
+        ref_volts: f32,
+    ) -> anyhow::Result<Self> {
+        if !(ref_watts > 0.0 && ref_watts.is_finite() && ref_volts > 0.0 && ref_volts.is_finite()) {
+            anyhow::bail!(
+                "reference of {} W at {} V, both must be positive",
+                ref_watts,
+                ref_volts
+            );
+        }
+        // The sign follows the power convention and the direction of the CT, not the calibration.
+        let real_power = f32::abs(reading.real_power);
+        let v_rms = reading.v_rms;
+        let vcal_factor = if v_rms > 0.0 { ref_volts / v_rms } else { 1.0 };
+        let power_factor = if real_power > 0.0 {
+            ref_watts / real_power
+        } else {
+            1.0
         };
-        self.reading += new_reading;
-        Ok(())
+        Ok(CalibrationError {
+            power_error_percent: (real_power - ref_watts) / ref_watts * 100.0,
+            voltage_error_percent: (v_rms - ref_volts) / ref_volts * 100.0,
+            vcal_factor,
+            ical_factor: power_factor / vcal_factor,
+        })
     }
 
-    pub(crate) fn init(pins: Pins) -> anyhow::Result<[CT; AC_PHASE]> {
-        #[cfg(feature = "single-phase")]
-        {
-            Ok([CT {
-                id: 1,
-                current_pin: CurrentPin {
-                    pin: pins.gpio35.into_analog_atten_11db()?,
-                    ical: 102.0,
-                    offset_i: 1066.0,
-                },
-                voltage_pin: VoltagePin {
-                    pin: pins.gpio34.into_analog_atten_11db()?,
-                    vcal: 232.5,
-                    phase_cal: 1.7,
-                    offset_v: 1288.0,
-                },
-                reading: CTReading {
-                    i_rms: 0.0,
-                    v_rms: 0.0,
-                    timestamp: 0,
-                    real_power: 0.0,
-                    apparent_power: 0.0,
-                    kwh: 0.0,
-                },
-            }])
+    /// The calibration that closes the gap to the reference, ready for CT::set_calibration.
+    pub(crate) fn corrected(&self, cal: Calibration) -> Calibration {
+        Calibration {
+            vcal: cal.vcal * self.vcal_factor,
+            ical: cal.ical * self.ical_factor,
+            phase_cal: cal.phase_cal,
         }
-        #[cfg(feature = "three-phase")]
-        {
-            Ok([
-                CT {
-                    id: 1,
-                    current_pin: CurrentPin {
-                        pin: pins.gpio32.into_analog_atten_11db()?,
-                        ical: 30.0,
-                        offset_i: 1066.0,
-                    },
-                    voltage_pin: VoltagePin {
-                        pin: pins.gpio39.into_analog_atten_11db()?,
-                        vcal: 219.25,
-                        phase_cal: 1.7,
-                        offset_v: 1288.0,
-                    },
-                    reading: CTReading {
-                        i_rms: 0.0,
-                        v_rms: 0.0,
-                        timestamp: 0,
-                        real_power: 0.0,
-                        apparent_power: 0.0,
-                        kwh: 0.0,
-                    },
-                },
-                CT {
-                    id: 2,
-                    current_pin: CurrentPin {
-                        pin: pins.gpio35.into_analog_atten_11db()?,
-                        ical: 30.0,
-                        offset_i: 1066.0,
-                    },
-                    voltage_pin: VoltagePin {
-                        pin: pins.gpio36.into_analog_atten_11db()?,
-                        vcal: 219.25,
-                        phase_cal: 1.7,
-                        offset_v: 1288.0,
-                    },
-                    reading: CTReading {
-                        i_rms: 0.0,
-                        v_rms: 0.0,
-                        timestamp: 0,
-                        real_power: 0.0,
-                        apparent_power: 0.0,
-                        kwh: 0.0,
-                    },
-                },
-                CT {
-                    id: 3,
-                    current_pin: CurrentPin {
-                        pin: pins.gpio34.into_analog_atten_11db()?,
-                        ical: 30.0,
-                        offset_i: 1066.0,
-                    },
-                    voltage_pin: VoltagePin {
-                        pin: pins.gpio33.into_analog_atten_11db()?,
-                        vcal: 219.25,
-                        phase_cal: 1.7,
-                        offset_v: 1288.0,
-                    },
-                    reading: CTReading {
-                        i_rms: 0.0,
-                        v_rms: 0.0,
-                        timestamp: 0,
-                        real_power: 0.0,
-                        apparent_power: 0.0,
-                        kwh: 0.0,
-                    },
-                },
-            ])
+    }
+}
+
+/// Noise of the signal of a CT with nothing to measure, see CT::measure_noise_floor.
+#[derive(Debug, Clone, Copy)]
+pub struct NoiseFloor {
+    /// Standard deviation of the filtered current samples, in the units of the raw samples.
+    pub i_raw: f32,
+    /// Standard deviation of the filtered voltage samples, in the units of the raw samples.
+    pub v_raw: f32,
+    /// i_raw in A, about the smallest current the CT can tell from noise.
+    pub i_rms: f32,
+    /// v_raw in V.
+    pub v_rms: f32,
+    /// Number of samples the deviations are over.
+    pub samples: usize,
+}
+
+/// Outcome of CT::self_test.
+#[derive(Debug, Clone, Copy)]
+pub struct SelfTestReport {
+    pub id: u16,
+    pub noise_floor: NoiseFloor,
+    /// Whether the current noise floor is at most MAX_NOISE_FLOOR.
+    pub passed: bool,
+}
+
+impl fmt::Display for BootReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.storage_available {
+            return write!(f, "storage unavailable");
+        }
+        write!(
+            f,
+            "{} records in {} shards ({} damaged, {} unreadable), newest shard {}, sequence {}",
+            self.records,
+            self.shards,
+            self.damaged_shards,
+            self.unreadable_shards,
+            self.newest_shard,
+            self.sequence
+        )?;
+        if self.truncated_bytes > 0 {
+            write!(f, ", {} bytes truncated", self.truncated_bytes)?;
+        }
+        if self.rolled_shard {
+            write!(f, ", rolled to a new shard")?;
+        }
+        if let Some(corrupt) = self.corrupt_records {
+            write!(f, ", {} corrupt records", corrupt)?;
+        }
+        write!(
+            f,
+            ", time restored: {}, calibration {:?} for {} CTs, energy totals for {} CTs",
+            self.time_restored,
+            self.calibration,
+            self.calibrations_loaded,
+            self.energy_totals_loaded
+        )?;
+        if self.lifetime_stats_rebuilt {
+            write!(f, ", lifetime stats rebuilt")?;
         }
+        if self.tou_totals_rebuilt {
+            write!(f, ", time of use totals rebuilt")?;
+        }
+        Ok(())
     }
+}
 
-    pub(crate) fn reset(&mut self) {
-        self.reading.reset();
+impl fmt::Display for NoiseFloor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.3} A ({:.1} raw), {:.3} V ({:.1} raw) over {} samples",
+            self.i_rms, self.i_raw, self.v_rms, self.v_raw, self.samples
+        )
     }
 }
 
-impl ops::AddAssign<CTReading> for CTReading {
-    fn add_assign(&mut self, rhs: CTReading) {
-        self.i_rms = (self.i_rms + rhs.i_rms) / 2.0;
-        self.v_rms = (self.v_rms + rhs.v_rms) / 2.0;
-        self.real_power = (self.real_power + rhs.real_power) / 2.0;
-        self.apparent_power = (self.apparent_power + rhs.apparent_power) / 2.0;
-        self.kwh = self.kwh + rhs.kwh;
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let outcome = if self.passed { "passed" } else { "failed" };
+        write!(
+            f,
+            "CT {} {}, noise floor {}",
+            self.id, outcome, self.noise_floor
+        )
     }
 }
 
-impl CTReading {
-    fn reset(&mut self) {
-        self.i_rms = 0.0;
-        self.v_rms = 0.0;
+/// What the last calculate_energy of a CT saw.
+#[derive(Default)]
+struct MeasurementDiagnostics {
+    /// Time between two samples in seconds, 0 before the first measurement.
+    sample_period: f32,
+    /// Mains period in seconds measured between rising crossings, 0 if unknown.
+    mains_period: f64,
+    /// Whether the current or voltage pin read the same value all measurement long.
+    channel_stuck: bool,
+    /// Peak to peak swing in mV of the (current, voltage) samples.
+    swing: (u16, u16),
+    /// Crossings (reached, requested).
+    crossings: (u32, u32),
+    /// How far in mV the dc offsets moved during the last measurement, the larger of both.
+    offset_convergence: f32,
+    /// Whether a dc offset is beyond max_offset_drift, so it is only warned about once.
+    offset_drifted: bool,
+    /// Measurements since the last whose diagnostics were logged at info, see log_every.
+    unlogged_measurements: u32,
+    /// Whether a fault was captured since set_fault_capture, only the first one is.
+    fault_captured: bool,
+    /// Raw (current, voltage) samples of the captured fault until CTStorage::store_fault_captures
+    /// stores them.
+    fault_capture: Option<Vec<(u16, u16)>>,
+    /// v_rms of the last measurement within plausible_voltage, None before the first one.
+    last_plausible_v_rms: Option<f32>,
+}
+
+// Filter state and running sums of a single calculate_energy pass.
+struct Measurement {
+    // Used for delay/phase compensation
+    last_filtered_v: f32,
+    last_filtered_i: f32,
+    offset_v: f32,
+    offset_i: f32,
+    phase_cal: f32,
+    // Smoothing factor of the anti-aliasing filter and its outputs.
+    lowpass_alpha: Option<f32>,
+    lowpass_v: f32,
+    lowpass_i: f32,
+    // Distance in mV from offset_v that counts as near zero, see is_near_zero.
+    zero_band: f32,
+
+    min_sample_i: u16,
+    min_sample_v: u16,
+    max_sample_i: u16,
+    max_sample_v: u16,
+
+    sum_v: f32,
+    sum_i: f32,
+    sum_p: f32,
+    // Sum of the cross products of the phase corrected voltage and the current with those of the
+    // sample before, it has the sign of the reactive power, see CTReading::is_leading.
+    sum_q: f32,
+    last_shift_v: f32,
+    n_samples: u32,
+    // Fraction of samples cut off the sums by trim_overshoot.
+    trimmed_samples: f32,
+    // Sums of the raw (current, voltage) samples and of their squares, for raw_variance.
+    sum_raw: [f64; 2],
+    sum_raw_sq: [f64; 2],
+    // Raw voltage and sum terms (v, i, p) of the last sample, those of the sample before it and
+    // of the first sample of the slice, and where between the previous and the last sample the
+    // voltage crossed, if it did.
+    last_sample_v: u16,
+    last_terms: [f32; 3],
+    previous_terms: [f32; 3],
+    first_terms: Option<[f32; 3]>,
+    crossing_fraction: Option<f32>,
+    // Whether the sample that starts a slice is added too, see MeasurementConfig::cycle_correction.
+    cycle_correction: bool,
+
+    // Voltage the crossings are counted against.
+    start_v: u16,
+    check_v_cross: bool,
+    cross_count: u32,
+    // Whether the next sample is the first of a slice, see start_slice.
+    slice_start: bool,
+
+    // Whether the voltage never came near zero to start on, see ZeroCrossTimeout.
+    voltage_lost: bool,
+
+    // Counters for the quality score.
+    requested_crossings: u32,
+    clipped_samples: u32,
+    noisy_samples: u32,
+    // One-shot reads and those that failed or timed out, see MAX_FAILED_READS.
+    reads: u32,
+    failed_reads: u32,
+
+    // Timing of the rising crossings, for the mains period. Only crossings of the same slice are
+    // timed against each other.
+    slice_rising_crossing: Option<std::time::Instant>,
+    sum_mains_periods: f64,
+    n_mains_periods: u32,
+
+    // Raw samples around the first fault, None unless CT::set_fault_capture is on.
+    fault_ring: Option<FaultRing>,
+}
+
+// The last raw (current, voltage) samples of a measurement, frozen around its first fault, see
+// CT::set_fault_capture.
+struct FaultRing {
+    samples: VecDeque<(u16, u16)>,
+    capacity: usize,
+    // Distance in mV of a current sample from its dc offset that counts as overcurrent.
+    max_deviation_i: Option<f32>,
+    // Samples still kept after the fault, None until there is one.
+    remaining: Option<usize>,
+}
+
+impl FaultRing {
+    fn new(capacity: usize, max_deviation_i: Option<f32>) -> Self {
+        FaultRing {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            max_deviation_i,
+            remaining: None,
+        }
+    }
+
+    // Keep the sample, dropping the oldest. Half the ring is kept from before the first fault and
+    // half from after it, then it stops taking samples.
+    fn push(&mut self, sample: (u16, u16), fault: bool) {
+        match self.remaining.as_mut() {
+            Some(0) => return,
+            Some(remaining) => *remaining -= 1,
+            None if fault => self.remaining = Some(self.capacity / 2),
+            None => {}
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    fn is_tripped(&self) -> bool {
+        self.remaining.is_some()
+    }
+}
+
+impl Measurement {
+    fn new(
+        offset_i: f32,
+        offset_v: f32,
+        phase_cal: f32,
+        lowpass_alpha: Option<f32>,
+        zero_band: f32,
+    ) -> Self {
+        Measurement {
+            last_filtered_v: 0.0,
+            last_filtered_i: 0.0,
+            offset_v,
+            offset_i,
+            phase_cal,
+            lowpass_alpha,
+            lowpass_v: 0.0,
+            lowpass_i: 0.0,
+            zero_band,
+            min_sample_i: MAX_MV_ATTEN_11,
+            min_sample_v: MAX_MV_ATTEN_11,
+            max_sample_i: 0,
+            max_sample_v: 0,
+            sum_v: 0.0,
+            sum_i: 0.0,
+            sum_p: 0.0,
+            sum_q: 0.0,
+            last_shift_v: 0.0,
+            n_samples: 0,
+            trimmed_samples: 0.0,
+            sum_raw: [0.0; 2],
+            sum_raw_sq: [0.0; 2],
+            last_sample_v: 0,
+            last_terms: [0.0; 3],
+            previous_terms: [0.0; 3],
+            first_terms: None,
+            crossing_fraction: None,
+            cycle_correction: false,
+            start_v: 0,
+            check_v_cross: false,
+            cross_count: 0,
+            slice_start: true,
+            voltage_lost: false,
+            requested_crossings: 0,
+            clipped_samples: 0,
+            noisy_samples: 0,
+            reads: 0,
+            failed_reads: 0,
+            slice_rising_crossing: None,
+            sum_mains_periods: 0.0,
+            n_mains_periods: 0,
+            fault_ring: None,
+        }
+    }
+
+    // The sample of a read, or `previous` again if it failed.
+    fn sample_or(&mut self, read: anyhow::Result<u16>, previous: u16) -> u16 {
+        self.reads += 1;
+        read.unwrap_or_else(|_| {
+            self.failed_reads += 1;
+            previous
+        })
+    }
+
+    // Whether so many reads failed that the repeated samples in their place skew the result.
+    fn too_many_failed_reads(&self) -> bool {
+        self.failed_reads as f32 > MAX_FAILED_READS * self.reads as f32
+    }
+
+    // Continue the measurement with a new run of samples starting at `start_v`. The samples of a
+    // slice must directly follow each other, but there may be a gap between two slices.
+    fn start_slice(&mut self, start_v: u16) {
+        self.start_v = start_v;
+        self.last_sample_v = start_v;
+        self.first_terms = None;
+        self.crossing_fraction = None;
+        self.slice_start = true;
+        self.slice_rising_crossing = None;
+    }
+
+    // Turn the sums of the slice into the trapezoidal integral from its first sample to the last
+    // crossing, see MeasurementConfig::cycle_correction: the first sample counts half, and of the
+    // interval the last crossing falls in only the part before it, with the terms interpolated
+    // linearly. A slice that didn't end on a crossing ends at its last sample, counting half too.
+    fn trim_edges(&mut self) {
+        let first = match self.first_terms.take() {
+            Some(first) => first,
+            None => return,
+        };
+        let mut correction = first.map(|term| -0.5 * term);
+        let mut trimmed = 0.5;
+        match self.crossing_fraction.take() {
+            Some(fraction) => {
+                let terms = self.previous_terms.iter().zip(&self.last_terms);
+                for (correction, (&previous, &last)) in correction.iter_mut().zip(terms) {
+                    let at_crossing = previous + fraction * (last - previous);
+                    *correction +=
+                        0.5 * fraction * (previous + at_crossing) - 0.5 * previous - last;
+                }
+                trimmed += 1.5 - fraction;
+            }
+            None => {
+                for (correction, &last) in correction.iter_mut().zip(&self.last_terms) {
+                    *correction -= 0.5 * last;
+                }
+                trimmed += 0.5;
+            }
+        }
+        self.sum_v += correction[0];
+        self.sum_i += correction[1];
+        self.sum_p += correction[2];
+        self.trimmed_samples += trimmed;
+    }
+
+    // Variance in mV^2 of the raw (current, voltage) samples, 0 without samples.
+    fn raw_variance(&self) -> [f64; 2] {
+        if self.n_samples == 0 {
+            return [0.0; 2];
+        }
+        let n = self.n_samples as f64;
+        let variance = |k: usize| {
+            let mean = self.sum_raw[k] / n;
+            f64::max(self.sum_raw_sq[k] / n - mean * mean, 0.0)
+        };
+        [variance(0), variance(1)]
+    }
+
+    // Number of samples the sums are averaged over.
+    fn effective_samples(&self) -> f32 {
+        self.n_samples as f32 - self.trimmed_samples
+    }
+
+    // Whether a sample is at either end of the ADC range, where the signal may be cut off.
+    fn is_clipped(sample: u16) -> bool {
+        sample <= CLIP_MARGIN || sample >= MAX_MV_ATTEN_11 - CLIP_MARGIN
+    }
+
+    // Quality score of the samples so far, see CTReading::quality.
+    fn quality(&self) -> u8 {
+        if self.n_samples == 0 || self.requested_crossings == 0 {
+            return 0;
+        }
+        let n = self.n_samples as f32;
+        let samples = f32::min(
+            n / (self.requested_crossings * MIN_SAMPLES_PER_CROSSING) as f32,
+            1.0,
+        );
+        let clipping = 1.0 - f32::min(self.clipped_samples as f32 / n * 10.0, 1.0);
+        // Without a voltage there are no crossings to count.
+        let crossings = if self.voltage_lost {
+            1.0
+        } else {
+            f32::min(
+                self.cross_count as f32 / self.requested_crossings as f32,
+                1.0,
+            )
+        };
+        let noise = 1.0 - f32::min(self.noisy_samples as f32 / (2.0 * n), 1.0);
+        (100.0 * samples * clipping * crossings * noise).round() as u8
+    }
+
+    // Whether the voltage is close to the 'zero' part of the sin curve. That is the dc offset of
+    // the voltage, which follows a bias network that doesn't sit exactly at mid-scale.
+    fn is_near_zero(&self, sample_v: u16) -> bool {
+        f32::abs(sample_v as f32 - self.offset_v) < self.zero_band
+    }
+
+    fn add_sample(&mut self, sample_i: u16, sample_v: u16) {
+        // B) Apply digital low pass filters to extract the 2.5 V or 1.65 V dc offset,
+        //     then subtract this - signal is now centred on 0 counts.
+        self.offset_i = self.offset_i + ((sample_i as f32 - self.offset_i) / 512.0);
+        let filtered_i = sample_i as f32 - self.offset_i;
+
+        self.offset_v = self.offset_v + ((sample_v as f32 - self.offset_v) / 512.0);
+        let filtered_v = sample_v as f32 - self.offset_v;
+
+        // Optional anti-aliasing low-pass filter
+        let (filtered_i, filtered_v) = match self.lowpass_alpha {
+            Some(alpha) => {
+                self.lowpass_i += alpha * (filtered_i - self.lowpass_i);
+                self.lowpass_v += alpha * (filtered_v - self.lowpass_v);
+                (self.lowpass_i, self.lowpass_v)
+            }
+            None => (filtered_i, filtered_v),
+        };
+
+        // Ignore noise
+        if f32::abs(self.last_filtered_v - filtered_v) < NOISE_THRESHOLD {
+            self.min_sample_v = u16::min(self.min_sample_v, sample_v);
+            self.max_sample_v = u16::max(self.max_sample_v, sample_v);
+        } else {
+            self.noisy_samples += 1;
+        }
+        if f32::abs(self.last_filtered_i - filtered_i) < NOISE_THRESHOLD {
+            self.min_sample_i = u16::min(self.min_sample_i, sample_i);
+            self.max_sample_i = u16::max(self.max_sample_i, sample_i);
+        } else {
+            self.noisy_samples += 1;
+        }
+        let clipped = Measurement::is_clipped(sample_i) || Measurement::is_clipped(sample_v);
+        if clipped {
+            self.clipped_samples += 1;
+        }
+        if let Some(ring) = self.fault_ring.as_mut() {
+            let deviation_i = f32::abs(sample_i as f32 - self.offset_i);
+            let overcurrent = matches!(ring.max_deviation_i, Some(max) if deviation_i > max);
+            ring.push((sample_i, sample_v), clipped || overcurrent);
+        }
+
+        for (k, sample) in [sample_i, sample_v].iter().enumerate() {
+            let sample = *sample as f64;
+            self.sum_raw[k] += sample;
+            self.sum_raw_sq[k] += sample * sample;
+        }
+
+        // C) RMS
+        self.sum_v += filtered_v * filtered_v;
+        self.sum_i += filtered_i * filtered_i;
+
+        // E) Phase calibration
+        //    The last sample of the previous slice is too old to interpolate with.
+        if self.slice_start && self.n_samples > 0 {
+            self.last_filtered_v = filtered_v;
+        }
+        let phase_shift_v =
+            self.last_filtered_v + self.phase_cal * (filtered_v - self.last_filtered_v);
+
+        // F) Instantaneous power calc
+        self.sum_p += phase_shift_v * filtered_i;
+        // With the current lagging, each current sample is closer in phase to the voltage sample
+        // before it than the voltage sample is to the current before. The sample before a slice
+        // is too old to pair with.
+        if !self.slice_start {
+            self.sum_q += self.last_shift_v * filtered_i - phase_shift_v * self.last_filtered_i;
+        }
+        self.last_shift_v = phase_shift_v;
+
+        // G) Find the number of times the voltage has crossed the initial voltage
+        //    - every 2 crosses we will have sampled 1 wavelength
+        //    - so this method allows us to sample an integer number of half wavelengths which increases accuracy
+        let mut last_v_cross = self.check_v_cross;
+        self.check_v_cross = sample_v > self.start_v;
+        if self.slice_start {
+            last_v_cross = self.check_v_cross;
+            self.slice_start = false;
+        }
+
+        self.crossing_fraction = None;
+        if last_v_cross != self.check_v_cross {
+            self.cross_count += 1;
+            let (previous, last) = (self.last_sample_v as f32, sample_v as f32);
+            if last != previous {
+                let fraction = (self.start_v as f32 - previous) / (last - previous);
+                self.crossing_fraction = Some(fraction.clamp(0.0, 1.0));
+            }
+            if self.check_v_cross {
+                let now = std::time::Instant::now();
+                if let Some(previous) = self.slice_rising_crossing {
+                    self.sum_mains_periods += (now - previous).as_secs_f64();
+                    self.n_mains_periods += 1;
+                }
+                self.slice_rising_crossing = Some(now);
+            }
+        }
+
+        self.n_samples += 1;
+        self.last_sample_v = sample_v;
+        let terms = [
+            filtered_v * filtered_v,
+            filtered_i * filtered_i,
+            phase_shift_v * filtered_i,
+        ];
+        if self.first_terms.is_none() {
+            self.first_terms = Some(terms);
+        }
+        self.previous_terms = self.last_terms;
+        self.last_terms = terms;
+        self.last_filtered_v = filtered_v;
+        self.last_filtered_i = filtered_i;
+    }
+}
+
+impl CT {
+    /// Measure the CT and add the result to its reading.
+    ///
+    /// Samples are taken until the voltage has crossed its starting point `crossing` times or
+    /// `timeout` has passed, using whichever backend `sampler` was set up with. A measurement
+    /// with too many failed reads, see MAX_FAILED_READS, is logged and dropped, and the reading
+    /// stays as it was.
+    // The firmware measures with calculate_energy_async in async builds.
+    #[cfg_attr(feature = "async", allow(dead_code))]
+    pub(crate) fn calculate_energy(
+        &mut self,
+        sampler: &mut Sampler,
+        crossing: u32,
+        timeout: std::time::Duration,
+    ) -> anyhow::Result<()> {
+        if !self.can_sample(sampler) {
+            return Ok(());
+        }
+        let mut retries = 0;
+        loop {
+            let (measurement, duration) = self.sample_min_crossings(sampler, crossing, timeout)?;
+            if let Err(err) = self.check_failed_reads(&measurement) {
+                warn!("{}", err);
+                return Ok(());
+            }
+            let reading = self.finish_measurement(measurement, duration);
+            if self.keep_reading(reading, &mut retries) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Async version of calculate_energy, for firmware running on an async executor.
+    ///
+    /// Samples in slices of ASYNC_BATCH_CROSSINGS crossings and awaits `yield_now()` after each
+    /// one, so other tasks get to run during the measurement. `yield_now` comes from the executor,
+    /// e.g. a yield or a short timer future. The samples and the math are the same as for
+    /// calculate_energy, only the gaps between the slices are new.
+    #[cfg(feature = "async")]
+    pub(crate) async fn calculate_energy_async<Y, F>(
+        &mut self,
+        sampler: &mut Sampler,
+        crossing: u32,
+        timeout: std::time::Duration,
+        mut yield_now: Y,
+    ) -> anyhow::Result<()>
+    where
+        Y: FnMut() -> F,
+        F: std::future::Future<Output = ()>,
+    {
+        if !self.can_sample(sampler) {
+            return Ok(());
+        }
+        let mut retries = 0;
+        loop {
+            let mut measurement = self.new_measurement(sampler);
+            let mut duration = std::time::Duration::ZERO;
+            let start = std::time::Instant::now();
+            while measurement.cross_count < crossing && start.elapsed() < timeout {
+                let slice_crossing =
+                    u32::min(ASYNC_BATCH_CROSSINGS, crossing - measurement.cross_count);
+                let slice_timeout = timeout.saturating_sub(start.elapsed());
+                duration +=
+                    self.sample(sampler, &mut measurement, slice_crossing, slice_timeout)?;
+                yield_now().await;
+            }
+            if let Err(err) = self.check_failed_reads(&measurement) {
+                warn!("{}", err);
+                return Ok(());
+            }
+            let reading = self.finish_measurement(measurement, duration);
+            if self.keep_reading(reading, &mut retries) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Measure the CT once and return the reading, without adding it to the reading of the period.
+    ///
+    /// For calibration, which needs readings that are neither averaged with earlier ones nor
+    /// dropped as warm-up. The dc offsets are still refined. Fails if the pins can't be sampled
+    /// now, see Sampler::can_sample, or too many reads failed, see MAX_FAILED_READS.
+    pub(crate) fn measure_once(
+        &mut self,
+        sampler: &mut Sampler,
+        crossing: u32,
+        timeout: std::time::Duration,
+    ) -> anyhow::Result<CTReading> {
+        if !self.can_sample(sampler) {
+            anyhow::bail!("CT {}: its pins can't be read now", self.id);
+        }
+        let (measurement, duration) = self.sample_min_crossings(sampler, crossing, timeout)?;
+        self.check_failed_reads(&measurement)?;
+        Ok(self.finish_measurement(measurement, duration))
+    }
+
+    // Sample a new measurement of `crossing` crossings, acting on the ShortCrossings of the config
+    // if it falls short of the minimum.
+    fn sample_min_crossings(
+        &mut self,
+        sampler: &mut Sampler,
+        crossing: u32,
+        timeout: std::time::Duration,
+    ) -> anyhow::Result<(Measurement, std::time::Duration)> {
+        let mut measurement = self.new_measurement(sampler);
+        let mut duration = self.sample(sampler, &mut measurement, crossing, timeout)?;
+        let policy = match self.config.min_crossings {
+            Some((_, policy)) if self.is_short_of_crossings(&measurement) => policy,
+            _ => return Ok((measurement, duration)),
+        };
+        warn!(
+            "CT {}: reached {} of {} crossings in {:?}, {:?}",
+            self.id, measurement.cross_count, crossing, timeout, policy
+        );
+        match policy {
+            ShortCrossings::Flag => {}
+            ShortCrossings::Retry => {
+                measurement = self.new_measurement(sampler);
+                duration = self.sample(sampler, &mut measurement, crossing, timeout)?;
+            }
+            ShortCrossings::ExtendTimeout => {
+                let missing = crossing.saturating_sub(measurement.cross_count);
+                duration += self.sample(sampler, &mut measurement, missing, timeout)?;
+                // The extension is part of the crossings asked for, not on top of them.
+                measurement.requested_crossings -= missing;
+            }
+        }
+        Ok((measurement, duration))
+    }
+
+    // Whether `measurement` reached fewer crossings than the minimum of the config, or than it
+    // asked for if that is less.
+    fn is_short_of_crossings(&self, measurement: &Measurement) -> bool {
+        match self.config.min_crossings {
+            Some((min, _)) if !self.config.current_only => {
+                measurement.cross_count < u32::min(min, measurement.requested_crossings)
+            }
+            _ => false,
+        }
+    }
+
+    /// Sample both pins `samples` times and report the noise of the filtered signal.
+    ///
+    /// For commissioning: with no load on the CT the current signal should be flat, so whatever
+    /// is left after the dc offset removal and the low-pass filter of calculate_energy is noise.
+    /// Its standard deviation is about the smallest current that can be measured, and a high one
+    /// points to bad shielding or wiring. The same holds for the voltage with its input
+    /// disconnected, with mains on it the voltage result is the mains voltage instead. Needs the
+    /// one-shot backend. Neither the reading nor the dc offsets of the CT are changed.
+    pub(crate) fn measure_noise_floor(
+        &mut self,
+        sampler: &mut Sampler,
+        samples: usize,
+    ) -> anyhow::Result<NoiseFloor> {
+        if !self.can_sample(sampler) {
+            anyhow::bail!("CT {}: its pins can't be read now", self.id);
+        }
+        let mut measurement = self.new_measurement(sampler);
+        let adcs = match sampler {
+            Sampler::OneShot(adcs) => adcs,
+            Sampler::Continuous(_) => {
+                anyhow::bail!("CT {}: the noise floor needs the one-shot backend", self.id)
+            }
+        };
+        let mut source = OneShotSource {
+            adcs,
+            current_pin: self.current_pin.pin.as_mut(),
+            voltage_pin: self.voltage_pin.pin.as_mut(),
+            oversampling: self.config.oversampling,
+        };
+        let (mut sample_i, mut sample_v) = (0, 0);
+        for _ in 0..samples {
+            sample_i = measurement.sample_or(source.read_current(), sample_i);
+            sample_v = measurement.sample_or(source.read_voltage(), sample_v);
+            measurement.add_sample(sample_i, sample_v);
+        }
+        if measurement.n_samples == 0 {
+            anyhow::bail!("CT {}: no samples for the noise floor", self.id);
+        }
+        let n = measurement.n_samples as f32;
+        let i_raw = f32::sqrt(measurement.sum_i / n);
+        let v_raw = f32::sqrt(measurement.sum_v / n);
+        let scale = SUPPLY_VOLTAGE / (MAX_MV_ATTEN_11 as f32);
+        Ok(NoiseFloor {
+            i_raw,
+            v_raw,
+            i_rms: self.current_pin.ical * scale * i_raw,
+            v_rms: self.v_ratio() * v_raw,
+            samples,
+        })
+    }
+
+    /// Check the CT for commissioning, with no load on it: measure its noise floor over `samples`
+    /// samples, see measure_noise_floor, and pass if the current noise is at most MAX_NOISE_FLOOR.
+    ///
+    /// Only the current is judged, the voltage input normally has the mains on it.
+    pub(crate) fn self_test(
+        &mut self,
+        sampler: &mut Sampler,
+        samples: usize,
+    ) -> anyhow::Result<SelfTestReport> {
+        let noise_floor = self.measure_noise_floor(sampler, samples)?;
+        let passed = noise_floor.i_raw <= MAX_NOISE_FLOOR;
+        if !passed {
+            warn!(
+                "CT {}: current noise floor of {} mV, check the shielding and wiring.",
+                self.id, noise_floor.i_raw
+            );
+        }
+        Ok(SelfTestReport {
+            id: self.id,
+            noise_floor,
+            passed,
+        })
+    }
+
+    // Drop the reading while warming up, otherwise pass it to the reading callback, then add it
+    // to the reading of the period unless paused.
+    pub(crate) fn add_reading(&mut self, mut reading: CTReading) {
+        if self.warmup_remaining > 0 {
+            self.warmup_remaining -= 1;
+            debug!("CT {}: dropped warm-up reading {:?}", self.id, reading);
+            return;
+        }
+        if self.paused {
+            // Still shown live, but neither stored nor counted in the energy totals.
+            if let Some(callback) = self.reading_callback.as_mut() {
+                callback(self.id, &reading);
+            }
+            debug!("CT {}: paused, dropped reading {:?}", self.id, reading);
+            return;
+        }
+        let skipped =
+            self.config.zero_cross_timeout == ZeroCrossTimeout::Skip && !self.config.current_only;
+        if skipped && reading.apparent_only {
+            debug!("CT {}: no voltage, skipped reading {:?}", self.id, reading);
+            self.accumulator.push_flags(&reading);
+            self.reading = self.accumulator.finalize();
+            return;
+        }
+        self.sanitize_energy(&mut reading);
+        self.update_direction(&reading);
+        if let Some(callback) = self.reading_callback.as_mut() {
+            callback(self.id, &reading);
+        }
+        self.accumulator.push(&reading);
+        self.reading = self.accumulator.finalize();
+    }
+
+    // Drop non-finite kWh of a reading and act on negative_energy, so a math error in a single
+    // measurement can't corrupt the energy total.
+    fn sanitize_energy(&mut self, reading: &mut CTReading) {
+        if !reading.kwh.is_finite() {
+            debug!(
+                "CT {}: dropped kWh {} of {:?}",
+                self.id, reading.kwh, reading
+            );
+            reading.kwh = 0.0;
+            return;
+        }
+        // Back to import positive, whatever the reported convention.
+        let import = self.config.power_convention.apply(reading.kwh);
+        if import >= 0.0 {
+            return;
+        }
+        match self.config.negative_energy {
+            NegativeEnergy::Keep => {}
+            NegativeEnergy::Clamp => {
+                debug!("CT {}: dropped exported kWh {}", self.id, -import);
+                reading.kwh = 0.0;
+            }
+            NegativeEnergy::Export => {
+                self.exported_kwh -= import as f64;
+                reading.kwh = 0.0;
+            }
+        }
+    }
+
+    // Flip the direction once export_hysteresis readings in a row are beyond the dead zone on
+    // the other side of zero.
+    fn update_direction(&mut self, reading: &CTReading) {
+        // Back to import positive, whatever the reported convention.
+        let import = self.config.power_convention.apply(reading.real_power);
+        let dead_zone = self.config.export_dead_zone;
+        let opposite = if self.exporting {
+            import > dead_zone
+        } else {
+            import < -dead_zone
+        };
+        if !opposite {
+            self.opposite_readings = 0;
+            return;
+        }
+        self.opposite_readings += 1;
+        if self.opposite_readings >= self.config.export_hysteresis {
+            self.exporting = !self.exporting;
+            self.opposite_readings = 0;
+            debug!("CT {}: exporting {}", self.id, self.exporting);
+        }
+    }
+
+    /// Whether power is fed back to the grid, smoothed so it doesn't flicker around zero.
+    ///
+    /// The direction only changes after set_export_hysteresis readings in a row on the other side
+    /// of zero, beyond the dead zone. A house balancing solar and consumption keeps its direction
+    /// until the balance tips for a while. False until the first export.
+    pub(crate) fn is_exporting(&self) -> bool {
+        self.exporting
+    }
+
+    /// Set the dead zone in W around zero and the number of readings in a row beyond it that
+    /// change the direction of is_exporting. Defaults to 10 W and 3 readings, 0 W and 1 reading
+    /// follow the sign of every reading.
+    pub(crate) fn set_export_hysteresis(&mut self, dead_zone: f32, readings: u32) {
+        self.config.export_dead_zone = f32::max(dead_zone, 0.0);
+        self.config.export_hysteresis = u32::max(readings, 1);
+        self.opposite_readings = 0;
+    }
+
+    /// Whether the voltage pin read the same value all through the last measurement.
+    ///
+    /// The mains keeps a working voltage channel moving, a channel with a sample variance below
+    /// STUCK_CHANNEL_VARIANCE is stuck, e.g. a driver fault or a pin shorted to a rail. Its reading
+    /// looks like no load at all, so it is marked anomalous instead. The current pin isn't
+    /// checked, with no load on the CT it reads a constant value just the same. Neither is a CT
+    /// that measures the current only, see MeasurementMode::CurrentOnly.
+    pub(crate) fn last_channel_stuck(&self) -> bool {
+        self.diagnostics.channel_stuck
+    }
+
+    /// Crossings the last measurement reached and the ones it asked for, see set_min_crossings.
+    pub(crate) fn last_crossings(&self) -> (u32, u32) {
+        self.diagnostics.crossings
+    }
+
+    /// How far in mV the adaptive dc offsets moved from the start to the end of the last
+    /// measurement, the larger of the current and voltage offset. 0 before the first one.
+    ///
+    /// The offsets are low-pass filtered from the samples, so they take a few measurements after
+    /// boot or reset_offsets to settle, and the rms values are skewed until they do. Small values,
+    /// a few mV, mean they had settled and the reading can be trusted as far as the offsets go,
+    /// large ones explain a noisy early reading or point to a bias that moves, e.g. a supply that
+    /// sags under load. Complements CTReading::quality, which doesn't see the offsets.
+    pub(crate) fn last_offset_convergence(&self) -> f32 {
+        self.diagnostics.offset_convergence
+    }
+
+    /// Whether the last measurement suggests the current and voltage pins are swapped.
+    ///
+    /// The voltage divider is sized to use most of the ADC range at mains voltage, while the
+    /// current only does at the highest load. So the current samples swinging more than
+    /// SWAPPED_SWING_RATIO times the voltage samples means the CT is wired to the voltage pin and
+    /// the other way around, which gives plausible but wrong readings. Only meaningful with a
+    /// load on, a swapped pair without a load looks like a missing voltage. There is no self
+    /// test in this firmware to report it, check it after the first measurement of an install.
+    pub(crate) fn pins_likely_swapped(&self) -> bool {
+        let (current, voltage) = self.diagnostics.swing;
+        current as f32 > voltage as f32 * SWAPPED_SWING_RATIO
+    }
+
+    /// Call `cb` with the CT id and the reading of every new measurement.
+    ///
+    /// Runs right after the measurement, before the reading is averaged into the reading of the
+    /// save period, and outside of any lock. Use it to push readings out as they come, e.g. to
+    /// MQTT or a display. Keep it short, it delays the next measurement.
+    pub(crate) fn on_reading(&mut self, cb: ReadingCallback) {
+        self.reading_callback = Some(cb);
+    }
+
+    // Whether the pins of this CT can be sampled now, warns if not. A CT with a pin on ADC2 is
+    // skipped while WiFi is on, its reading is left as it is.
+    fn can_sample(&self, sampler: &Sampler) -> bool {
+        let available = sampler.can_sample(self.current_pin.pin.as_ref())
+            && sampler.can_sample(self.voltage_pin.pin.as_ref());
+        if !available {
+            warn!("CT {}: skipped, its ADC2 pins can't be read now.", self.id);
+        }
+        available
+    }
+
+    // Warn if the measured mains frequency is off the expected one, see set_expected_frequency.
+    fn check_frequency(&self) {
+        let (expected, tolerance) = match self.config.expected_frequency {
+            Some(expected) => expected,
+            None => return,
+        };
+        let frequency = (1.0 / self.diagnostics.mains_period) as f32;
+        if f32::abs(frequency - expected) > tolerance {
+            warn!(
+                "CT {}: measured {:.2} Hz instead of {} Hz, check the sampling (noise, dropped \
+                 reads, ADC2 contention).",
+                self.id, frequency, expected
+            );
+        }
+    }
+
+    // Warn when a dc offset drifts beyond max_offset_drift, and when it is back within.
+    fn check_offset_drift(&mut self) {
+        let max = match self.config.max_offset_drift {
+            Some(max) => max,
+            None => return,
+        };
+        let (drift_i, drift_v) = self.offset_drift();
+        let drifted = f32::abs(drift_i) > max || f32::abs(drift_v) > max;
+        if drifted && !self.diagnostics.offset_drifted {
+            warn!(
+                "CT {}: dc offsets {} mV (current) and {} mV (voltage) from mid-scale, check the \
+                 burden resistor and the bias network.",
+                self.id, drift_i, drift_v
+            );
+        } else if !drifted && self.diagnostics.offset_drifted {
+            info!(
+                "CT {}: dc offsets back within {} mV of mid-scale",
+                self.id, max
+            );
+        }
+        self.diagnostics.offset_drifted = drifted;
+    }
+
+    // Whether `v_rms` is within plausible_voltage, which also keeps the last plausible one.
+    fn is_plausible_voltage(&mut self, v_rms: f32) -> bool {
+        let (low, high) = match self.config.plausible_voltage {
+            Some((band, _)) => band,
+            None => return true,
+        };
+        if v_rms >= low && v_rms <= high {
+            self.diagnostics.last_plausible_v_rms = Some(v_rms);
+            return true;
+        }
+        warn!(
+            "CT {}: implausible voltage {} V, outside {} to {} V, check the voltage channel.",
+            self.id, v_rms, low, high
+        );
+        false
+    }
+
+    // The v_rms and real power of a measurement, with the last plausible voltage in place of an
+    // implausible one under ImplausibleVoltage::Substitute.
+    fn substitute_voltage(&self, v_rms: f32, real_power: f32, implausible: bool) -> (f32, f32) {
+        let substitute = matches!(
+            self.config.plausible_voltage,
+            Some((_, ImplausibleVoltage::Substitute))
+        );
+        match self.diagnostics.last_plausible_v_rms {
+            Some(plausible) if implausible && substitute => {
+                // The power was measured with the same faulty voltage, so it is off by as much.
+                // Without any voltage there is no power factor to keep.
+                let real_power = if v_rms > 0.0 {
+                    real_power * plausible / v_rms
+                } else {
+                    0.0
+                };
+                (plausible, real_power)
+            }
+            _ => (v_rms, real_power),
+        }
+    }
+
+    // Whether the diagnostics of this measurement are due at info under log_every, counting it.
+    fn is_logged_measurement(&mut self) -> bool {
+        let every = match self.config.log_every {
+            Some(every) => every,
+            None => return false,
+        };
+        self.diagnostics.unlogged_measurements += 1;
+        if self.diagnostics.unlogged_measurements < every {
+            return false;
+        }
+        self.diagnostics.unlogged_measurements = 0;
+        true
+    }
+
+    // Add the reading to this CT's reading unless it is anomalous and there are retries left.
+    // Returns whether the reading was kept.
+    fn keep_reading(&mut self, reading: CTReading, retries: &mut u8) -> bool {
+        if !reading.is_anomalous(self.config.nominal_voltage) {
+            self.add_reading(reading);
+            return true;
+        }
+        if *retries >= self.config.measurement_retries {
+            warn!(
+                "CT {}: keeping anomalous reading after {} retries: {:?}",
+                self.id, retries, reading
+            );
+            self.add_reading(reading);
+            return true;
+        }
+        *retries += 1;
+        warn!(
+            "CT {}: anomalous reading {:?}, retrying ({} of {})",
+            self.id, reading, retries, self.config.measurement_retries
+        );
+        false
+    }
+
+    // Add the samples of `crossing` crossings, or of `timeout` if that is shorter, to the
+    // measurement as one slice. Returns how long the sampling took.
+    fn sample(
+        &mut self,
+        sampler: &mut Sampler,
+        measurement: &mut Measurement,
+        crossing: u32,
+        timeout: std::time::Duration,
+    ) -> anyhow::Result<std::time::Duration> {
+        measurement.requested_crossings += crossing;
+        measurement.voltage_lost |= self.config.current_only;
+        let duration = match sampler {
+            Sampler::OneShot(adcs) => self.sample_oneshot(adcs, measurement, crossing, timeout)?,
+            Sampler::Continuous(continuous_adc) => {
+                self.sample_continuous(continuous_adc, measurement, crossing, timeout)?
+            }
+        };
+        if self.config.cycle_correction {
+            measurement.trim_edges();
+        }
+        Ok(duration)
+    }
+
+    // An error if so many reads of `measurement` failed that it has to be dropped, see
+    // MAX_FAILED_READS.
+    fn check_failed_reads(&self, measurement: &Measurement) -> anyhow::Result<()> {
+        if measurement.too_many_failed_reads() {
+            anyhow::bail!(
+                "CT {}: {} of {} ADC reads failed, measurement rejected",
+                self.id,
+                measurement.failed_reads,
+                measurement.reads
+            );
+        }
+        Ok(())
+    }
+
+    fn sample_oneshot(
+        &mut self,
+        adcs: &mut Adcs,
+        measurement: &mut Measurement,
+        crossing: u32,
+        timeout: std::time::Duration,
+    ) -> anyhow::Result<std::time::Duration> {
+        let mut source = OneShotSource {
+            adcs,
+            current_pin: self.current_pin.pin.as_mut(),
+            voltage_pin: self.voltage_pin.pin.as_mut(),
+            oversampling: self.config.oversampling,
+        };
+        Self::sample_source(
+            &mut source,
+            self.config.read_order,
+            self.config.zero_cross_timeout,
+            self.config.current_only,
+            measurement,
+            crossing,
+            timeout,
+        )
+    }
+
+    // The one-shot sampling of sample_oneshot from any source of samples.
+    fn sample_source(
+        source: &mut dyn SampleSource,
+        read_order: ReadOrder,
+        on_timeout: ZeroCrossTimeout,
+        current_only: bool,
+        measurement: &mut Measurement,
+        crossing: u32,
+        timeout: std::time::Duration,
+    ) -> anyhow::Result<std::time::Duration> {
+        let mut sample_v: u16 = 0;
+        let mut sample_i: u16 = 0;
+        let mut start = std::time::Instant::now(); // start.elapsed() makes sure it doesnt get stuck in the loop if there is an error.
+
+        // 1) Waits for the waveform to be close to 'zero' (mid-scale adc) part in sin curve.
+        // Without a voltage it stays at mid-scale and never crosses.
+        // Skip waits a second timeout before it gives up on the voltage.
+        let waits = if on_timeout == ZeroCrossTimeout::Skip {
+            2
+        } else {
+            1
+        };
+        if current_only {
+            sample_v = MAX_MV_ATTEN_11 / 2;
+        } else {
+            for _ in 0..waits {
+                loop {
+                    sample_v = measurement.sample_or(source.read_voltage(), sample_v);
+                    if measurement.is_near_zero(sample_v)
+                        || start.elapsed() > timeout
+                        || source.is_exhausted()
+                    {
+                        break;
+                    }
+                }
+                if measurement.is_near_zero(sample_v) || source.is_exhausted() {
+                    break;
+                }
+                start = std::time::Instant::now();
+            }
+        }
+        if !measurement.is_near_zero(sample_v) && !source.is_exhausted() {
+            match on_timeout {
+                ZeroCrossTimeout::Abort => anyhow::bail!(
+                    "voltage at {} mV didn't come near zero within {:?}, is the voltage channel \
+                     dead?",
+                    sample_v,
+                    timeout
+                ),
+                ZeroCrossTimeout::ApparentOnly => {
+                    warn!(
+                        "Voltage at {} mV didn't come near zero within {:?}, measuring apparent \
+                         power only.",
+                        sample_v, timeout
+                    );
+                    measurement.voltage_lost = true;
+                }
+                ZeroCrossTimeout::Skip => {
+                    warn!(
+                        "Voltage at {} mV didn't come near zero within {:?} twice, skipping the \
+                         measurement.",
+                        sample_v, timeout
+                    );
+                    measurement.voltage_lost = true;
+                }
+            }
+        }
+        measurement.start_slice(sample_v);
+        let crossing = measurement.cross_count.saturating_add(crossing);
+        // The sample the crossings are counted against is the leading edge of the slice, see
+        // MeasurementConfig::cycle_correction.
+        if measurement.cycle_correction {
+            sample_i = measurement.sample_or(source.read_current(), sample_i);
+            measurement.add_sample(sample_i, sample_v);
+        }
+
+        // 2) Main measurement loop
+        start = std::time::Instant::now();
+        while (measurement.cross_count < crossing)
+            && (start.elapsed() < timeout)
+            && !source.is_exhausted()
+        {
+            // A) Read in raw voltage and current samples
+            match read_order {
+                _ if current_only => {
+                    sample_i = measurement.sample_or(source.read_current(), sample_i);
+                }
+                ReadOrder::CurrentFirst => {
+                    sample_i = measurement.sample_or(source.read_current(), sample_i);
+                    sample_v = measurement.sample_or(source.read_voltage(), sample_v);
+                }
+                ReadOrder::VoltageFirst => {
+                    sample_v = measurement.sample_or(source.read_voltage(), sample_v);
+                    sample_i = measurement.sample_or(source.read_current(), sample_i);
+                }
+            }
+            measurement.add_sample(sample_i, sample_v);
+        }
+        Ok(start.elapsed())
+    }
+
+    // Keep the samples around a fault for CTStorage::store_fault_captures.
+    fn keep_fault_capture(&mut self, ring: &FaultRing) {
+        warn!(
+            "CT {}: clipped or overcurrent samples, captured {} samples around them",
+            self.id,
+            ring.samples.len()
+        );
+        self.diagnostics.fault_capture = Some(ring.samples.iter().copied().collect());
+        self.diagnostics.fault_captured = true;
+    }
+
+    /// Keep the last `samples` raw samples of every measurement in RAM and store them when a
+    /// sample clips or, with `max_current`, when the current exceeds that many A at its peak.
+    /// 0 samples turn it off, the default.
+    ///
+    /// The samples are frozen with half of them from before the first fault of the measurement
+    /// and half from after it, and kept until CTStorage::store_fault_captures stores them to
+    /// "fault_capture_<id>" in the format of capture_waveform, for replay_waveform or offline
+    /// analysis. Only the first fault is captured, so a CT that keeps clipping doesn't wear out the
+    /// flash, call this again to catch the next. The ring takes 4 bytes per sample while
+    /// measuring, and as much again once a fault is captured until it is stored.
+    pub(crate) fn set_fault_capture(&mut self, samples: usize, max_current: Option<f32>) {
+        self.config.fault_capture = if samples > 0 {
+            Some((samples, max_current))
+        } else {
+            None
+        };
+        self.diagnostics.fault_captured = false;
+        self.diagnostics.fault_capture = None;
+    }
+
+    // The samples of the fault captured since set_fault_capture, for
+    // CTStorage::store_fault_captures to store.
+    pub(crate) fn take_fault_capture(&mut self) -> Option<Vec<(u16, u16)>> {
+        self.diagnostics.fault_capture.take()
+    }
+
+    /// Read `samples` raw (current, voltage) samples back to back and store them in the file at
+    /// `path` of `fs`, for replay_waveform. Only the one-shot backend can capture.
+    pub(crate) fn capture_waveform(
+        &mut self,
+        sampler: &mut Sampler,
+        samples: usize,
+        fs: &dyn Filesystem,
+        path: &str,
+    ) -> anyhow::Result<()> {
+        let adcs = match sampler {
+            Sampler::OneShot(adcs) => adcs,
+            Sampler::Continuous(_) => {
+                anyhow::bail!("CT {}: capturing needs the one-shot backend", self.id)
+            }
+        };
+        let mut source = OneShotSource {
+            adcs,
+            current_pin: self.current_pin.pin.as_mut(),
+            voltage_pin: self.voltage_pin.pin.as_mut(),
+            oversampling: self.config.oversampling,
+        };
+        let mut buf = Vec::with_capacity(samples * 4);
+        for _ in 0..samples {
+            buf.extend_from_slice(&source.read_current()?.to_le_bytes());
+            buf.extend_from_slice(&source.read_voltage()?.to_le_bytes());
+        }
+        fs.write_atomic(path, &buf)?;
+        info!("CT {}: captured {} samples to {}", self.id, samples, path);
+        Ok(())
+    }
+
+    fn sample_continuous(
+        &mut self,
+        continuous_adc: &mut ContinuousAdc,
+        measurement: &mut Measurement,
+        crossing: u32,
+        timeout: std::time::Duration,
+    ) -> anyhow::Result<std::time::Duration> {
+        let crossing = measurement.cross_count + crossing;
+        if self.current_pin.pin.unit() != AdcUnit::Adc1
+            || self.voltage_pin.pin.unit() != AdcUnit::Adc1
+        {
+            anyhow::bail!("CT {}: the continuous backend only samples ADC1", self.id);
+        }
+        let current_channel = self.current_pin.pin.channel();
+        continuous_adc.start(current_channel, self.voltage_pin.pin.channel())?;
+
+        let mut pairs = [(0_u16, 0_u16); DMA_FRAME_SIZE / 4];
+        let mut waiting_for_zero = true;
+        let mut start = std::time::Instant::now();
+        'frames: while start.elapsed() < timeout {
+            let n = continuous_adc.read(current_channel, &mut pairs, timeout.as_millis() as u32)?;
+            for &(sample_i, sample_v) in &pairs[..n] {
+                // Like the one-shot path, without a voltage it stays at mid-scale.
+                let sample_v = if self.config.current_only {
+                    MAX_MV_ATTEN_11 / 2
+                } else {
+                    sample_v
+                };
+                // 1) Same as the one-shot path, start at the 'zero' of the voltage waveform.
+                if waiting_for_zero {
+                    if !measurement.is_near_zero(sample_v) && start.elapsed() <= timeout {
+                        continue;
+                    }
+                    waiting_for_zero = false;
+                    measurement.start_slice(sample_v);
+                    start = std::time::Instant::now();
+                }
+                // 2) Run the same math over the buffered samples.
+                measurement.add_sample(sample_i, sample_v);
+                if measurement.cross_count >= crossing {
+                    break 'frames;
+                }
+            }
+        }
+        continuous_adc.stop()?;
+        Ok(start.elapsed())
+    }
+
+    fn new_measurement(&self, sampler: &Sampler) -> Measurement {
+        // alpha = dt / (RC + dt) of the filter, with dt from the previous measurement. The filter
+        // stays off until the sample period is known.
+        let lowpass_alpha = match self.config.lowpass_cutoff {
+            Some(cutoff) if self.diagnostics.sample_period > 0.0 => {
+                let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+                let dt = self.diagnostics.sample_period;
+                Some(dt / (rc + dt))
+            }
+            _ => None,
+        };
+        // The continuous backend always converts the current first.
+        let phase_cal = match sampler {
+            Sampler::OneShot(_) => {
+                self.voltage_pin.phase_cal + self.config.read_order.phase_cal_offset()
+            }
+            Sampler::Continuous(_) => self.voltage_pin.phase_cal,
+        };
+        let mut measurement = Measurement::new(
+            self.current_pin.offset_i,
+            self.voltage_pin.offset_v,
+            phase_cal,
+            lowpass_alpha,
+            self.config.zero_cross_band,
+        );
+        measurement.cycle_correction = self.config.cycle_correction;
+        measurement.fault_ring = match self.config.fault_capture {
+            Some((samples, max_current)) if !self.diagnostics.fault_captured => {
+                let i_ratio = self.current_pin.ical * (SUPPLY_VOLTAGE / (MAX_MV_ATTEN_11 as f32));
+                Some(FaultRing::new(
+                    samples,
+                    max_current.map(|amps| amps / i_ratio),
+                ))
+            }
+            _ => None,
+        };
+        measurement
+    }
+
+    // Run the whole of `source` through one measurement, see replay_waveform.
+    fn measure_source(&mut self, source: &mut dyn SampleSource) -> anyhow::Result<CTReading> {
+        // Read one-shot style, as the waveform was captured.
+        let mut measurement = Measurement::new(
+            self.current_pin.offset_i,
+            self.voltage_pin.offset_v,
+            self.voltage_pin.phase_cal,
+            None,
+            self.config.zero_cross_band,
+        );
+        measurement.cycle_correction = self.config.cycle_correction;
+        let timeout = std::time::Duration::from_secs(60);
+        let duration = Self::sample_source(
+            source,
+            ReadOrder::CurrentFirst,
+            self.config.zero_cross_timeout,
+            self.config.current_only,
+            &mut measurement,
+            u32::MAX,
+            timeout,
+        )?;
+        if self.config.cycle_correction {
+            measurement.trim_edges();
+        }
+        if measurement.n_samples == 0 {
+            anyhow::bail!("CT {}: the waveform has no samples", self.id);
+        }
+        Ok(self.finish_measurement(measurement, duration))
+    }
+
+    // Turn the sums of a measurement into a reading.
+    fn finish_measurement(
+        &mut self,
+        measurement: Measurement,
+        duration: std::time::Duration,
+    ) -> CTReading {
+        let Measurement {
+            mut offset_i,
+            mut offset_v,
+            min_sample_i,
+            min_sample_v,
+            max_sample_i,
+            max_sample_v,
+            sum_v,
+            sum_i,
+            sum_p,
+            sum_q,
+            n_samples,
+            cross_count,
+            ..
+        } = measurement;
+        let quality = measurement.quality();
+        let n = measurement.effective_samples();
+        if let Some(ring) = measurement
+            .fault_ring
+            .as_ref()
+            .filter(|ring| ring.is_tripped())
+        {
+            self.keep_fault_capture(ring);
+        }
+
+        // The offsets of the pins are still the ones the measurement started from.
+        self.diagnostics.offset_convergence = f32::max(
+            f32::abs(offset_i - self.current_pin.offset_i),
+            f32::abs(offset_v - self.voltage_pin.offset_v),
+        );
+
+        // The peaks are measured from the offsets the samples were centred on.
+        let i_peak_mv = f32::max(
+            f32::abs(min_sample_i as f32 - offset_i),
+            f32::abs(max_sample_i as f32 - offset_i),
+        );
+        let v_peak_mv = f32::max(
+            f32::abs(min_sample_v as f32 - offset_v),
+            f32::abs(max_sample_v as f32 - offset_v),
+        );
+
+        // Improve the approximation for mid point (dc offset)
+        offset_i = (offset_i + ((max_sample_i + min_sample_i) as f32 / 2.0)) / 2.0;
+        offset_v = (offset_v + ((max_sample_v + min_sample_v) as f32 / 2.0)) / 2.0;
+
+        self.current_pin.offset_i = offset_i;
+        self.voltage_pin.offset_v = offset_v;
+        self.check_offset_drift();
+        if n_samples > 0 {
+            self.diagnostics.sample_period = duration.as_secs_f32() / n_samples as f32;
+        }
+        let [_, variance_v] = measurement.raw_variance();
+        // Only the voltage pin can be told from an idle CT, whose current pin reads the bias all
+        // the time. Without a voltage only the current pin is read, so nothing is checked.
+        let channel_stuck =
+            n_samples > 1 && !self.config.current_only && variance_v < STUCK_CHANNEL_VARIANCE;
+        if channel_stuck {
+            error!(
+                "CT {}: voltage channel stuck, sample variance {} mV^2",
+                self.id, variance_v
+            );
+        }
+        self.diagnostics.channel_stuck = channel_stuck;
+        self.diagnostics.crossings = (cross_count, measurement.requested_crossings);
+        let under_sampled = self.is_short_of_crossings(&measurement);
+        self.diagnostics.swing = (
+            max_sample_i.saturating_sub(min_sample_i),
+            max_sample_v.saturating_sub(min_sample_v),
+        );
+        if measurement.n_mains_periods > 0 {
+            self.diagnostics.mains_period =
+                measurement.sum_mains_periods / measurement.n_mains_periods as f64;
+            self.check_frequency();
+        }
+
+        // Diagnostics of this measurement. They are only logged at info when asked for, since this
+        // runs for every CT on every measurement.
+        let level = if self.config.verbose || self.is_logged_measurement() {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        };
+        log::log!(
+            level,
+            "CT {}: offset_i {} offset_v {} samples {} crossings {} in {:?} quality {} failed \
+             reads {}",
+            self.id,
+            offset_i,
+            offset_v,
+            n_samples,
+            cross_count,
+            duration,
+            quality,
+            measurement.failed_reads
+        );
+
+        let v_ratio = self.v_ratio();
+        let v_rms = if measurement.voltage_lost {
+            self.config.nominal_voltage
+        } else {
+            v_ratio * f32::sqrt(sum_v / n)
+        };
+
+        let i_ratio = self.current_pin.ical * (SUPPLY_VOLTAGE / (MAX_MV_ATTEN_11 as f32));
+        let measured_i_rms = i_ratio * f32::sqrt(sum_i / n);
+        let i_rms = interpolate(&self.config.current_correction, measured_i_rms)
+            * gain_at(&self.config.current_gain, measured_i_rms);
+        let i_correction = if measured_i_rms > 0.0 {
+            i_rms / measured_i_rms
+        } else {
+            1.0
+        };
+
+        // Calculate power values. Real power is signed, positive when importing. Without a voltage
+        // there is no real power to measure.
+        let direction = if self.config.reversed { -1.0 } else { 1.0 };
+        let real_power = if measurement.voltage_lost {
+            0.0
+        } else {
+            direction * v_ratio * i_ratio * i_correction * (sum_p / n)
+        };
+        let implausible_voltage = !measurement.voltage_lost && !self.is_plausible_voltage(v_rms);
+        let (v_rms, real_power) = self.substitute_voltage(v_rms, real_power, implausible_voltage);
+        let apparent_power = v_rms * i_rms;
+        // The larger of the two half waves, an asymmetric waveform has its peak on one side only.
+        let v_peak = v_ratio * v_peak_mv;
+        let i_peak = i_ratio * i_correction * i_peak_mv;
+        let kwh = real_power / 1000.0 * duration.as_secs_f32() / 3600.0;
+        CTReading {
+            real_power: self.config.power_convention.apply(real_power),
+            apparent_power,
+            kwh: self.config.power_convention.apply(kwh),
+            i_rms,
+            v_rms,
+            timestamp: now().as_millis() as u64,
+            uptime: uptime().as_millis() as u64,
+            sequence: 0,
+            quality: Some(quality),
+            v_peak,
+            i_peak,
+            reduced_precision: measurement.requested_crossings < MEASUREMENT_CROSSINGS,
+            clipped: measurement.clipped_samples > 0,
+            stuck: channel_stuck,
+            apparent_only: measurement.voltage_lost,
+            under_sampled,
+            unsynced_time: !clock_set(),
+            implausible_voltage,
+            leading: !measurement.voltage_lost && direction * sum_q < 0.0,
+        }
+    }
+
+    pub(crate) fn init(pins: Pins) -> anyhow::Result<[CT; AC_PHASE]> {
+        if CT_ON_ADC2 {
+            return CT::init_adc2(pins);
+        }
+        #[cfg(feature = "single-phase")]
+        let channels: [(Box<dyn AdcChannel>, Box<dyn AdcChannel>); AC_PHASE] = [(
+            Box::new(Adc1Pin(pins.gpio35.into_analog_atten_11db()?)),
+            Box::new(Adc1Pin(pins.gpio34.into_analog_atten_11db()?)),
+        )];
+        #[cfg(feature = "three-phase")]
+        let channels: [(Box<dyn AdcChannel>, Box<dyn AdcChannel>); AC_PHASE] = [
+            (
+                Box::new(Adc1Pin(pins.gpio32.into_analog_atten_11db()?)),
+                Box::new(Adc1Pin(pins.gpio39.into_analog_atten_11db()?)),
+            ),
+            (
+                Box::new(Adc1Pin(pins.gpio35.into_analog_atten_11db()?)),
+                Box::new(Adc1Pin(pins.gpio36.into_analog_atten_11db()?)),
+            ),
+            (
+                Box::new(Adc1Pin(pins.gpio34.into_analog_atten_11db()?)),
+                Box::new(Adc1Pin(pins.gpio33.into_analog_atten_11db()?)),
+            ),
+        ];
+        Ok(CT::with_channels(channels))
+    }
+
+    // The CTs of a board that wires them to ADC2 pins, see CT_ON_ADC2.
+    fn init_adc2(pins: Pins) -> anyhow::Result<[CT; AC_PHASE]> {
+        #[cfg(feature = "single-phase")]
+        let channels: [(Box<dyn AdcChannel>, Box<dyn AdcChannel>); AC_PHASE] = [(
+            Box::new(Adc2Pin(pins.gpio26.into_analog_atten_11db()?)),
+            Box::new(Adc2Pin(pins.gpio25.into_analog_atten_11db()?)),
+        )];
+        #[cfg(feature = "three-phase")]
+        let channels: [(Box<dyn AdcChannel>, Box<dyn AdcChannel>); AC_PHASE] = [
+            (
+                Box::new(Adc2Pin(pins.gpio26.into_analog_atten_11db()?)),
+                Box::new(Adc2Pin(pins.gpio25.into_analog_atten_11db()?)),
+            ),
+            (
+                Box::new(Adc2Pin(pins.gpio27.into_analog_atten_11db()?)),
+                Box::new(Adc2Pin(pins.gpio14.into_analog_atten_11db()?)),
+            ),
+            (
+                Box::new(Adc2Pin(pins.gpio13.into_analog_atten_11db()?)),
+                Box::new(Adc2Pin(pins.gpio4.into_analog_atten_11db()?)),
+            ),
+        ];
+        Ok(CT::with_channels(channels))
+    }
+
+    /// The CTs sampled through the given (current, voltage) channels, numbered from 1, with the
+    /// default calibration of the board.
+    pub(crate) fn with_channels(
+        channels: [(Box<dyn AdcChannel>, Box<dyn AdcChannel>); AC_PHASE],
+    ) -> [CT; AC_PHASE] {
+        #[cfg(feature = "single-phase")]
+        let (ical, vcal) = (102.0, 232.5);
+        #[cfg(feature = "three-phase")]
+        let (ical, vcal) = (30.0, 219.25);
+        let mut id = 0;
+        channels.map(|(current, voltage)| {
+            id += 1;
+            CT {
+                id,
+                current_pin: CurrentPin {
+                    pin: current,
+                    ical,
+                    offset_i: DEFAULT_OFFSET_I,
+                },
+                voltage_pin: VoltagePin {
+                    pin: voltage,
+                    vcal,
+                    vt_ratio: 1.0,
+                    phase_cal: 1.7,
+                    offset_v: DEFAULT_OFFSET_V,
+                },
+                config: MeasurementConfig::default(),
+                diagnostics: MeasurementDiagnostics::default(),
+                energy_total_kwh: 0.0,
+                exported_kwh: 0.0,
+                reading_callback: None,
+                accumulator: Accumulator::default(),
+                warmup_remaining: WARMUP_READINGS,
+                exporting: false,
+                opposite_readings: 0,
+                paused: false,
+                reading: CTReading::default(),
+            }
+        })
+    }
+
+    /// Start a new save period: zero the interval reading, including its kWh, after moving that
+    /// kWh into the lifetime energy, see lifetime_kwh.
+    pub(crate) fn reset_interval(&mut self) {
+        self.energy_total_kwh += self.reading.kwh as f64;
+        self.discard_interval();
+    }
+
+    /// Start a new save period like reset_interval, but drop the kWh of the interval reading, for
+    /// a reading that was not stored, see CTStorage::skips.
+    pub(crate) fn discard_interval(&mut self) {
+        self.accumulator.reset();
+        self.reading.reset();
+    }
+
+    /// Keep new measurements out of the stored data, e.g. while test loads are applied during
+    /// maintenance, until resume.
+    ///
+    /// Measurements still run and reach the reading callback for live display, but they are not
+    /// added to the reading of the period, so they are missing from the records, the energy totals
+    /// and the time of use totals. The measurements of the period from before the pause are still
+    /// saved, a period paused all through stores no record for the CT. A save with every CT paused
+    /// all through is no save at all and takes no sequence number.
+    pub(crate) fn pause(&mut self) {
+        if !self.paused {
+            info!("CT {}: paused, measurements are not stored.", self.id);
+        }
+        self.paused = true;
+    }
+
+    /// Store new measurements again after pause.
+    pub(crate) fn resume(&mut self) {
+        if self.paused {
+            info!("CT {}: resumed, measurements are stored again.", self.id);
+        }
+        self.paused = false;
+    }
+
+    /// Whether new measurements are kept out of the stored data, see pause.
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// kWh since the device was first set up, including the running save period. Survives
+    /// reset_interval, and restarts through save_energy_totals and load_energy_totals.
+    pub(crate) fn lifetime_kwh(&self) -> f64 {
+        self.energy_total_kwh + self.reading.kwh as f64
+    }
+
+    /// What to do with the kWh a measurement fed back to the grid, see NegativeEnergy. Defaults to
+    /// Keep.
+    pub(crate) fn set_negative_energy(&mut self, negative_energy: NegativeEnergy) {
+        self.config.negative_energy = negative_energy;
+    }
+
+    /// kWh fed back to the grid since the device was first set up, counted under
+    /// NegativeEnergy::Export. Stored and loaded with the energy total, see save_energy_totals.
+    pub(crate) fn exported_kwh(&self) -> f64 {
+        self.exported_kwh
+    }
+
+    /// The rms mains voltage in V, e.g. 120.0 in North America. Defaults to NOMINAL_VOLTAGE.
+    ///
+    /// Readings more than MAX_VOLTAGE_DEVIATION away from it are anomalous, see
+    /// set_measurement_retries, and measurements without a voltage report it as their v_rms.
+    pub(crate) fn set_nominal_voltage(&mut self, volts: f32) {
+        self.config.nominal_voltage = volts;
+    }
+
+    /// Treat a v_rms outside `band`, (lowest, highest) in V, as a fault of the voltage channel and
+    /// act on `policy`, see ImplausibleVoltage. None turns the check off. Defaults to
+    /// PLAUSIBLE_VOLTAGE and Flag.
+    pub(crate) fn set_plausible_voltage(
+        &mut self,
+        band: Option<(f32, f32)>,
+        policy: ImplausibleVoltage,
+    ) {
+        self.config.plausible_voltage = band.map(|band| (band, policy));
+    }
+
+    /// Start measurements once the voltage is within `mv` of its dc offset. Defaults to
+    /// ZERO_CROSS_BAND, 5% of full scale either side.
+    ///
+    /// The band follows the offset, so a bias network that sits off mid-scale still gets its
+    /// measurements started. A noisy voltage may need a wider band to be caught near zero at all,
+    /// at the cost of starting the measurement further from its crossing.
+    pub(crate) fn set_zero_cross_band(&mut self, mv: f32) {
+        self.config.zero_cross_band = f32::max(mv, 0.0);
+    }
+
+    /// Report real power and kWh with the sign of the given convention. Defaults to import
+    /// positive.
+    pub(crate) fn set_power_convention(&mut self, convention: PowerConvention) {
+        self.config.power_convention = convention;
+    }
+
+    /// Whether the CT is clamped on the wrong way round, defaults to false. Its measured power
+    /// is flipped, so import is import again before the PowerConvention applies.
+    ///
+    /// Firmware from before signed real power reported the magnitude only, so a reversed CT
+    /// wasn't noticed. It now reports its import as export, is_exporting turns true while drawing
+    /// from the grid. Set this for such a CT instead of turning it around.
+    pub(crate) fn set_reversed(&mut self, reversed: bool) {
+        self.config.reversed = reversed;
+    }
+
+    /// Expect every measurement to reach at least `min` crossings, or all it asks for if that is
+    /// less, and act on `policy` when it falls short, see ShortCrossings. None, the default,
+    /// accepts any. calculate_energy_async and the round robin only flag short readings.
+    pub(crate) fn set_min_crossings(&mut self, min_crossings: Option<(u32, ShortCrossings)>) {
+        self.config.min_crossings = min_crossings;
+    }
+
+    /// Repeat a measurement up to `n` times while its reading is anomalous, 0 keeps every reading.
+    ///
+    /// Rides out transient glitches, e.g. the inrush of a motor starting, instead of averaging
+    /// them into the reading of the period. If every retry is anomalous too, the last one is kept.
+    /// See CTReading::is_anomalous for what counts as anomalous.
+    pub(crate) fn set_measurement_retries(&mut self, n: u8) {
+        self.config.measurement_retries = n;
+    }
+
+    /// Correct the rms current with (measured, actual) points, an empty Vec turns it off.
+    ///
+    /// Between the points the correction is interpolated linearly, beyond them the outer segments
+    /// are extended. Measure a few loads across the range of the CT against a reference meter to
+    /// get the points.
+    pub(crate) fn set_current_correction(&mut self, mut points: Vec<(f32, f32)>) {
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        self.config.current_correction = points;
+    }
+
+    /// Correct the ratio error of the CT with (current, gain) points, an empty Vec turns it off.
+    ///
+    /// A CT's secondary current isn't exactly its primary over the turns ratio. Its core needs
+    /// magnetizing current, which takes a larger share at low currents, so the ratio error grows
+    /// towards the bottom of the range, while phase_cal only covers the phase error. Accuracy
+    /// classes (IEC 61869-2) and calibration certificates give the ratio error in percent at a few
+    /// percentages of the rated current, e.g. -1.5% at 5%: enter such a point as
+    /// (0.05 * rated amps, 1.0 / (1.0 - 0.015)).
+    ///
+    /// The gain at the measured i_rms, interpolated linearly between the points and held beyond
+    /// them, multiplies the i_rms after set_current_correction, and the power with it. Unlike the
+    /// points of set_current_correction these never extrapolate, so a gain measured at a few loads
+    /// can't run away below or above them.
+    pub(crate) fn set_current_gain(&mut self, mut points: Vec<(f32, f32)>) {
+        points.retain(|(current, gain)| current.is_finite() && gain.is_finite() && *gain > 0.0);
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        self.config.current_gain = points;
+    }
+
+    /// Read voltage or current first in each one-shot sample, see ReadOrder for phase_cal.
+    pub(crate) fn set_read_order(&mut self, order: ReadOrder) {
+        self.config.read_order = order;
+    }
+
+    /// Low-pass filter the sampled signal at `hz` against aliasing, None turns the filter off.
+    pub(crate) fn set_lowpass_cutoff(&mut self, hz: Option<f32>) {
+        self.config.lowpass_cutoff = hz;
+    }
+
+    /// What to do when the voltage doesn't come near zero to start a one-shot measurement on, see
+    /// ZeroCrossTimeout. Defaults to Skip.
+    pub(crate) fn set_zero_cross_timeout(&mut self, on_timeout: ZeroCrossTimeout) {
+        self.config.zero_cross_timeout = on_timeout;
+    }
+
+    /// Measure in `mode` from the next measurement on, see available_modes. Defaults to Standard.
+    ///
+    /// Sets the options the mode stands for, so it replaces an earlier set_cycle_correction.
+    pub(crate) fn set_mode(&mut self, mode: MeasurementMode) {
+        self.config.cycle_correction = mode == MeasurementMode::CycleCorrected;
+        self.config.current_only = mode == MeasurementMode::CurrentOnly;
+    }
+
+    /// The mode the CT measures in, see set_mode.
+    pub(crate) fn mode(&self) -> MeasurementMode {
+        if self.config.current_only {
+            MeasurementMode::CurrentOnly
+        } else if self.config.cycle_correction {
+            MeasurementMode::CycleCorrected
+        } else {
+            MeasurementMode::Standard
+        }
+    }
+
+    /// Cut each slice at the exact last crossing, see MeasurementConfig::cycle_correction. Off by
+    /// default.
+    pub(crate) fn set_cycle_correction(&mut self, enabled: bool) {
+        self.config.cycle_correction = enabled;
+    }
+
+    /// Warn after every measurement whose mains frequency is more than `tolerance` Hz off `hz`.
+    ///
+    /// The grid holds its frequency within a fraction of a Hz, so a measured frequency that is
+    /// further off almost always means the sampling went wrong: crossings counted on noise, reads
+    /// that failed or samples far apart. 50 or 60 Hz with a tolerance of 1 Hz catches those.
+    pub(crate) fn set_expected_frequency(&mut self, hz: f32, tolerance: f32) {
+        self.config.expected_frequency = Some((hz, f32::abs(tolerance)));
+    }
+
+    /// Average the rms values of the period instead of the root mean square, see
+    /// Aggregation::RmsOfSquares. Off by default.
+    pub(crate) fn set_average_rms(&mut self, enabled: bool) {
+        let aggregation = if enabled {
+            Aggregation::Mean
+        } else {
+            Aggregation::RmsOfSquares
+        };
+        self.accumulator.aggregations.i_rms = aggregation;
+        self.accumulator.aggregations.v_rms = aggregation;
+    }
+
+    /// Combine the measurements of a save period by `aggregations`, see Accumulator. Applies from
+    /// the next measurement on, the ones already in the period are combined anew.
+    pub(crate) fn set_aggregations(&mut self, aggregations: Aggregations) {
+        self.accumulator.aggregations = aggregations;
+    }
+
+    /// Log offsets, sample and crossing counts and duration of every measurement at info level.
+    pub(crate) fn set_verbose(&mut self, v: bool) {
+        self.config.verbose = v;
+    }
+
+    /// Log the diagnostics of set_verbose for every `n`th measurement only, the others at debug
+    /// level. Keeps an eye on the health of the CT in production without a line per measurement.
+    /// None, the default, logs none of them at info unless verbose, 1 all of them.
+    pub(crate) fn set_log_every(&mut self, n: Option<u32>) {
+        self.config.log_every = n.map(|n| u32::max(n, 1));
+        self.diagnostics.unlogged_measurements = 0;
+    }
+
+    /// Compare the current reading against a reference meter.
+    ///
+    /// v_rms scales with vcal and real power with vcal * ical, so the voltage fixes vcal_factor
+    /// and whatever is left of the power error goes into ical_factor. Measure a steady resistive
+    /// load with both meters, then apply the result with CalibrationError::corrected.
+    /// If the CT measured nothing the factors are left at 1.0. Fails for a reference of 0 or less.
+    pub(crate) fn compare_to_reference(
+        &self,
+        ref_watts: f32,
+        ref_volts: f32,
+    ) -> anyhow::Result<CalibrationError> {
+        CalibrationError::between(&self.reading, ref_watts, ref_volts)
+    }
+
+    /// Set the ratio of an external voltage transformer, mains over secondary voltage, e.g. 25.6
+    /// for a 230 V to 9 V transformer. Defaults to 1.
+    ///
+    /// The mains voltage goes through the transformer, then the divider and bias network to the
+    /// ADC, so v_rms = vt_ratio * vcal * SUPPLY_VOLTAGE / MAX_MV_ATTEN_11 * the rms mV at the pin.
+    /// With the ratio set, vcal only covers the divider on the board, and a new transformer only
+    /// needs its ratio changed. A calibration made while the ratio was 1 has the transformer in
+    /// vcal, divide vcal by the ratio when setting it. The ratio is not part of Calibration, it is
+    /// stored with it by CTStorage::save_calibration.
+    pub(crate) fn set_vt_ratio(&mut self, ratio: f32) {
+        if ratio > 0.0 && ratio.is_finite() {
+            self.voltage_pin.vt_ratio = ratio;
+        } else {
+            warn!(
+                "CT {}: ignored voltage transformer ratio {}",
+                self.id, ratio
+            );
+        }
+    }
+
+    /// Ratio of the external voltage transformer, see set_vt_ratio.
+    pub(crate) fn vt_ratio(&self) -> f32 {
+        self.voltage_pin.vt_ratio
+    }
+
+    pub(crate) fn calibration(&self) -> Calibration {
+        Calibration {
+            vcal: self.voltage_pin.vcal,
+            ical: self.current_pin.ical,
+            phase_cal: self.voltage_pin.phase_cal,
+        }
+    }
+
+    pub(crate) fn set_calibration(&mut self, cal: Calibration) {
+        self.voltage_pin.vcal = cal.vcal;
+        self.current_pin.ical = cal.ical;
+        self.voltage_pin.phase_cal = cal.phase_cal;
+    }
+
+    /// Apply a calibration given as text, e.g. "vcal=232.5,ical=102,phase=1.7".
+    ///
+    /// For provisioning tools that push the calibration over serial or HTTP. Each field is
+    /// optional, the ones left out keep their value. vcal and ical must be positive, phase a
+    /// finite number. Unknown keys, repeated keys and malformed values fail the whole string, and
+    /// nothing is applied then. The fields that were set are logged. calibration_str gives the
+    /// same format back.
+    pub(crate) fn apply_calibration_str(&mut self, s: &str) -> anyhow::Result<()> {
+        let mut cal = self.calibration();
+        let mut set: Vec<&str> = Vec::new();
+        for field in s
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            let (key, value) = match field.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => anyhow::bail!("CT {}: expected key=value, got {:?}", self.id, field),
+            };
+            if set.contains(&key) {
+                anyhow::bail!("CT {}: {} is set twice", self.id, key);
+            }
+            let target = match key {
+                "vcal" => &mut cal.vcal,
+                "ical" => &mut cal.ical,
+                "phase" => &mut cal.phase_cal,
+                _ => anyhow::bail!("CT {}: unknown calibration key {:?}", self.id, key),
+            };
+            *target = match value.parse::<f32>() {
+                Ok(value) if key == "phase" && value.is_finite() => value,
+                Ok(value) if key != "phase" && value.is_finite() && value > 0.0 => value,
+                _ => anyhow::bail!("CT {}: invalid {} {:?}", self.id, key, value),
+            };
+            set.push(key);
+        }
+        self.set_calibration(cal);
+        info!(
+            "CT {}: set {} of the calibration: {:?}",
+            self.id,
+            set.join(", "),
+            cal
+        );
+        Ok(())
+    }
+
+    /// The calibration in the text format of apply_calibration_str.
+    pub(crate) fn calibration_str(&self) -> String {
+        let cal = self.calibration();
+        format!(
+            "vcal={},ical={},phase={}",
+            cal.vcal, cal.ical, cal.phase_cal
+        )
+    }
+
+    // Volts at the mains per mV at the voltage pin, the whole chain of set_vt_ratio.
+    fn v_ratio(&self) -> f32 {
+        self.voltage_pin.vt_ratio
+            * self.voltage_pin.vcal
+            * (SUPPLY_VOLTAGE / (MAX_MV_ATTEN_11 as f32))
+    }
+
+    /// The adaptive (current, voltage) dc offsets in mV, as refined by the last measurement.
+    pub(crate) fn current_offsets(&self) -> (f32, f32) {
+        (self.current_pin.offset_i, self.voltage_pin.offset_v)
+    }
+
+    /// How far the (current, voltage) dc offsets are from mid-scale, in mV.
+    ///
+    /// The bias network holds the signals at half the ADC range, and the offsets follow it from
+    /// measurement to measurement. An offset that wanders off over time points to a failing
+    /// burden resistor or bias network, long before the readings look wrong. Every measurement
+    /// warns once a drift exceeds the bound of set_max_offset_drift.
+    pub(crate) fn offset_drift(&self) -> (f32, f32) {
+        let mid_scale = MAX_MV_ATTEN_11 as f32 / 2.0;
+        (
+            self.current_pin.offset_i - mid_scale,
+            self.voltage_pin.offset_v - mid_scale,
+        )
+    }
+
+    /// Warn when a dc offset is more than `max` mV from mid-scale, see offset_drift. None turns the
+    /// check off. Defaults to MAX_OFFSET_DRIFT.
+    pub(crate) fn set_max_offset_drift(&mut self, max: Option<f32>) {
+        self.config.max_offset_drift = max;
+    }
+
+    /// Put the dc offsets back to their compiled defaults.
+    ///
+    /// The offsets follow the signal from measurement to measurement. If a bad measurement pushed
+    /// them far off, this makes them converge again from the defaults.
+    pub(crate) fn reset_offsets(&mut self) {
+        self.current_pin.offset_i = DEFAULT_OFFSET_I;
+        self.voltage_pin.offset_v = DEFAULT_OFFSET_V;
+        self.warmup_remaining = self.config.warmup_readings;
+    }
+
+    /// Drop the first `n` readings after boot and after reset_offsets. Defaults to WARMUP_READINGS.
+    ///
+    /// The dc offsets start from compiled defaults and only converge over the first measurements,
+    /// so the first readings are off. Warm-up readings are measured, which moves the offsets
+    /// along, but they are neither passed to the reading callback nor added to the reading of the
+    /// period, their energy is lost. 0 keeps every reading. Readings after the reset of a save
+    /// period are not dropped, the offsets are settled by then.
+    pub(crate) fn set_warmup_readings(&mut self, n: u32) {
+        self.config.warmup_readings = n;
+        self.warmup_remaining = n;
+    }
+
+    /// Average `n` ADC reads into each logical sample. 1 keeps the plain one read per sample.
+    pub(crate) fn set_oversampling(&mut self, n: u8) {
+        self.config.oversampling = u8::max(n, 1);
+    }
+
+    /// Make the ADC reads of both pins fail at the adc_failure_rate of `faults`.
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn inject_adc_faults(&mut self, faults: &FaultInjector) {
+        inject_channel_faults(&mut self.current_pin.pin, faults);
+        inject_channel_faults(&mut self.voltage_pin.pin, faults);
+    }
+}
+
+/// Measure all CTs within one window of `budget`, taking turns.
+///
+/// calculate_energy measures the CTs one after the other, so with three phases the reading of
+/// the first CT is two full measurements older than the last one. Here the budget is split
+/// equally across the CTs and each CT's share into `rounds` slices of crossing / rounds
+/// crossings. The CTs take turns slice by slice, so all readings cover the same window and none
+/// is systematically stale. Every reading is timestamped at the end of the window.
+/// Anomalous readings are not retried in this mode, and a measurement with too many failed reads
+/// is dropped as in calculate_energy.
+pub(crate) fn calculate_energy_round_robin(
+    cts: &mut [CT; AC_PHASE],
+    sampler: &mut Sampler,
+    crossing: u32,
+    budget: std::time::Duration,
+    rounds: u32,
+) -> anyhow::Result<()> {
+    let rounds = u32::max(rounds, 1);
+    let slice_timeout = budget / (AC_PHASE as u32 * rounds);
+    let slice_crossing = u32::max(crossing / rounds, 1);
+    let mut measurements = Vec::with_capacity(AC_PHASE);
+    for ct in cts.iter() {
+        measurements.push((ct.new_measurement(sampler), std::time::Duration::ZERO));
+    }
+    let available: Vec<bool> = cts.iter().map(|ct| ct.can_sample(sampler)).collect();
+    for _ in 0..rounds {
+        for ((ct, (measurement, duration)), &available) in cts
+            .iter_mut()
+            .zip(measurements.iter_mut())
+            .zip(available.iter())
+        {
+            if available {
+                *duration += ct.sample(sampler, measurement, slice_crossing, slice_timeout)?;
+            }
+        }
+    }
+    let timestamp = now().as_millis() as u64;
+    let since_boot = uptime().as_millis() as u64;
+    for ((ct, (measurement, duration)), available) in
+        cts.iter_mut().zip(measurements).zip(available)
+    {
+        if !available {
+            continue;
+        }
+        if let Err(err) = ct.check_failed_reads(&measurement) {
+            warn!("{}", err);
+            continue;
+        }
+        let mut reading = ct.finish_measurement(measurement, duration);
+        reading.set_time(timestamp, since_boot);
+        ct.add_reading(reading);
+        ct.reading.set_time(timestamp, since_boot);
+    }
+    Ok(())
+}
+
+/// Order in which the phases of three CTs reach their peaks, see check_phase_rotation.
+#[cfg(feature = "three-phase")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseRotation {
+    /// The second CT lags the first by 120 degrees and the third by 240.
+    Abc,
+    /// The second CT lags the first by 240 degrees and the third by 120.
+    Acb,
+    /// The voltages are not 120 degrees apart, e.g. two taps are on the same phase.
+    Fault,
+    /// A CT has not seen enough voltage crossings to tell.
+    Unknown,
+}
+
+/// Check that the voltage taps of the three CTs are on three phases, see
+/// measure_voltage_phases.
+///
+/// Every phase must be within PHASE_TOLERANCE_DEG of 120 or 240 degrees, otherwise a warning is
+/// logged and Fault returned.
+#[cfg(feature = "three-phase")]
+pub(crate) fn check_phase_rotation(
+    cts: &mut [CT; 3],
+    sampler: &mut Sampler,
+) -> anyhow::Result<PhaseRotation> {
+    Ok(phase_rotation(measure_voltage_phases(cts, sampler)?))
+}
+
+/// Phases in degrees of the voltages of the second and third CT after the first, None if a
+/// voltage didn't cross.
+///
+/// Reads the three voltage pins in turns for up to PHASE_CHECK_TIMEOUT, until the first one has
+/// risen through its dc offset three times. The reads are the time base: every crossing is placed
+/// between the two reads of its pin around it, so all three are timed against each other on the
+/// same count of reads, and the mains period is counted in reads too. Needs the one-shot backend,
+/// the continuous one converts only two pins.
+#[cfg(feature = "three-phase")]
+pub(crate) fn measure_voltage_phases(
+    cts: &mut [CT; 3],
+    sampler: &mut Sampler,
+) -> anyhow::Result<Option<[f64; 2]>> {
+    let adcs = match sampler {
+        Sampler::OneShot(adcs) => adcs,
+        Sampler::Continuous(_) => anyhow::bail!("the phase check needs the one-shot backend"),
+    };
+    let offsets = [
+        cts[0].voltage_pin.offset_v,
+        cts[1].voltage_pin.offset_v,
+        cts[2].voltage_pin.offset_v,
+    ];
+    let [ct1, ct2, ct3] = cts;
+    let mut pins = [
+        ct1.voltage_pin.pin.as_mut(),
+        ct2.voltage_pin.pin.as_mut(),
+        ct3.voltage_pin.pin.as_mut(),
+    ];
+    interleaved_voltage_phases(|pin| pins[pin].read(adcs), offsets, PHASE_CHECK_TIMEOUT)
+}
+
+// The rotation of the phases of the second and third voltage after the first, in degrees.
+#[cfg(feature = "three-phase")]
+fn phase_rotation(phases: Option<[f64; 2]>) -> PhaseRotation {
+    let phases = match phases {
+        Some(phases) => phases,
+        None => return PhaseRotation::Unknown,
+    };
+    let near = |phase: f64, expected: f64| f64::abs(phase - expected) <= PHASE_TOLERANCE_DEG;
+    if near(phases[0], 120.0) && near(phases[1], 240.0) {
+        PhaseRotation::Abc
+    } else if near(phases[0], 240.0) && near(phases[1], 120.0) {
+        PhaseRotation::Acb
+    } else {
+        warn!(
+            "Voltage phases of CT 2 and 3 are {:.0} and {:.0} degrees from CT 1, check the wiring.",
+            phases[0], phases[1]
+        );
+        PhaseRotation::Fault
+    }
+}
+
+// Phases in degrees of the second and third voltage after the first, see check_phase_rotation.
+// `read(pin)` reads pin 0, 1 or 2 in mV. None if a pin didn't rise through its offset in time.
+#[cfg(feature = "three-phase")]
+fn interleaved_voltage_phases<F>(
+    mut read: F,
+    offsets: [f32; 3],
+    timeout: std::time::Duration,
+) -> anyhow::Result<Option<[f64; 2]>>
+where
+    F: FnMut(usize) -> anyhow::Result<u16>,
+{
+    let start = std::time::Instant::now();
+    let mut previous = [0.0_f32; 3];
+    // Whether the pin went below the hysteresis since its last rising crossing.
+    let mut armed = [false; 3];
+    // Rising crossings in reads since the start, interpolated between the reads of the pin.
+    let mut crossings: [Vec<f64>; 3] = Default::default();
+    let mut reads = 0_u64;
+    while crossings[0].len() < 3 && start.elapsed() < timeout {
+        for pin in 0..3 {
+            let voltage = read(pin)? as f32 - offsets[pin];
+            if armed[pin] && voltage >= 0.0 {
+                let fraction = -previous[pin] / (voltage - previous[pin]);
+                crossings[pin].push(reads as f64 - 3.0 * (1.0 - fraction as f64));
+                armed[pin] = false;
+            } else if voltage < -PHASE_CHECK_HYSTERESIS {
+                armed[pin] = true;
+            }
+            previous[pin] = voltage;
+            reads += 1;
+        }
+    }
+    let first = &crossings[0];
+    if first.len() < 2 || crossings[1..].iter().any(Vec::is_empty) {
+        return Ok(None);
+    }
+    let period = (first[first.len() - 1] - first[0]) / (first.len() - 1) as f64;
+    let mut phases = [0.0_f64; 2];
+    for (phase, crossings) in phases.iter_mut().zip(&crossings[1..]) {
+        let offset = crossings[crossings.len() - 1] - first[first.len() - 1];
+        *phase = (offset / period).rem_euclid(1.0) * 360.0;
+    }
+    Ok(Some(phases))
+}
+
+/// Line to line rms voltages between the taps of CT 1 and 2, 2 and 3, and 3 and 1.
+///
+/// Only the line to neutral voltages are measured, so these are derived with the law of cosines,
+/// V12 = sqrt(V1^2 + V2^2 - 2 V1 V2 cos(phase1 - phase2)). `phases` are those of the second and
+/// third tap after the first in degrees, as measured by measure_voltage_phases. Without them the
+/// taps are taken as 120 degrees apart, where a balanced system gives the familiar sqrt(3) times
+/// the phase voltage. The waveforms are taken to be sinusoidal, so expect the result to be off by
+/// a few percent.
+#[cfg(feature = "three-phase")]
+pub(crate) fn line_to_line_voltages(cts: &[CT; 3], phases: Option<[f64; 2]>) -> [f32; 3] {
+    let phases = match phases {
+        Some([phase2, phase3]) => [0.0, phase2, phase3],
+        None => [0.0, 120.0, 240.0],
+    };
+    let mut voltages = [0.0; 3];
+    for (i, voltage) in voltages.iter_mut().enumerate() {
+        let j = (i + 1) % 3;
+        let (vi, vj) = (cts[i].reading.v_rms as f64, cts[j].reading.v_rms as f64);
+        let angle = (phases[i] - phases[j]).to_radians();
+        *voltage = f64::sqrt(f64::max(
+            vi * vi + vj * vj - 2.0 * vi * vj * angle.cos(),
+            0.0,
+        )) as f32;
+    }
+    voltages
+}
+
+/// The readings of the save period so far of CT `a` and `b`, indices into `cts`, combined into
+/// the reading of a single split-phase circuit, e.g. a 240 V circuit across the two 120 V legs of a
+/// North American service.
+///
+/// Assumes each CT clamps one leg, facing the same way, with the voltage input on its own leg, so
+/// each CT measures the power of its leg against the neutral. The legs are 180 degrees apart,
+/// so the circuit voltage is the sum of the leg voltages. Real and apparent power and kWh add up,
+/// and i_rms is the current at the circuit voltage that carries the apparent power, which is the
+/// current of either leg for a pure 240 V load. A CT that reads its leg against the other leg's
+/// voltage, e.g. both sharing one voltage transformer, reports the negative power of its leg, flip
+/// the CT or its power convention. The flags of both legs are combined, the timestamp is the later
+/// one, and the sequence number is 0, as the circuit is never stored.
+pub(crate) fn group_channels(cts: &[CT], a: usize, b: usize) -> anyhow::Result<CTReading> {
+    if a == b {
+        anyhow::bail!("Can't group CT {} with itself", a);
+    }
+    let (leg_a, leg_b) = match (cts.get(a), cts.get(b)) {
+        (Some(leg_a), Some(leg_b)) => (&leg_a.reading, &leg_b.reading),
+        _ => anyhow::bail!("Can't group CTs {} and {} of {}", a, b, cts.len()),
+    };
+    let v_rms = leg_a.v_rms + leg_b.v_rms;
+    let apparent_power = leg_a.apparent_power + leg_b.apparent_power;
+    let mut circuit = CTReading {
+        real_power: leg_a.real_power + leg_b.real_power,
+        apparent_power,
+        i_rms: if v_rms > 0.0 {
+            apparent_power / v_rms
+        } else {
+            0.0
+        },
+        v_rms,
+        kwh: leg_a.kwh + leg_b.kwh,
+        timestamp: u64::max(leg_a.timestamp, leg_b.timestamp),
+        uptime: u64::max(leg_a.uptime, leg_b.uptime),
+        quality: match (leg_a.quality, leg_b.quality) {
+            (Some(a), Some(b)) => Some(u8::min(a, b)),
+            (a, b) => a.or(b),
+        },
+        v_peak: leg_a.v_peak + leg_b.v_peak,
+        i_peak: f32::max(leg_a.i_peak, leg_b.i_peak),
+        ..Default::default()
+    };
+    circuit.set_flags(ReadingFlags::from_bits(
+        leg_a.flags().bits() | leg_b.flags().bits(),
+    ));
+    Ok(circuit)
+}
+
+/// How far apart in time, in ms, the given readings were taken.
+///
+/// The CTs are measured one after the other, so every saved record keeps the timestamp of its own
+/// measurement. With three phases the last CT is measured up to two full measurements after the
+/// first one, and tools that treat the records of one save as a single point in time should check
+/// this skew first.
+pub(crate) fn max_channel_skew(readings: &[CTReading]) -> u64 {
+    let first = readings.iter().map(|reading| reading.timestamp).min();
+    let last = readings.iter().map(|reading| reading.timestamp).max();
+    match (first, last) {
+        (Some(first), Some(last)) => last - first,
+        _ => 0,
+    }
+}
+
+/// Run a waveform stored in `fs` by CT::capture_waveform through the measurement math of `ct`.
+///
+/// For regression checks of the signal processing: the same waveform through the same
+/// calibration has to give the same real power, rms values and power factor. All samples form a
+/// single measurement, which ends at the last crossing if cycle correction is on. Like any
+/// measurement it moves the dc offsets of `ct` along, but the reading is not added to the CT's
+/// reading. kWh and the mains period follow from how fast the samples were replayed, not from
+/// when they were captured, so they can't be compared.
+pub(crate) fn replay_waveform(
+    fs: &dyn Filesystem,
+    path: &str,
+    ct: &mut CT,
+) -> anyhow::Result<CTReading> {
+    let buf = fs.read(path)?;
+    let samples = buf
+        .chunks_exact(4)
+        .map(|pair| {
+            (
+                u16::from_le_bytes([pair[0], pair[1]]),
+                u16::from_le_bytes([pair[2], pair[3]]),
+            )
+        })
+        .collect();
+    replay_samples(samples, ct)
+}
+
+// (name, type, help) of the metrics of to_prometheus, in the order of prometheus_values.
+const PROMETHEUS_METRICS: [(&str, &str, &str); 6] = [
+    (
+        "ofmon_real_power_watts",
+        "gauge",
+        "Real power of the save period so far, with the sign of the power convention.",
+    ),
+    (
+        "ofmon_apparent_power_voltamperes",
+        "gauge",
+        "Apparent power of the save period so far.",
+    ),
+    (
+        "ofmon_current_rms_amperes",
+        "gauge",
+        "RMS current of the save period so far.",
+    ),
+    (
+        "ofmon_voltage_rms_volts",
+        "gauge",
+        "RMS voltage of the save period so far.",
+    ),
+    (
+        "ofmon_power_factor",
+        "gauge",
+        "Power factor of the save period so far.",
+    ),
+    (
+        "ofmon_energy_kilowatt_hours",
+        "gauge",
+        "Energy since the device was first set up, net of any export.",
+    ),
+];
+
+// The values of `ct` for the metrics of PROMETHEUS_METRICS.
+fn prometheus_values(ct: &CT) -> [f64; 6] {
+    [
+        ct.reading.real_power as f64,
+        ct.reading.apparent_power as f64,
+        ct.reading.i_rms as f64,
+        ct.reading.v_rms as f64,
+        ct.reading.period_power_factor() as f64,
+        ct.lifetime_kwh(),
+    ]
+}
+
+/// The readings of the save period so far of `cts` in the Prometheus text exposition format, as
+/// served by a /metrics endpoint.
+///
+/// Every metric gets its HELP and TYPE lines followed by one sample per CT, labelled with its id
+/// as `channel`. The ids are numbers, so the labels never need escaping. Values that are not
+/// finite are written as NaN, +Inf and -Inf, the spellings of the format.
+pub(crate) fn to_prometheus(cts: &[CT]) -> String {
+    use std::fmt::Write;
+
+    let values: Vec<_> = cts
+        .iter()
+        .map(|ct| (ct.id, prometheus_values(ct)))
+        .collect();
+    let mut out = String::with_capacity(PROMETHEUS_METRICS.len() * (120 + 48 * cts.len()));
+    for (n, (name, kind, help)) in PROMETHEUS_METRICS.iter().enumerate() {
+        // Writing to a String can't fail.
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (id, values) in &values {
+            let value = values[n];
+            let _ = if value.is_nan() {
+                writeln!(out, "{}{{channel=\"{}\"}} NaN", name, id)
+            } else if value.is_infinite() {
+                let sign = if value > 0.0 { '+' } else { '-' };
+                writeln!(out, "{}{{channel=\"{}\"}} {}Inf", name, id, sign)
+            } else {
+                writeln!(out, "{}{{channel=\"{}\"}} {}", name, id, value)
+            };
+        }
+    }
+    out
+}
+
+/// Run (current, voltage) samples in mV through the measurement math of `ct`, like
+/// replay_waveform.
+pub(crate) fn replay_samples(samples: Vec<(u16, u16)>, ct: &mut CT) -> anyhow::Result<CTReading> {
+    ct.measure_source(&mut ReplaySource::new(samples))
+}
+
+impl ops::AddAssign<CTReading> for CTReading {
+    fn add_assign(&mut self, rhs: CTReading) {
+        let reactive_power = self.reactive_power() + rhs.reactive_power();
+        self.quality = match (self.quality, rhs.quality) {
+            (Some(a), Some(b)) => Some(u8::min(a, b)),
+            (a, b) => a.or(b),
+        };
+        self.i_rms = (self.i_rms + rhs.i_rms) / 2.0;
+        self.v_rms = (self.v_rms + rhs.v_rms) / 2.0;
+        self.real_power = (self.real_power + rhs.real_power) / 2.0;
+        self.apparent_power = (self.apparent_power + rhs.apparent_power) / 2.0;
+        self.reduced_precision |= rhs.reduced_precision;
+        self.clipped |= rhs.clipped;
+        self.stuck |= rhs.stuck;
+        self.apparent_only |= rhs.apparent_only;
+        self.under_sampled |= rhs.under_sampled;
+        self.unsynced_time |= rhs.unsynced_time;
+        self.implausible_voltage |= rhs.implausible_voltage;
+        self.v_peak = (self.v_peak + rhs.v_peak) / 2.0;
+        self.i_peak = (self.i_peak + rhs.i_peak) / 2.0;
+        self.kwh = self.kwh + rhs.kwh;
+        self.leading = reactive_power < 0.0;
+    }
+}
+
+impl CTReading {
+    /// Whether this reading can't be right: a value that is not a number, a power factor above
+    /// MAX_POWER_FACTOR, a voltage more than MAX_VOLTAGE_DEVIATION away from `nominal_voltage` or
+    /// a stuck ADC channel, see CT::last_channel_stuck.
+    pub(crate) fn is_anomalous(&self, nominal_voltage: f32) -> bool {
+        if self.stuck {
+            return true;
+        }
+        let values = [
+            self.real_power,
+            self.apparent_power,
+            self.i_rms,
+            self.v_rms,
+            self.kwh,
+        ];
+        if values.iter().any(|value| !value.is_finite()) {
+            return true;
+        }
+        if self.apparent_power > 0.0
+            && f32::abs(self.real_power) / self.apparent_power > MAX_POWER_FACTOR
+        {
+            return true;
+        }
+        f32::abs(self.v_rms - nominal_voltage) > nominal_voltage * MAX_VOLTAGE_DEVIATION
+    }
+
+    fn reset(&mut self) {
+        self.i_rms = 0.0;
+        self.v_rms = 0.0;
         self.real_power = 0.0;
         self.apparent_power = 0.0;
         self.kwh = 0.0;
         self.timestamp = 0;
+        self.uptime = 0;
+        self.quality = None;
+        self.v_peak = 0.0;
+        self.i_peak = 0.0;
+        self.reduced_precision = false;
+        self.clipped = false;
+        self.stuck = false;
+        self.apparent_only = false;
+        self.under_sampled = false;
+        self.unsynced_time = false;
+        self.implausible_voltage = false;
+        self.leading = false;
+    }
+
+    // The values an Accumulator combines, in the order of Aggregations::as_array.
+    fn aggregated_values(&self) -> [f32; 6] {
+        [
+            self.real_power,
+            self.apparent_power,
+            self.i_rms,
+            self.v_rms,
+            self.v_peak,
+            self.i_peak,
+        ]
+    }
+
+    // The measured values with their names, in the order of the exports.
+    pub(crate) fn values(&self) -> [(&'static str, f32); 5] {
+        [
+            ("real_power", self.real_power),
+            ("apparent_power", self.apparent_power),
+            ("i_rms", self.i_rms),
+            ("v_rms", self.v_rms),
+            ("kwh", self.kwh),
+        ]
+    }
+
+    /// Write this reading of CT `id` as a JSON object into `buf`, without allocating.
+    ///
+    /// Returns the number of bytes written, or an error if `buf` is too small. Values that are
+    /// not finite are written as null.
+    pub(crate) fn write_json(&self, id: u16, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let len = buf.len();
+        let mut out = &mut buf[..];
+        let result = (|| -> std::io::Result<()> {
+            write!(
+                out,
+                "{{\"id\":{},\"timestamp\":{},\"sequence\":{}",
+                id, self.timestamp, self.sequence
+            )?;
+            for (name, value) in self.values() {
+                if value.is_finite() {
+                    write!(out, ",\"{}\":{}", name, value)?;
+                } else {
+                    write!(out, ",\"{}\":null", name)?;
+                }
+            }
+            write!(out, "}}")
+        })();
+        result.map_err(|_| anyhow::anyhow!("{} bytes are too small for the JSON", len))?;
+        Ok(len - out.len())
+    }
+
+    /// Write this reading of CT `id` as a CSV row, ending in a newline, into `buf`, without
+    /// allocating. The columns are those of CSV_HEADER.
+    ///
+    /// Returns the number of bytes written, or an error if `buf` is too small.
+    pub(crate) fn write_csv(&self, id: u16, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let len = buf.len();
+        let mut out = &mut buf[..];
+        let result = (|| -> std::io::Result<()> {
+            write!(out, "{},{},{}", id, self.timestamp, self.sequence)?;
+            for (_, value) in self.values() {
+                write!(out, ",{}", value)?;
+            }
+            writeln!(out)
+        })();
+        result.map_err(|_| anyhow::anyhow!("{} bytes are too small for the CSV row", len))?;
+        Ok(len - out.len())
+    }
+
+    /// Quality score of this reading from 0 (useless) to 100, the lowest of its measurements.
+    ///
+    /// The score of a measurement is 100 * samples * clipping * crossings * noise, with
+    /// - samples: samples taken / (MIN_SAMPLES_PER_CROSSING * requested crossings), at most 1,
+    /// - clipping: 1 - 10 * the fraction of samples within CLIP_MARGIN of either ADC limit, at
+    ///   least 0, so 10% clipped samples give 0,
+    /// - crossings: voltage crossings seen / requested, at most 1, falls when the timeout hits,
+    /// - noise: 1 - the fraction of current and voltage samples the noise filter rejected.
+    ///
+    /// Readings loaded from records without the quality byte have no score and return 0, see
+    /// RecordSchema.
+    pub(crate) fn quality(&self) -> u8 {
+        self.quality.unwrap_or(0)
+    }
+
+    /// The flags of this reading, as stored in its record.
+    pub(crate) fn flags(&self) -> ReadingFlags {
+        let mut flags = ReadingFlags::default();
+        flags.set(ReadingFlags::CLIPPED, self.clipped);
+        flags.set(ReadingFlags::UNDER_SAMPLED, self.under_sampled);
+        flags.set(ReadingFlags::UNSYNCED_TIME, self.unsynced_time);
+        flags.set(ReadingFlags::APPARENT_ONLY, self.apparent_only);
+        flags.set(ReadingFlags::STUCK, self.stuck);
+        flags.set(ReadingFlags::REDUCED_PRECISION, self.reduced_precision);
+        flags.set(ReadingFlags::IMPLAUSIBLE_VOLTAGE, self.implausible_voltage);
+        flags.set(ReadingFlags::LEADING, self.leading);
+        flags
+    }
+
+    // Set the flags of a reading read back from its record.
+    pub(crate) fn set_flags(&mut self, flags: ReadingFlags) {
+        self.clipped = flags.contains(ReadingFlags::CLIPPED);
+        self.under_sampled = flags.contains(ReadingFlags::UNDER_SAMPLED);
+        self.unsynced_time = flags.contains(ReadingFlags::UNSYNCED_TIME);
+        self.apparent_only = flags.contains(ReadingFlags::APPARENT_ONLY);
+        self.stuck = flags.contains(ReadingFlags::STUCK);
+        self.reduced_precision = flags.contains(ReadingFlags::REDUCED_PRECISION);
+        self.implausible_voltage = flags.contains(ReadingFlags::IMPLAUSIBLE_VOLTAGE);
+        self.leading = flags.contains(ReadingFlags::LEADING);
+    }
+
+    /// Whether a measurement of this reading was timestamped before the clock was set since boot,
+    /// neither to the real time nor to the time restored from storage. Its timestamp may be far
+    /// off, date it with reconcile_time once the offset is known.
+    pub(crate) fn is_unsynced_time(&self) -> bool {
+        self.unsynced_time
+    }
+
+    /// Whether a measurement of this reading covered fewer than MEASUREMENT_CROSSINGS crossings,
+    /// e.g. because the main loop fell behind and downgraded it. Such readings average out less
+    /// noise.
+    pub(crate) fn is_reduced_precision(&self) -> bool {
+        self.reduced_precision
+    }
+
+    /// Whether a measurement of this reading had samples within CLIP_MARGIN of the ends of the
+    /// ADC range. The peaks of the signal were cut off there, so i_rms, and with it
+    /// apparent_power, come out too low. See ClippedPolicy for how they are stored.
+    pub(crate) fn is_clipped(&self) -> bool {
+        self.clipped
+    }
+
+    /// Whether a measurement of this reading reached fewer crossings than the minimum, see
+    /// CT::set_min_crossings.
+    pub(crate) fn is_under_sampled(&self) -> bool {
+        self.under_sampled
+    }
+
+    /// Whether a measurement of this reading had a v_rms outside the plausible band, see
+    /// ImplausibleVoltage.
+    pub(crate) fn is_implausible_voltage(&self) -> bool {
+        self.implausible_voltage
+    }
+
+    /// Whether a measurement of this reading found no voltage, see ZeroCrossTimeout. Under
+    /// ApparentOnly its v_rms is the nominal voltage and it has no real power or energy, under Skip
+    /// the measurement was left out.
+    pub(crate) fn is_apparent_only(&self) -> bool {
+        self.apparent_only
+    }
+
+    /// Peak over rms of the voltage, 0 if there is no voltage or for readings loaded from storage.
+    ///
+    /// A clean sine has a crest factor of sqrt(2), about 1.414. Values well above that point to a
+    /// distorted waveform with sharp peaks, values well below it to a flattened or clipped one.
+    pub(crate) fn v_crest_factor(&self) -> f32 {
+        if self.v_rms > 0.0 {
+            self.v_peak / self.v_rms
+        } else {
+            0.0
+        }
+    }
+
+    /// Peak over rms of the current, 0 if there is no current or for readings loaded from storage.
+    ///
+    /// See v_crest_factor. The current drawn by rectifiers, e.g. of switching power supplies,
+    /// typically has a crest factor of 2 to 3.
+    pub(crate) fn i_crest_factor(&self) -> f32 {
+        if self.i_rms > 0.0 {
+            self.i_peak / self.i_rms
+        } else {
+            0.0
+        }
+    }
+
+    /// Cost of the energy of this reading at a flat `rate_per_kwh`, in the currency of the rate.
+    /// Exported energy has a negative kWh and so a negative cost.
+    pub(crate) fn estimated_cost(&self, rate_per_kwh: f32) -> f32 {
+        self.cost(&rate_per_kwh)
+    }
+
+    /// Cost of the energy of this reading under `tariff`, at the rate of its timestamp.
+    pub(crate) fn cost(&self, tariff: &dyn Tariff) -> f32 {
+        self.kwh * tariff.rate_per_kwh(self.timestamp)
+    }
+
+    /// Set the timestamp, in ms since the epoch, and the `uptime` it was taken at, in ms since
+    /// boot.
+    pub(crate) fn set_time(&mut self, time: u64, uptime: u64) {
+        self.timestamp = time;
+        self.uptime = uptime;
+    }
+
+    /// Time since boot in ms when this reading was taken, 0 for records stored without it.
+    pub(crate) fn uptime(&self) -> u64 {
+        self.uptime
+    }
+
+    /// Date this reading from its uptime, given the time of the boot it was taken in, in ms since
+    /// the epoch, see boot_time. For readings taken before the clock was synced, once the offset
+    /// is known. The reading is no longer is_unsynced_time then.
+    pub(crate) fn reconcile_time(&mut self, boot_time: u64) {
+        self.timestamp = boot_time + self.uptime;
+        self.unsynced_time = false;
+    }
+
+    /// Frame this reading of CT `id` for the serial link, see serial::encode_frame.
+    pub(crate) fn reading_to_frame(&self, id: u16) -> Vec<u8> {
+        // A fixed size record can't fail to serialize.
+        let record = CTStorage::ct_reading_to_le_bytes(id, self, self.sequence)
+            .unwrap_or([0_u8; CT_READING_SIZE]);
+        encode_frame(&record)
+    }
+
+    /// Power factor of the whole save period.
+    ///
+    /// real_power and apparent_power are accumulated with the same weights by Accumulator, so their
+    /// ratio is sum(real) / sum(apparent) over the period. This is what a meter would report.
+    /// Averaging the power factor of each measurement instead is misleading under varying load:
+    /// a few light-load measurements (e.g. a 5W standby supply at PF 0.3) would count as much as
+    /// the heavy ones and drag the period value far below the PF of the energy actually consumed.
+    pub(crate) fn period_power_factor(&self) -> f32 {
+        if self.apparent_power > 0.0 {
+            self.real_power / self.apparent_power
+        } else {
+            0.0
+        }
+    }
+
+    /// Angle in degrees by which the current lags the voltage: the arccos of
+    /// period_power_factor, with the sign of the reactive power.
+    ///
+    /// 0 for a resistive load, positive for an inductive load, negative for a capacitive one, see
+    /// is_leading, towards ±90 the more reactive the load. Beyond ±90 when the reported real
+    /// power is negative, see PowerConvention. 0 without apparent power.
+    pub(crate) fn phase_angle_degrees(&self) -> f32 {
+        if self.apparent_power <= 0.0 {
+            return 0.0;
+        }
+        let angle = f32::acos(self.period_power_factor().clamp(-1.0, 1.0)).to_degrees();
+        if self.leading {
+            -angle
+        } else {
+            angle
+        }
+    }
+
+    /// Reactive power in var, positive for an inductive load and negative for a capacitive one,
+    /// see is_leading. Its size is what apparent_power has beyond real_power.
+    pub(crate) fn reactive_power(&self) -> f32 {
+        let reactive = f32::sqrt(f32::max(
+            self.apparent_power * self.apparent_power - self.real_power * self.real_power,
+            0.0,
+        ));
+        if self.leading {
+            -reactive
+        } else {
+            reactive
+        }
+    }
+
+    /// Whether the current leads the voltage, a capacitive load, rather than lagging it, an
+    /// inductive one. For a save period, whether its reactive power adds up to capacitive.
+    pub(crate) fn is_leading(&self) -> bool {
+        self.leading
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::sampling::tests::{test_sampler, MockAdc2Channel, MockChannel};
+    use std::time::Duration;
+
+    // The CTs of the board on pins that read 0 mV, like an ADC with nothing connected.
+    pub(crate) fn test_cts() -> [CT; AC_PHASE] {
+        CT::with_channels([(); AC_PHASE].map(|_| {
+            let current: Box<dyn AdcChannel> = Box::new(MockChannel(|| Ok(0)));
+            let voltage: Box<dyn AdcChannel> = Box::new(MockChannel(|| Ok(0)));
+            (current, voltage)
+        }))
+    }
+
+    pub(crate) fn test_ct() -> CT {
+        let [ct, ..] = test_cts();
+        ct
+    }
+
+    pub(crate) fn reading(real_power: f32, timestamp: u64) -> CTReading {
+        CTReading {
+            real_power,
+            apparent_power: real_power * 1.25,
+            i_rms: real_power * 1.25 / 230.0,
+            v_rms: 230.0,
+            kwh: real_power / 1000.0 / 120.0,
+            timestamp,
+            uptime: timestamp,
+            quality: Some(100),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn period_power_factor_weighs_by_power() {
+        let mut accumulator = Accumulator::default();
+        let heavy = CTReading {
+            real_power: 2000.0,
+            apparent_power: 2000.0,
+            ..Default::default()
+        };
+        let standby = CTReading {
+            real_power: 5.0,
+            apparent_power: 5.0 / 0.3,
+            ..Default::default()
+        };
+        for reading in [&heavy, &standby, &standby, &standby] {
+            accumulator.push(reading);
+        }
+        let period = accumulator.finalize();
+        let energy_pf = (2000.0 + 3.0 * 5.0) / (2000.0 + 3.0 * 5.0 / 0.3);
+        assert!((period.period_power_factor() - energy_pf).abs() < 1e-4);
+
+        let mean_pf = (heavy.period_power_factor() + 3.0 * standby.period_power_factor()) / 4.0;
+        assert!(period.period_power_factor() > 0.98);
+        assert!(mean_pf < 0.5);
+        assert_eq!(CTReading::default().period_power_factor(), 0.0);
+    }
+
+    #[test]
+    fn calibration_error_against_a_reference() {
+        let reading = CTReading {
+            real_power: 1100.0,
+            v_rms: 220.0,
+            ..Default::default()
+        };
+        let error = CalibrationError::between(&reading, 1000.0, 230.0).unwrap();
+        assert!((error.power_error_percent - 10.0).abs() < 1e-3);
+        assert!((error.voltage_error_percent - (220.0 - 230.0) / 230.0 * 100.0).abs() < 1e-3);
+        assert!((error.vcal_factor - 230.0 / 220.0).abs() < 1e-6);
+        assert!((error.vcal_factor * error.ical_factor - 1000.0 / 1100.0).abs() < 1e-6);
+
+        let cal = error.corrected(Calibration {
+            vcal: 200.0,
+            ical: 100.0,
+            phase_cal: 1.7,
+        });
+        assert!((cal.vcal - 200.0 * 230.0 / 220.0).abs() < 1e-3);
+        assert_eq!(cal.phase_cal, 1.7);
+    }
+
+    #[test]
+    fn calibration_error_rejects_bad_references() {
+        let reading = CTReading {
+            real_power: 1100.0,
+            v_rms: 220.0,
+            ..Default::default()
+        };
+        for (watts, volts) in [
+            (0.0, 230.0),
+            (1000.0, 0.0),
+            (-5.0, 230.0),
+            (f32::NAN, 230.0),
+        ] {
+            assert!(CalibrationError::between(&reading, watts, volts).is_err());
+        }
+        let nothing = CalibrationError::between(&CTReading::default(), 1000.0, 230.0).unwrap();
+        assert_eq!((nothing.vcal_factor, nothing.ical_factor), (1.0, 1.0));
+    }
+
+    // Rms in mV of the filtered voltage of `samples` samples of `signal` around mid-scale.
+    fn filtered_rms(
+        lowpass_alpha: Option<f32>,
+        samples: usize,
+        signal: impl Fn(usize) -> f32,
+    ) -> f32 {
+        let mid = MAX_MV_ATTEN_11 as f32 / 2.0;
+        let mut measurement = Measurement::new(mid, mid, 1.0, lowpass_alpha, ZERO_CROSS_BAND);
+        for n in 0..samples {
+            let sample = (mid + signal(n)).round() as u16;
+            measurement.add_sample(mid as u16, sample);
+        }
+        f32::sqrt(measurement.sum_v / measurement.n_samples as f32)
+    }
+
+    #[test]
+    fn lowpass_attenuates_high_frequencies_only() {
+        let mut ct = test_ct();
+        let sampler = crate::sampling::tests::test_sampler();
+        ct.set_lowpass_cutoff(Some(200.0));
+        // Off until the sample period is known.
+        assert!(ct.new_measurement(&sampler).lowpass_alpha.is_none());
+        let dt = 1.0 / 4000.0;
+        ct.diagnostics.sample_period = dt;
+        let alpha = ct.new_measurement(&sampler).lowpass_alpha.unwrap();
+
+        let mains = |n: usize| 800.0 * f32::sin(2.0 * std::f32::consts::PI * 50.0 * n as f32 * dt);
+        let hiss = |n: usize| if n % 2 == 0 { 100.0 } else { -100.0 };
+        let samples = 4000;
+        assert!(
+            filtered_rms(Some(alpha), samples, hiss) < 0.25 * filtered_rms(None, samples, hiss)
+        );
+        assert!(
+            filtered_rms(Some(alpha), samples, mains) > 0.9 * filtered_rms(None, samples, mains)
+        );
     }
-    pub(crate) fn set_time(&mut self, time: u64) {
-        self.timestamp = time;
+
+    pub(crate) const MID_SCALE: f32 = MAX_MV_ATTEN_11 as f32 / 2.0;
+
+    // (current, voltage) samples in mV of `cycles` mains cycles of 80 samples around mid-scale,
+    // the current lagging the voltage by `lag` radians, like bench::synthetic_waveform.
+    pub(crate) fn sine_samples(
+        cycles: usize,
+        v_amplitude: f32,
+        i_amplitude: f32,
+        lag: f32,
+    ) -> Vec<(u16, u16)> {
+        (0..cycles * 80)
+            .map(|n| {
+                let angle = 2.0 * std::f32::consts::PI * n as f32 / 80.0;
+                let voltage = MID_SCALE + v_amplitude * f32::sin(angle);
+                let current = MID_SCALE + i_amplitude * f32::sin(angle - lag);
+                (current.round() as u16, voltage.round() as u16)
+            })
+            .collect()
+    }
+
+    // A CT whose dc offsets already settled at mid-scale.
+    pub(crate) fn centred_ct() -> CT {
+        let mut ct = test_ct();
+        ct.current_pin.offset_i = MID_SCALE;
+        ct.voltage_pin.offset_v = MID_SCALE;
+        ct
+    }
+
+    // Run `samples` through one measurement of `ct` like replay_samples, as if it took `duration`.
+    pub(crate) fn measure_samples(
+        ct: &mut CT,
+        samples: Vec<(u16, u16)>,
+        duration: Duration,
+    ) -> CTReading {
+        let mut measurement = Measurement::new(
+            ct.current_pin.offset_i,
+            ct.voltage_pin.offset_v,
+            ct.voltage_pin.phase_cal,
+            None,
+            ct.config.zero_cross_band,
+        );
+        CT::sample_source(
+            &mut ReplaySource::new(samples),
+            ReadOrder::CurrentFirst,
+            ct.config.zero_cross_timeout,
+            ct.config.current_only,
+            &mut measurement,
+            u32::MAX,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        ct.finish_measurement(measurement, duration)
+    }
+
+    #[test]
+    fn kwh_is_real_power_over_the_measured_time() {
+        let mut ct = centred_ct();
+        let hour = measure_samples(
+            &mut ct,
+            sine_samples(20, 800.0, 400.0, 0.3),
+            Duration::from_secs(3600),
+        );
+        assert!(hour.real_power > 0.0);
+        assert!((hour.kwh - hour.real_power / 1000.0).abs() < 1e-4 * hour.real_power);
+
+        let mut ct = centred_ct();
+        let minute = measure_samples(
+            &mut ct,
+            sine_samples(20, 800.0, 400.0, 0.3),
+            Duration::from_secs(60),
+        );
+        assert!((minute.kwh * 60.0 - hour.kwh).abs() < 1e-4 * hour.kwh);
+    }
+
+    // An hour of a load drawing power from the grid, as measured by `ct`.
+    fn import_reading(ct: &mut CT) -> CTReading {
+        ct.current_pin.offset_i = MID_SCALE;
+        ct.voltage_pin.offset_v = MID_SCALE;
+        measure_samples(
+            ct,
+            sine_samples(20, 800.0, 400.0, 0.3),
+            Duration::from_secs(3600),
+        )
+    }
+
+    #[test]
+    fn power_convention_flips_real_power_and_kwh() {
+        let mut ct = test_ct();
+        let import = import_reading(&mut ct);
+        assert!(import.real_power > 0.0 && import.kwh > 0.0);
+
+        ct.set_power_convention(PowerConvention::ExportPositive);
+        let flipped = import_reading(&mut ct);
+        assert!((flipped.real_power + import.real_power).abs() < 1e-3);
+        assert!((flipped.kwh + import.kwh).abs() < 1e-6);
+        assert_eq!(flipped.apparent_power, import.apparent_power);
+    }
+
+    #[test]
+    fn reversed_ct_measures_import_again() {
+        let mut ct = test_ct();
+        let import = import_reading(&mut ct);
+        // Clamped the other way round the current is inverted.
+        let reversed_samples = sine_samples(20, 800.0, -400.0, 0.3);
+        ct.current_pin.offset_i = MID_SCALE;
+        ct.voltage_pin.offset_v = MID_SCALE;
+        let backwards =
+            measure_samples(&mut ct, reversed_samples.clone(), Duration::from_secs(3600));
+        assert!(backwards.real_power < 0.0);
+
+        ct.set_reversed(true);
+        ct.current_pin.offset_i = MID_SCALE;
+        ct.voltage_pin.offset_v = MID_SCALE;
+        let fixed = measure_samples(&mut ct, reversed_samples, Duration::from_secs(3600));
+        assert!((fixed.real_power - import.real_power).abs() < 0.01 * import.real_power);
+        assert!(fixed.kwh > 0.0);
+    }
+
+    #[test]
+    fn negative_energy_follows_the_direction_not_the_sign() {
+        for &convention in &[
+            PowerConvention::ImportPositive,
+            PowerConvention::ExportPositive,
+        ] {
+            let mut ct = test_ct();
+            ct.set_warmup_readings(0);
+            ct.set_power_convention(convention);
+            ct.set_negative_energy(NegativeEnergy::Export);
+            let import = convention.apply(0.5);
+            let export = convention.apply(-0.25);
+            for &kwh in &[import, export] {
+                ct.add_reading(CTReading {
+                    kwh,
+                    ..reading(convention.apply(kwh * 1000.0), 0)
+                });
+            }
+            assert_eq!(ct.reading.kwh, import);
+            assert_eq!(ct.exported_kwh(), 0.25);
+        }
+    }
+
+    // A resistive load sampled one read at a time, each read half a sample period after the one
+    // before, as the one-shot reads are spaced.
+    struct InterleavedSource {
+        reads: u32,
+    }
+
+    impl InterleavedSource {
+        fn read(&mut self, amplitude: f32) -> u16 {
+            let angle = std::f32::consts::PI * self.reads as f32 / 80.0;
+            self.reads += 1;
+            (MID_SCALE + amplitude * f32::sin(angle)).round() as u16
+        }
+    }
+
+    impl SampleSource for InterleavedSource {
+        fn read_current(&mut self) -> anyhow::Result<u16> {
+            Ok(self.read(400.0))
+        }
+
+        fn read_voltage(&mut self) -> anyhow::Result<u16> {
+            Ok(self.read(800.0))
+        }
+
+        fn is_exhausted(&self) -> bool {
+            self.reads >= 2 * 80 * 20
+        }
+    }
+
+    fn interleaved_power_factor(ct: &mut CT, order: ReadOrder) -> f32 {
+        let mut measurement = ct.new_measurement(&test_sampler());
+        CT::sample_source(
+            &mut InterleavedSource { reads: 0 },
+            order,
+            ct.config.zero_cross_timeout,
+            false,
+            &mut measurement,
+            u32::MAX,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        let reading = ct.finish_measurement(measurement, Duration::from_secs(1));
+        reading.real_power / reading.apparent_power
+    }
+
+    #[test]
+    fn read_order_keeps_the_calibrated_phase() {
+        let mut ct = centred_ct();
+        // Lines the voltage up with a current read half a sample period before it.
+        ct.voltage_pin.phase_cal = 0.5;
+        for &order in &[ReadOrder::CurrentFirst, ReadOrder::VoltageFirst] {
+            ct.set_read_order(order);
+            let pf = interleaved_power_factor(&mut ct, order);
+            assert!(pf > 0.999, "{:?}: power factor {}", order, pf);
+        }
+
+        // Without the offset of the order the voltage ends up a sample period off.
+        ct.set_read_order(ReadOrder::CurrentFirst);
+        let pf = interleaved_power_factor(&mut ct, ReadOrder::VoltageFirst);
+        assert!(pf < 0.999, "power factor {}", pf);
+    }
+
+    #[test]
+    fn current_correction_interpolates_and_extrapolates() {
+        let points = [(1.0, 1.2), (5.0, 5.4), (10.0, 10.4)];
+        // On, between and beyond the points.
+        assert!((interpolate(&points, 5.0) - 5.4).abs() < 1e-6);
+        assert!((interpolate(&points, 3.0) - 3.3).abs() < 1e-6);
+        assert!((interpolate(&points, 7.5) - 7.9).abs() < 1e-6);
+        assert!((interpolate(&points, 0.0) - 0.15).abs() < 1e-6);
+        assert!((interpolate(&points, 20.0) - 20.4).abs() < 1e-5);
+        // No points, a single point and a vertical segment.
+        assert_eq!(interpolate(&[], 3.0), 3.0);
+        assert_eq!(interpolate(&[(2.0, 3.0)], 4.0), 6.0);
+        assert_eq!(interpolate(&[(0.0, 3.0)], 4.0), 4.0);
+        assert_eq!(interpolate(&[(2.0, 3.0), (2.0, 5.0)], 4.0), 3.0);
+    }
+
+    #[test]
+    fn current_correction_scales_i_rms_and_power() {
+        let samples = sine_samples(20, 800.0, 400.0, 0.3);
+        let mut ct = centred_ct();
+        let plain = measure_samples(&mut ct, samples.clone(), Duration::from_secs(1));
+
+        // Unsorted points are sorted, this curve reads 10% high everywhere.
+        let mut ct = centred_ct();
+        ct.set_current_correction(vec![(2.0 * plain.i_rms, 2.2 * plain.i_rms), (0.0, 0.0)]);
+        let corrected = measure_samples(&mut ct, samples, Duration::from_secs(1));
+        assert!((corrected.i_rms - 1.1 * plain.i_rms).abs() < 1e-3 * plain.i_rms);
+        assert!((corrected.real_power - 1.1 * plain.real_power).abs() < 1e-3 * plain.real_power);
+        assert!((corrected.apparent_power - 1.1 * plain.apparent_power).abs() < 0.01);
+    }
+
+    #[test]
+    fn anomalous_voltage_is_relative_to_the_nominal_one() {
+        let us_mains = CTReading {
+            v_rms: 120.0,
+            ..reading(100.0, 0)
+        };
+        assert!(us_mains.is_anomalous(NOMINAL_VOLTAGE));
+        assert!(!us_mains.is_anomalous(120.0));
+
+        let mut ct = test_ct();
+        ct.set_warmup_readings(0);
+        ct.set_measurement_retries(1);
+        let mut retries = 0;
+        assert!(!ct.keep_reading(us_mains.clone(), &mut retries));
+        assert_eq!(retries, 1);
+
+        ct.set_nominal_voltage(120.0);
+        let mut retries = 0;
+        assert!(ct.keep_reading(us_mains, &mut retries));
+        assert_eq!(retries, 0);
+        assert_eq!(ct.reading.v_rms, 120.0);
+    }
+
+    #[test]
+    fn reset_offsets_goes_back_to_the_defaults() {
+        let mut ct = test_ct();
+        ct.set_warmup_readings(1);
+        assert_eq!(ct.current_offsets(), (DEFAULT_OFFSET_I, DEFAULT_OFFSET_V));
+        // A waveform biased well above the defaults pulls the offsets up.
+        let shifted: Vec<(u16, u16)> = sine_samples(20, 800.0, 400.0, 0.0)
+            .into_iter()
+            .map(|(i, v)| (i + 300, v + 300))
+            .collect();
+        for _ in 0..5 {
+            let reading = measure_samples(&mut ct, shifted.clone(), Duration::from_secs(1));
+            ct.add_reading(reading);
+        }
+        let (offset_i, offset_v) = ct.current_offsets();
+        assert!(offset_i > DEFAULT_OFFSET_I + 100.0 && offset_v > DEFAULT_OFFSET_V + 100.0);
+        assert_eq!(ct.warmup_remaining, 0);
+
+        ct.reset_offsets();
+        assert_eq!(ct.current_offsets(), (DEFAULT_OFFSET_I, DEFAULT_OFFSET_V));
+        assert_eq!(ct.warmup_remaining, 1);
+    }
+
+    // Three voltages of 800 mV around mid-scale, lagging the first by `lags` degrees, read in
+    // turns with 80 reads of each per mains period. An amplitude of 0 keeps a pin flat.
+    #[cfg(feature = "three-phase")]
+    fn three_phases(
+        lags: [f32; 3],
+        amplitudes: [f32; 3],
+    ) -> impl FnMut(usize) -> anyhow::Result<u16> {
+        let mut reads = 0_u32;
+        move |pin| {
+            let angle = 2.0 * std::f32::consts::PI * reads as f32 / 240.0 - lags[pin].to_radians();
+            reads += 1;
+            Ok((MID_SCALE + amplitudes[pin] * f32::sin(angle)).round() as u16)
+        }
+    }
+
+    #[cfg(feature = "three-phase")]
+    fn rotation_of(lags: [f32; 3], amplitudes: [f32; 3]) -> (PhaseRotation, Option<[f64; 2]>) {
+        let phases = interleaved_voltage_phases(
+            three_phases(lags, amplitudes),
+            [MID_SCALE; 3],
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        (phase_rotation(phases), phases)
+    }
+
+    #[cfg(feature = "three-phase")]
+    #[test]
+    fn phase_rotation_times_all_taps_on_the_same_reads() {
+        let taps = [800.0; 3];
+        let (rotation, phases) = rotation_of([0.0, 120.0, 240.0], taps);
+        assert_eq!(rotation, PhaseRotation::Abc);
+        let [second, third] = phases.unwrap();
+        assert!((second - 120.0).abs() < 2.0 && (third - 240.0).abs() < 2.0);
+
+        assert_eq!(rotation_of([0.0, 240.0, 120.0], taps).0, PhaseRotation::Acb);
+        // The first and the second tap on the same phase.
+        assert_eq!(rotation_of([0.0, 0.0, 240.0], taps).0, PhaseRotation::Fault);
+        // No voltage on the third tap.
+        assert_eq!(
+            rotation_of([0.0, 120.0, 240.0], [800.0, 800.0, 0.0]).0,
+            PhaseRotation::Unknown
+        );
+    }
+
+    #[test]
+    fn json_and_csv_fit_the_buffer_or_fail() {
+        let reading = CTReading {
+            v_rms: f32::NAN,
+            sequence: 7,
+            ..reading(100.0, 1_000)
+        };
+        let mut buf = [0_u8; 256];
+        let n = reading.write_json(3, &mut buf).unwrap();
+        let json = std::str::from_utf8(&buf[..n]).unwrap();
+        assert!(json.starts_with("{\"id\":3,\"timestamp\":1000,\"sequence\":7,"));
+        assert!(json.contains(",\"real_power\":100,"));
+        assert!(json.contains(",\"v_rms\":null,"));
+        assert!(json.ends_with('}'));
+        assert!(reading.write_json(3, &mut buf[..n - 1]).is_err());
+
+        let n = reading.write_csv(3, &mut buf).unwrap();
+        let csv = std::str::from_utf8(&buf[..n]).unwrap();
+        assert!(csv.starts_with("3,1000,7,100,125,"));
+        assert_eq!(csv.matches(',').count(), CSV_HEADER.matches(',').count());
+        assert!(csv.ends_with('\n'));
+        assert!(reading.write_csv(3, &mut buf[..n - 1]).is_err());
+        assert!(reading.write_csv(3, &mut []).is_err());
+    }
+
+    #[test]
+    fn peaks_of_a_sine_give_its_crest_factor() {
+        let mut ct = centred_ct();
+        let reading = measure_samples(
+            &mut ct,
+            sine_samples(20, 800.0, 400.0, 0.0),
+            Duration::from_secs(1),
+        );
+        let sqrt_2 = std::f32::consts::SQRT_2;
+        assert!((reading.v_crest_factor() - sqrt_2).abs() < 0.05);
+        assert!((reading.i_crest_factor() - sqrt_2).abs() < 0.05);
+    }
+
+    #[test]
+    fn peak_of_an_asymmetric_waveform_is_its_larger_half_wave() {
+        // A second harmonic makes the current swing from 0.75 above the offset to 1.5 below it.
+        let samples: Vec<(u16, u16)> = sine_samples(20, 800.0, 400.0, 0.0)
+            .into_iter()
+            .enumerate()
+            .map(|(n, (_, voltage))| {
+                let angle = 2.0 * std::f32::consts::PI * n as f32 / 80.0;
+                let current = f32::sin(angle) + 0.5 * f32::cos(2.0 * angle);
+                ((MID_SCALE + 400.0 * current).round() as u16, voltage)
+            })
+            .collect();
+        let mut distorted = centred_ct();
+        let reading = measure_samples(&mut distorted, samples, Duration::from_secs(1));
+        let mut clean = centred_ct();
+        let sine = measure_samples(
+            &mut clean,
+            sine_samples(20, 800.0, 400.0, 0.0),
+            Duration::from_secs(1),
+        );
+        // The larger half wave, not half the swing, which would be 1.125. The running offset
+        // ripples a little with the harmonic.
+        assert!((reading.i_peak / sine.i_peak - 1.5).abs() < 0.1);
+        assert!((reading.v_peak - sine.v_peak).abs() < 0.01 * sine.v_peak);
+    }
+
+    #[test]
+    fn calibration_error_ignores_the_sign_and_rejects_empty_references() {
+        let export = CTReading {
+            real_power: -1100.0,
+            v_rms: 220.0,
+            ..Default::default()
+        };
+        let error = CalibrationError::between(&export, 1000.0, 230.0).unwrap();
+        assert!((error.power_error_percent - 10.0).abs() < 1e-3);
+        assert!((error.vcal_factor - 230.0 / 220.0).abs() < 1e-6);
+        assert!((error.vcal_factor * error.ical_factor - 1000.0 / 1100.0).abs() < 1e-6);
+
+        assert!(CalibrationError::between(&export, 0.0, 230.0).is_err());
+        assert!(CalibrationError::between(&export, 1000.0, 0.0).is_err());
+        assert!(CalibrationError::between(&export, f32::NAN, 230.0).is_err());
+    }
+
+    // A CT whose pins read a 50 Hz sine of 80 samples per cycle, one sample per voltage read.
+    // With `fail_every` every that many current reads fail.
+    pub(crate) fn mock_sine_ct(fail_every: Option<usize>) -> CT {
+        let sample = std::rc::Rc::new(std::cell::Cell::new(0_usize));
+        let at = |n: usize, amplitude: f32| {
+            let angle = 2.0 * std::f32::consts::PI * (n % 80) as f32 / 80.0;
+            (MID_SCALE + amplitude * f32::sin(angle)).round() as u16
+        };
+        let mut ct = centred_ct();
+        let current = sample.clone();
+        let mut reads = 0;
+        ct.current_pin.pin = Box::new(MockChannel(move || {
+            reads += 1;
+            match fail_every {
+                Some(every) if reads % every == 0 => anyhow::bail!("read timed out"),
+                _ => Ok(at(current.get(), 400.0)),
+            }
+        }));
+        ct.voltage_pin.pin = Box::new(MockChannel(move || {
+            sample.set(sample.get() + 1);
+            Ok(at(sample.get() - 1, 800.0))
+        }));
+        ct
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn measurement_rides_out_failed_adc_reads() {
+        use crate::fault::{FaultConfig, FaultInjector};
+
+        let timeout = Duration::from_secs(5);
+        let clean = mock_sine_ct(None)
+            .measure_once(&mut test_sampler(), 40, timeout)
+            .unwrap();
+        let mut ct = mock_sine_ct(None);
+        ct.inject_adc_faults(&FaultInjector::new(FaultConfig {
+            adc_failure_rate: 0.02,
+            ..Default::default()
+        }));
+        let degraded = ct.measure_once(&mut test_sampler(), 40, timeout).unwrap();
+        // Failed reads repeat the sample before, which barely moves the rms values.
+        assert!((degraded.v_rms - clean.v_rms).abs() < 0.02 * clean.v_rms);
+        assert!((degraded.i_rms - clean.i_rms).abs() < 0.02 * clean.i_rms);
+        assert!((degraded.real_power - clean.real_power).abs() < 0.05 * clean.real_power);
+
+        // With every read failing there is nothing to measure.
+        let mut ct = mock_sine_ct(None);
+        ct.inject_adc_faults(&FaultInjector::new(FaultConfig {
+            adc_failure_rate: 1.0,
+            ..Default::default()
+        }));
+        assert!(ct
+            .measure_once(&mut test_sampler(), 40, Duration::from_millis(100))
+            .is_err());
+    }
+
+    // Whether `ct` reports exporting after each of the readings of `powers`.
+    fn directions(ct: &mut CT, powers: &[f32]) -> Vec<bool> {
+        powers
+            .iter()
+            .map(|&real_power| {
+                ct.add_reading(reading(real_power, 0));
+                ct.is_exporting()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn direction_only_flips_after_a_sustained_crossing() {
+        let mut ct = test_ct();
+        ct.set_warmup_readings(0);
+        ct.set_export_hysteresis(10.0, 3);
+        // Flickering around zero and short dips keep the direction.
+        let flicker = [5.0, -5.0, 8.0, -9.0, -50.0, -50.0, 20.0, -50.0, -5.0];
+        assert_eq!(directions(&mut ct, &flicker), [false; 9]);
+        // Three readings in a row beyond the dead zone flip it, and back.
+        let export = [-50.0, -50.0, -50.0, 50.0, -50.0, 50.0, 50.0, 50.0];
+        assert_eq!(
+            directions(&mut ct, &export),
+            [false, false, true, true, true, true, true, false]
+        );
+
+        // Without a dead zone and hysteresis every reading sets the direction.
+        ct.set_export_hysteresis(0.0, 1);
+        assert_eq!(directions(&mut ct, &[-1.0, 1.0, -1.0]), [true, false, true]);
+    }
+
+    #[test]
+    fn direction_is_the_same_in_either_power_convention() {
+        let mut ct = test_ct();
+        ct.set_warmup_readings(0);
+        ct.set_power_convention(PowerConvention::ExportPositive);
+        // The readings are already flipped to export positive.
+        assert_eq!(
+            directions(&mut ct, &[50.0, 50.0, 50.0]),
+            [false, false, true]
+        );
+    }
+
+    #[test]
+    fn measurement_with_too_many_failed_reads_is_rejected() {
+        let timeout = Duration::from_secs(5);
+        let clean = mock_sine_ct(None)
+            .measure_once(&mut test_sampler(), 40, timeout)
+            .unwrap();
+        // 1 of 50 current reads, 1 % of all reads, repeats the sample before.
+        let few = mock_sine_ct(Some(50))
+            .measure_once(&mut test_sampler(), 40, timeout)
+            .unwrap();
+        assert!((few.i_rms - clean.i_rms).abs() < 0.02 * clean.i_rms);
+        // 1 of 5, 10 % of all reads, is too many.
+        let err = mock_sine_ct(Some(5))
+            .measure_once(&mut test_sampler(), 40, timeout)
+            .unwrap_err();
+        assert!(err.to_string().contains("measurement rejected"), "{}", err);
+
+        // calculate_energy drops it and the measurement loop goes on with the next CT.
+        let mut cts = [mock_sine_ct(Some(5)), mock_sine_ct(None)];
+        for ct in &mut cts {
+            ct.set_warmup_readings(0);
+            ct.calculate_energy(&mut test_sampler(), 40, timeout)
+                .unwrap();
+        }
+        assert_eq!(cts[0].reading.i_rms, 0.0);
+        assert!((cts[1].reading.i_rms - clean.i_rms).abs() < 0.02 * clean.i_rms);
+    }
+
+    #[test]
+    fn self_test_reports_the_noise_floor() {
+        use crate::sampling::tests::noisy;
+
+        let mut quiet = centred_ct();
+        quiet.current_pin.pin = Box::new(MockChannel(noisy(MID_SCALE as u16, 2)));
+        quiet.voltage_pin.pin = Box::new(MockChannel(|| Ok(MID_SCALE as u16)));
+        let report = quiet.self_test(&mut test_sampler(), 2000).unwrap();
+        assert!(report.passed, "{:?}", report);
+        assert!(report.noise_floor.i_raw > 0.0);
+        assert!(report.noise_floor.i_raw <= MAX_NOISE_FLOOR);
+        assert_eq!(report.noise_floor.samples, 2000);
+
+        let mut noisy_ct = centred_ct();
+        noisy_ct.current_pin.pin = Box::new(MockChannel(noisy(MID_SCALE as u16, 100)));
+        noisy_ct.voltage_pin.pin = Box::new(MockChannel(|| Ok(MID_SCALE as u16)));
+        let report = noisy_ct.self_test(&mut test_sampler(), 2000).unwrap();
+        assert!(!report.passed, "{:?}", report);
+        assert!(report.noise_floor.i_raw > MAX_NOISE_FLOOR);
+        // The amps are the mV through the calibration.
+        assert!(report.noise_floor.i_rms > 0.0);
+    }
+
+    #[test]
+    fn cycle_correction_integrates_whole_cycles() {
+        // A sine of 73.3 samples per cycle, so the crossings fall between samples.
+        let period = 73.3;
+        let samples: Vec<(u16, u16)> = (0..2000)
+            .map(|n| {
+                let angle = 2.0 * std::f64::consts::PI * n as f64 / period;
+                let voltage = MID_SCALE as f64 + 800.0 * f64::sin(angle + 0.3);
+                let current = MID_SCALE as f64 + 400.0 * f64::sin(angle + 0.3 - 0.5);
+                (current.round() as u16, voltage.round() as u16)
+            })
+            .collect();
+        let measure = |corrected: bool, crossings: u32| {
+            let mut ct = centred_ct();
+            ct.set_cycle_correction(corrected);
+            let mut measurement =
+                Measurement::new(MID_SCALE, MID_SCALE, 0.0, None, ZERO_CROSS_BAND);
+            measurement.cycle_correction = corrected;
+            CT::sample_source(
+                &mut ReplaySource::new(samples.clone()),
+                ReadOrder::CurrentFirst,
+                ct.config.zero_cross_timeout,
+                false,
+                &mut measurement,
+                crossings,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+            if corrected {
+                measurement.trim_edges();
+            }
+            ct.finish_measurement(measurement, Duration::from_secs(1))
+        };
+        // The edges hardly matter over many crossings.
+        let reference = measure(false, 50);
+        let error = |value: f32, reference: f32| f32::abs(value / reference - 1.0);
+        // Crossings of a voltage a bit off zero are whole cycles apart, not half cycles.
+        for &crossings in &[2, 4, 6] {
+            let corrected = measure(true, crossings);
+            for &(corrected, reference) in &[
+                (corrected.v_rms, reference.v_rms),
+                (corrected.i_rms, reference.i_rms),
+                (corrected.real_power, reference.real_power),
+            ] {
+                assert!(
+                    error(corrected, reference) < 0.003,
+                    "{} crossings: {} vs {}",
+                    crossings,
+                    corrected,
+                    reference
+                );
+            }
+        }
+        // Without the correction a slice of one cycle is about a sample too long.
+        let plain = measure(false, 2).real_power;
+        assert!(error(plain, reference.real_power) > 0.005, "{}", plain);
+    }
+
+    #[test]
+    fn period_rms_is_the_root_mean_square_of_the_measurements() {
+        // A heater switching between off and 10 A, every other measurement.
+        let period = |average_rms: bool| {
+            let mut ct = test_ct();
+            ct.set_warmup_readings(0);
+            ct.set_average_rms(average_rms);
+            for i in 0..10 {
+                let (i_rms, v_rms) = if i % 2 == 0 {
+                    (0.0, 240.0)
+                } else {
+                    (10.0, 220.0)
+                };
+                ct.add_reading(CTReading {
+                    i_rms,
+                    v_rms,
+                    ..reading(0.0, 0)
+                });
+            }
+            ct.reading
+        };
+        let rms = period(false);
+        assert!((rms.i_rms - f32::sqrt(50.0)).abs() < 1e-3, "{}", rms.i_rms);
+        let v_rms = f32::sqrt((240.0_f32.powi(2) + 220.0_f32.powi(2)) / 2.0);
+        assert!((rms.v_rms - v_rms).abs() < 1e-3, "{}", rms.v_rms);
+        // The mean of the rms values falls short on the varying load.
+        let averaged = period(true);
+        assert!((averaged.i_rms - 5.0).abs() < 1e-3, "{}", averaged.i_rms);
+        assert!((averaged.v_rms - 230.0).abs() < 1e-3, "{}", averaged.v_rms);
+        assert!(averaged.i_rms < rms.i_rms);
+    }
+
+    #[test]
+    fn calibration_str_round_trips() {
+        let mut ct = test_ct();
+        ct.apply_calibration_str(" vcal=232.5, ical=102 ,phase=-1.7,")
+            .unwrap();
+        let cal = ct.calibration();
+        assert_eq!((cal.vcal, cal.ical, cal.phase_cal), (232.5, 102.0, -1.7));
+        assert_eq!(ct.calibration_str(), "vcal=232.5,ical=102,phase=-1.7");
+
+        let mut copy = test_ct();
+        copy.apply_calibration_str(&ct.calibration_str()).unwrap();
+        assert_eq!(copy.calibration_str(), ct.calibration_str());
+
+        // Fields left out keep their value.
+        ct.apply_calibration_str("ical=95.5").unwrap();
+        assert_eq!(ct.calibration_str(), "vcal=232.5,ical=95.5,phase=-1.7");
+        ct.apply_calibration_str("").unwrap();
+        assert_eq!(ct.calibration_str(), "vcal=232.5,ical=95.5,phase=-1.7");
+    }
+
+    #[test]
+    fn malformed_calibration_str_applies_nothing() {
+        let mut ct = test_ct();
+        ct.apply_calibration_str("vcal=232.5,ical=102,phase=1.7")
+            .unwrap();
+        for malformed in [
+            "vcal",
+            "vcal=",
+            "vcal=abc",
+            "vcal=0",
+            "ical=-3",
+            "ical=inf",
+            "phase=NaN",
+            "gain=2",
+            "VCAL=230",
+            "vcal=230,vcal=231",
+            "ical=90,vcal=230;phase=1",
+            "ical=90,offset=2048",
+        ] {
+            assert!(
+                ct.apply_calibration_str(malformed).is_err(),
+                "{:?} was accepted",
+                malformed
+            );
+            assert_eq!(ct.calibration_str(), "vcal=232.5,ical=102,phase=1.7");
+        }
+    }
+
+    #[test]
+    fn lifetime_energy_survives_an_interval_reset() {
+        let mut ct = test_ct();
+        ct.reading = reading(1200.0, 1_000);
+        let kwh = ct.reading.kwh as f64;
+        assert_eq!(ct.lifetime_kwh(), kwh);
+        ct.reset_interval();
+        assert_eq!(ct.reading.kwh, 0.0);
+        assert_eq!(ct.reading.real_power, 0.0);
+        assert_eq!(ct.reading.i_rms, 0.0);
+        assert_eq!(ct.reading.v_rms, 0.0);
+        assert!(ct.reading.quality.is_none());
+        assert_eq!(ct.lifetime_kwh(), kwh);
+
+        ct.reading = reading(600.0, 2_000);
+        let total = kwh + ct.reading.kwh as f64;
+        assert_eq!(ct.lifetime_kwh(), total);
+        ct.reset_interval();
+        ct.reset_interval();
+        assert_eq!(ct.lifetime_kwh(), total);
+    }
+
+    #[test]
+    fn only_a_flat_voltage_is_a_stuck_channel() {
+        // No load on the CT: the current pin reads the bias while the voltage swings.
+        let mut ct = centred_ct();
+        let idle = measure_samples(
+            &mut ct,
+            sine_samples(10, 800.0, 0.0, 0.0),
+            Duration::from_millis(200),
+        );
+        assert!(!ct.last_channel_stuck());
+        assert!(!idle.stuck);
+
+        // A voltage pin that reads the same value all through.
+        let mut ct = centred_ct();
+        let stuck = measure_samples(
+            &mut ct,
+            sine_samples(10, 0.0, 400.0, 0.0),
+            Duration::from_millis(200),
+        );
+        assert!(ct.last_channel_stuck());
+        assert!(stuck.stuck);
+        assert!(stuck.is_anomalous(230.0));
+    }
+
+    // The reading of a period of measurements that had every metric at `values` in turn,
+    // combined by `aggregation`.
+    fn aggregated(aggregation: Aggregation, values: &[f32]) -> CTReading {
+        let mut accumulator = Accumulator {
+            aggregations: Aggregations {
+                real_power: aggregation,
+                apparent_power: aggregation,
+                i_rms: aggregation,
+                v_rms: aggregation,
+                v_peak: aggregation,
+                i_peak: aggregation,
+            },
+            ..Default::default()
+        };
+        for (n, &value) in values.iter().enumerate() {
+            accumulator.push(&CTReading {
+                real_power: value,
+                apparent_power: value,
+                i_rms: value,
+                v_rms: value,
+                v_peak: value,
+                i_peak: value,
+                kwh: 0.5,
+                quality: Some(100 - n as u8),
+                timestamp: 1_000 * (n as u64 + 1),
+                ..Default::default()
+            });
+        }
+        accumulator.finalize()
+    }
+
+    fn assert_all_metrics(reading: &CTReading, expected: f32) {
+        for value in reading.aggregated_values() {
+            assert!(
+                (value - expected).abs() < 1e-3,
+                "{} instead of {}",
+                value,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn mean_aggregation_averages_every_measurement_evenly() {
+        let reading = aggregated(Aggregation::Mean, &[100.0, 200.0, 600.0]);
+        assert_all_metrics(&reading, 300.0);
+        // The kWh are summed whatever the aggregation, the rest comes from all measurements.
+        assert_eq!(reading.kwh, 1.5);
+        assert_eq!(reading.quality, Some(98));
+        assert_eq!(reading.timestamp, 3_000);
+    }
+
+    #[test]
+    fn max_aggregation_keeps_the_peak() {
+        assert_all_metrics(&aggregated(Aggregation::Max, &[100.0, 600.0, 200.0]), 600.0);
+        assert_all_metrics(&aggregated(Aggregation::Max, &[-300.0, -100.0]), -100.0);
+    }
+
+    #[test]
+    fn last_aggregation_keeps_the_last_measurement() {
+        assert_all_metrics(
+            &aggregated(Aggregation::Last, &[100.0, 600.0, 200.0]),
+            200.0,
+        );
+    }
+
+    #[test]
+    fn rms_of_squares_aggregation_is_the_rms_of_the_period() {
+        // A heater switching between 0 and 10 A.
+        let reading = aggregated(Aggregation::RmsOfSquares, &[0.0, 10.0, 0.0, 10.0]);
+        assert_all_metrics(&reading, f32::sqrt(50.0));
+        assert_all_metrics(&aggregated(Aggregation::Mean, &[0.0, 10.0, 0.0, 10.0]), 5.0);
+    }
+
+    #[test]
+    fn aggregations_apply_per_metric() {
+        let mut accumulator = Accumulator {
+            aggregations: Aggregations {
+                i_rms: Aggregation::Max,
+                v_rms: Aggregation::Mean,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_all_metrics(&accumulator.finalize(), 0.0);
+        for (i_rms, v_rms) in [(2.0, 228.0), (9.0, 232.0), (4.0, 230.0)] {
+            accumulator.push(&CTReading {
+                i_rms,
+                v_rms,
+                ..reading(500.0, 1_000)
+            });
+        }
+        let period = accumulator.finalize();
+        assert_eq!(period.i_rms, 9.0);
+        assert!((period.v_rms - 230.0).abs() < 1e-3);
+
+        // A reset starts the next period with the same aggregations.
+        accumulator.reset();
+        accumulator.push(&CTReading {
+            i_rms: 1.0,
+            ..reading(500.0, 2_000)
+        });
+        accumulator.push(&CTReading {
+            i_rms: 3.0,
+            ..reading(500.0, 3_000)
+        });
+        assert_eq!(accumulator.finalize().i_rms, 3.0);
+    }
+
+    #[test]
+    fn reconcile_time_dates_a_reading_from_its_uptime() {
+        let mut unsynced = reading(500.0, 3_000);
+        unsynced.unsynced_time = true;
+        unsynced.uptime = 45_000;
+        unsynced.reconcile_time(1_700_000_000_000);
+        assert_eq!(unsynced.timestamp, 1_700_000_045_000);
+        assert!(!unsynced.is_unsynced_time());
+    }
+
+    // A dead voltage channel, flat well away from mid-scale, with a live current.
+    struct FlatVoltageSource {
+        reads: u32,
+    }
+
+    impl SampleSource for FlatVoltageSource {
+        fn read_current(&mut self) -> anyhow::Result<u16> {
+            self.reads += 1;
+            let angle = std::f32::consts::PI * self.reads as f32 / 40.0;
+            Ok((MID_SCALE + 400.0 * f32::sin(angle)).round() as u16)
+        }
+
+        fn read_voltage(&mut self) -> anyhow::Result<u16> {
+            Ok((MID_SCALE + 1000.0) as u16)
+        }
+
+        fn is_exhausted(&self) -> bool {
+            false
+        }
+    }
+
+    fn measure_flat_voltage(ct: &mut CT) -> anyhow::Result<CTReading> {
+        // The offset tracking would otherwise settle on the flat voltage.
+        ct.voltage_pin.offset_v = MID_SCALE;
+        let mut measurement = ct.new_measurement(&test_sampler());
+        CT::sample_source(
+            &mut FlatVoltageSource { reads: 0 },
+            ReadOrder::CurrentFirst,
+            ct.config.zero_cross_timeout,
+            false,
+            &mut measurement,
+            u32::MAX,
+            Duration::from_millis(5),
+        )?;
+        Ok(ct.finish_measurement(measurement, Duration::from_secs(1)))
+    }
+
+    #[test]
+    fn flat_voltage_is_skipped_and_flagged() {
+        let mut ct = centred_ct();
+        assert_eq!(ct.config.zero_cross_timeout, ZeroCrossTimeout::Skip);
+        ct.set_warmup_readings(0);
+        ct.add_reading(reading(100.0, 0));
+        let kept = ct.reading.clone();
+
+        let flat = measure_flat_voltage(&mut ct).unwrap();
+        ct.add_reading(flat);
+        assert!(ct.reading.is_apparent_only());
+        assert_eq!(ct.reading.real_power, kept.real_power);
+        assert_eq!(ct.reading.kwh, kept.kwh);
+        assert_eq!(ct.accumulator.count, 1);
+
+        // Skipped before anything was measured, the period is flagged rather than lost.
+        ct.reset_interval();
+        let flat = measure_flat_voltage(&mut ct).unwrap();
+        ct.add_reading(flat);
+        assert!(ct.reading.is_apparent_only());
+        assert_eq!(ct.reading.kwh, 0.0);
+
+        ct.set_zero_cross_timeout(ZeroCrossTimeout::ApparentOnly);
+        let apparent = measure_flat_voltage(&mut ct).unwrap();
+        assert!(apparent.is_apparent_only());
+        assert_eq!(apparent.v_rms, ct.config.nominal_voltage);
+        assert_eq!(apparent.real_power, 0.0);
+        assert!(apparent.apparent_power > 0.0);
+
+        ct.set_zero_cross_timeout(ZeroCrossTimeout::Abort);
+        assert!(measure_flat_voltage(&mut ct).is_err());
+    }
+
+    // A CT on a weak voltage that crosses slowly: a sine of 150 mV, 40 voltage reads per cycle and
+    // a millisecond per read, one crossing every 20 ms. Counts the voltage reads in `reads`.
+    fn slow_crossing_ct(reads: std::rc::Rc<std::cell::Cell<usize>>) -> CT {
+        let at = |n: usize, amplitude: f32| {
+            let angle = 2.0 * std::f32::consts::PI * (n % 40) as f32 / 40.0;
+            (MID_SCALE + amplitude * f32::sin(angle)).round() as u16
+        };
+        let mut ct = centred_ct();
+        let current = reads.clone();
+        ct.current_pin.pin = Box::new(MockChannel(move || Ok(at(current.get(), 400.0))));
+        ct.voltage_pin.pin = Box::new(MockChannel(move || {
+            std::thread::sleep(Duration::from_millis(1));
+            reads.set(reads.get() + 1);
+            Ok(at(reads.get() - 1, 150.0))
+        }));
+        ct
+    }
+
+    #[test]
+    fn slow_crossings_act_on_the_minimum_crossings_policy() {
+        let timeout = Duration::from_millis(60);
+        let reads = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut ct = slow_crossing_ct(reads.clone());
+        let reading = ct.measure_once(&mut test_sampler(), 40, timeout).unwrap();
+        // Without a minimum the short reading isn't flagged, the diagnostics still tell.
+        assert!(!reading.is_under_sampled());
+        let (reached, requested) = ct.last_crossings();
+        assert!(reached < 10, "reached {} crossings", reached);
+        assert_eq!(requested, 40);
+
+        ct.set_min_crossings(Some((10, ShortCrossings::Flag)));
+        reads.set(0);
+        let reading = ct.measure_once(&mut test_sampler(), 40, timeout).unwrap();
+        assert!(reading.is_under_sampled());
+        let flagged_reads = reads.get();
+
+        // Retry and ExtendTimeout sample for another timeout and still fall short.
+        for policy in [ShortCrossings::Retry, ShortCrossings::ExtendTimeout] {
+            ct.set_min_crossings(Some((10, policy)));
+            reads.set(0);
+            let reading = ct.measure_once(&mut test_sampler(), 40, timeout).unwrap();
+            assert!(reading.is_under_sampled(), "{:?}", policy);
+            assert!(reads.get() > flagged_reads * 3 / 2, "{:?}", policy);
+            assert_eq!(ct.last_crossings().1, 40, "{:?}", policy);
+        }
+
+        // A minimum within reach of the timeout isn't short.
+        ct.set_min_crossings(Some((1, ShortCrossings::Flag)));
+        let reading = ct.measure_once(&mut test_sampler(), 40, timeout).unwrap();
+        assert!(!reading.is_under_sampled());
+
+        // The mains crossing at full speed reaches all it asks for.
+        let mut ct = mock_sine_ct(None);
+        ct.set_min_crossings(Some((10, ShortCrossings::Flag)));
+        let reading = ct
+            .measure_once(&mut test_sampler(), 40, Duration::from_secs(5))
+            .unwrap();
+        assert!(!reading.is_under_sampled());
+        assert_eq!(ct.last_crossings(), (40, 40));
+    }
+
+    #[test]
+    fn non_finite_and_negative_energy_is_sanitized() {
+        let mut ct = test_ct();
+        ct.set_warmup_readings(0);
+        for &kwh in &[0.5, f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -0.25] {
+            ct.add_reading(CTReading {
+                kwh,
+                ..reading(100.0, 0)
+            });
+        }
+        // Keep, the default, nets the export, the broken kWh are dropped whatever the policy.
+        assert_eq!(ct.reading.kwh, 0.25);
+        assert_eq!(ct.exported_kwh(), 0.0);
+
+        ct.discard_interval();
+        ct.set_negative_energy(NegativeEnergy::Clamp);
+        for &kwh in &[0.5, f32::NAN, -0.25] {
+            ct.add_reading(CTReading {
+                kwh,
+                ..reading(100.0, 0)
+            });
+        }
+        assert_eq!(ct.reading.kwh, 0.5);
+        assert_eq!(ct.exported_kwh(), 0.0);
+    }
+
+    // The reading of a load whose current lags the voltage by `lag` radians.
+    fn load_reading(lag: f32) -> CTReading {
+        let mut ct = centred_ct();
+        ct.voltage_pin.phase_cal = 1.0;
+        measure_samples(
+            &mut ct,
+            sine_samples(20, 800.0, 400.0, lag),
+            Duration::from_secs(1),
+        )
+    }
+
+    #[test]
+    fn phase_angle_tells_inductive_from_capacitive_loads() {
+        let resistive = load_reading(0.0);
+        assert!(resistive.phase_angle_degrees().abs() < 2.0);
+
+        let lag = 30.0_f32.to_radians();
+        let inductive = load_reading(lag);
+        assert!(!inductive.is_leading());
+        assert!((inductive.phase_angle_degrees() - 30.0).abs() < 2.0);
+        assert!(inductive.reactive_power() > 0.0);
+
+        let capacitive = load_reading(-lag);
+        assert!(capacitive.is_leading());
+        assert!((capacitive.phase_angle_degrees() + 30.0).abs() < 2.0);
+        assert!(capacitive.reactive_power() < 0.0);
+        assert!(
+            (capacitive.reactive_power() + inductive.reactive_power()).abs()
+                < 0.02 * inductive.reactive_power()
+        );
+
+        // Without apparent power there is no angle.
+        assert_eq!(CTReading::default().phase_angle_degrees(), 0.0);
+    }
+
+    #[test]
+    fn period_reactive_power_nets_out() {
+        let lag = 30.0_f32.to_radians();
+        let mut ct = centred_ct();
+        ct.set_warmup_readings(0);
+        // Two inductive measurements outweigh a capacitive one.
+        for lag in [lag, lag, -lag] {
+            ct.add_reading(load_reading(lag));
+        }
+        assert!(!ct.reading.is_leading());
+        assert!(ct.reading.phase_angle_degrees() > 0.0);
+
+        ct.discard_interval();
+        for lag in [lag, -lag, -lag] {
+            ct.add_reading(load_reading(lag));
+        }
+        assert!(ct.reading.is_leading());
+        assert!(ct.reading.phase_angle_degrees() < 0.0);
+    }
+
+    // A measurement of `ct` with a voltage amplitude of `v_amplitude` and a resistive load.
+    fn measure_voltage(ct: &mut CT, v_amplitude: f32) -> CTReading {
+        ct.voltage_pin.offset_v = MID_SCALE;
+        ct.current_pin.offset_i = MID_SCALE;
+        measure_samples(
+            ct,
+            sine_samples(20, v_amplitude, 400.0, 0.0),
+            Duration::from_secs(1),
+        )
+    }
+
+    #[test]
+    fn voltage_in_the_plausible_band_is_kept() {
+        let mut ct = centred_ct();
+        ct.set_plausible_voltage(None, ImplausibleVoltage::Flag);
+        let unchecked = measure_voltage(&mut ct, 800.0);
+        let v_rms = unchecked.v_rms;
+
+        let band = Some((0.75 * v_rms, 1.5 * v_rms));
+        ct.set_plausible_voltage(band, ImplausibleVoltage::Substitute);
+        let reading = measure_voltage(&mut ct, 800.0);
+        assert!(!reading.is_implausible_voltage());
+        assert_eq!(reading.v_rms, v_rms);
+        assert_eq!(reading.real_power, unchecked.real_power);
+        assert_eq!(ct.diagnostics.last_plausible_v_rms, Some(v_rms));
+    }
+
+    #[test]
+    fn voltage_out_of_the_plausible_band_is_flagged_or_substituted() {
+        let mut ct = centred_ct();
+        ct.set_plausible_voltage(None, ImplausibleVoltage::Flag);
+        let good = measure_voltage(&mut ct, 800.0);
+        let band = Some((0.75 * good.v_rms, 1.5 * good.v_rms));
+
+        ct.set_plausible_voltage(band, ImplausibleVoltage::Flag);
+        let flagged = measure_voltage(&mut ct, 400.0);
+        assert!(flagged.is_implausible_voltage());
+        assert!((flagged.v_rms - good.v_rms / 2.0).abs() < 0.02 * good.v_rms);
+        assert!((flagged.real_power - good.real_power / 2.0).abs() < 0.02 * good.real_power);
+
+        let mut ct = centred_ct();
+        ct.set_plausible_voltage(band, ImplausibleVoltage::Substitute);
+        let first = measure_voltage(&mut ct, 400.0);
+        assert!(first.is_implausible_voltage());
+        assert_eq!(first.v_rms, flagged.v_rms);
+        measure_voltage(&mut ct, 800.0);
+        let substituted = measure_voltage(&mut ct, 400.0);
+        assert!(substituted.is_implausible_voltage());
+        assert_eq!(
+            substituted.v_rms,
+            ct.diagnostics.last_plausible_v_rms.unwrap()
+        );
+        assert!((substituted.real_power - good.real_power).abs() < 0.02 * good.real_power);
+        assert!(
+            (substituted.apparent_power - good.apparent_power).abs() < 0.02 * good.apparent_power
+        );
+    }
+
+    // A CT whose voltage swings ±300 mV around a bias `bias` mV off mid-scale, with its voltage dc
+    // offset settled at `offset` mV off mid-scale.
+    fn offset_sine_ct(bias: f32, offset: f32) -> CT {
+        let sample = std::rc::Rc::new(std::cell::Cell::new(0_usize));
+        let at = |n: usize, mid: f32, amplitude: f32| {
+            let angle = 2.0 * std::f32::consts::PI * (n % 80) as f32 / 80.0;
+            (mid + amplitude * f32::sin(angle)).round() as u16
+        };
+        let mut ct = centred_ct();
+        ct.voltage_pin.offset_v = MID_SCALE + offset;
+        ct.set_zero_cross_timeout(ZeroCrossTimeout::Abort);
+        let current = sample.clone();
+        ct.current_pin.pin = Box::new(MockChannel(move || Ok(at(current.get(), MID_SCALE, 400.0))));
+        ct.voltage_pin.pin = Box::new(MockChannel(move || {
+            sample.set(sample.get() + 1);
+            Ok(at(sample.get() - 1, MID_SCALE + bias, 300.0))
+        }));
+        ct
+    }
+
+    #[test]
+    fn zero_cross_band_follows_a_shifted_bias() {
+        let timeout = Duration::from_millis(200);
+        let bias = 600.0;
+        assert!(bias - 300.0 > ZERO_CROSS_BAND);
+
+        // Around mid-scale the voltage is never near zero.
+        let mut ct = offset_sine_ct(bias, 0.0);
+        assert!(ct.measure_once(&mut test_sampler(), 40, timeout).is_err());
+
+        // Around the settled offset it starts right away and measures every crossing.
+        let mut ct = offset_sine_ct(bias, bias);
+        let reading = ct.measure_once(&mut test_sampler(), 40, timeout).unwrap();
+        assert!(!reading.is_apparent_only());
+        assert_eq!(ct.last_crossings(), (40, 40));
+        assert!(reading.v_rms > 0.0);
+
+        // A band wide enough reaches the voltage before the offset settled.
+        let mut ct = offset_sine_ct(bias, 0.0);
+        ct.set_zero_cross_band(bias);
+        assert!(ct.measure_once(&mut test_sampler(), 40, timeout).is_ok());
+    }
+
+    #[test]
+    fn prometheus_export_has_every_metric_for_every_channel() {
+        let mut cts = test_cts();
+        for (n, ct) in cts.iter_mut().enumerate() {
+            ct.reading = reading(100.0 * (n + 1) as f32, 1_000);
+            ct.energy_total_kwh = 1.5;
+        }
+        let out = to_prometheus(&cts);
+        assert!(out.ends_with('\n'));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), PROMETHEUS_METRICS.len() * (2 + AC_PHASE));
+
+        for (block, (name, kind, help)) in lines.chunks(2 + AC_PHASE).zip(PROMETHEUS_METRICS.iter())
+        {
+            assert_eq!(block[0], format!("# HELP {} {}", name, help));
+            assert_eq!(block[1], format!("# TYPE {} {}", name, kind));
+            for (line, ct) in block[2..].iter().zip(cts.iter()) {
+                let prefix = format!("{}{{channel=\"{}\"}} ", name, ct.id);
+                let value = line.strip_prefix(&prefix).unwrap();
+                assert!(value.parse::<f64>().unwrap().is_finite());
+            }
+        }
+
+        let real_power: String = cts
+            .iter()
+            .map(|ct| {
+                format!(
+                    "ofmon_real_power_watts{{channel=\"{}\"}} {}\n",
+                    ct.id, ct.reading.real_power
+                )
+            })
+            .collect();
+        assert!(out.contains(&format!(
+            "# TYPE ofmon_real_power_watts gauge\n{}# HELP ofmon_apparent_power_voltamperes",
+            real_power
+        )));
+        assert!(out.contains(&format!(
+            "ofmon_energy_kilowatt_hours{{channel=\"{}\"}} {}\n",
+            cts[0].id,
+            cts[0].lifetime_kwh()
+        )));
+    }
+
+    #[test]
+    fn prometheus_export_spells_non_finite_values() {
+        let mut ct = test_ct();
+        ct.reading = reading(100.0, 1_000);
+        ct.reading.real_power = f32::NAN;
+        ct.reading.apparent_power = f32::INFINITY;
+        ct.reading.i_rms = f32::NEG_INFINITY;
+        let out = to_prometheus(std::slice::from_ref(&ct));
+        let id = ct.id;
+        assert!(out.contains(&format!(
+            "ofmon_real_power_watts{{channel=\"{}\"}} NaN\n",
+            id
+        )));
+        assert!(out.contains(&format!(
+            "ofmon_apparent_power_voltamperes{{channel=\"{}\"}} +Inf\n",
+            id
+        )));
+        assert!(out.contains(&format!(
+            "ofmon_current_rms_amperes{{channel=\"{}\"}} -Inf\n",
+            id
+        )));
+        assert!(out.contains(&format!(
+            "ofmon_voltage_rms_volts{{channel=\"{}\"}} 230\n",
+            id
+        )));
+        assert!(to_prometheus(&[])
+            .lines()
+            .all(|line| line.starts_with("# ")));
+    }
+
+    #[test]
+    fn diagnostics_are_logged_every_nth_measurement() {
+        let mut ct = test_ct();
+        let fired = |ct: &mut CT, calls: u32| -> Vec<u32> {
+            (1..=calls).filter(|_| ct.is_logged_measurement()).collect()
+        };
+        assert!(fired(&mut ct, 10).is_empty());
+
+        ct.set_log_every(Some(3));
+        assert_eq!(fired(&mut ct, 10), vec![3, 6, 9]);
+        // Changing it starts the count over.
+        ct.set_log_every(Some(4));
+        assert_eq!(fired(&mut ct, 9), vec![4, 8]);
+        ct.set_log_every(Some(0));
+        assert_eq!(fired(&mut ct, 3), vec![1, 2, 3]);
+
+        // Every measurement counts.
+        let mut ct = centred_ct();
+        ct.set_log_every(Some(3));
+        for _ in 0..2 {
+            measure_samples(
+                &mut ct,
+                sine_samples(4, 800.0, 400.0, 0.0),
+                Duration::from_secs(1),
+            );
+        }
+        assert!(ct.is_logged_measurement());
+    }
+
+    #[test]
+    fn current_gain_is_interpolated_between_the_points() {
+        let points = [(1.0, 1.02), (5.0, 1.01), (10.0, 1.0)];
+        assert_eq!(gain_at(&[], 3.0), 1.0);
+        assert_eq!(gain_at(&points, 1.0), 1.02);
+        assert_eq!(gain_at(&points, 5.0), 1.01);
+        assert_eq!(gain_at(&points, 10.0), 1.0);
+        assert!((gain_at(&points, 3.0) - 1.015).abs() < 1e-6);
+        assert!((gain_at(&points, 7.5) - 1.005).abs() < 1e-6);
+        assert_eq!(gain_at(&[(2.0, 1.05)], 20.0), 1.05);
+    }
+
+    #[test]
+    fn current_gain_holds_beyond_its_points() {
+        let points = [(1.0, 1.02), (5.0, 1.01), (5.0, 1.03), (10.0, 1.0)];
+        assert_eq!(gain_at(&points, 0.0), 1.02);
+        assert_eq!(gain_at(&points, -3.0), 1.02);
+        assert_eq!(gain_at(&points, 100.0), 1.0);
+        // Two points at the same current step from one gain to the other.
+        assert!((gain_at(&points, 7.5) - 1.015).abs() < 1e-6);
+
+        // Points that can't be a gain are dropped and the others sorted.
+        let mut ct = test_ct();
+        ct.set_current_gain(vec![
+            (10.0, 1.0),
+            (f32::NAN, 1.5),
+            (2.0, 0.0),
+            (3.0, -1.0),
+            (4.0, f32::INFINITY),
+            (1.0, 1.02),
+        ]);
+        assert_eq!(ct.config.current_gain, vec![(1.0, 1.02), (10.0, 1.0)]);
+    }
+
+    #[test]
+    fn current_gain_scales_the_measured_current_and_power() {
+        let measure = |ct: &mut CT| {
+            measure_samples(
+                ct,
+                sine_samples(20, 800.0, 400.0, 0.0),
+                Duration::from_secs(1),
+            )
+        };
+        let mut ct = centred_ct();
+        let plain = measure(&mut ct);
+
+        let mut ct = centred_ct();
+        ct.set_current_gain(vec![(0.5 * plain.i_rms, 1.1), (2.0 * plain.i_rms, 1.0)]);
+        let corrected = measure(&mut ct);
+        let gain = 1.1 - 0.1 * (plain.i_rms - 0.5 * plain.i_rms) / (1.5 * plain.i_rms);
+        assert!((corrected.i_rms / plain.i_rms - gain).abs() < 1e-3);
+        assert!((corrected.real_power / plain.real_power - gain).abs() < 1e-3);
+        assert_eq!(corrected.v_rms, plain.v_rms);
+    }
+
+    #[test]
+    fn offset_convergence_shows_a_bias_shifting_mid_window() {
+        let mut ct = centred_ct();
+        assert_eq!(ct.last_offset_convergence(), 0.0);
+        let duration = Duration::from_secs(1);
+        measure_samples(&mut ct, sine_samples(20, 800.0, 400.0, 0.0), duration);
+        let settled = ct.last_offset_convergence();
+        // The per-sample filter ripples with the waveform, so a centred bias still moves a little.
+        assert!(settled < 30.0, "settled offsets moved {} mV", settled);
+
+        // The voltage bias jumps by 400 mV halfway through, e.g. a supply sagging under load.
+        let mut samples = sine_samples(20, 800.0, 400.0, 0.0);
+        let half = samples.len() / 2;
+        for (_, sample_v) in &mut samples[half..] {
+            *sample_v += 400;
+        }
+        let mut ct = centred_ct();
+        measure_samples(&mut ct, samples, duration);
+        let shifted = ct.last_offset_convergence();
+        assert!(
+            shifted > 3.0 * settled,
+            "shifted offsets moved {} mV",
+            shifted
+        );
+        assert!(shifted <= 400.0);
+
+        // Small again once the pin offset has followed the new bias.
+        let shifted_bias: Vec<(u16, u16)> = sine_samples(20, 800.0, 400.0, 0.0)
+            .into_iter()
+            .map(|(sample_i, sample_v)| (sample_i, sample_v + 400))
+            .collect();
+        ct.voltage_pin.offset_v = MID_SCALE + 400.0;
+        measure_samples(&mut ct, shifted_bias, duration);
+        assert!(ct.last_offset_convergence() < 30.0);
+    }
+
+    #[test]
+    fn ct_on_adc2_is_skipped_while_wifi_is_on() {
+        let timeout = Duration::from_millis(50);
+        let mut ct = centred_ct();
+        ct.set_warmup_readings(0);
+        ct.current_pin.pin = Box::new(MockChannel(|| Ok(1300)));
+        ct.voltage_pin.pin = Box::new(MockAdc2Channel(|| Ok(1300)));
+        let mut sampler = test_sampler();
+        sampler.set_wifi_active(true);
+        ct.calculate_energy(&mut sampler, 1, timeout).unwrap();
+        assert_eq!(ct.reading.v_rms, 0.0);
+        let err = ct.measure_once(&mut sampler, 1, timeout).unwrap_err();
+        assert!(err.to_string().contains("can't be read now"), "{}", err);
+
+        sampler.set_wifi_active(false);
+        let reading = ct.measure_once(&mut sampler, 1, timeout).unwrap();
+        assert!(reading.v_rms > 0.0);
     }
 }
@@ -0,0 +1,336 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::sampling::{AdcChannel, AdcUnit, Adcs};
+use crate::storage::{Filesystem, OpenMode, StorageFile};
+
+/// How often each kind of fault is injected, rates are probabilities from 0 to 1.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FaultConfig {
+    /// Of a single ADC read failing.
+    pub adc_failure_rate: f32,
+    /// Of an operation that writes to storage failing.
+    pub storage_failure_rate: f32,
+    /// Of the clock jumping by clock_jump on a read.
+    pub clock_jump_rate: f32,
+    /// In ms, negative jumps go back in time. Jumps add up.
+    pub clock_jump: i64,
+    /// Of the pseudo random sequence, the same seed injects the same faults.
+    pub seed: u64,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            adc_failure_rate: 0.0,
+            storage_failure_rate: 0.0,
+            clock_jump_rate: 0.0,
+            clock_jump: 0,
+            seed: 1,
+        }
+    }
+}
+
+struct FaultState {
+    config: FaultConfig,
+    rng: u64,
+    clock_offset: i64,
+}
+
+/// Decides when to inject faults, shared by the wrappers of this module.
+///
+/// Cloning gives another handle to the same state, so a single injector can drive the ADC, the
+/// storage and the clock, and its config can be changed while they run.
+#[derive(Clone)]
+pub(crate) struct FaultInjector {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(config: FaultConfig) -> Self {
+        FaultInjector {
+            state: Arc::new(Mutex::new(FaultState {
+                config,
+                // xorshift gets stuck at 0.
+                rng: u64::max(config.seed, 1),
+                clock_offset: 0,
+            })),
+        }
+    }
+
+    pub(crate) fn set_config(&self, config: FaultConfig) {
+        self.lock().config = config;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, FaultState> {
+        match self.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Whether to inject a fault of the rate `rate` picks from the config.
+    fn roll(&self, rate: fn(&FaultConfig) -> f32) -> bool {
+        let mut state = self.lock();
+        state.rng ^= state.rng << 13;
+        state.rng ^= state.rng >> 7;
+        state.rng ^= state.rng << 17;
+        let sample = (state.rng >> 40) as f32 / (1_u64 << 24) as f32;
+        sample < rate(&state.config)
+    }
+
+    fn storage_fault(&self) -> io::Result<()> {
+        if self.roll(|config| config.storage_failure_rate) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "injected storage fault",
+            ));
+        }
+        Ok(())
+    }
+
+    // `real` shifted by all the clock jumps so far, maybe jumping once more.
+    fn clock(&self, real: Duration) -> Duration {
+        let jump = self.roll(|config| config.clock_jump_rate);
+        let mut state = self.lock();
+        if jump {
+            state.clock_offset += state.config.clock_jump;
+        }
+        let ms = real.as_millis() as i64 + state.clock_offset;
+        Duration::from_millis(i64::max(ms, 0) as u64)
+    }
+}
+
+/// An ADC channel whose reads fail at adc_failure_rate.
+pub(crate) struct FaultyChannel {
+    inner: Box<dyn AdcChannel>,
+    faults: FaultInjector,
+}
+
+impl AdcChannel for FaultyChannel {
+    fn unit(&self) -> AdcUnit {
+        self.inner.unit()
+    }
+
+    fn channel(&self) -> u8 {
+        self.inner.channel()
+    }
+
+    fn read(&mut self, adcs: &mut Adcs) -> anyhow::Result<u16> {
+        if self.faults.roll(|config| config.adc_failure_rate) {
+            anyhow::bail!("injected fault of ADC channel {}", self.inner.channel());
+        }
+        self.inner.read(adcs)
+    }
+}
+
+// Stands in for a pin while it is moved into a FaultyChannel.
+struct Detached;
+
+impl AdcChannel for Detached {
+    fn unit(&self) -> AdcUnit {
+        AdcUnit::Adc1
+    }
+
+    fn channel(&self) -> u8 {
+        0
+    }
+
+    fn read(&mut self, _adcs: &mut Adcs) -> anyhow::Result<u16> {
+        anyhow::bail!("read of a detached pin")
+    }
+}
+
+/// Make the reads of `pin` fail at the adc_failure_rate of `faults`.
+pub(crate) fn inject_channel_faults(pin: &mut Box<dyn AdcChannel>, faults: &FaultInjector) {
+    let inner = std::mem::replace(pin, Box::new(Detached));
+    *pin = Box::new(FaultyChannel {
+        inner,
+        faults: faults.clone(),
+    });
+}
+
+/// A filesystem whose writes fail at storage_failure_rate.
+///
+/// Opening a file for writing, creating directories, renames and removals fail, reads always go
+/// through. A failed atomic write fails before its rename, so it leaves the old file in place.
+pub(crate) struct FaultyFs<F> {
+    inner: F,
+    faults: FaultInjector,
+}
+
+impl<F: Filesystem> FaultyFs<F> {
+    pub(crate) fn new(inner: F, faults: &FaultInjector) -> Self {
+        FaultyFs {
+            inner,
+            faults: faults.clone(),
+        }
+    }
+}
+
+impl<F: Filesystem> Filesystem for FaultyFs<F> {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        if mode != OpenMode::Read {
+            self.faults.storage_fault()?;
+        }
+        self.inner.open(path, mode)
+    }
+
+    fn file_size(&self, path: &str) -> io::Result<u64> {
+        self.inner.file_size(path)
+    }
+
+    fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+        self.inner.read_dir(path)
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.faults.storage_fault()?;
+        self.inner.create_dir(path)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.faults.storage_fault()?;
+        self.inner.remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        self.faults.storage_fault()?;
+        self.inner.remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.faults.storage_fault()?;
+        self.inner.rename(from, to)
+    }
+
+    fn space(&self, root: &str) -> io::Result<(u64, u64)> {
+        self.inner.space(root)
+    }
+}
+
+// Injector of the system clock, see inject_clock_faults.
+static CLOCK_FAULTS: Mutex<Option<FaultInjector>> = Mutex::new(None);
+
+/// Make the system clock, as read by now(), jump at the clock_jump_rate of `faults`.
+pub(crate) fn inject_clock_faults(faults: &FaultInjector) {
+    if let Ok(mut clock) = CLOCK_FAULTS.lock() {
+        *clock = Some(faults.clone());
+    }
+}
+
+/// `real` with the injected clock jumps, unchanged if there are none.
+pub(crate) fn faulty_clock(real: Duration) -> Duration {
+    match CLOCK_FAULTS.lock() {
+        Ok(clock) => match clock.as_ref() {
+            Some(faults) => faults.clock(real),
+            None => real,
+        },
+        Err(_) => real,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ct::tests::test_cts;
+    use crate::sampling::tests::{test_adcs, MockChannel};
+    use crate::storage::tests::MemFs;
+    use crate::storage::tests::{save, stored};
+    use crate::storage::CTStorage;
+    use crate::utils::ByteOrder;
+
+    fn faults(config: FaultConfig) -> FaultInjector {
+        FaultInjector::new(config)
+    }
+
+    // Reads of a channel that always reads 1000 mV, with the ADC faults of `faults`.
+    fn failed_reads(faults: &FaultInjector, reads: usize) -> usize {
+        let mut adcs = test_adcs();
+        let mut pin: Box<dyn AdcChannel> = Box::new(MockChannel(|| Ok(1000)));
+        inject_channel_faults(&mut pin, faults);
+        (0..reads)
+            .map(|_| pin.read(&mut adcs))
+            .filter(|read| match read {
+                Ok(sample) => {
+                    assert_eq!(*sample, 1000);
+                    false
+                }
+                Err(_) => true,
+            })
+            .count()
+    }
+
+    #[test]
+    fn adc_reads_fail_at_the_rate() {
+        let config = |adc_failure_rate| FaultConfig {
+            adc_failure_rate,
+            ..Default::default()
+        };
+        assert_eq!(failed_reads(&faults(config(0.0)), 1000), 0);
+        assert_eq!(failed_reads(&faults(config(1.0)), 1000), 1000);
+        let some = failed_reads(&faults(config(0.25)), 10_000);
+        assert!((2000..3000).contains(&some), "{}", some);
+        // The same seed fails the same reads.
+        assert_eq!(failed_reads(&faults(config(0.25)), 10_000), some);
+    }
+
+    #[test]
+    fn failed_storage_writes_keep_what_was_stored() {
+        let fs = MemFs::new();
+        let faults = faults(FaultConfig::default());
+        let mut storage = CTStorage::with_fs(
+            Box::new(FaultyFs::new(fs.clone(), &faults)),
+            ByteOrder::Little,
+        );
+        storage.set_min_save_interval(Duration::ZERO);
+        storage.set_write_batching(None);
+        storage.find_newest_readings_shard_num().unwrap();
+        let mut cts = test_cts();
+        save(&mut storage, &mut cts, 1_000);
+        let before = stored(&storage);
+        assert!(!before.is_empty());
+
+        faults.set_config(FaultConfig {
+            storage_failure_rate: 1.0,
+            ..Default::default()
+        });
+        let _ = storage.save_to_storage(&cts);
+        assert_eq!(stored(&storage).len(), before.len());
+        assert!(fs.write_atomic("/littlefs/probe", b"old").is_ok());
+        assert!(FaultyFs::new(fs.clone(), &faults)
+            .write_atomic("/littlefs/probe", b"new")
+            .is_err());
+        assert_eq!(fs.read("/littlefs/probe").unwrap(), b"old");
+
+        // Once the storage works again the held back save is written too.
+        faults.set_config(FaultConfig::default());
+        save(&mut storage, &mut cts, 3_000);
+        assert_eq!(stored(&storage).len(), 3 * before.len());
+    }
+
+    #[test]
+    fn clock_jumps_add_up_and_stop_at_zero() {
+        let faults = faults(FaultConfig {
+            clock_jump_rate: 1.0,
+            clock_jump: -4_000,
+            ..Default::default()
+        });
+        let real = Duration::from_secs(10);
+        assert_eq!(faults.clock(real), Duration::from_secs(6));
+        assert_eq!(faults.clock(real), Duration::from_secs(2));
+        assert_eq!(faults.clock(real), Duration::ZERO);
+
+        faults.set_config(FaultConfig {
+            clock_jump: 1_000,
+            ..Default::default()
+        });
+        // No more jumps, the ones so far stay.
+        assert_eq!(faults.clock(real), Duration::ZERO);
+        assert_eq!(
+            faults.clock(Duration::from_secs(20)),
+            Duration::from_secs(8)
+        );
+    }
+}
@@ -1,7 +1,23 @@
+#[cfg(feature = "bench")]
+mod bench;
+mod calibration;
+mod commands;
 mod ct;
+#[cfg(feature = "fault-injection")]
+mod fault;
 mod ota;
+mod sampling;
+mod scheduler;
+mod serial;
+mod settings;
+mod storage;
 pub(crate) mod utils;
 
+use std::ffi::CString;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
@@ -14,25 +30,40 @@ use embedded_svc::io::Read as SvcRead;
 use embedded_svc::ipv4::{Ipv4Addr, Mask, RouterConfiguration, Subnet};
 use embedded_svc::wifi::Wifi;
 use embedded_svc::wifi::{AccessPointConfiguration, ApIpStatus, ApStatus, AuthMethod, Status};
-use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::server::{EspHttpRequest, EspHttpServer};
 use esp_idf_svc::netif::EspNetifStack;
 use esp_idf_svc::nvs::EspDefaultNvs;
 use esp_idf_svc::nvs_storage::EspNvsStorage;
 
 use esp_idf_svc::sysloop::EspSysLoopStack;
 use esp_idf_svc::wifi::EspWifi;
-use esp_idf_sys::{esp, gettimeofday, settimeofday, timeval};
+use esp_idf_sys::{esp, esp_timer_get_time, gettimeofday, settimeofday, timeval};
 
-use esp_idf_hal::adc;
 use esp_idf_hal::prelude::Peripherals;
 
 use anyhow::bail;
-use cstr::cstr;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 
-use crate::ct::{CTStorage, CT};
+use crate::commands::{CommandHandler, Commands};
+#[cfg(feature = "three-phase")]
+use crate::ct::check_phase_rotation;
+#[cfg(feature = "three-phase")]
+use crate::ct::line_to_line_voltages;
+use crate::ct::{
+    available_modes, calculate_energy_round_robin, group_channels, max_channel_skew,
+    significant_change, ChangeThresholds, CSV_HEADER, CT,
+};
+#[cfg(feature = "fault-injection")]
+use crate::fault::{inject_clock_faults, FaultConfig, FaultInjector};
 use crate::ota::{first_run_validate, ota_update_from_reader};
+use crate::sampling::{Sampler, SamplingBackend};
+use crate::scheduler::{MeasurementScheduler, SchedulerAction};
+use crate::storage::{
+    is_save_busy, max_shard_size, record_size, records_per_shard, shard_header_size, CTStorage,
+    FsKind, LowSpacePolicy, ShardRecovery, StorageMount,
+};
+use crate::utils::ByteOrder;
 
 // const SINGLE_PHASE_CURRENT_PIN: u8 = 35;
 // const SINGLE_PHASE_VOLTAGE_PIN: u8 = 34;
@@ -57,20 +88,119 @@ const VERSION: u32 = 100;
 // ADC constants
 // const ADC_BITS: u32 = 12;
 // const MAX_READING: u32 = 1 << ADC_BITS;
+const ADC_MAX_READING: u32 = 4095;
+const SAMPLING_BACKEND: SamplingBackend = SamplingBackend::OneShot;
+const DMA_SAMPLE_FREQ_HZ: u32 = 20_000; // current/voltage pairs per second
+const DMA_FRAME_SIZE: usize = 256; // in bytes, 2 bytes per conversion
 const MAX_MV_ATTEN_11: u16 = 2450;
 const SUPPLY_VOLTAGE: f32 = 3.3;
 const NOISE_THRESHOLD: f32 = MAX_MV_ATTEN_11 as f32 / 8.0;
+const ADC_OVERSAMPLING: u8 = 1; // ADC reads averaged into each sample
+const ADC_READ_TIMEOUT: Duration = Duration::from_millis(20); // one-shot reads taking longer fail
+const MAX_FAILED_READS: f32 = 0.05; // of the reads of a measurement, more and it is rejected
+const VERBOSE_MEASUREMENTS: bool = false; // log measurement diagnostics at info level
+const NOMINAL_VOLTAGE: f32 = 230.0; // rms mains voltage in V
+const MAX_VOLTAGE_DEVIATION: f32 = 0.25; // of the nominal voltage, beyond it a reading is anomalous
+const MAX_POWER_FACTOR: f32 = 1.1; // above that a reading is anomalous
+const CLIP_MARGIN: u16 = 20; // in mV, samples this close to the ADC limits count as clipped
+const CLIPPED_SENTINEL: f32 = -1.0; // stored i_rms and apparent_power, see ClippedPolicy
+const STUCK_CHANNEL_VARIANCE: f64 = 0.01; // in mV^2, a voltage pin with less is stuck
+const SWAPPED_SWING_RATIO: f32 = 4.0; // current over voltage swing that suggests swapped pins
+const MAX_OFFSET_DRIFT: f32 = 400.0; // in mV, dc offsets further from mid-scale are warned about
+const ZERO_CROSS_BAND: f32 = MAX_MV_ATTEN_11 as f32 * 0.05; // in mV either side of the dc offset
+const PLAUSIBLE_VOLTAGE: (f32, f32) = (80.0, 280.0); // rms V range, readings outside it are faulty
+const MIN_SAMPLES_PER_CROSSING: u32 = 20; // fewer lower the reading quality
+const WARMUP_READINGS: u32 = 1; // dropped after boot while the dc offsets converge
+const SELF_TEST_AT_BOOT: bool = false; // log CT::self_test at boot, for commissioning with no load
+const SELF_TEST_SAMPLES: usize = 2000; // per CT, see CT::measure_noise_floor
+const MAX_NOISE_FLOOR: f32 = 5.0; // in mV, of the filtered current of a CT with no load
+const CT_REVERSED: [bool; AC_PHASE] = [false; AC_PHASE]; // clamped backwards, see CT::set_reversed
+const CT_ON_ADC2: bool = false; // the CTs are wired to ADC2 pins, which need ACCESS_POINT off
+const SPLIT_PHASE_LEGS: Option<(usize, usize)> = None; // CTs of a split phase circuit, logged
+const ENERGY_RATE: f32 = 0.25; // per kWh, for the cost estimates
+const SERIAL_FRAMES: bool = false; // print readings that changed as frames, see serial.rs
 
 // Periodic actions constants
 const SAVE_PERIOD_TIMEOUT: u64 = 60; // 3600 for one hour
+const MEASUREMENT_CROSSINGS: u32 = 200; // voltage crossings per measurement
+const PRECISION_DOWNGRADE: bool = false; // measure fewer crossings when measuring falls behind
+const MIN_MEASUREMENT_CROSSINGS: u32 = 50; // floor of the downgrade
+const MEASUREMENT_INTERVAL: Duration = Duration::from_secs(3); // between measurement starts
+const MAX_CHANNEL_SKEW: u64 = 10_000; // in ms, between the measurements of the CTs
+const ROUND_ROBIN: bool = false; // measure the CTs taking turns instead of one after the other
+const MEASUREMENT_BUDGET: Duration = Duration::from_secs(9); // per round robin window, all CTs
+const ROUND_ROBIN_ROUNDS: u32 = 4; // turns of each CT per window
+#[cfg(feature = "three-phase")]
+const PHASE_TOLERANCE_DEG: f64 = 30.0; // allowed deviation from 120 degrees between phases
+#[cfg(feature = "three-phase")]
+const PHASE_CHECK_TIMEOUT: Duration = Duration::from_millis(200); // see check_phase_rotation
+#[cfg(feature = "three-phase")]
+const PHASE_CHECK_HYSTERESIS: f32 = 50.0; // in mV below the offset before a rise is a crossing
+#[cfg(feature = "async")]
+const ASYNC_BATCH_CROSSINGS: u32 = 10; // sampled between two yields of calculate_energy_async
+#[cfg(feature = "fault-injection")]
+const FAULT_CONFIG: FaultConfig = FaultConfig {
+    adc_failure_rate: 0.0,
+    storage_failure_rate: 0.0,
+    clock_jump_rate: 0.0,
+    clock_jump: 0, // in ms
+    seed: 1,
+}; // until the faults setting replaces it
 
 // Storage constants
+// Mounted if in the partition table, the first writable holds the storage, see detect_root
+const STORAGE_MOUNTS: &[StorageMount] = &[
+    StorageMount {
+        root: "/littlefs",
+        partition: "littlefs",
+        kind: FsKind::LittleFs,
+    },
+    StorageMount {
+        root: "/spiffs",
+        partition: "spiffs",
+        kind: FsKind::Spiffs,
+    },
+];
 const MAX_SHARD_SIZE: u64 = 64; // in bytes
+const SHARD_NAME_WIDTH: usize = 10; // digits of zero-padded shard names
+const PAD_SHARD_NAMES: bool = false; // zero-pad shard names, existing ones are renamed at boot
 const MAX_TIME_STORAGE_SIZE: u64 = 64; // in bytes
-const CT_READING_SIZE: usize = 30; // in bytes
+const CT_READING_SIZE: usize = 44; // in bytes
+const LEGACY_RECORD_SIZE: usize = 30; // in bytes, of shards from before the sequence numbers
+const SHARD_HEADER_SIZE: usize = 4; // in bytes, see CTStorage::shard_header
+const SHARD_FORMAT_VERSION: u8 = 1; // of the shard header
+const RECORD_BYTE_ORDER: ByteOrder = ByteOrder::Little; // of new shards, see CTStorage::byte_order
+const CALIBRATION_SIZE: usize = 18; // in bytes, per CT, with its voltage transformer ratio
+const LEGACY_CALIBRATION_SIZE: usize = 14; // in bytes, per CT, from before the ratio
+const CALIBRATION_VERSION: u8 = 2; // of the calibration file, see CTStorage::load_calibration
+const ENERGY_TOTAL_SIZE: usize = 18; // in bytes, per CT, its energy total and exported kWh
+const LEGACY_ENERGY_TOTAL_SIZE: usize = 10; // in bytes, per CT, from before the exported kWh
+const LIFETIME_STATS_SIZE: usize = 136; // in bytes, 5 metrics, the sequence and the peak demand
+const PEAK_DEMAND_SIZE: usize = 12; // in bytes, W and timestamp of the peak of the lifetime stats
+const STATS_STORE_INTERVAL: u32 = 10; // written saves between stores of the lifetime statistics
+const TOU_TOTALS_SIZE: usize = 28; // in bytes, kWh of the 3 time of use periods and the sequence
+const SEQUENCE_INDEX_ENTRY_SIZE: usize = 12; // in bytes, sequence, shard id and offset of a save
+const SEQUENCE_INDEX_MAX_ENTRIES: usize = 1024; // beyond that the index is thinned
+const STATE_VERSION: u8 = 2; // of the blobs of CTStorage::export_state
+const STATE_SIZE: usize = 44 + (CALIBRATION_SIZE + 8) * AC_PHASE; // in bytes, of export_state
+const STORAGE_RETRIES: u32 = 3; // attempts to create the readings directory at boot
+const STORAGE_RETRY_DELAY: Duration = Duration::from_millis(100); // doubled after every attempt
+const MAX_BUFFERED_SAVES: usize = 60; // saves kept in RAM while the storage is unavailable
+const MIN_SAVE_INTERVAL: Duration = Duration::from_secs(30); // faster saves are coalesced
+const WRITE_BATCH: Option<usize> = None; // in bytes, see CTStorage::set_write_batching
+const LOW_SPACE_USED: f32 = 0.9; // of the filesystem, above it LowSpacePolicy::Coarsen kicks in
+const LOW_SPACE_SAVE_INTERVAL: Duration = Duration::from_secs(3600); // between saves on low space
+const LOW_SPACE_POLICY: LowSpacePolicy = LowSpacePolicy::Off; // see set_low_space_policy
+const INTEGRITY_SCAN: bool = false; // read every record at boot, see CTStorage::set_integrity_scan
+const SHARD_RECOVERY: ShardRecovery = ShardRecovery::Truncate; // see CTStorage::set_shard_recovery
 
 // Network constants
+const ACCESS_POINT: bool = true; // ADC2 can't be sampled while it is on
 const ACCESS_TOKEN_SIZE: usize = 56;
+const JSON_SIZE: usize = 256; // in bytes, of a reading, see CTReading::write_json
+const CSV_ROW_SIZE: usize = 256; // in bytes, of a reading, see CTReading::write_csv
+const MAX_REQUEST_SIZE: usize = 4096; // in bytes, of the body of a settings or state post
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(90); // for the main loop to serve a request
 const GATEWAY_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
 
 fn main() -> anyhow::Result<()> {
@@ -81,37 +211,86 @@ fn main() -> anyhow::Result<()> {
 
     println!("SEM Device running version: {}", VERSION);
 
-    // Initialize LittleFS storage
-    let _fs_conf = init_littlefs_storage()?;
-    info!("Initialized and mounted littlefs storage.");
+    // Mount the storage partitions
+    for mount in STORAGE_MOUNTS {
+        match mount_storage(mount) {
+            Ok(()) => info!("Mounted {:?} storage at {}.", mount.kind, mount.root),
+            Err(err) => warn!(
+                "Can't mount {:?} storage at {}: {}",
+                mount.kind, mount.root, err
+            ),
+        }
+    }
+
+    #[cfg(feature = "fault-injection")]
+    let faults = FaultInjector::new(FAULT_CONFIG);
+    #[cfg(feature = "fault-injection")]
+    inject_clock_faults(&faults);
 
     // Initialize CT readings shards
-    let storage_lock = Arc::new(Mutex::new(CTStorage::new()));
-    {
+    let storage_lock = Arc::new(Mutex::new(CTStorage::new(
+        RECORD_BYTE_ORDER,
+        #[cfg(feature = "fault-injection")]
+        &faults,
+    )));
+    let settings = {
         let mut ct_storage = match storage_lock.lock() {
             Ok(gaurd) => gaurd,
             Err(poisoned) => poisoned.into_inner(),
         };
+        let roots: Vec<&str> = STORAGE_MOUNTS.iter().map(|mount| mount.root).collect();
+        if let Err(err) = ct_storage.detect_root(&roots) {
+            error!("{}", err);
+        }
+        ct_storage.set_pad_shard_names(PAD_SHARD_NAMES);
+        // Before finding the newest shard, which the record schema and shard recovery apply to.
+        let settings = match ct_storage
+            .settings()
+            .and_then(|text| settings::parse(&text))
+        {
+            Ok(settings) => settings,
+            Err(err) => {
+                warn!("Can't load the settings, using the defaults: {}", err);
+                Vec::new()
+            }
+        };
+        for setting in &settings {
+            setting.apply_to_storage(&mut ct_storage);
+            #[cfg(feature = "fault-injection")]
+            setting.apply_to_faults(&faults);
+        }
         info!("Finding newest shard.");
         ct_storage.find_newest_readings_shard_num()?;
-        ct_storage.update_system_time()?;
-        ct_storage.log_powerloss()?;
-    }
+        if ct_storage.storage_available() {
+            if let Err(err) = ct_storage.migrate_shard_names() {
+                warn!("Can't rename the shards: {}", err);
+            }
+            ct_storage.load_persisted_state()?;
+        }
+        settings
+    };
 
     // Initialize NVS storage
     let (default_nvs, _keystore) = init_nvs_storage()?;
     info!("Initialized default NVS storage.");
 
-    // SSID and password for the Wifi access point.
-    let mut ap_ssid: String = String::new();
-    let ap_password: &str = "12345678";
-    configure_access_point_ssid(&mut ap_ssid)?;
-    info!("Configured AP SSID as: {}.", ap_ssid);
+    let _wifi = if ACCESS_POINT {
+        // SSID and password for the Wifi access point.
+        let mut ap_ssid: String = String::new();
+        let ap_password: &str = "12345678";
+        configure_access_point_ssid(&mut ap_ssid)?;
+        info!("Configured AP SSID as: {}.", ap_ssid);
 
-    let _wifi = init_access_point(&ap_ssid, ap_password, default_nvs)?;
-    info!("Initialized Wifi.");
+        let wifi = init_access_point(&ap_ssid, ap_password, default_nvs)?;
+        info!("Initialized Wifi.");
+        Some(wifi)
+    } else {
+        None
+    };
 
-    let _web_server = init_web_server(storage_lock.clone())?;
+    // The handlers ask the main loop for what needs the CTs, see commands.rs.
+    let (commands, receiver) = commands::channel();
+    let _web_server = init_web_server(storage_lock.clone(), Arc::new(commands))?;
     info!("Initialized Web Server.");
 
     // Initilize peripherals and pins
@@ -119,76 +298,235 @@ fn main() -> anyhow::Result<()> {
     let pins = peripherals.pins;
 
     // Initilize ADC
-    let mut powered_adc1 = adc::PoweredAdc::new(
-        peripherals.adc1,
-        adc::config::Config::new().calibration(false),
-    )?;
+    let backend = settings::backend(&settings);
+    let mut sampler = Sampler::new(backend, peripherals.adc1, peripherals.adc2)?;
+    // ADC2 pins can't be sampled while the access point is on.
+    sampler.set_wifi_active(ACCESS_POINT);
     let mut cts = CT::init(pins)?;
+    for (ct, reversed) in cts.iter_mut().zip(CT_REVERSED) {
+        ct.set_oversampling(ADC_OVERSAMPLING);
+        ct.set_verbose(VERBOSE_MEASUREMENTS);
+        ct.set_reversed(reversed);
+        #[cfg(feature = "fault-injection")]
+        ct.inject_adc_faults(&faults);
+        if SERIAL_FRAMES {
+            // Report by exception, against the last reading sent.
+            let mut sent = None;
+            ct.on_reading(Box::new(move |id, reading| {
+                let changed = match &sent {
+                    Some(sent) => significant_change(sent, reading, &ChangeThresholds::default()),
+                    None => true,
+                };
+                if changed {
+                    let mut stdout = std::io::stdout();
+                    let _ = stdout.write_all(&reading.reading_to_frame(id));
+                    let _ = stdout.flush();
+                    sent = Some(reading.clone());
+                }
+            }));
+        }
+    }
+    for setting in &settings {
+        cts.iter_mut().for_each(|ct| setting.apply_to_ct(ct));
+        setting.apply_to_sampler(&mut sampler);
+    }
+    #[cfg(feature = "bench")]
+    for ct in &mut cts {
+        crate::bench::run_benchmark(ct)?;
+    }
+    if SELF_TEST_AT_BOOT {
+        for ct in &mut cts {
+            match ct.self_test(&mut sampler, SELF_TEST_SAMPLES) {
+                Ok(report) => info!("Self-test: {}", report),
+                Err(err) => warn!("Can't self-test: {}", err),
+            }
+        }
+    }
+    {
+        let mut ct_storage = match storage_lock.lock() {
+            Ok(gaurd) => gaurd,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        ct_storage.load_calibration(&mut cts)?;
+        ct_storage.load_energy_totals(&mut cts)?;
+        match ct_storage.boot_report() {
+            Ok(report) => info!("Boot report: {}", report),
+            Err(err) => warn!("Can't make the boot report: {}", err),
+        }
+    }
     info!("Initialized ADC 1.");
 
     // If everything is working fine, cancel rollback on the next restart to the previous firmware
     first_run_validate()?;
 
     // Main Loop
-    let mut save_period_start = Instant::now();
+    let mut handler = CommandHandler::new(
+        #[cfg(feature = "fault-injection")]
+        faults,
+    );
+    let mut scheduler = MeasurementScheduler::new(
+        MEASUREMENT_INTERVAL,
+        Duration::from_secs(SAVE_PERIOD_TIMEOUT),
+    );
     loop {
-        for ct in &mut cts {
-            ct.calculate_energy(&mut powered_adc1, 200, std::time::Duration::new(3, 0))?;
-            ct.reading.set_time(now().as_millis() as u64);
-            info!("Energy Reading: {:?}", ct.reading);
-        }
+        match scheduler.poll(Instant::now()) {
+            SchedulerAction::Measure => {
+                let crossing = if PRECISION_DOWNGRADE {
+                    scheduler.crossings(MEASUREMENT_CROSSINGS, MIN_MEASUREMENT_CROSSINGS)
+                } else {
+                    MEASUREMENT_CROSSINGS
+                };
+                if crossing < MEASUREMENT_CROSSINGS {
+                    warn!(
+                        "Measurement {:?} behind, measuring {} crossings instead of {}.",
+                        scheduler.lag(),
+                        crossing,
+                        MEASUREMENT_CROSSINGS
+                    );
+                }
+                measure(&mut cts, &mut sampler, crossing);
+            }
+            SchedulerAction::Save => {
+                info!("Saving to storage.");
+                let mut ct_storage = match storage_lock.lock() {
+                    Ok(gaurd) => gaurd,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                info!("Got storage lock.");
+                if let Some((a, b)) = SPLIT_PHASE_LEGS {
+                    match group_channels(&cts, a, b) {
+                        Ok(circuit) => info!("Split phase circuit: {:?}", circuit),
+                        Err(err) => warn!("Can't group the legs: {}", err),
+                    }
+                }
+                #[cfg(feature = "three-phase")]
+                info!(
+                    "Line to line voltages: {:?}",
+                    line_to_line_voltages(&cts, None)
+                );
+                for ct in &mut cts {
+                    info!("Period power factor: {}", ct.reading.period_power_factor());
+                    // Readings taken before the clock was set are dated from their uptime.
+                    if let Some(boot_time) = boot_time().filter(|_| ct.reading.is_unsynced_time()) {
+                        ct.reading.reconcile_time(boot_time);
+                    }
+                }
+                match ct_storage.save_to_storage(&cts) {
+                    // Nothing was saved, the readings go on into the next save period.
+                    Err(err) if is_save_busy(&err) => {
+                        warn!("Another write to the storage is running, saving later.");
+                        continue;
+                    }
+                    Err(err) => warn!("Can't save the readings: {}", err),
+                    Ok(()) => {}
+                }
+                if let Err(err) = ct_storage.store_time(now().as_millis() as u64) {
+                    warn!("Can't store the time: {}", err);
+                }
+                ct_storage.store_fault_captures(&mut cts);
 
-        // save the readings of CTs to storage.
-        if save_period_start.elapsed() > Duration::new(SAVE_PERIOD_TIMEOUT, 0) {
-            info!("Saving to storage.");
-            let mut ct_storage = match storage_lock.lock() {
-                Ok(gaurd) => gaurd,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            info!("Got storage lock.");
-            let res = ct_storage.save_to_storage(&cts);
-            println!("{:?}", res);
-            let res = ct_storage.store_time(now().as_millis() as u64);
-            println!("{:?}", res);
-
-            // Reset CT readings.
-            for ct in &mut cts {
-                ct.reset();
+                // Reset CT readings.
+                for ct in &mut cts {
+                    if ct_storage.skips(ct) {
+                        ct.discard_interval();
+                    } else {
+                        ct.reset_interval();
+                    }
+                }
+                if let Err(err) = ct_storage.save_energy_totals(&cts) {
+                    warn!("Can't store the energy totals: {}", err);
+                }
             }
-            save_period_start = Instant::now();
+            // Serve the web handlers while waiting for the next measurement.
+            SchedulerAction::Wait(duration) => match receiver.recv_timeout(duration) {
+                Ok(command) => handler.serve(command, &mut cts, &mut sampler, &storage_lock),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => sleep(duration),
+            },
         }
-        sleep(Duration::from_millis(1000));
     }
 }
 
-/// Initializes a littlefs file system.
+/// Measure all CTs once over `crossing` voltage crossings and log their readings.
 ///
-/// A partition with name `LITTLEFS_PARTITION_NAME` has to be specified
-/// in the partition table csv file.
-fn init_littlefs_storage() -> anyhow::Result<esp_idf_sys::esp_vfs_littlefs_conf_t> {
-    let mut fs_conf = esp_idf_sys::esp_vfs_littlefs_conf_t {
-        base_path: cstr!("/littlefs").as_ptr(),
-        partition_label: cstr!("littlefs").as_ptr(),
-        ..Default::default()
-    };
-    fs_conf.set_format_if_mount_failed(true as u8);
-    fs_conf.set_dont_mount(false as u8);
-
-    unsafe { esp!(esp_idf_sys::esp_vfs_littlefs_register(&fs_conf))? };
-    let (mut fs_total_bytes, mut fs_used_bytes) = (0, 0);
-    unsafe {
-        esp!(esp_idf_sys::esp_littlefs_info(
-            fs_conf.partition_label,
-            &mut fs_total_bytes,
-            &mut fs_used_bytes
-        ))?
-    };
-    info!(
-        "LittleFs Info: total bytes = {}, used bytes = {}.",
-        fs_total_bytes, fs_used_bytes
-    );
+/// A CT that can't be measured keeps its reading and is measured again on the next turn.
+fn measure(cts: &mut [CT; AC_PHASE], sampler: &mut Sampler, crossing: u32) {
+    if ROUND_ROBIN {
+        if let Err(err) = calculate_energy_round_robin(
+            cts,
+            sampler,
+            crossing,
+            MEASUREMENT_BUDGET,
+            ROUND_ROBIN_ROUNDS,
+        ) {
+            warn!("Can't measure the CTs: {}", err);
+        }
+        for ct in cts.iter() {
+            info!("Energy Reading: {:?}", ct.reading);
+        }
+        #[cfg(feature = "three-phase")]
+        {
+            match check_phase_rotation(cts, sampler) {
+                Ok(rotation) => info!("Phase rotation: {:?}", rotation),
+                Err(err) => warn!("Can't check the phase rotation: {}", err),
+            }
+        }
+    } else {
+        for ct in cts.iter_mut() {
+            #[cfg(not(feature = "async"))]
+            let res = ct.calculate_energy(sampler, crossing, Duration::new(3, 0));
+            #[cfg(feature = "async")]
+            let res = utils::block_on(ct.calculate_energy_async(
+                sampler,
+                crossing,
+                Duration::new(3, 0),
+                utils::YieldNow::default,
+            ));
+            if let Err(err) = res {
+                warn!("Can't measure a CT: {}", err);
+                continue;
+            }
+            ct.reading
+                .set_time(now().as_millis() as u64, uptime().as_millis() as u64);
+            info!("Energy Reading: {:?}", ct.reading);
+        }
+    }
+    let readings: Vec<_> = cts.iter().map(|ct| ct.reading.clone()).collect();
+    let skew = max_channel_skew(&readings);
+    if skew > MAX_CHANNEL_SKEW {
+        warn!("CT measurements are {} ms apart.", skew);
+    }
+}
 
-    Ok(fs_conf)
+/// Mounts the partition of `mount` at its root, formatting it if it can't be mounted.
+///
+/// The partition has to be specified in the partition table csv file.
+fn mount_storage(mount: &StorageMount) -> anyhow::Result<()> {
+    // The VFS keeps the pointers for as long as the partition is mounted, which is until reboot.
+    let base_path = Box::leak(CString::new(mount.root)?.into_boxed_c_str());
+    let partition_label = Box::leak(CString::new(mount.partition)?.into_boxed_c_str());
+    match mount.kind {
+        FsKind::LittleFs => {
+            let mut fs_conf = esp_idf_sys::esp_vfs_littlefs_conf_t {
+                base_path: base_path.as_ptr(),
+                partition_label: partition_label.as_ptr(),
+                ..Default::default()
+            };
+            fs_conf.set_format_if_mount_failed(true as u8);
+            fs_conf.set_dont_mount(false as u8);
+            unsafe { esp!(esp_idf_sys::esp_vfs_littlefs_register(&fs_conf))? };
+        }
+        FsKind::Spiffs => {
+            let fs_conf = esp_idf_sys::esp_vfs_spiffs_conf_t {
+                base_path: base_path.as_ptr(),
+                partition_label: partition_label.as_ptr(),
+                max_files: 5,
+                format_if_mount_failed: true,
+            };
+            unsafe { esp!(esp_idf_sys::esp_vfs_spiffs_register(&fs_conf))? };
+        }
+    }
+    Ok(())
 }
 
 /// Initializes a nvs file system.
@@ -267,7 +605,10 @@ fn init_access_point(
 }
 
 /// Initilizes the web server and registers some handlers.
-fn init_web_server(storage_lock: Arc<Mutex<CTStorage>>) -> anyhow::Result<EspHttpServer> {
+fn init_web_server(
+    storage_lock: Arc<Mutex<CTStorage>>,
+    commands: Arc<Commands>,
+) -> anyhow::Result<EspHttpServer> {
     let mut server = EspHttpServer::new(&Default::default())?;
 
     server.handle_get("/", |_req, res| {
@@ -332,7 +673,47 @@ fn init_web_server(storage_lock: Arc<Mutex<CTStorage>>) -> anyhow::Result<EspHtt
             println!("Response: {}", time);
         }
         set_system_time(time)?;
+        CLOCK_SYNCED.store(true, Ordering::Relaxed);
+
+        log::info!("Request handler done");
+        Ok(())
+    })?;
 
+    let handler_storage_lock = storage_lock.clone();
+    server.handle_post("/synced", move |mut req, _res| {
+        log::info!("Handling synced post request.");
+        let mut buf = [0_u8; std::mem::size_of::<u32>()];
+        let mut size = 0;
+        let mut reader = req.reader();
+        loop {
+            let n = reader.read(&mut buf[size..])?;
+            if n == 0 {
+                break;
+            }
+            size += n;
+        }
+        if size != buf.len() {
+            bail!(
+                "Expected a {} byte sequence number, got {} bytes",
+                buf.len(),
+                size
+            );
+        }
+
+        // The backend has every save up to this sequence number, see CTStorage::mark_synced.
+        let sequence = u32::from_le_bytes(buf);
+        {
+            let mut ct_storage = match handler_storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            ct_storage.mark_synced(sequence)?;
+            info!(
+                "Synced up to {}, {} bytes left to sync.",
+                sequence,
+                ct_storage.unsynced_bytes()
+            );
+        }
         log::info!("Request handler done");
         Ok(())
     })?;
@@ -433,9 +814,391 @@ fn init_web_server(storage_lock: Arc<Mutex<CTStorage>>) -> anyhow::Result<EspHtt
         Ok(())
     })?;
 
+    // Queries of the stored readings, answered as "key=value" lines or CSV.
+    let handler_storage_lock = storage_lock.clone();
+    server.handle_get("/shards", move |_req, res| {
+        log::info!("Handling shards get request.");
+        let mut out = String::new();
+        {
+            let ct_storage = match handler_storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            for shard in ct_storage.list_shards()? {
+                out.push_str(&format!(
+                    "shard={} size={} records={} active={}\n",
+                    shard.id, shard.size, shard.records, shard.active
+                ));
+            }
+        }
+        res.send_str(&out)?;
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    let handler_storage_lock = storage_lock.clone();
+    server.handle_get("/shard", move |req, res| {
+        log::info!("Handling shard get request.");
+        let shard_id: i32 = header(&req, "X-SHARD")?;
+        let out = {
+            let ct_storage = match handler_storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let summary = ct_storage.shard_summary(shard_id)?;
+            let mut out = format!(
+                "records={}\ntrailing_bytes={}\ntotal_kwh={}\n",
+                ct_storage.record_count(shard_id)?,
+                summary.trailing_bytes,
+                summary.total_kwh
+            );
+            for (name, metric) in [
+                ("real_power", summary.real_power),
+                ("i_rms", summary.i_rms),
+                ("v_rms", summary.v_rms),
+            ] {
+                out.push_str(&format!(
+                    "{}_min={}\n{}_max={}\n{}_mean={}\n",
+                    name, metric.min, name, metric.max, name, metric.mean
+                ));
+            }
+            out.push_str(&format!(
+                "ordered={}\ncost={}\n",
+                ct_storage.validate_shard_ordering(shard_id)?,
+                ct_storage.shard_cost(shard_id, ENERGY_RATE)?
+            ));
+            out
+        };
+        res.send_str(&out)?;
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    let handler_storage_lock = storage_lock.clone();
+    server.handle_get("/preview", move |req, res| {
+        log::info!("Handling preview get request.");
+        let shard_id: i32 = header(&req, "X-SHARD")?;
+        let target: usize = header(&req, "X-TARGET")?;
+        let readings = {
+            let ct_storage = match handler_storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            ct_storage.decimate_shard(shard_id, target)?
+        };
+        res.send_str(&readings_csv(&readings)?)?;
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    let handler_storage_lock = storage_lock.clone();
+    server.handle_get("/readings", move |req, res| {
+        log::info!("Handling readings get request.");
+        let since: u32 = header(&req, "X-SINCE")?;
+        let readings = {
+            let ct_storage = match handler_storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            ct_storage.readings_since(since)?
+        };
+        res.send_str(&readings_csv(&readings)?)?;
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    let handler_storage_lock = storage_lock.clone();
+    server.handle_get("/hash", move |req, res| {
+        log::info!("Handling hash get request.");
+        let from: i32 = header(&req, "X-FROM")?;
+        let to: i32 = header(&req, "X-TO")?;
+        let hash = {
+            let ct_storage = match handler_storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            ct_storage.content_hash(from, to)?
+        };
+        res.send_str(&format!("{:016x}", hash))?;
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    let handler_storage_lock = storage_lock.clone();
+    server.handle_get("/stats", move |_req, res| {
+        log::info!("Handling stats get request.");
+        let mut out = String::new();
+        {
+            let ct_storage = match handler_storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let stats = ct_storage.lifetime_stats();
+            for (name, metric) in [
+                ("real_power", stats.real_power),
+                ("apparent_power", stats.apparent_power),
+                ("i_rms", stats.i_rms),
+                ("v_rms", stats.v_rms),
+                ("kwh", stats.kwh),
+            ] {
+                out.push_str(&format!(
+                    "{}_mean={}\n{}_std_dev={}\n",
+                    name,
+                    metric.mean(),
+                    name,
+                    metric.std_dev()
+                ));
+            }
+            let tou = ct_storage.tou_totals();
+            let cache = ct_storage.shard_cache_stats();
+            out.push_str(&format!(
+                "peak_demand={}\npeak_demand_at={}\npeak_kwh={}\nshoulder_kwh={}\n\
+                 off_peak_kwh={}\nsequence={}\ncache_hits={}\ncache_misses={}\n\
+                 cache_evictions={}\ncache_bytes={}\nlow_space={}\nroot={}\n\
+                 byte_order={:?}\nrecord_schema={:?}\n",
+                stats.peak_demand,
+                stats.peak_demand_at,
+                tou.peak,
+                tou.shoulder,
+                tou.off_peak,
+                ct_storage.sequence(),
+                cache.hits,
+                cache.misses,
+                cache.evictions,
+                cache.bytes,
+                ct_storage.is_low_space(),
+                ct_storage.root(),
+                ct_storage.byte_order(),
+                ct_storage.record_schema()
+            ));
+        }
+        res.send_str(&out)?;
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    server.handle_get("/format", move |_req, res| {
+        log::info!("Handling format get request.");
+        res.send_str(&format!(
+            "record_size={}\nshard_header_size={}\nmax_shard_size={}\nrecords_per_shard={}\n",
+            record_size(),
+            shard_header_size(),
+            max_shard_size(),
+            records_per_shard()
+        ))?;
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    server.handle_get("/modes", move |_req, res| {
+        log::info!("Handling modes get request.");
+        let mut out = String::new();
+        for mode in available_modes() {
+            out.push_str(&format!(
+                "{}={}\n",
+                settings::mode_name(*mode),
+                mode.description()
+            ));
+        }
+        res.send_str(&out)?;
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    let handler_storage_lock = storage_lock.clone();
+    server.handle_post("/prune", move |req, _res| {
+        log::info!("Handling prune post request.");
+        let max_age: u64 = header(&req, "X-MAX-AGE")?;
+        let mut ct_storage = match handler_storage_lock.lock() {
+            Ok(gaurd) => gaurd,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let pruned =
+            ct_storage.prune_older_than(Duration::from_secs(max_age), now().as_millis() as u64)?;
+        info!("Pruned {} shards older than {} s.", pruned, max_age);
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    let handler_storage_lock = storage_lock.clone();
+    server.handle_post("/recompute", move |req, _res| {
+        log::info!("Handling recompute post request.");
+        let period: u64 = header(&req, "X-PERIOD")?;
+        let mut ct_storage = match handler_storage_lock.lock() {
+            Ok(gaurd) => gaurd,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let records = ct_storage.recompute_energy(Duration::from_secs(period))?;
+        info!("Recomputed the energy of {} records.", records);
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    let handler_storage_lock = storage_lock.clone();
+    server.handle_get("/settings", move |_req, res| {
+        log::info!("Handling settings get request.");
+        let settings = {
+            let ct_storage = match handler_storage_lock.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            ct_storage.settings()?
+        };
+        res.send_str(&settings)?;
+        log::info!("Request handler done");
+        Ok(())
+    })?;
+
+    // Requests that need the CTs, served by the main loop, see commands.rs.
+    let handler_commands = commands.clone();
+    server.handle_get("/live", move |_req, res| {
+        res.send_bytes(&handler_commands.ask(commands::Request::Live)?)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_get("/metrics", move |_req, res| {
+        res.send_bytes(&handler_commands.ask(commands::Request::Metrics)?)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_get("/status", move |_req, res| {
+        res.send_bytes(&handler_commands.ask(commands::Request::Status)?)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_get("/calibration", move |req, res| {
+        let id = header(&req, "X-CT")?;
+        res.send_bytes(&handler_commands.ask(commands::Request::Calibration(id))?)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_post("/calibration", move |mut req, _res| {
+        let id = header(&req, "X-CT")?;
+        let calibration = String::from_utf8(read_body(&mut req, MAX_REQUEST_SIZE)?)?;
+        handler_commands.ask(commands::Request::SetCalibration(id, calibration))?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_post("/calibrate", move |mut req, res| {
+        let id = header(&req, "X-CT")?;
+        let input = String::from_utf8(read_body(&mut req, MAX_REQUEST_SIZE)?)?;
+        let request = commands::Request::Calibrate(id, input.trim().to_string());
+        res.send_bytes(&handler_commands.ask(request)?)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_post("/compare", move |req, res| {
+        let request = commands::Request::Compare(
+            header(&req, "X-CT")?,
+            header(&req, "X-WATTS")?,
+            header(&req, "X-VOLTS")?,
+        );
+        res.send_bytes(&handler_commands.ask(request)?)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_post("/pause", move |_req, _res| {
+        handler_commands.ask(commands::Request::Pause)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_post("/resume", move |_req, _res| {
+        handler_commands.ask(commands::Request::Resume)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_get("/state", move |_req, res| {
+        res.send_bytes(&handler_commands.ask(commands::Request::ExportState)?)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_post("/state", move |mut req, _res| {
+        let state = read_body(&mut req, MAX_REQUEST_SIZE)?;
+        handler_commands.ask(commands::Request::ImportState(state))?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_post("/settings", move |mut req, res| {
+        let update = String::from_utf8(read_body(&mut req, MAX_REQUEST_SIZE)?)?;
+        res.send_bytes(&handler_commands.ask(commands::Request::Settings(update))?)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_post("/capture", move |req, res| {
+        let request = commands::Request::Capture(header(&req, "X-CT")?, header(&req, "X-SAMPLES")?);
+        res.send_bytes(&handler_commands.ask(request)?)?;
+        Ok(())
+    })?;
+
+    let handler_commands = commands.clone();
+    server.handle_post("/replay", move |req, res| {
+        let request = commands::Request::Replay(header(&req, "X-CT")?, header(&req, "X-FILE")?);
+        res.send_bytes(&handler_commands.ask(request)?)?;
+        Ok(())
+    })?;
+
+    server.handle_post("/shutdown", move |_req, _res| {
+        commands.ask(commands::Request::Shutdown)?;
+        Ok(())
+    })?;
+
     Ok(server)
 }
 
+/// The value of the header `name` of `req`, failing if it is missing or doesn't parse.
+fn header<T: FromStr>(req: &EspHttpRequest, name: &str) -> anyhow::Result<T> {
+    let value = match req.header(name) {
+        Some(value) => value,
+        None => bail!("Missing header {}", name),
+    };
+    match value.parse() {
+        Ok(value) => Ok(value),
+        Err(_) => bail!("Invalid header {}: {:?}", name, value),
+    }
+}
+
+/// The body of `req`, failing if it is longer than `max` bytes.
+fn read_body(req: &mut EspHttpRequest, max: usize) -> anyhow::Result<Vec<u8>> {
+    let mut body = vec![0_u8; max + 1];
+    let mut size = 0;
+    let reader = req.reader();
+    while size < body.len() {
+        let n = reader.read(&mut body[size..])?;
+        if n == 0 {
+            break;
+        }
+        size += n;
+    }
+    if size > max {
+        bail!("Request body is over {} bytes", max);
+    }
+    body.truncate(size);
+    Ok(body)
+}
+
+/// `readings` as CSV, with CSV_HEADER as the first line.
+fn readings_csv(readings: &[(u16, ct::CTReading)]) -> anyhow::Result<String> {
+    let mut out = String::from(CSV_HEADER);
+    let mut buf = [0_u8; CSV_ROW_SIZE];
+    for (id, reading) in readings {
+        let len = reading.write_csv(*id, &mut buf)?;
+        out.push_str(std::str::from_utf8(&buf[..len])?);
+    }
+    Ok(out)
+}
+
 fn templated_webpage(content: impl AsRef<str>) -> String {
     format!(
         r#"
@@ -460,6 +1223,30 @@ fn calc_rms(samples: &[f32], size: usize) -> f32 {
     (samples[..size].iter().fold(0.0, |sum, &x| sum + (x * x)) / size as f32).sqrt()
 }
 
+// Whether the clock was set to the real time since boot. A time restored from storage is the one
+// of the last save, so it is behind by however long the device was off.
+static CLOCK_SYNCED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the clock was set to the real time since boot, so now() can be trusted.
+fn clock_synced() -> bool {
+    CLOCK_SYNCED.load(Ordering::Relaxed)
+}
+
+// Whether the clock was set to the time stored by the last save since boot, see
+// CTStorage::update_system_time.
+static CLOCK_RESTORED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the clock was set at all since boot, to the real time or to the stored one, so now() is
+/// a date rather than the time since 1970, see CTReading::is_unsynced_time.
+fn clock_set() -> bool {
+    clock_synced() || CLOCK_RESTORED.load(Ordering::Relaxed)
+}
+
+/// Record that the clock was set to the stored time, see clock_set.
+fn set_clock_restored() {
+    CLOCK_RESTORED.store(true, Ordering::Relaxed);
+}
+
 fn now() -> Duration {
     let mut tv_now: timeval = Default::default();
 
@@ -467,7 +1254,26 @@ fn now() -> Duration {
         gettimeofday(&mut tv_now as *mut _, core::ptr::null_mut());
     }
 
-    Duration::from_micros(tv_now.tv_sec as u64 * 1000000_u64 + tv_now.tv_usec as u64)
+    let now = Duration::from_micros(tv_now.tv_sec as u64 * 1000000_u64 + tv_now.tv_usec as u64);
+    #[cfg(feature = "fault-injection")]
+    let now = crate::fault::faulty_clock(now);
+    now
+}
+
+/// The time of the boot in ms since the epoch, once the clock was set to the real time, see
+/// CTReading::reconcile_time.
+fn boot_time() -> Option<u64> {
+    if clock_synced() {
+        Some(now().saturating_sub(uptime()).as_millis() as u64)
+    } else {
+        None
+    }
+}
+
+/// Time since boot, monotonic and right even before the clock is set.
+fn uptime() -> Duration {
+    let micros = unsafe { esp_timer_get_time() };
+    Duration::from_micros(micros as u64)
 }
 
 fn set_system_time(time_milis: u64) -> anyhow::Result<()> {
@@ -0,0 +1,569 @@
+use std::time::{Duration, Instant};
+
+use embedded_hal_0_2_7::adc::{Channel, OneShot};
+use esp_idf_hal::adc::{PoweredAdc, ADC1, ADC2};
+use esp_idf_sys::esp;
+
+use crate::{
+    ADC_MAX_READING, ADC_READ_TIMEOUT, DMA_FRAME_SIZE, DMA_SAMPLE_FREQ_HZ, MAX_MV_ATTEN_11,
+};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+/// The ADC driver used to sample the CTs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamplingBackend {
+    /// One blocking read per sample. Simple, but the sample rate is limited by the read call and
+    /// the time between two samples jitters with everything else running on the core.
+    OneShot,
+    /// The ADC continuously converts at a fixed rate and the DMA fills a buffer in the
+    /// background. Gives a higher and uniform sample rate, which improves the RMS accuracy.
+    Continuous,
+}
+
+/// The ADCs, set up for the selected backend.
+pub enum Sampler {
+    OneShot(Adcs),
+    Continuous(ContinuousAdc),
+}
+
+impl Sampler {
+    pub(crate) fn new(backend: SamplingBackend, adc1: ADC1, adc2: ADC2) -> anyhow::Result<Self> {
+        match backend {
+            SamplingBackend::OneShot => Ok(Sampler::OneShot(Adcs {
+                adc1: PoweredAdc::new(
+                    adc1,
+                    esp_idf_hal::adc::config::Config::new().calibration(false),
+                )?,
+                adc2: PoweredAdc::new(
+                    adc2,
+                    esp_idf_hal::adc::config::Config::new().calibration(false),
+                )?,
+                wifi_active: false,
+                read_timeout: ADC_READ_TIMEOUT,
+            })),
+            SamplingBackend::Continuous => {
+                Ok(Sampler::Continuous(ContinuousAdc::new(DMA_SAMPLE_FREQ_HZ)?))
+            }
+        }
+    }
+
+    /// Tell the sampler whether WiFi is on, see can_sample.
+    pub(crate) fn set_wifi_active(&mut self, active: bool) {
+        if let Sampler::OneShot(adcs) = self {
+            adcs.wifi_active = active;
+        }
+    }
+
+    /// Give up on a one-shot read that hasn't completed after `timeout`. Defaults to
+    /// ADC_READ_TIMEOUT.
+    ///
+    /// Until then a read that is busy or fails is retried. A read that is given up on fails like
+    /// any other, so the sample keeps its previous value and the measurement goes on, unless more
+    /// than MAX_FAILED_READS of its reads failed. A read call that blocks inside the driver can't
+    /// be interrupted, but its result is dropped if it took longer than the timeout. Keep it above
+    /// a FreeRTOS tick, a read preempted by another task shouldn't count as failed.
+    pub(crate) fn set_read_timeout(&mut self, timeout: Duration) {
+        if let Sampler::OneShot(adcs) = self {
+            adcs.read_timeout = timeout;
+        }
+    }
+
+    /// Whether `channel` can be sampled right now.
+    ///
+    /// On the ESP32 the WiFi driver takes over ADC2, so ADC2 channels can't be read while WiFi is
+    /// on. The continuous backend only drives ADC1.
+    pub(crate) fn can_sample(&self, channel: &dyn AdcChannel) -> bool {
+        match (self, channel.unit()) {
+            (_, AdcUnit::Adc1) => true,
+            (Sampler::OneShot(adcs), AdcUnit::Adc2) => !adcs.wifi_active,
+            (Sampler::Continuous(_), AdcUnit::Adc2) => false,
+        }
+    }
+}
+
+/// The powered ADC units for one-shot reads.
+pub struct Adcs {
+    adc1: PoweredAdc<ADC1>,
+    // Only read by Adc2Pin.
+    adc2: PoweredAdc<ADC2>,
+    wifi_active: bool,
+    read_timeout: Duration,
+}
+
+/// ADC unit of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdcUnit {
+    Adc1,
+    // Only of Adc2Pin.
+    Adc2,
+}
+
+/// An analog input pin on either ADC unit.
+///
+/// Lets a CT use pins of both units, which have different types, through one type.
+pub(crate) trait AdcChannel {
+    fn unit(&self) -> AdcUnit;
+    /// Channel number within the unit.
+    fn channel(&self) -> u8;
+    /// One blocking read of the pin.
+    fn read(&mut self, adcs: &mut Adcs) -> anyhow::Result<u16>;
+}
+
+/// A pin of ADC1.
+pub(crate) struct Adc1Pin<P>(pub(crate) P);
+
+impl<P> AdcChannel for Adc1Pin<P>
+where
+    P: Channel<ADC1, ID = u8>,
+    PoweredAdc<ADC1>: OneShot<ADC1, u16, P>,
+{
+    fn unit(&self) -> AdcUnit {
+        AdcUnit::Adc1
+    }
+
+    fn channel(&self) -> u8 {
+        P::channel()
+    }
+
+    fn read(&mut self, adcs: &mut Adcs) -> anyhow::Result<u16> {
+        let (adc1, pin) = (&mut adcs.adc1, &mut self.0);
+        read_until(|| adc1.read(pin), adcs.read_timeout)
+            .map_err(|_| anyhow::anyhow!("read of ADC1 channel {} failed", P::channel()))
+    }
+}
+
+/// A pin of ADC2. Only readable while WiFi is off, see Sampler::can_sample.
+///
+/// The board has its CTs on ADC1, this is for boards that need more pins.
+pub(crate) struct Adc2Pin<P>(pub(crate) P);
+
+impl<P> AdcChannel for Adc2Pin<P>
+where
+    P: Channel<ADC2, ID = u8>,
+    PoweredAdc<ADC2>: OneShot<ADC2, u16, P>,
+{
+    fn unit(&self) -> AdcUnit {
+        AdcUnit::Adc2
+    }
+
+    fn channel(&self) -> u8 {
+        P::channel()
+    }
+
+    fn read(&mut self, adcs: &mut Adcs) -> anyhow::Result<u16> {
+        if adcs.wifi_active {
+            anyhow::bail!("ADC2 can't be read while WiFi is on");
+        }
+        let (adc2, pin) = (&mut adcs.adc2, &mut self.0);
+        read_until(|| adc2.read(pin), adcs.read_timeout)
+            .map_err(|_| anyhow::anyhow!("read of ADC2 channel {} failed", P::channel()))
+    }
+}
+
+// Retry a one-shot `read` while it is busy or fails, until it succeeds or `timeout` has passed.
+// A sample read after the timeout is dropped too.
+fn read_until<E>(mut read: impl FnMut() -> Result<u16, E>, timeout: Duration) -> Result<u16, ()> {
+    let start = Instant::now();
+    loop {
+        let res = read();
+        if start.elapsed() > timeout {
+            return Err(());
+        }
+        if let Ok(sample) = res {
+            return Ok(sample);
+        }
+    }
+}
+
+/// Source of raw current and voltage samples of a CT, in mV.
+pub(crate) trait SampleSource {
+    fn read_current(&mut self) -> anyhow::Result<u16>;
+    fn read_voltage(&mut self) -> anyhow::Result<u16>;
+
+    /// Whether the source has run out of samples, the measurement stops then. ADCs never do.
+    fn is_exhausted(&self) -> bool {
+        false
+    }
+}
+
+/// Samples a CT with one blocking ADC read per sample.
+pub(crate) struct OneShotSource<'a> {
+    pub(crate) adcs: &'a mut Adcs,
+    pub(crate) current_pin: &'a mut dyn AdcChannel,
+    pub(crate) voltage_pin: &'a mut dyn AdcChannel,
+    /// Number of back to back reads averaged into one sample.
+    pub(crate) oversampling: u8,
+}
+
+impl<'a> SampleSource for OneShotSource<'a> {
+    fn read_current(&mut self) -> anyhow::Result<u16> {
+        read_oversampled(self.adcs, self.current_pin, self.oversampling)
+    }
+
+    fn read_voltage(&mut self) -> anyhow::Result<u16> {
+        read_oversampled(self.adcs, self.voltage_pin, self.oversampling)
+    }
+}
+
+/// Plays back (current, voltage) samples captured before, see ct::replay_waveform.
+///
+/// Each read_voltage moves on to the next pair, so the current has to be read first, as with
+/// ReadOrder::CurrentFirst.
+pub(crate) struct ReplaySource {
+    samples: Vec<(u16, u16)>,
+    pos: usize,
+}
+
+impl ReplaySource {
+    pub(crate) fn new(samples: Vec<(u16, u16)>) -> Self {
+        ReplaySource { samples, pos: 0 }
+    }
+}
+
+impl SampleSource for ReplaySource {
+    fn read_current(&mut self) -> anyhow::Result<u16> {
+        match self.samples.get(self.pos) {
+            Some(&(current, _)) => Ok(current),
+            None => anyhow::bail!("end of the replayed waveform"),
+        }
+    }
+
+    fn read_voltage(&mut self) -> anyhow::Result<u16> {
+        match self.samples.get(self.pos) {
+            Some(&(_, voltage)) => {
+                self.pos += 1;
+                Ok(voltage)
+            }
+            None => anyhow::bail!("end of the replayed waveform"),
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.pos >= self.samples.len()
+    }
+}
+
+// Read `oversampling` samples back to back and return their average.
+// Failed reads are skipped, only if every read fails an error is returned.
+fn read_oversampled(
+    adcs: &mut Adcs,
+    pin: &mut dyn AdcChannel,
+    oversampling: u8,
+) -> anyhow::Result<u16> {
+    let (mut sum, mut count) = (0_u32, 0_u32);
+    for _ in 0..oversampling {
+        if let Ok(sample) = pin.read(adcs) {
+            sum += sample as u32;
+            count += 1;
+        }
+    }
+    sum.checked_div(count)
+        .map(|avg| avg as u16)
+        .ok_or_else(|| anyhow::anyhow!("all {} ADC reads failed", oversampling))
+}
+
+/// The ADC1 continuous (DMA) driver.
+///
+/// The conversions of a CT are set up as a pattern of two channels, current then voltage, that
+/// the ADC repeats at `sample_freq_hz`. So every current sample is followed by the voltage sample
+/// taken 1 / sample_freq_hz later and read returns them as (current, voltage) pairs.
+pub struct ContinuousAdc {
+    sample_freq_hz: u32,
+    running: bool,
+    // A current sample still waiting for its voltage sample from the next frame.
+    pending_current: Option<u16>,
+    frame: Vec<u8>,
+}
+
+impl ContinuousAdc {
+    pub(crate) fn new(sample_freq_hz: u32) -> anyhow::Result<Self> {
+        let init_config = esp_idf_sys::adc_digi_init_config_t {
+            max_store_buf_size: (DMA_FRAME_SIZE * 4) as u32,
+            conv_num_each_intr: DMA_FRAME_SIZE as u32,
+            adc1_chan_mask: 0xff,
+            adc2_chan_mask: 0,
+        };
+        esp!(unsafe { esp_idf_sys::adc_digi_initialize(&init_config) })?;
+        info!("Initialized continuous ADC at {} Hz.", sample_freq_hz);
+        Ok(ContinuousAdc {
+            sample_freq_hz,
+            running: false,
+            pending_current: None,
+            frame: vec![0_u8; DMA_FRAME_SIZE],
+        })
+    }
+
+    /// Start converting the given current and voltage channels.
+    pub(crate) fn start(&mut self, current_channel: u8, voltage_channel: u8) -> anyhow::Result<()> {
+        self.stop()?;
+        let mut pattern = [current_channel, voltage_channel].map(|channel| {
+            esp_idf_sys::adc_digi_pattern_config_t {
+                atten: esp_idf_sys::adc_atten_t_ADC_ATTEN_DB_11 as u8,
+                channel,
+                unit: 0,
+                bit_width: esp_idf_sys::SOC_ADC_DIGI_MAX_BITWIDTH as u8,
+            }
+        });
+        let config = esp_idf_sys::adc_digi_configuration_t {
+            conv_limit_en: true,
+            conv_limit_num: 250,
+            pattern_num: pattern.len() as u32,
+            adc_pattern: pattern.as_mut_ptr(),
+            // Both channels of the pattern are converted at this rate.
+            sample_freq_hz: self.sample_freq_hz * pattern.len() as u32,
+            conv_mode: esp_idf_sys::adc_digi_convert_mode_t_ADC_CONV_SINGLE_UNIT_1,
+            format: esp_idf_sys::adc_digi_output_format_t_ADC_DIGI_OUTPUT_FORMAT_TYPE1,
+        };
+        esp!(unsafe { esp_idf_sys::adc_digi_controller_configure(&config) })?;
+        esp!(unsafe { esp_idf_sys::adc_digi_start() })?;
+        self.running = true;
+        self.pending_current = None;
+        Ok(())
+    }
+
+    /// Stop converting. Samples still in the DMA buffer are dropped on the next start.
+    pub(crate) fn stop(&mut self) -> anyhow::Result<()> {
+        if self.running {
+            esp!(unsafe { esp_idf_sys::adc_digi_stop() })?;
+            self.running = false;
+        }
+        Ok(())
+    }
+
+    /// Read the next frame from the DMA buffer into (current, voltage) pairs in mV.
+    ///
+    /// Returns the number of pairs written, `pairs` must hold at least DMA_FRAME_SIZE / 4 pairs.
+    pub(crate) fn read(
+        &mut self,
+        current_channel: u8,
+        pairs: &mut [(u16, u16)],
+        timeout_ms: u32,
+    ) -> anyhow::Result<usize> {
+        let mut len = 0_u32;
+        esp!(unsafe {
+            esp_idf_sys::adc_digi_read_bytes(
+                self.frame.as_mut_ptr(),
+                self.frame.len() as u32,
+                &mut len,
+                timeout_ms,
+            )
+        })?;
+
+        let mut n = 0;
+        // ESP32 type 1 output: 12 bits of data followed by 4 bits of channel.
+        for conversion in self.frame[..len as usize].chunks_exact(2) {
+            let raw = u16::from_le_bytes([conversion[0], conversion[1]]);
+            let channel = (raw >> 12) as u8;
+            let sample = ((raw & 0xfff) as u32 * MAX_MV_ATTEN_11 as u32 / ADC_MAX_READING) as u16;
+            if channel == current_channel {
+                self.pending_current = Some(sample);
+            } else if let Some(sample_i) = self.pending_current.take() {
+                if n < pairs.len() {
+                    pairs[n] = (sample_i, sample);
+                    n += 1;
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for ContinuousAdc {
+    fn drop(&mut self) {
+        let _ = self.stop();
+        unsafe {
+            esp_idf_sys::adc_digi_deinitialize();
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use esp_idf_hal::prelude::Peripherals;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    // Peripherals::take only hands the ADCs out once per process, so all tests share one sampler.
+    static SAMPLER: Mutex<Option<Sampler>> = Mutex::new(None);
+
+    /// The one-shot sampler of the tests, which take turns with it.
+    pub(crate) struct TestSampler(MutexGuard<'static, Option<Sampler>>);
+
+    impl Deref for TestSampler {
+        type Target = Sampler;
+
+        fn deref(&self) -> &Sampler {
+            self.0.as_ref().unwrap()
+        }
+    }
+
+    impl DerefMut for TestSampler {
+        fn deref_mut(&mut self) -> &mut Sampler {
+            self.0.as_mut().unwrap()
+        }
+    }
+
+    pub(crate) fn test_sampler() -> TestSampler {
+        let mut sampler = SAMPLER.lock().unwrap_or_else(PoisonError::into_inner);
+        sampler.get_or_insert_with(|| {
+            let peripherals = Peripherals::take().unwrap();
+            Sampler::new(SamplingBackend::OneShot, peripherals.adc1, peripherals.adc2).unwrap()
+        });
+        let shared = sampler.as_mut().unwrap();
+        shared.set_read_timeout(ADC_READ_TIMEOUT);
+        shared.set_wifi_active(false);
+        TestSampler(sampler)
+    }
+
+    /// The ADCs of test_sampler.
+    pub(crate) struct TestAdcs(TestSampler);
+
+    impl Deref for TestAdcs {
+        type Target = Adcs;
+
+        fn deref(&self) -> &Adcs {
+            match &*self.0 {
+                Sampler::OneShot(adcs) => adcs,
+                Sampler::Continuous(_) => unreachable!(),
+            }
+        }
+    }
+
+    impl DerefMut for TestAdcs {
+        fn deref_mut(&mut self) -> &mut Adcs {
+            match &mut *self.0 {
+                Sampler::OneShot(adcs) => adcs,
+                Sampler::Continuous(_) => unreachable!(),
+            }
+        }
+    }
+
+    pub(crate) fn test_adcs() -> TestAdcs {
+        TestAdcs(test_sampler())
+    }
+
+    /// A pin of ADC1 whose reads return what `F` returns, in mV.
+    pub(crate) struct MockChannel<F>(pub(crate) F);
+
+    impl<F: FnMut() -> anyhow::Result<u16>> AdcChannel for MockChannel<F> {
+        fn unit(&self) -> AdcUnit {
+            AdcUnit::Adc1
+        }
+
+        fn channel(&self) -> u8 {
+            0
+        }
+
+        fn read(&mut self, _adcs: &mut Adcs) -> anyhow::Result<u16> {
+            (self.0)()
+        }
+    }
+
+    /// A pin of ADC2 whose reads return what `F` returns, but fail while WiFi is on like Adc2Pin.
+    pub(crate) struct MockAdc2Channel<F>(pub(crate) F);
+
+    impl<F: FnMut() -> anyhow::Result<u16>> AdcChannel for MockAdc2Channel<F> {
+        fn unit(&self) -> AdcUnit {
+            AdcUnit::Adc2
+        }
+
+        fn channel(&self) -> u8 {
+            0
+        }
+
+        fn read(&mut self, adcs: &mut Adcs) -> anyhow::Result<u16> {
+            if adcs.wifi_active {
+                anyhow::bail!("ADC2 can't be read while WiFi is on");
+            }
+            (self.0)()
+        }
+    }
+
+    // Uniform noise of ±`amplitude` around `mid`, from a fixed seed.
+    pub(crate) fn noisy(mid: u16, amplitude: u16) -> impl FnMut() -> anyhow::Result<u16> {
+        let mut state = 0x2545_f491_u32;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            Ok(mid - amplitude + (state % (2 * amplitude as u32 + 1)) as u16)
+        }
+    }
+
+    fn variance(pin: &mut dyn AdcChannel, adcs: &mut Adcs, oversampling: u8) -> f64 {
+        let samples: Vec<f64> = (0..2000)
+            .map(|_| read_oversampled(adcs, pin, oversampling).unwrap() as f64)
+            .collect();
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / samples.len() as f64
+    }
+
+    #[test]
+    fn oversampling_reduces_the_noise() {
+        let mut adcs = test_adcs();
+        let mut pin = MockChannel(noisy(1200, 50));
+        let single = variance(&mut pin, &mut adcs, 1);
+        let oversampled = variance(&mut pin, &mut adcs, 8);
+        // Averaging 8 independent reads divides the variance by about 8.
+        assert!(oversampled < single / 4.0, "{} vs {}", oversampled, single);
+    }
+
+    #[test]
+    fn oversampling_skips_failed_reads() {
+        let mut adcs = test_adcs();
+        let mut reads = 0;
+        let mut pin = MockChannel(move || {
+            reads += 1;
+            if reads % 2 == 0 {
+                anyhow::bail!("busy")
+            }
+            Ok(1000)
+        });
+        assert_eq!(read_oversampled(&mut adcs, &mut pin, 4).unwrap(), 1000);
+        let mut failing = MockChannel(|| anyhow::bail!("busy"));
+        assert!(read_oversampled(&mut adcs, &mut failing, 4).is_err());
+    }
+
+    #[test]
+    fn slow_reads_are_given_up_on() {
+        let timeout = Duration::from_millis(5);
+        assert_eq!(read_until(|| Ok::<_, ()>(1000), timeout), Ok(1000));
+        // Busy twice, then the sample.
+        let mut busy = 2;
+        let flaky = || {
+            if busy > 0 {
+                busy -= 1;
+                return Err(());
+            }
+            Ok(1000)
+        };
+        assert_eq!(read_until(flaky, timeout), Ok(1000));
+
+        // A read that stalls in the driver returns late, its sample is dropped.
+        let start = Instant::now();
+        let stalled = || {
+            std::thread::sleep(Duration::from_millis(20));
+            Ok::<_, ()>(1000)
+        };
+        assert_eq!(read_until(stalled, timeout), Err(()));
+        // One read that never succeeds keeps retrying only until the timeout.
+        assert_eq!(read_until(|| Err::<u16, _>(()), timeout), Err(()));
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn adc2_channels_cant_be_sampled_while_wifi_is_on() {
+        let mut sampler = test_sampler();
+        let adc1 = MockChannel(|| Ok(1000));
+        let adc2 = MockAdc2Channel(|| Ok(1000));
+        assert!(sampler.can_sample(&adc1));
+        assert!(sampler.can_sample(&adc2));
+
+        sampler.set_wifi_active(true);
+        assert!(sampler.can_sample(&adc1));
+        assert!(!sampler.can_sample(&adc2));
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::{Duration, Instant};
+
+/// What the main loop should do next, see MeasurementScheduler::poll.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SchedulerAction {
+    /// Measure the CTs.
+    Measure,
+    /// Save the readings of the CTs to storage.
+    Save,
+    /// Nothing is due for the given duration.
+    Wait(Duration),
+}
+
+/// Keeps track of when to measure and when to save, independent of the clock.
+///
+/// Measurements are due every `sampling_interval` and saves every `save_period`, both counted from
+/// the first poll, which is always a measurement. A save takes precedence over a measurement that
+/// is due at the same time. If the loop falls behind, for example because measuring takes longer
+/// than the interval, the missed turns are not made up for, the next one is scheduled a full
+/// interval or period after the late one.
+pub(crate) struct MeasurementScheduler {
+    sampling_interval: Duration,
+    save_period: Duration,
+    next_measurement: Option<Instant>,
+    next_save: Option<Instant>,
+    // How late the last measurement was returned, see crossings.
+    lag: Duration,
+}
+
+impl MeasurementScheduler {
+    pub(crate) fn new(sampling_interval: Duration, save_period: Duration) -> Self {
+        MeasurementScheduler {
+            sampling_interval,
+            save_period,
+            next_measurement: None,
+            next_save: None,
+            lag: Duration::ZERO,
+        }
+    }
+
+    /// Return the action that is due at `now` and schedule the next one of its kind.
+    pub(crate) fn poll(&mut self, now: Instant) -> SchedulerAction {
+        let (next_measurement, next_save) = match (self.next_measurement, self.next_save) {
+            (Some(next_measurement), Some(next_save)) => (next_measurement, next_save),
+            _ => {
+                self.next_measurement = Some(now + self.sampling_interval);
+                self.next_save = Some(now + self.save_period);
+                return SchedulerAction::Measure;
+            }
+        };
+        if now >= next_save {
+            self.next_save = Some(Self::advance(next_save, self.save_period, now));
+            SchedulerAction::Save
+        } else if now >= next_measurement {
+            self.lag = now - next_measurement;
+            self.next_measurement =
+                Some(Self::advance(next_measurement, self.sampling_interval, now));
+            SchedulerAction::Measure
+        } else {
+            SchedulerAction::Wait(next_measurement.min(next_save) - now)
+        }
+    }
+
+    /// How late the last measurement was returned by poll, after the time it was due.
+    pub(crate) fn lag(&self) -> Duration {
+        self.lag
+    }
+
+    /// Crossings to measure so that the measurements catch up with the interval again.
+    ///
+    /// `full` while the measurements are on time. When the last one came late, because measuring
+    /// and saving took longer than the interval, the crossings are cut in proportion to the lag,
+    /// interval / (interval + lag) of `full`, but not below `min`.
+    pub(crate) fn crossings(&self, full: u32, min: u32) -> u32 {
+        if self.lag.is_zero() {
+            return full;
+        }
+        let interval = self.sampling_interval.as_secs_f32();
+        let scale = interval / (interval + self.lag.as_secs_f32());
+        u32::max((full as f32 * scale) as u32, u32::min(min, full))
+    }
+
+    // The deadline after `due`, or a full `step` after `now` if that has already passed too.
+    fn advance(due: Instant, step: Duration, now: Instant) -> Instant {
+        let next = due + step;
+        if next <= now {
+            now + step
+        } else {
+            next
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(10);
+    const PERIOD: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn measures_every_interval_and_saves_every_period() {
+        let start = Instant::now();
+        let mut scheduler = MeasurementScheduler::new(INTERVAL, PERIOD);
+        let mut actions = Vec::new();
+        for second in (0..=125).step_by(5) {
+            // Poll until nothing is due, like the main loop does.
+            let now = start + Duration::from_secs(second);
+            loop {
+                match scheduler.poll(now) {
+                    SchedulerAction::Wait(wait) => {
+                        assert_eq!(wait, Duration::from_secs(10 - second % 10));
+                        break;
+                    }
+                    action => actions.push((second, action)),
+                }
+            }
+        }
+        let mut expected = Vec::new();
+        for second in (0..=120).step_by(10) {
+            if second > 0 && second % 60 == 0 {
+                expected.push((second, SchedulerAction::Save));
+            }
+            expected.push((second, SchedulerAction::Measure));
+        }
+        assert_eq!(actions, expected);
+        assert!(scheduler.lag().is_zero());
+    }
+
+    #[test]
+    fn save_goes_first_and_the_measurement_follows() {
+        let start = Instant::now();
+        let mut scheduler = MeasurementScheduler::new(INTERVAL, PERIOD);
+        assert_eq!(scheduler.poll(start), SchedulerAction::Measure);
+        let at = |seconds| start + Duration::from_secs(seconds);
+        for seconds in (10..60).step_by(10) {
+            assert_eq!(scheduler.poll(at(seconds)), SchedulerAction::Measure);
+        }
+        assert_eq!(scheduler.poll(at(60)), SchedulerAction::Save);
+        assert_eq!(scheduler.poll(at(60)), SchedulerAction::Measure);
+        assert_eq!(
+            scheduler.poll(at(61)),
+            SchedulerAction::Wait(Duration::from_secs(9))
+        );
+    }
+
+    #[test]
+    fn missed_turns_are_not_made_up_for() {
+        let start = Instant::now();
+        let mut scheduler = MeasurementScheduler::new(INTERVAL, PERIOD);
+        assert_eq!(scheduler.poll(start), SchedulerAction::Measure);
+        // The loop stalls for 35 s, three measurements are missed.
+        let late = start + Duration::from_secs(35);
+        assert_eq!(scheduler.poll(late), SchedulerAction::Measure);
+        assert_eq!(scheduler.lag(), Duration::from_secs(25));
+        assert_eq!(scheduler.poll(late), SchedulerAction::Wait(INTERVAL));
+        // A save missed by more than its period comes once and a full period later again.
+        let later = start + Duration::from_secs(150);
+        assert_eq!(scheduler.poll(later), SchedulerAction::Save);
+        assert_eq!(scheduler.poll(later), SchedulerAction::Measure);
+        assert_eq!(
+            scheduler.poll(later + Duration::from_secs(59)),
+            SchedulerAction::Measure
+        );
+        assert_eq!(scheduler.poll(later + PERIOD), SchedulerAction::Save);
+    }
+
+    #[test]
+    fn crossings_shrink_with_the_lag() {
+        let start = Instant::now();
+        let mut scheduler = MeasurementScheduler::new(INTERVAL, PERIOD);
+        scheduler.poll(start);
+        scheduler.poll(start + INTERVAL);
+        assert_eq!(scheduler.crossings(100, 20), 100);
+
+        // 10 s late on a 10 s interval halves them, but never below the minimum.
+        scheduler.poll(start + Duration::from_secs(30));
+        assert_eq!(scheduler.lag(), INTERVAL);
+        assert_eq!(scheduler.crossings(100, 20), 50);
+        assert_eq!(scheduler.crossings(100, 60), 60);
+        assert_eq!(scheduler.crossings(10, 60), 10);
+    }
+}
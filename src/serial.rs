@@ -0,0 +1,176 @@
+use crate::utils::crc16;
+use crate::CT_READING_SIZE;
+
+/// Frame layout: START, VERSION, LEN, LEN bytes of record, CRC-16 (little endian) of VERSION, LEN
+/// and the record, END. The record is the same le-bytes record that is stored in the shards.
+const FRAME_START: u8 = 0x7e;
+const FRAME_END: u8 = 0x7f;
+/// Layout of the record in a frame, bumped whenever CT_READING_SIZE or the record changes, so a
+/// reader can tell the frames it knows from those of newer firmware.
+pub(crate) const FRAME_VERSION: u8 = 1;
+pub(crate) const FRAME_SIZE: usize = CT_READING_SIZE + 6;
+
+/// Wrap a stored record into a frame for the serial link.
+pub(crate) fn encode_frame(record: &[u8; CT_READING_SIZE]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_SIZE);
+    frame.push(FRAME_START);
+    frame.push(FRAME_VERSION);
+    frame.push(CT_READING_SIZE as u8);
+    frame.extend_from_slice(record);
+    let crc = crc16(&frame[1..]);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame.push(FRAME_END);
+    frame
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ct::tests::reading;
+    use crate::ct::CTReading;
+    use crate::storage::tests::decode_le_record;
+    use crate::storage::CTStorage;
+    use log::warn;
+
+    // Bytes of a frame around the record.
+    const FRAME_OVERHEAD: usize = FRAME_SIZE - CT_READING_SIZE;
+
+    // Find and decode the first valid frame in `buf`, as a reader on the other end of the link
+    // does. Returns the decoded record, if any, and the number of bytes at the start of `buf`
+    // that have been consumed and can be dropped. A frame is only accepted if its CRC and end
+    // byte match, otherwise the search goes on from the next byte. So after a dropped or
+    // corrupted byte the reader throws away the broken frame and picks up again at the next
+    // start byte. An intact frame of another FRAME_VERSION is skipped as a whole. If `buf` ends
+    // in an incomplete frame, it is not consumed and parsing should be retried when more bytes
+    // arrive.
+    pub(crate) fn parse_frame(buf: &[u8]) -> (Option<(u16, CTReading)>, usize) {
+        let mut start = 0;
+        while let Some(offset) = buf[start..].iter().position(|&b| b == FRAME_START) {
+            start += offset;
+            let size = match buf.get(start + 2) {
+                Some(&len) => len as usize + FRAME_OVERHEAD,
+                None => return (None, start),
+            };
+            let frame = match buf.get(start..start + size) {
+                Some(frame) => frame,
+                // Incomplete frame, wait for the rest of it.
+                None => return (None, start),
+            };
+            let crc_pos = size - 3;
+            let crc = u16::from_le_bytes([frame[crc_pos], frame[crc_pos + 1]]);
+            if frame[size - 1] == FRAME_END && crc == crc16(&frame[1..crc_pos]) {
+                if frame[1] != FRAME_VERSION || size != FRAME_SIZE {
+                    warn!("Skipped a frame of version {}, {} bytes", frame[1], size);
+                    start += size;
+                    continue;
+                }
+                let mut record = [0_u8; CT_READING_SIZE];
+                record.copy_from_slice(&frame[3..crc_pos]);
+                if let Ok(reading) = decode_le_record(&record) {
+                    return (Some(reading), start + FRAME_SIZE);
+                }
+            }
+            start += 1;
+        }
+        (None, buf.len())
+    }
+
+    fn record(real_power: f32, sequence: u32) -> [u8; CT_READING_SIZE] {
+        CTStorage::ct_reading_to_le_bytes(3, &reading(real_power, 1_700_000_000), sequence).unwrap()
+    }
+
+    // The record of a parsed frame, encoded again.
+    fn parsed_record(buf: &[u8], sequence: u32) -> (Option<[u8; CT_READING_SIZE]>, usize) {
+        let (parsed, consumed) = parse_frame(buf);
+        let parsed = parsed.map(|(id, reading)| {
+            assert_eq!(id, 3);
+            CTStorage::ct_reading_to_le_bytes(id, &reading, sequence).unwrap()
+        });
+        (parsed, consumed)
+    }
+
+    #[test]
+    fn frame_round_trips() {
+        let frame = encode_frame(&record(1200.0, 7));
+        assert_eq!(frame.len(), FRAME_SIZE);
+        assert_eq!(frame[0], FRAME_START);
+        assert_eq!(frame[1], FRAME_VERSION);
+        assert_eq!(frame[2] as usize, CT_READING_SIZE);
+        assert_eq!(frame[FRAME_SIZE - 1], FRAME_END);
+        assert_eq!(
+            parsed_record(&frame, 7),
+            (Some(record(1200.0, 7)), FRAME_SIZE)
+        );
+
+        // Two frames back to back come out one after the other.
+        let mut buf = frame.clone();
+        buf.extend(encode_frame(&record(80.0, 8)));
+        assert_eq!(
+            parsed_record(&buf, 7),
+            (Some(record(1200.0, 7)), FRAME_SIZE)
+        );
+        assert_eq!(
+            parsed_record(&buf[FRAME_SIZE..], 8),
+            (Some(record(80.0, 8)), FRAME_SIZE)
+        );
+    }
+
+    #[test]
+    fn incomplete_frame_is_kept_for_later() {
+        let frame = encode_frame(&record(1200.0, 7));
+        // Noise before the start byte is dropped, the partial frame is kept.
+        let mut buf = vec![0x00, 0x55];
+        buf.extend_from_slice(&frame[..FRAME_SIZE - 1]);
+        assert_eq!(parsed_record(&buf, 7), (None, 2));
+        assert_eq!(parsed_record(&[0x00, 0x55], 7), (None, 2));
+        assert_eq!(parsed_record(&[], 7), (None, 0));
+    }
+
+    #[test]
+    fn corrupted_frame_is_skipped() {
+        let good = encode_frame(&record(80.0, 8));
+        for corrupt in [1, 2, 3, 3 + CT_READING_SIZE, FRAME_SIZE - 1] {
+            let mut buf = encode_frame(&record(1200.0, 7));
+            buf[corrupt] ^= 0x01;
+            buf.extend_from_slice(&good);
+            assert_eq!(
+                parsed_record(&buf, 8),
+                (Some(record(80.0, 8)), 2 * FRAME_SIZE),
+                "byte {} corrupted",
+                corrupt
+            );
+        }
+
+        // A dropped byte breaks the frame, the reader picks up at the next start byte.
+        let mut buf = encode_frame(&record(1200.0, 7));
+        buf.remove(10);
+        buf.extend_from_slice(&good);
+        assert_eq!(
+            parsed_record(&buf, 8),
+            (Some(record(80.0, 8)), 2 * FRAME_SIZE - 1)
+        );
+    }
+
+    #[test]
+    fn frame_of_another_version_is_skipped() {
+        // A newer firmware with a longer record.
+        let mut newer = vec![FRAME_START, FRAME_VERSION + 1, CT_READING_SIZE as u8 + 4];
+        newer.extend_from_slice(&[0x55; CT_READING_SIZE + 4]);
+        let crc = crc16(&newer[1..]);
+        newer.extend_from_slice(&crc.to_le_bytes());
+        newer.push(FRAME_END);
+        let mut buf = newer.clone();
+        buf.extend(encode_frame(&record(80.0, 8)));
+        assert_eq!(
+            parsed_record(&buf, 8),
+            (Some(record(80.0, 8)), newer.len() + FRAME_SIZE)
+        );
+
+        // One of the same size but another version.
+        let mut other = encode_frame(&record(1200.0, 7));
+        other[1] = FRAME_VERSION + 1;
+        let crc = crc16(&other[1..FRAME_SIZE - 3]);
+        other[FRAME_SIZE - 3..FRAME_SIZE - 1].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(parsed_record(&other, 7), (None, FRAME_SIZE));
+    }
+}
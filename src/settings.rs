@@ -0,0 +1,585 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+
+use crate::ct::{
+    Aggregation, Aggregations, ClippedPolicy, ImplausibleVoltage, MeasurementMode, NegativeEnergy,
+    PowerConvention, ReadOrder, ShortCrossings, TouSchedule, ZeroCrossTimeout, CT,
+};
+#[cfg(feature = "fault-injection")]
+use crate::fault::{FaultConfig, FaultInjector};
+use crate::sampling::{Sampler, SamplingBackend};
+use crate::storage::{
+    CTStorage, LowSpacePolicy, RecordSchema, ShardRecovery, SyncPolicy, UnsyncedPolicy,
+};
+use crate::SAMPLING_BACKEND;
+
+// The names of the values of each setting that picks one of a few.
+const READ_ORDERS: &[(&str, ReadOrder)] = &[
+    ("current_first", ReadOrder::CurrentFirst),
+    ("voltage_first", ReadOrder::VoltageFirst),
+];
+const MODES: &[(&str, MeasurementMode)] = &[
+    ("standard", MeasurementMode::Standard),
+    ("cycle_corrected", MeasurementMode::CycleCorrected),
+    ("current_only", MeasurementMode::CurrentOnly),
+];
+const ZERO_CROSS_TIMEOUTS: &[(&str, ZeroCrossTimeout)] = &[
+    ("abort", ZeroCrossTimeout::Abort),
+    ("apparent_only", ZeroCrossTimeout::ApparentOnly),
+    ("skip", ZeroCrossTimeout::Skip),
+];
+const SHORT_CROSSINGS: &[(&str, ShortCrossings)] = &[
+    ("flag", ShortCrossings::Flag),
+    ("retry", ShortCrossings::Retry),
+    ("extend_timeout", ShortCrossings::ExtendTimeout),
+];
+const NEGATIVE_ENERGY: &[(&str, NegativeEnergy)] = &[
+    ("keep", NegativeEnergy::Keep),
+    ("clamp", NegativeEnergy::Clamp),
+    ("export", NegativeEnergy::Export),
+];
+const POWER_CONVENTIONS: &[(&str, PowerConvention)] = &[
+    ("import_positive", PowerConvention::ImportPositive),
+    ("export_positive", PowerConvention::ExportPositive),
+];
+const IMPLAUSIBLE_VOLTAGE: &[(&str, ImplausibleVoltage)] = &[
+    ("flag", ImplausibleVoltage::Flag),
+    ("substitute", ImplausibleVoltage::Substitute),
+];
+const AGGREGATIONS: &[(&str, Aggregation)] = &[
+    ("mean", Aggregation::Mean),
+    ("max", Aggregation::Max),
+    ("last", Aggregation::Last),
+    ("rms", Aggregation::RmsOfSquares),
+];
+const BACKENDS: &[(&str, SamplingBackend)] = &[
+    ("one_shot", SamplingBackend::OneShot),
+    ("continuous", SamplingBackend::Continuous),
+];
+const UNSYNCED_POLICIES: &[(&str, UnsyncedPolicy)] = &[
+    ("ring", UnsyncedPolicy::Ring),
+    ("back_pressure", UnsyncedPolicy::BackPressure),
+];
+const CLIPPED_POLICIES: &[(&str, ClippedPolicy)] = &[
+    ("store", ClippedPolicy::Store),
+    ("sentinel", ClippedPolicy::Sentinel),
+    ("skip", ClippedPolicy::Skip),
+];
+const SHARD_RECOVERIES: &[(&str, ShardRecovery)] = &[
+    ("off", ShardRecovery::Off),
+    ("truncate", ShardRecovery::Truncate),
+    ("roll", ShardRecovery::Roll),
+];
+const LOW_SPACE_POLICIES: &[(&str, LowSpacePolicy)] = &[
+    ("off", LowSpacePolicy::Off),
+    ("coarsen", LowSpacePolicy::Coarsen),
+];
+// The metrics of a record schema, in the order of the fields of RecordSchema.
+const RECORD_METRICS: [&str; 8] = [
+    "real_power",
+    "apparent_power",
+    "i_rms",
+    "v_rms",
+    "kwh",
+    "uptime",
+    "flags",
+    "quality",
+];
+
+/// A setting of the device that can be changed without a firmware update, see parse.
+///
+/// Each one stands for a setter of the CTs, the sampler or the storage and takes its arguments.
+#[derive(Debug, Clone)]
+pub(crate) enum Setting {
+    ReadOrder(ReadOrder),
+    Mode(MeasurementMode),
+    ZeroCrossTimeout(ZeroCrossTimeout),
+    MinCrossings(Option<(u32, ShortCrossings)>),
+    NegativeEnergy(NegativeEnergy),
+    PowerConvention(PowerConvention),
+    PlausibleVoltage(Option<(f32, f32)>, ImplausibleVoltage),
+    NominalVoltage(f32),
+    ZeroCrossBand(f32),
+    MeasurementRetries(u8),
+    CurrentCorrection(Vec<(f32, f32)>),
+    CurrentGain(Vec<(f32, f32)>),
+    LowpassCutoff(Option<f32>),
+    ExpectedFrequency(f32, f32),
+    Aggregations(Aggregations),
+    AverageRms(bool),
+    LogEvery(Option<u32>),
+    ExportHysteresis(f32, u32),
+    FaultCapture(usize, Option<f32>),
+    MaxOffsetDrift(Option<f32>),
+    WarmupReadings(u32),
+    CycleCorrection(bool),
+    Backend(SamplingBackend),
+    ReadTimeout(Duration),
+    SyncPolicy(SyncPolicy),
+    UnsyncedLimit(Option<(u64, UnsyncedPolicy)>),
+    ShardCache(usize),
+    RecordSchema(RecordSchema),
+    ClippedPolicy(ClippedPolicy),
+    ShardRecovery(ShardRecovery),
+    LowSpacePolicy(LowSpacePolicy),
+    MinSaveInterval(Duration),
+    WriteBatching(Option<usize>),
+    IntegrityScan(bool),
+    UtcOffset(i32),
+    #[cfg(feature = "fault-injection")]
+    Faults(FaultConfig),
+}
+
+impl Setting {
+    /// Whether the setting only takes effect at boot, when the sampler and the storage are set up.
+    pub(crate) fn boot_only(&self) -> bool {
+        matches!(
+            self,
+            Setting::Backend(_)
+                | Setting::RecordSchema(_)
+                | Setting::ShardRecovery(_)
+                | Setting::IntegrityScan(_)
+        )
+    }
+
+    /// Apply the setting to `ct` if it is one of the CTs.
+    pub(crate) fn apply_to_ct(&self, ct: &mut CT) {
+        match self {
+            Setting::ReadOrder(order) => ct.set_read_order(*order),
+            Setting::Mode(mode) => ct.set_mode(*mode),
+            Setting::ZeroCrossTimeout(on_timeout) => ct.set_zero_cross_timeout(*on_timeout),
+            Setting::MinCrossings(min_crossings) => ct.set_min_crossings(*min_crossings),
+            Setting::NegativeEnergy(negative_energy) => ct.set_negative_energy(*negative_energy),
+            Setting::PowerConvention(convention) => ct.set_power_convention(*convention),
+            Setting::PlausibleVoltage(band, policy) => ct.set_plausible_voltage(*band, *policy),
+            Setting::NominalVoltage(volts) => ct.set_nominal_voltage(*volts),
+            Setting::ZeroCrossBand(mv) => ct.set_zero_cross_band(*mv),
+            Setting::MeasurementRetries(n) => ct.set_measurement_retries(*n),
+            Setting::CurrentCorrection(points) => ct.set_current_correction(points.clone()),
+            Setting::CurrentGain(points) => ct.set_current_gain(points.clone()),
+            Setting::LowpassCutoff(hz) => ct.set_lowpass_cutoff(*hz),
+            Setting::ExpectedFrequency(hz, tolerance) => ct.set_expected_frequency(*hz, *tolerance),
+            Setting::Aggregations(aggregations) => ct.set_aggregations(*aggregations),
+            Setting::AverageRms(enabled) => ct.set_average_rms(*enabled),
+            Setting::LogEvery(n) => ct.set_log_every(*n),
+            Setting::ExportHysteresis(dead_zone, readings) => {
+                ct.set_export_hysteresis(*dead_zone, *readings)
+            }
+            Setting::FaultCapture(samples, max_current) => {
+                ct.set_fault_capture(*samples, *max_current)
+            }
+            Setting::MaxOffsetDrift(max) => ct.set_max_offset_drift(*max),
+            Setting::WarmupReadings(n) => ct.set_warmup_readings(*n),
+            Setting::CycleCorrection(enabled) => ct.set_cycle_correction(*enabled),
+            _ => {}
+        }
+    }
+
+    /// Apply the setting to `sampler` if it is one of the sampler. The backend is picked when the
+    /// sampler is created, see backend.
+    pub(crate) fn apply_to_sampler(&self, sampler: &mut Sampler) {
+        if let Setting::ReadTimeout(timeout) = self {
+            sampler.set_read_timeout(*timeout);
+        }
+    }
+
+    /// Apply the setting to `storage` if it is one of the storage. Apply them before
+    /// find_newest_readings_shard_num, which the record schema and the shard recovery need.
+    pub(crate) fn apply_to_storage(&self, storage: &mut CTStorage) {
+        match self {
+            Setting::SyncPolicy(policy) => storage.set_sync_policy(*policy),
+            Setting::UnsyncedLimit(limit) => match limit {
+                Some((bytes, policy)) => storage.set_unsynced_limit(Some(*bytes), *policy),
+                None => storage.set_unsynced_limit(None, UnsyncedPolicy::Ring),
+            },
+            Setting::ShardCache(budget) => storage.set_shard_cache(*budget),
+            Setting::RecordSchema(schema) => storage.set_record_schema(*schema),
+            Setting::ClippedPolicy(policy) => storage.set_clipped_policy(*policy),
+            Setting::ShardRecovery(recovery) => storage.set_shard_recovery(*recovery),
+            Setting::LowSpacePolicy(policy) => storage.set_low_space_policy(*policy),
+            Setting::MinSaveInterval(interval) => storage.set_min_save_interval(*interval),
+            Setting::WriteBatching(block_size) => storage.set_write_batching(*block_size),
+            Setting::IntegrityScan(enabled) => storage.set_integrity_scan(*enabled),
+            Setting::UtcOffset(minutes) => storage.set_tou_schedule(TouSchedule {
+                utc_offset_minutes: *minutes,
+                ..TouSchedule::default()
+            }),
+            _ => {}
+        }
+    }
+
+    /// Apply the setting to `faults` if it is the fault config.
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fn apply_to_faults(&self, faults: &FaultInjector) {
+        if let Setting::Faults(config) = self {
+            faults.set_config(*config);
+        }
+    }
+}
+
+/// The sampling backend of `settings`, SAMPLING_BACKEND if they don't pick one.
+pub(crate) fn backend(settings: &[Setting]) -> SamplingBackend {
+    settings
+        .iter()
+        .rev()
+        .find_map(|setting| match setting {
+            Setting::Backend(backend) => Some(*backend),
+            _ => None,
+        })
+        .unwrap_or(SAMPLING_BACKEND)
+}
+
+/// The name of `mode` in the settings, e.g. "current_only".
+pub(crate) fn mode_name(mode: MeasurementMode) -> &'static str {
+    MODES
+        .iter()
+        .find(|(_, value)| *value == mode)
+        .map_or("", |(name, _)| name)
+}
+
+/// Parse settings given as "key=value" lines, e.g. "nominal_voltage=120\nmode=current_only".
+///
+/// Blank lines and lines starting with '#' are skipped. Numbers are in the units of the setters,
+/// durations in ms for read_timeout and in s for min_save_interval, "off" turns an optional
+/// setting off, and settings of several values take them separated by spaces. The points of
+/// current_correction and current_gain are "x:y" pairs separated by ';'. Unknown keys, repeated
+/// keys and malformed values fail the whole text, like CT::apply_calibration_str, so a typo never
+/// leaves the device half configured.
+pub(crate) fn parse(text: &str) -> anyhow::Result<Vec<Setting>> {
+    let mut settings = Vec::new();
+    let mut keys: Vec<&str> = Vec::new();
+    for line in lines(text) {
+        let (key, value) = split(line)?;
+        if keys.contains(&key) {
+            bail!("{} is set twice", key);
+        }
+        let setting = parse_setting(key, value)
+            .map_err(|err| anyhow!("Invalid {} {:?}: {}", key, value, err))?;
+        settings.push(setting);
+        keys.push(key);
+    }
+    Ok(settings)
+}
+
+/// `stored` settings with the lines of `update` in place of the ones with the same keys.
+///
+/// The other lines of `stored`, comments included, are kept in their order, and the new keys are
+/// added at the end. Parse `update` first, this only looks at the keys.
+pub(crate) fn merge(stored: &str, update: &str) -> String {
+    let updated: Vec<&str> = lines(update)
+        .filter_map(|line| split(line).ok())
+        .map(|(key, _)| key)
+        .collect();
+    let mut merged = String::new();
+    for line in stored.lines() {
+        let key = split(line.trim()).map(|(key, _)| key);
+        if !matches!(key, Ok(key) if updated.contains(&key)) {
+            merged.push_str(line);
+            merged.push('\n');
+        }
+    }
+    for line in lines(update) {
+        merged.push_str(line);
+        merged.push('\n');
+    }
+    merged
+}
+
+// The lines of `text` that are neither blank nor comments, trimmed.
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+// The key and value of a "key=value" line.
+fn split(line: &str) -> anyhow::Result<(&str, &str)> {
+    match line.split_once('=') {
+        Some((key, value)) => Ok((key.trim(), value.trim())),
+        None => bail!("expected key=value, got {:?}", line),
+    }
+}
+
+fn parse_setting(key: &str, value: &str) -> anyhow::Result<Setting> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    Ok(match key {
+        "read_order" => Setting::ReadOrder(choice(value, READ_ORDERS)?),
+        "mode" => Setting::Mode(choice(value, MODES)?),
+        "zero_cross_timeout" => Setting::ZeroCrossTimeout(choice(value, ZERO_CROSS_TIMEOUTS)?),
+        "min_crossings" => Setting::MinCrossings(optional(value, |_| match words[..] {
+            [min, policy] => Ok((number(min)?, choice(policy, SHORT_CROSSINGS)?)),
+            _ => bail!("expected off or the crossings and the policy"),
+        })?),
+        "negative_energy" => Setting::NegativeEnergy(choice(value, NEGATIVE_ENERGY)?),
+        "power_convention" => Setting::PowerConvention(choice(value, POWER_CONVENTIONS)?),
+        "plausible_voltage" => match words[..] {
+            ["off"] => Setting::PlausibleVoltage(None, ImplausibleVoltage::Flag),
+            [low, high, policy] => Setting::PlausibleVoltage(
+                Some((float(low)?, float(high)?)),
+                choice(policy, IMPLAUSIBLE_VOLTAGE)?,
+            ),
+            _ => bail!("expected off or the lowest and highest V and the policy"),
+        },
+        "nominal_voltage" => Setting::NominalVoltage(float(value)?),
+        "zero_cross_band" => Setting::ZeroCrossBand(float(value)?),
+        "measurement_retries" => Setting::MeasurementRetries(number(value)?),
+        "current_correction" => Setting::CurrentCorrection(points(value)?),
+        "current_gain" => Setting::CurrentGain(points(value)?),
+        "lowpass_cutoff" => Setting::LowpassCutoff(optional(value, float)?),
+        "expected_frequency" => match words[..] {
+            [hz, tolerance] => Setting::ExpectedFrequency(float(hz)?, float(tolerance)?),
+            _ => bail!("expected the frequency and the tolerance in Hz"),
+        },
+        "aggregations" => match words[..] {
+            [real_power, apparent_power, i_rms, v_rms, v_peak, i_peak] => {
+                Setting::Aggregations(Aggregations {
+                    real_power: choice(real_power, AGGREGATIONS)?,
+                    apparent_power: choice(apparent_power, AGGREGATIONS)?,
+                    i_rms: choice(i_rms, AGGREGATIONS)?,
+                    v_rms: choice(v_rms, AGGREGATIONS)?,
+                    v_peak: choice(v_peak, AGGREGATIONS)?,
+                    i_peak: choice(i_peak, AGGREGATIONS)?,
+                })
+            }
+            _ => bail!("expected real_power, apparent_power, i_rms, v_rms, v_peak and i_peak"),
+        },
+        "average_rms" => Setting::AverageRms(number(value)?),
+        "log_every" => Setting::LogEvery(optional(value, number)?),
+        "export_hysteresis" => match words[..] {
+            [dead_zone, readings] => {
+                Setting::ExportHysteresis(float(dead_zone)?, number(readings)?)
+            }
+            _ => bail!("expected the dead zone in W and the readings"),
+        },
+        "fault_capture" => match words[..] {
+            ["off"] => Setting::FaultCapture(0, None),
+            [samples] => Setting::FaultCapture(number(samples)?, None),
+            [samples, max_current] => {
+                Setting::FaultCapture(number(samples)?, Some(float(max_current)?))
+            }
+            _ => bail!("expected off or the samples and an optional A"),
+        },
+        "max_offset_drift" => Setting::MaxOffsetDrift(optional(value, float)?),
+        "warmup_readings" => Setting::WarmupReadings(number(value)?),
+        "cycle_correction" => Setting::CycleCorrection(number(value)?),
+        "sampling_backend" => Setting::Backend(choice(value, BACKENDS)?),
+        "read_timeout" => Setting::ReadTimeout(Duration::from_millis(number(value)?)),
+        "sync_policy" => Setting::SyncPolicy(match value {
+            "never" => SyncPolicy::Never,
+            "every_save" => SyncPolicy::EverySave,
+            n => SyncPolicy::EveryNSaves(number(n)?),
+        }),
+        "unsynced_limit" => Setting::UnsyncedLimit(optional(value, |_| match words[..] {
+            [bytes, policy] => Ok((number(bytes)?, choice(policy, UNSYNCED_POLICIES)?)),
+            _ => bail!("expected off or the bytes and the policy"),
+        })?),
+        "shard_cache" => Setting::ShardCache(number(value)?),
+        "record_schema" => Setting::RecordSchema(record_schema(&words)?),
+        "clipped_policy" => Setting::ClippedPolicy(choice(value, CLIPPED_POLICIES)?),
+        "shard_recovery" => Setting::ShardRecovery(choice(value, SHARD_RECOVERIES)?),
+        "low_space_policy" => Setting::LowSpacePolicy(choice(value, LOW_SPACE_POLICIES)?),
+        "min_save_interval" => Setting::MinSaveInterval(Duration::from_secs(number(value)?)),
+        "write_batching" => Setting::WriteBatching(optional(value, number)?),
+        "integrity_scan" => Setting::IntegrityScan(number(value)?),
+        "utc_offset" => Setting::UtcOffset(number(value)?),
+        #[cfg(feature = "fault-injection")]
+        "faults" => match words[..] {
+            [adc, storage, clock, jump, seed] => Setting::Faults(FaultConfig {
+                adc_failure_rate: float(adc)?,
+                storage_failure_rate: float(storage)?,
+                clock_jump_rate: float(clock)?,
+                clock_jump: number(jump)?,
+                seed: number(seed)?,
+            }),
+            _ => bail!("expected the ADC, storage and clock rates, the jump in ms and the seed"),
+        },
+        _ => bail!("unknown setting"),
+    })
+}
+
+// The value of `choices` named `value`.
+fn choice<T: Copy>(value: &str, choices: &[(&str, T)]) -> anyhow::Result<T> {
+    match choices.iter().find(|(name, _)| *name == value) {
+        Some((_, choice)) => Ok(*choice),
+        None => {
+            let names: Vec<&str> = choices.iter().map(|(name, _)| *name).collect();
+            bail!("expected one of {}", names.join(", "))
+        }
+    }
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> anyhow::Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("expected a {}", std::any::type_name::<T>()))
+}
+
+// A finite number, parse takes "inf" and "NaN" too.
+fn float(value: &str) -> anyhow::Result<f32> {
+    match value.parse::<f32>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => bail!("expected a number"),
+    }
+}
+
+// None for "off", otherwise the value of `parse`.
+fn optional<T>(
+    value: &str,
+    parse: impl FnOnce(&str) -> anyhow::Result<T>,
+) -> anyhow::Result<Option<T>> {
+    match value {
+        "off" => Ok(None),
+        value => parse(value).map(Some),
+    }
+}
+
+// "x:y" points separated by ';', none for "off".
+fn points(value: &str) -> anyhow::Result<Vec<(f32, f32)>> {
+    if value == "off" {
+        return Ok(Vec::new());
+    }
+    value
+        .split(';')
+        .map(|point| match point.split_once(':') {
+            Some((x, y)) => Ok((float(x.trim())?, float(y.trim())?)),
+            None => bail!("expected x:y points, got {:?}", point),
+        })
+        .collect()
+}
+
+// The schema of the metrics named in `words`, see RECORD_METRICS.
+fn record_schema(words: &[&str]) -> anyhow::Result<RecordSchema> {
+    let mut bits = [false; 8];
+    for word in words {
+        match RECORD_METRICS.iter().position(|metric| metric == word) {
+            Some(n) => bits[n] = true,
+            None => bail!("expected metrics of {}", RECORD_METRICS.join(", ")),
+        }
+    }
+    let [real_power, apparent_power, i_rms, v_rms, kwh, uptime, flags, quality] = bits;
+    Ok(RecordSchema {
+        real_power,
+        apparent_power,
+        i_rms,
+        v_rms,
+        kwh,
+        uptime,
+        flags,
+        quality,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ct::tests::test_ct;
+    use crate::sampling::tests::test_sampler;
+    use crate::storage::tests::{storage, MemFs};
+
+    #[test]
+    fn settings_apply_to_their_targets() {
+        let settings = parse(
+            "# Installed on a 120 V service.\n\
+             nominal_voltage=120\n\
+             mode = current_only\n\
+             \n\
+             read_timeout=50\n\
+             clipped_policy=skip\n\
+             utc_offset=-300\n",
+        )
+        .unwrap();
+        assert_eq!(settings.len(), 5);
+
+        let mut ct = test_ct();
+        let mut sampler = test_sampler();
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        for setting in &settings {
+            setting.apply_to_ct(&mut ct);
+            setting.apply_to_sampler(&mut sampler);
+            setting.apply_to_storage(&mut storage);
+        }
+        assert_eq!(ct.mode(), MeasurementMode::CurrentOnly);
+        ct.reading.clipped = true;
+        assert!(storage.skips(&ct));
+    }
+
+    #[test]
+    fn settings_take_several_values() {
+        let settings = parse(
+            "min_crossings=150 retry\n\
+             plausible_voltage=off\n\
+             current_gain=0.5:1.02; 5:1.0\n\
+             aggregations=mean mean max rms max max\n\
+             unsynced_limit=65536 back_pressure\n\
+             record_schema=real_power kwh\n\
+             sync_policy=10\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            settings[0],
+            Setting::MinCrossings(Some((150, ShortCrossings::Retry)))
+        ));
+        assert!(matches!(settings[1], Setting::PlausibleVoltage(None, _)));
+        assert!(matches!(&settings[2], Setting::CurrentGain(points) if points.len() == 2));
+        assert!(matches!(
+            settings[3],
+            Setting::Aggregations(Aggregations {
+                i_rms: Aggregation::Max,
+                ..
+            })
+        ));
+        assert!(matches!(
+            settings[4],
+            Setting::UnsyncedLimit(Some((65536, UnsyncedPolicy::BackPressure)))
+        ));
+        assert!(matches!(
+            settings[5],
+            Setting::RecordSchema(RecordSchema {
+                real_power: true,
+                kwh: true,
+                v_rms: false,
+                ..
+            })
+        ));
+        assert!(matches!(
+            settings[6],
+            Setting::SyncPolicy(SyncPolicy::EveryNSaves(10))
+        ));
+    }
+
+    #[test]
+    fn bad_settings_fail_the_whole_text() {
+        for text in [
+            "nominal_voltage=120\nnominal_volts=120",
+            "nominal_voltage=120\nnominal_voltage=230",
+            "nominal_voltage=NaN",
+            "mode=fast",
+            "expected_frequency=50",
+            "current_gain=0.5,1.02",
+            "log_every",
+        ] {
+            assert!(parse(text).is_err(), "{:?} parsed", text);
+        }
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn backend_is_the_last_one_set() {
+        assert_eq!(backend(&[]), SAMPLING_BACKEND);
+        let settings = parse("sampling_backend=continuous").unwrap();
+        assert!(settings[0].boot_only());
+        assert_eq!(backend(&settings), SamplingBackend::Continuous);
+    }
+
+    #[test]
+    fn merge_replaces_the_updated_keys() {
+        let stored = "# Site A\nnominal_voltage=120\nmode=current_only\n";
+        assert_eq!(
+            merge(stored, "mode=standard\nshard_cache=4096"),
+            "# Site A\nnominal_voltage=120\nmode=standard\nshard_cache=4096\n"
+        );
+        assert_eq!(merge("", "mode=standard"), "mode=standard\n");
+    }
+}
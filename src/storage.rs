@@ -0,0 +1,6209 @@
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use embedded_svc::io::Write as SvcWrite;
+use esp_idf_svc::http::server::EspHttpResponseWrite;
+
+use crate::ct::{
+    Accumulator, BootReport, CTReading, Calibration, CalibrationLoad, ClippedPolicy, LifetimeStats,
+    ReadingFlags, ShardInfo, ShardSummary, Tariff, TouSchedule, TouTotals, CT,
+};
+#[cfg(feature = "fault-injection")]
+use crate::fault::{FaultInjector, FaultyFs};
+use crate::sampling::Sampler;
+use crate::{
+    now, set_clock_restored, set_system_time, utils::*, ACCESS_TOKEN_SIZE, AC_PHASE,
+    CALIBRATION_SIZE, CALIBRATION_VERSION, CLIPPED_SENTINEL, CT_READING_SIZE, ENERGY_TOTAL_SIZE,
+    INTEGRITY_SCAN, LEGACY_CALIBRATION_SIZE, LEGACY_ENERGY_TOTAL_SIZE, LEGACY_RECORD_SIZE,
+    LIFETIME_STATS_SIZE, LOW_SPACE_POLICY, LOW_SPACE_SAVE_INTERVAL, LOW_SPACE_USED,
+    MAX_BUFFERED_SAVES, MAX_SHARD_SIZE, MAX_TIME_STORAGE_SIZE, MIN_SAVE_INTERVAL, PEAK_DEMAND_SIZE,
+    SEQUENCE_INDEX_ENTRY_SIZE, SEQUENCE_INDEX_MAX_ENTRIES, SHARD_FORMAT_VERSION, SHARD_HEADER_SIZE,
+    SHARD_NAME_WIDTH, SHARD_RECOVERY, STATE_SIZE, STATE_VERSION, STATS_STORE_INTERVAL,
+    STORAGE_MOUNTS, STORAGE_RETRIES, STORAGE_RETRY_DELAY, TOU_TOTALS_SIZE, WRITE_BATCH,
+};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+/// How a file is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OpenMode {
+    /// Read only, the file must exist.
+    Read,
+    /// Read and write, the file is created if missing and its contents are kept.
+    ReadWrite,
+    /// Writes go to the end of the file, the file is created if missing.
+    Append,
+    /// Write only, the file is created if missing and emptied otherwise.
+    Truncate,
+}
+
+/// When CTStorage syncs the shard it appended a save to.
+///
+/// Syncing makes sure the save is on flash before save_to_storage returns, and a failure to write
+/// it fails the save instead of going unnoticed. The price is a metadata commit per sync, which
+/// costs time and wears the flash. littlefs commits a file when it is closed too, which happens
+/// after every save, so there the extra wear is small. Filesystems that cache writes beyond the
+/// close lose all unsynced saves on a power loss, so there the policy is the trade off between the
+/// number of saves at risk and the flash wear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SyncPolicy {
+    /// Only flush, leave the commit to the filesystem.
+    Never,
+    /// Sync after every save.
+    EverySave,
+    /// Sync after every n-th save, at most n - 1 saves are at risk.
+    EveryNSaves(u32),
+}
+
+/// What CTStorage does once the records not synced to a backend yet exceed their limit, see
+/// CTStorage::set_unsynced_limit.
+///
+/// Both bound what is lost if the device dies before the next sync and how much flash the backlog
+/// takes. They differ in which records are given up when the connection stays down for too long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnsyncedPolicy {
+    /// Delete the oldest shards, the newest records are kept.
+    Ring,
+    /// Stop saving, the oldest records are kept and new ones dropped until the backend catches up.
+    BackPressure,
+}
+
+/// What CTStorage does when the filesystem is nearly full, see CTStorage::set_low_space_policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LowSpacePolicy {
+    /// Keep saving as usual until writes fail, then buffer in RAM.
+    Off,
+    /// Above LOW_SPACE_USED of the space, save at most every LOW_SPACE_SAVE_INTERVAL, coalescing
+    /// the saves in between like set_min_save_interval, each of them counting the same, see
+    /// Accumulator. With the default of an hour the records become hourly averages, which
+    /// stretches the remaining space by the number of saves per hour. Back to normal once the
+    /// usage drops below LOW_SPACE_USED again.
+    Coarsen,
+}
+
+/// What CTStorage does at boot when the newest shard ends with an incomplete record, see
+/// CTStorage::set_shard_recovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ShardRecovery {
+    /// Keep appending to the shard, the records after the incomplete one are read misaligned.
+    Off,
+    /// Cut the incomplete record off, keeping the complete records before it. Rolls to a new shard
+    /// if the shard can't be rewritten.
+    Truncate,
+    /// Leave the shard as it is and append to a new shard.
+    Roll,
+}
+
+/// An open file of a Filesystem.
+pub(crate) trait StorageFile: Read + Write + Seek {
+    /// Size of the file in bytes.
+    fn size(&self) -> io::Result<u64>;
+    /// Make sure everything written so far is on flash.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// Read the next record of a shard into `buf`, false at the end of the file.
+///
+/// An incomplete record at the end counts as the end, as on a shard cut off by a power loss. Any
+/// other error is returned, rather than taken for the end of the records.
+pub(crate) fn read_record<R: Read + ?Sized>(file: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// The file operations CTStorage needs.
+///
+/// CTStorage only goes through this trait, so it can run on the flash of the device and on an
+/// in-memory filesystem anywhere else.
+pub(crate) trait Filesystem: Send {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>>;
+    fn file_size(&self, path: &str) -> io::Result<u64>;
+    /// Names of the entries of a directory.
+    fn read_dir(&self, path: &str) -> io::Result<Vec<String>>;
+    fn create_dir(&self, path: &str) -> io::Result<()>;
+    fn remove_file(&self, path: &str) -> io::Result<()>;
+    fn remove_dir_all(&self, path: &str) -> io::Result<()>;
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// (total, used) bytes of the filesystem mounted at `root`, Unsupported if it can't tell.
+    fn space(&self, _root: &str) -> io::Result<(u64, u64)> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "filesystem space unknown",
+        ))
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.open(path, OpenMode::Read)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Replace the file at `path` with `data` atomically.
+    ///
+    /// The data is written and synced to `<path>.tmp` first, which is then renamed over `path`.
+    /// littlefs renames are atomic, so after a power loss the file holds either the complete old
+    /// contents or the complete new contents, never a partial write. A stale `.tmp` left behind by
+    /// an interrupted write is simply overwritten next time.
+    fn write_atomic(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        {
+            let mut file = self.open(&tmp_path, OpenMode::Truncate)?;
+            file.write_all(data)?;
+            file.sync()?;
+        }
+        self.rename(&tmp_path, path)
+    }
+}
+
+/// Filesystem of a flash partition the storage can be on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsKind {
+    LittleFs,
+    Spiffs,
+}
+
+/// A flash partition the storage can be on and the directory it is mounted at, see
+/// STORAGE_MOUNTS.
+#[derive(Debug)]
+pub struct StorageMount {
+    pub root: &'static str,
+    /// Label in the partition table.
+    pub partition: &'static str,
+    pub kind: FsKind,
+}
+
+/// The partitions of STORAGE_MOUNTS, mounted into the VFS and accessed through std::fs.
+pub(crate) struct FlashFs;
+
+impl StorageFile for std::fs::File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl Filesystem for FlashFs {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let mut options = std::fs::OpenOptions::new();
+        match mode {
+            OpenMode::Read => options.read(true),
+            OpenMode::ReadWrite => options.read(true).write(true).create(true).truncate(false),
+            OpenMode::Append => options.create(true).append(true),
+            OpenMode::Truncate => options.write(true).create(true).truncate(true),
+        };
+        Ok(Box::new(options.open(path)?))
+    }
+
+    fn file_size(&self, path: &str) -> io::Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        Ok(names)
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        std::fs::create_dir(path)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn space(&self, root: &str) -> io::Result<(u64, u64)> {
+        // The drivers only tell by the partition.
+        let mount = STORAGE_MOUNTS
+            .iter()
+            .find(|mount| mount.root == root)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("no partition is mounted at {}", root),
+                )
+            })?;
+        let label = std::ffi::CString::new(mount.partition)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let (mut total, mut used) = (0, 0);
+        let err = unsafe {
+            match mount.kind {
+                FsKind::LittleFs => {
+                    esp_idf_sys::esp_littlefs_info(label.as_ptr(), &mut total, &mut used)
+                }
+                FsKind::Spiffs => {
+                    esp_idf_sys::esp_spiffs_info(label.as_ptr(), &mut total, &mut used)
+                }
+            }
+        };
+        if err != esp_idf_sys::ESP_OK {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{:?} info of {} failed: {}",
+                    mount.kind, mount.partition, err
+                ),
+            ));
+        }
+        Ok((total as u64, used as u64))
+    }
+}
+
+/// Hits and misses of the shard cache, see CTStorage::set_shard_cache.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    /// Shard reads served from RAM.
+    pub hits: u64,
+    /// Shard reads that went to the filesystem.
+    pub misses: u64,
+    /// Shards dropped to stay within the budget.
+    pub evictions: u64,
+    /// Bytes cached now.
+    pub bytes: usize,
+}
+
+/// The contents of recently read shards, least recently used first, within a byte budget.
+#[derive(Default)]
+pub(crate) struct ShardCache {
+    budget: usize,
+    shards: VecDeque<(i32, Arc<[u8]>)>,
+    stats: CacheStats,
+}
+
+impl ShardCache {
+    /// Cache up to `budget` bytes, 0 turns the cache off. Evicts down to the new budget.
+    pub(crate) fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict(0);
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// The cached contents of `shard_id`, counting a hit or a miss.
+    pub(crate) fn get(&mut self, shard_id: i32) -> Option<Arc<[u8]>> {
+        match self.shards.iter().position(|(id, _)| *id == shard_id) {
+            Some(i) => {
+                self.stats.hits += 1;
+                let entry = self.shards.remove(i)?;
+                let data = entry.1.clone();
+                self.shards.push_back(entry);
+                Some(data)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache the contents of `shard_id`, evicting the least recently used shards to make room.
+    /// Shards larger than the whole budget are not cached.
+    pub(crate) fn insert(&mut self, shard_id: i32, data: Arc<[u8]>) {
+        self.remove(shard_id);
+        if data.len() > self.budget {
+            return;
+        }
+        self.evict(data.len());
+        self.stats.bytes += data.len();
+        self.shards.push_back((shard_id, data));
+    }
+
+    /// Forget `shard_id`, after it was written or deleted.
+    pub(crate) fn remove(&mut self, shard_id: i32) {
+        if let Some(i) = self.shards.iter().position(|(id, _)| *id == shard_id) {
+            if let Some((_, data)) = self.shards.remove(i) {
+                self.stats.bytes -= data.len();
+            }
+        }
+    }
+
+    /// Forget every shard.
+    pub(crate) fn clear(&mut self) {
+        self.shards.clear();
+        self.stats.bytes = 0;
+    }
+
+    // Evict until `room` more bytes fit into the budget.
+    fn evict(&mut self, room: usize) {
+        while self.stats.bytes + room > self.budget {
+            match self.shards.pop_front() {
+                Some((_, data)) => {
+                    self.stats.bytes -= data.len();
+                    self.stats.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// A read only file over the cached contents of a shard, see ShardCache.
+pub(crate) struct CachedFile {
+    data: io::Cursor<Arc<[u8]>>,
+}
+
+impl CachedFile {
+    pub(crate) fn new(data: Arc<[u8]>) -> Self {
+        CachedFile {
+            data: io::Cursor::new(data),
+        }
+    }
+}
+
+impl Read for CachedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Write for CachedFile {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "cached shards are read only",
+        ))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for CachedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl StorageFile for CachedFile {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.get_ref().len() as u64)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The metrics stored in each record of the shards, see CTStorage::set_record_schema.
+///
+/// Every record has the CT id, the timestamp and the sequence number. The metrics follow the id in
+/// the order of the fields here, the ones left out take no space. Left out metrics read back as 0.
+/// The uptime follows the timestamp, and the ReadingFlags byte and the quality byte the sequence
+/// number. Records without them, as stored before they were added, read back an uptime of 0, no
+/// flags and no quality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordSchema {
+    pub real_power: bool,
+    pub apparent_power: bool,
+    pub i_rms: bool,
+    pub v_rms: bool,
+    pub kwh: bool,
+    pub uptime: bool,
+    pub flags: bool,
+    pub quality: bool,
+}
+
+impl Default for RecordSchema {
+    /// All metrics, the uptime, the flags and the quality, records of CT_READING_SIZE.
+    fn default() -> Self {
+        RecordSchema::from_bits(0b1111_1111)
+    }
+}
+
+impl RecordSchema {
+    fn fields(&self) -> [bool; 5] {
+        [
+            self.real_power,
+            self.apparent_power,
+            self.i_rms,
+            self.v_rms,
+            self.kwh,
+        ]
+    }
+
+    // One bit per metric, real_power in bit 0, the uptime in bit 5, the flags in bit 6 and the
+    // quality in bit 7, as stored in the shard header.
+    fn to_bits(self) -> u8 {
+        let bits = self
+            .fields()
+            .iter()
+            .enumerate()
+            .filter(|(_, &stored)| stored)
+            .fold(0, |bits, (i, _)| bits | 1 << i);
+        bits | (self.uptime as u8) << 5 | (self.flags as u8) << 6 | (self.quality as u8) << 7
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        RecordSchema {
+            real_power: bits & 1 != 0,
+            apparent_power: bits & 1 << 1 != 0,
+            i_rms: bits & 1 << 2 != 0,
+            v_rms: bits & 1 << 3 != 0,
+            kwh: bits & 1 << 4 != 0,
+            uptime: bits & 1 << 5 != 0,
+            flags: bits & 1 << 6 != 0,
+            quality: bits & 1 << 7 != 0,
+        }
+    }
+
+    /// Size of a record in bytes, CT_READING_SIZE with all metrics, the uptime, the flags and the
+    /// quality.
+    pub(crate) fn record_size(&self) -> usize {
+        let metrics = self.fields().iter().filter(|&&stored| stored).count();
+        let uptime = if self.uptime {
+            0
+        } else {
+            std::mem::size_of::<u64>()
+        };
+        let flags = if self.flags { 0 } else { 1 };
+        let quality = if self.quality { 0 } else { 1 };
+        CT_READING_SIZE - (5 - metrics) * std::mem::size_of::<f32>() - uptime - flags - quality
+    }
+}
+
+/// Size in bytes of a stored record with every metric, the uptime and the flags, for off-device
+/// parsers of the shards.
+///
+/// Shards with a reduced RecordSchema store RecordSchema::record_size bytes per record instead,
+/// the schema is in the header of every shard, see shard_header_size.
+pub fn record_size() -> usize {
+    CT_READING_SIZE
+}
+
+/// Size in bytes of the header every shard starts with, before its records.
+///
+/// The header is the byte b'S', SHARD_FORMAT_VERSION, the byte order of the records (bit 0 set for
+/// big endian) and the RecordSchema bits of the records, so a reader of send_readings_shards can
+/// split each shard without knowing how the device is configured.
+pub fn shard_header_size() -> usize {
+    SHARD_HEADER_SIZE
+}
+
+/// Size in bytes a shard grows to before the next save starts a new one.
+///
+/// A save is never split, so a shard can end up larger by up to the records of one save.
+pub fn max_shard_size() -> u64 {
+    MAX_SHARD_SIZE
+}
+
+/// Number of records with every metric in a full shard.
+///
+/// Saves of AC_PHASE records are appended while there is room for at least one more record below
+/// max_shard_size, so this is a multiple of AC_PHASE.
+pub fn records_per_shard() -> usize {
+    let (record, max) = (record_size() as u64, max_shard_size());
+    let save = record * AC_PHASE as u64;
+    let saves = max.saturating_sub(SHARD_HEADER_SIZE as u64 + record) / save + 1;
+    saves as usize * AC_PHASE
+}
+
+// The utils helpers that write and read a value in one byte order.
+type AddFn<T> = fn(&T, &mut [u8], &usize) -> anyhow::Result<usize>;
+type ReadFn<T> = fn(&[u8], &mut usize) -> anyhow::Result<T>;
+
+// Quality byte of a record whose reading has no quality score.
+const NO_QUALITY: u8 = u8::MAX;
+
+// First byte of a shard header. Never the first byte of a record from before the headers, which
+// is that of a CT id from 1 to 3.
+const SHARD_MAGIC: u8 = b'S';
+
+// A save kept in RAM until it is written, see CTStorage::save_to_storage.
+struct PendingSave {
+    sequence: u32,
+    // The encoded records.
+    records: Vec<u8>,
+    // The readings of the records, counted into the lifetime statistics once written.
+    readings: Vec<CTReading>,
+}
+
+// Held for the duration of a save_to_storage, shutdown, recompute_energy, import_state,
+// reset_storage or the flush on drop, see CTStorage::saving.
+struct SaveGuard {
+    saving: Arc<AtomicBool>,
+}
+
+impl SaveGuard {
+    // Fails with WouldBlock if another write is running.
+    fn acquire(saving: &Arc<AtomicBool>) -> std::io::Result<Self> {
+        if saving
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "another save to storage is running",
+            ));
+        }
+        Ok(SaveGuard {
+            saving: saving.clone(),
+        })
+    }
+
+    // Waits for the other write to finish, for drop, which can't fail.
+    fn wait(saving: &Arc<AtomicBool>) -> Self {
+        loop {
+            if let Ok(guard) = SaveGuard::acquire(saving) {
+                return guard;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+}
+
+/// Whether `err` is that of a write to the storage that didn't run because another one was
+/// running, see CTStorage::save_to_storage. Nothing was written, retry it later.
+pub(crate) fn is_save_busy(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .map_or(false, |err| err.kind() == std::io::ErrorKind::WouldBlock)
+}
+
+impl Drop for SaveGuard {
+    fn drop(&mut self) {
+        self.saving.store(false, Ordering::Release);
+    }
+}
+
+pub struct CTStorage {
+    pub readings_shard_counter: i32,
+    pub readings_shards: HashSet<i32>,
+    // Sequence number of the last save. Every record of a save carries it.
+    sequence: u32,
+    fs: Box<dyn Filesystem>,
+    // Whether readings can be written. While not, saves are kept in `buffered`.
+    available: bool,
+    // The saves not written yet, oldest first.
+    buffered: VecDeque<PendingSave>,
+    // Saves closer together than this are coalesced into one.
+    min_save_interval: std::time::Duration,
+    last_save: Option<std::time::Instant>,
+    // Readings of the saves coalesced since the last one, by CT id, each save counting the same.
+    coalesced: Vec<(u16, Accumulator)>,
+    // Byte order of the records in the shards.
+    byte_order: ByteOrder,
+    // Byte order and schema of new storage, the stored ones win until reset_storage.
+    configured_format: (ByteOrder, RecordSchema),
+    stats: LifetimeStats,
+    // Sequence number of the last save counted into `stats`, see load_lifetime_stats.
+    stats_sequence: u32,
+    // Saves counted into `stats` since it was last stored.
+    unstored_stats: u32,
+    sync_policy: SyncPolicy,
+    // Saves appended since the last sync, see SyncPolicy::EveryNSaves.
+    saves_since_sync: u32,
+    tou_schedule: TouSchedule,
+    tou_totals: TouTotals,
+    // Sequence number of the last save counted into `tou_totals`.
+    tou_sequence: u32,
+    // Metrics in the records of the shards.
+    schema: RecordSchema,
+    // Sequence number of the last save a backend has synced, see mark_synced.
+    synced: u32,
+    // Unsynced bytes beyond which the policy kicks in, None for no limit.
+    unsynced_limit: Option<(u64, UnsyncedPolicy)>,
+    // What the loads at boot found, see boot_report.
+    boot: BootReport,
+    clipped_policy: ClippedPolicy,
+    // Whether new shards get zero-padded names, see set_pad_shard_names.
+    pad_shard_names: bool,
+    // Shards named in the other style than pad_shard_names.
+    other_style_shards: HashSet<i32>,
+    low_space_policy: LowSpacePolicy,
+    // Whether the filesystem was nearly full at the last save, see LowSpacePolicy::Coarsen.
+    low_space: bool,
+    // Contents of recently read shards, see set_shard_cache. Reads only borrow the storage.
+    cache: RefCell<ShardCache>,
+    shard_recovery: ShardRecovery,
+    // Whether boot_report reads every record, see set_integrity_scan.
+    integrity_scan: bool,
+    // Bytes of saves kept in `buffered` before they are written, see set_write_batching.
+    write_batch: Option<usize>,
+    // Directory the filesystem is mounted at, "<root>" in the docs, see detect_root.
+    root: String,
+    // Whether a save_to_storage or another write to the storage files is running, see SaveGuard.
+    saving: Arc<AtomicBool>,
+}
+
+impl CTStorage {
+    /// Storage on the flash that writes new shards in `byte_order`, see byte_order. With
+    /// fault-injection its writes fail at the storage_failure_rate of `faults`, see FaultyFs.
+    pub(crate) fn new(
+        byte_order: ByteOrder,
+        #[cfg(feature = "fault-injection")] faults: &FaultInjector,
+    ) -> Self {
+        #[cfg(feature = "fault-injection")]
+        let storage = CTStorage::with_fs(Box::new(FaultyFs::new(FlashFs, faults)), byte_order);
+        #[cfg(not(feature = "fault-injection"))]
+        let storage = CTStorage::with_fs(Box::new(FlashFs), byte_order);
+        storage
+    }
+
+    /// Storage on the given filesystem instead of the flash, e.g. one that injects faults.
+    pub(crate) fn with_fs(fs: Box<dyn Filesystem>, byte_order: ByteOrder) -> Self {
+        CTStorage {
+            readings_shard_counter: 1,
+            readings_shards: HashSet::new(),
+            sequence: 0,
+            fs,
+            available: true,
+            buffered: VecDeque::new(),
+            min_save_interval: MIN_SAVE_INTERVAL,
+            last_save: None,
+            coalesced: Vec::new(),
+            byte_order,
+            configured_format: (byte_order, RecordSchema::default()),
+            stats: LifetimeStats::default(),
+            stats_sequence: 0,
+            unstored_stats: 0,
+            sync_policy: SyncPolicy::Never,
+            saves_since_sync: 0,
+            tou_schedule: TouSchedule::default(),
+            tou_totals: TouTotals::default(),
+            tou_sequence: 0,
+            schema: RecordSchema::default(),
+            synced: 0,
+            unsynced_limit: None,
+            boot: BootReport::default(),
+            clipped_policy: ClippedPolicy::Store,
+            pad_shard_names: false,
+            other_style_shards: HashSet::new(),
+            low_space_policy: LOW_SPACE_POLICY,
+            low_space: false,
+            cache: RefCell::new(ShardCache::default()),
+            shard_recovery: SHARD_RECOVERY,
+            integrity_scan: INTEGRITY_SCAN,
+            write_batch: WRITE_BATCH,
+            root: STORAGE_MOUNTS[0].root.to_string(),
+            saving: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Use the first of `candidates` that is writable as the directory of all files, e.g. "/spiffs"
+    /// on a board that mounts its partition there. Defaults to the first of STORAGE_MOUNTS.
+    ///
+    /// Each candidate is probed by writing and removing a file, as a root can hold the files of
+    /// an earlier boot and still be mounted read only. Call it before anything is loaded. If none
+    /// is writable the root is left unchanged and an error lists the candidates, the storage then
+    /// turns unavailable as on a failed mount.
+    pub(crate) fn detect_root(&mut self, candidates: &[&str]) -> anyhow::Result<()> {
+        for &root in candidates {
+            let root = root.trim_end_matches('/');
+            if self.fs.read_dir(root).is_err() {
+                continue;
+            }
+            let probe = format!("{}/probe", root);
+            let writable = self.fs.write_atomic(&probe, &[]).is_ok();
+            let _ = self.fs.remove_file(&probe);
+            if writable {
+                info!("Storage root at {}.", root);
+                self.set_root(root);
+                return Ok(());
+            }
+        }
+        anyhow::bail!(
+            "no writable storage root among {:?}, is the partition mounted?",
+            candidates
+        )
+    }
+
+    /// Keep all files under `root` instead of detecting it, see detect_root.
+    pub(crate) fn set_root(&mut self, root: &str) {
+        self.root = root.trim_end_matches('/').to_string();
+        self.cache.get_mut().clear();
+    }
+
+    pub(crate) fn root(&self) -> &str {
+        &self.root
+    }
+
+    // Path of the file `name` under the root.
+    fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.root, name)
+    }
+
+    //Reset everything and clear all files
+    pub(crate) fn reset_storage(&mut self) -> anyhow::Result<()> {
+        let _writing = SaveGuard::acquire(&self.saving)?;
+        // The shards no longer hold the last sequence number, see load_sequence.
+        self.fs
+            .write_atomic(&self.path("sequence"), &self.sequence.to_le_bytes())?;
+        self.fs.remove_file(&self.path("powerloss_log"))?;
+        self.fs.remove_dir_all(&self.path("ct_readings"))?;
+        self.cache.get_mut().clear();
+        // Left by an interrupted migration, see migrate_legacy_shards.
+        let _ = self.fs.remove_file(&self.path("format"));
+        let _ = self.fs.remove_file(&self.path("sequence_index"));
+        info!("Deleted Everything.");
+        self.fs
+            .open(&self.path("powerloss_log"), OpenMode::Append)?;
+        self.readings_shards = HashSet::new();
+        self.other_style_shards = HashSet::new();
+        self.readings_shard_counter = 1;
+        let (byte_order, schema) = self.configured_format;
+        self.byte_order = byte_order;
+        self.schema = schema;
+        self.find_newest_readings_shard_num()?;
+        Ok(())
+    }
+
+    // Whenever the esp boots, it restores the previously set RTC and stores that RTC in a log.
+    pub(crate) fn log_powerloss(&mut self) -> anyhow::Result<()> {
+        if let Ok(mut file) = self.fs.open(&self.path("powerloss_log"), OpenMode::Append) {
+            file.seek(SeekFrom::End(0))?;
+            file.write_all(&now().as_millis().to_le_bytes())?;
+            info!("logged powerloss at {}", now().as_millis());
+        }
+        Ok(())
+    }
+
+    // Dump the powerloss log into the given writer and after that delete the log.
+    pub(crate) fn send_powerloss_log(
+        &mut self,
+        writer: &mut EspHttpResponseWrite,
+    ) -> anyhow::Result<()> {
+        // open the log file and send data. If no log is available an empty response is sent.
+        if let Ok(mut file) = self.fs.open(&self.path("powerloss_log"), OpenMode::Read) {
+            let mut buf = [0_u8; std::mem::size_of::<u128>() * 5];
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                writer.write_all(&buf)?;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Name new shards with SHARD_NAME_WIDTH digits, e.g. "0000000012", instead of "12". Off by
+    /// default.
+    ///
+    /// Padded names sort in order as strings, so file listings and tools that process the shards
+    /// in name order see them oldest first. Set it before find_newest_readings_shard_num. Shards
+    /// that already exist keep their names and are still found, the shard ids stay the same either
+    /// way. To give them the new names too, call migrate_shard_names after
+    /// find_newest_readings_shard_num, which the boot does with PAD_SHARD_NAMES.
+    pub(crate) fn set_pad_shard_names(&mut self, pad: bool) {
+        self.pad_shard_names = pad;
+    }
+
+    /// Rename the shards named in the other style than set_pad_shard_names.
+    ///
+    /// Each rename is atomic, so a power loss in between leaves a mix of styles, which works just
+    /// the same, and calling it again finishes the job. Returns the number of renamed shards.
+    pub(crate) fn migrate_shard_names(&mut self) -> anyhow::Result<usize> {
+        let mut shard_ids = self
+            .other_style_shards
+            .iter()
+            .copied()
+            .collect::<Vec<i32>>();
+        shard_ids.sort_unstable();
+        for &shard_id in &shard_ids {
+            let old = self.shard_path(shard_id);
+            self.other_style_shards.remove(&shard_id);
+            let new = self.shard_path(shard_id);
+            if let Err(err) = self.fs.rename(&old, &new) {
+                self.other_style_shards.insert(shard_id);
+                return Err(err.into());
+            }
+        }
+        if !shard_ids.is_empty() {
+            info!("Renamed {} shards.", shard_ids.len());
+        }
+        Ok(shard_ids.len())
+    }
+
+    // File name of a new shard, see set_pad_shard_names.
+    fn shard_name(&self, shard_id: i32) -> String {
+        if self.pad_shard_names {
+            format!("{:0width$}", shard_id, width = SHARD_NAME_WIDTH)
+        } else {
+            shard_id.to_string()
+        }
+    }
+
+    // Path of a shard, by whichever name style it has.
+    fn shard_path(&self, shard_id: i32) -> String {
+        if self.other_style_shards.contains(&shard_id) {
+            let name = if self.pad_shard_names {
+                shard_id.to_string()
+            } else {
+                format!("{:0width$}", shard_id, width = SHARD_NAME_WIDTH)
+            };
+            format!("{}/ct_readings/{}", self.root, name)
+        } else {
+            format!("{}/ct_readings/{}", self.root, self.shard_name(shard_id))
+        }
+    }
+
+    /// Find the newest readings shard id
+    ///
+    /// under "<root>/ct_readings" files are saved with a number as their filename.
+    /// here we iterate through all of them and find the newest file (the one with higher number as
+    /// its filename). This is the file that we will be appending new data to.
+    /// If the directory can't be created, the storage is marked unavailable instead of failing, see
+    /// storage_available.
+    pub(crate) fn find_newest_readings_shard_num(&mut self) -> anyhow::Result<()> {
+        if !self.open_readings_dir(STORAGE_RETRIES) {
+            warn!("Readings directory unavailable, buffering readings in RAM.");
+            self.available = false;
+            return Ok(());
+        }
+        self.migrate_legacy_shards()?;
+        self.load_format()?;
+        let mut max_num = 1;
+        for name in self.fs.read_dir(&self.path("ct_readings"))? {
+            info!("Shard: {:?}", name);
+            // Parses padded and unpadded names alike.
+            let num: i32 = name.parse()?;
+            max_num = i32::max(max_num, num);
+            self.readings_shards.insert(num);
+            if name != self.shard_name(num) {
+                self.other_style_shards.insert(num);
+            }
+        }
+        self.readings_shard_counter = max_num;
+
+        // if this the first ever shard, we must create it
+        if self.readings_shard_counter == 1 {
+            if let Err(err) = self.create_shard(self.readings_shard_counter) {
+                warn!(
+                    "Can't create the first shard, buffering readings in RAM: {}",
+                    err
+                );
+                self.available = false;
+                return Ok(());
+            }
+            self.readings_shards.insert(self.readings_shard_counter);
+            info!("Made sure the first shard is created.");
+        }
+        self.recover_newest_shard();
+        self.available = true;
+        info!("Next shard will be: {:?}", self.readings_shard_counter);
+        Ok(())
+    }
+
+    // Create shard `shard_id` with its header, see shard_header. A shard that is not empty is left
+    // as it is.
+    fn create_shard(&self, shard_id: i32) -> std::io::Result<()> {
+        let mut file = self.fs.open(&self.shard_path(shard_id), OpenMode::Append)?;
+        if file.size()? == 0 {
+            file.write_all(&CTStorage::shard_header(self.byte_order, self.schema))?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    // Header of a shard of records in `byte_order` and `schema`, see shard_header_size.
+    fn shard_header(byte_order: ByteOrder, schema: RecordSchema) -> [u8; SHARD_HEADER_SIZE] {
+        let order = match byte_order {
+            ByteOrder::Little => 0_u8,
+            ByteOrder::Big => 1_u8,
+        };
+        [SHARD_MAGIC, SHARD_FORMAT_VERSION, order, schema.to_bits()]
+    }
+
+    // Byte order and schema in the header of the shard at `path`, None if it has no header, like
+    // an empty shard or one from before the headers.
+    fn read_shard_header(&self, path: &str) -> anyhow::Result<Option<(ByteOrder, RecordSchema)>> {
+        let mut file = self.fs.open(path, OpenMode::Read)?;
+        let mut header = [0_u8; SHARD_HEADER_SIZE];
+        if !read_record(&mut file, &mut header)? {
+            return Ok(None);
+        }
+        match header {
+            [SHARD_MAGIC, SHARD_FORMAT_VERSION, order, bits] => {
+                let byte_order = if order & 1 == 1 {
+                    ByteOrder::Big
+                } else {
+                    ByteOrder::Little
+                };
+                Ok(Some((byte_order, RecordSchema::from_bits(bits))))
+            }
+            [SHARD_MAGIC, version, ..] => {
+                anyhow::bail!("{} has an unknown header version {}", path, version)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // The shards as (id, name, size), oldest first.
+    fn shard_files(&self) -> anyhow::Result<Vec<(i32, String, usize)>> {
+        let dir = self.path("ct_readings");
+        let mut shards = Vec::new();
+        for name in self.fs.read_dir(&dir)? {
+            if let Ok(id) = name.parse::<i32>() {
+                let size = self.fs.file_size(&format!("{}/{}", dir, name))? as usize;
+                shards.push((id, name, size));
+            }
+        }
+        shards.sort_unstable();
+        Ok(shards)
+    }
+
+    // Shards from before the shard headers come in two kinds. Those from before the format file
+    // hold little endian records of every metric, without the uptime and flags: LEGACY_RECORD_SIZE
+    // bytes before the sequence numbers and 4 more with them. Rewrite them in the current format,
+    // numbering the records without a sequence number by save. The rewritten shards go to
+    // "<shard>.new" first and are renamed over the shards once the format file is stored, so after
+    // a power loss halfway the migration either starts over from the untouched shards or only the
+    // renames are left, and are finished at the next boot. Those from the time of the format file
+    // only lack the header, see add_shard_headers.
+    fn migrate_legacy_shards(&mut self) -> anyhow::Result<()> {
+        let dir = self.path("ct_readings");
+        let names = self.fs.read_dir(&dir)?;
+        let format = self
+            .fs
+            .read(&self.path("format"))
+            .ok()
+            .filter(|format| !format.is_empty());
+        for name in names.iter().filter_map(|name| name.strip_suffix(".new")) {
+            let new = format!("{}/{}.new", dir, name);
+            if format.is_some() {
+                self.fs.rename(&new, &format!("{}/{}", dir, name))?;
+            } else {
+                self.fs.remove_file(&new)?;
+            }
+        }
+        if let Some(format) = format {
+            return self.add_shard_headers(&format);
+        }
+
+        let shards = self.shard_files()?;
+        match shards.iter().rev().find(|&&(_, _, size)| size > 0) {
+            None => return Ok(()),
+            Some((_, name, _)) => {
+                if self
+                    .read_shard_header(&format!("{}/{}", dir, name))?
+                    .is_some()
+                {
+                    return Ok(());
+                }
+            }
+        }
+        let with_sequence = RecordSchema::from_bits(0b1_1111);
+        let legacy_size = match [with_sequence.record_size(), LEGACY_RECORD_SIZE]
+            .iter()
+            .copied()
+            .find(|&size| shards.iter().all(|&(_, _, shard)| shard % size == 0))
+        {
+            Some(size) => size,
+            None => {
+                warn!(
+                    "Shards from before the format file of unknown record size, left as they are."
+                );
+                return Ok(());
+            }
+        };
+        info!(
+            "Migrating {} shards of {} byte records to the current format.",
+            shards.len(),
+            legacy_size
+        );
+        let mut record = [0_u8; CT_READING_SIZE];
+        let mut index = 0;
+        for (_, name, _) in &shards {
+            let mut buf = CTStorage::shard_header(self.byte_order, self.schema).to_vec();
+            for legacy in self
+                .fs
+                .read(&format!("{}/{}", dir, name))?
+                .chunks_exact(legacy_size)
+            {
+                record[..legacy.len()].copy_from_slice(legacy);
+                if legacy_size == LEGACY_RECORD_SIZE {
+                    let sequence = (index / AC_PHASE) as u32 + 1;
+                    record[legacy_size..legacy_size + 4].copy_from_slice(&sequence.to_le_bytes());
+                }
+                index += 1;
+                let (id, reading) = CTStorage::decode_record(
+                    &record[..with_sequence.record_size()],
+                    ByteOrder::Little,
+                    with_sequence,
+                )?;
+                self.sequence = u32::max(self.sequence, reading.sequence);
+                self.encode(id, &reading, reading.sequence, &mut buf)?;
+            }
+            self.fs
+                .write_atomic(&format!("{}/{}.new", dir, name), &buf)?;
+        }
+        self.fs
+            .write_atomic(&self.path("sequence"), &self.sequence.to_le_bytes())?;
+        self.store_format()?;
+        for (_, name, _) in &shards {
+            self.fs.rename(
+                &format!("{}/{}.new", dir, name),
+                &format!("{}/{}", dir, name),
+            )?;
+        }
+        self.fs.remove_file(&self.path("format"))?;
+        Ok(())
+    }
+
+    // Give the shards without a header the one of `format`, the contents of the format file that
+    // held the byte order and schema of every shard before the headers. Bit 0 of its first byte is
+    // set for big endian, the second byte holds the RecordSchema bits. Formats from before the
+    // schema have only the first byte and records with all metrics but no uptime or flags. Each
+    // shard is rewritten atomically and the format file is removed last, so after a power loss
+    // the next boot carries on with the shards that still lack the header.
+    fn add_shard_headers(&mut self, format: &[u8]) -> anyhow::Result<()> {
+        let byte_order = if format[0] & 1 == 1 {
+            ByteOrder::Big
+        } else {
+            ByteOrder::Little
+        };
+        let schema = RecordSchema::from_bits(format.get(1).map_or(0b1_1111, |&bits| bits));
+        let dir = self.path("ct_readings");
+        for (_, name, size) in self.shard_files()? {
+            let path = format!("{}/{}", dir, name);
+            if size == 0 || self.read_shard_header(&path)?.is_some() {
+                continue;
+            }
+            let mut buf = CTStorage::shard_header(byte_order, schema).to_vec();
+            buf.extend_from_slice(&self.fs.read(&path)?);
+            self.fs.write_atomic(&path, &buf)?;
+            info!("Added the header to shard {}.", name);
+        }
+        self.fs.remove_file(&self.path("format"))?;
+        Ok(())
+    }
+
+    // Read the byte order and schema of the stored records from the header of the newest shard
+    // that has one. Without any the storage is new, or was reset, and keeps the configured ones.
+    fn load_format(&mut self) -> anyhow::Result<()> {
+        let dir = self.path("ct_readings");
+        for (_, name, _) in self.shard_files()?.iter().rev() {
+            let (stored, schema) = match self.read_shard_header(&format!("{}/{}", dir, name))? {
+                Some(format) => format,
+                None => continue,
+            };
+            if stored != self.byte_order {
+                warn!(
+                    "Stored readings are {:?} endian, keeping that until the storage is reset.",
+                    stored
+                );
+                self.byte_order = stored;
+            }
+            if schema != self.schema {
+                warn!(
+                    "Stored readings have the metrics {:?}, keeping them until the storage is \
+                     reset.",
+                    schema
+                );
+                self.schema = schema;
+            }
+            break;
+        }
+        Ok(())
+    }
+
+    // Store the byte order and schema of new records in the format file, which marks a finished
+    // rewrite of the shards in migrate_legacy_shards.
+    fn store_format(&self) -> anyhow::Result<()> {
+        let format = match self.byte_order {
+            ByteOrder::Little => 0_u8,
+            ByteOrder::Big => 1_u8,
+        };
+        self.fs
+            .write_atomic(&self.path("format"), &[format, self.schema.to_bits()])?;
+        Ok(())
+    }
+
+    /// Store only the metrics of `schema` in new records. Defaults to all of them.
+    ///
+    /// Saves flash on installs that don't need every metric, e.g. current only installs without a
+    /// voltage tap. Like the byte order it is chosen when the storage is first set up, so set it
+    /// before find_newest_readings_shard_num. Existing storage keeps its schema, stored in the
+    /// header of every shard, until reset_storage. Readers of send_readings_shards find the schema
+    /// to split the records in that header, see shard_header_size.
+    pub(crate) fn set_record_schema(&mut self, schema: RecordSchema) {
+        self.schema = schema;
+        self.configured_format.1 = schema;
+    }
+
+    /// The metrics in the records of the shards, see set_record_schema.
+    pub(crate) fn record_schema(&self) -> RecordSchema {
+        self.schema
+    }
+
+    // Size of the records in the shards.
+    fn record_size(&self) -> usize {
+        self.schema.record_size()
+    }
+
+    // Decode a record of the shards.
+    fn decode(&self, buf: &[u8]) -> anyhow::Result<(u16, CTReading)> {
+        CTStorage::decode_record(buf, self.byte_order, self.schema)
+    }
+
+    // Encode a record for the shards and append it to `buf`.
+    fn encode(
+        &self,
+        id: u16,
+        reading: &CTReading,
+        sequence: u32,
+        buf: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let mut record = [0_u8; CT_READING_SIZE];
+        let size = CTStorage::encode_record(
+            id,
+            reading,
+            sequence,
+            self.byte_order,
+            self.schema,
+            &mut record,
+        )?;
+        buf.extend_from_slice(&record[..size]);
+        Ok(())
+    }
+
+    /// Byte order of the records in the shards.
+    ///
+    /// Chosen when the storage is first set up, or after reset_storage, and kept in the header of
+    /// every shard from then on so the shards never mix both orders. The other files
+    /// (sequence, time, calibration, ...) and the serial frames are always little endian.
+    pub(crate) fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+
+    // Make sure "<root>/ct_readings" exists, trying up to `attempts` times with a doubling
+    // delay in between. Returns whether it exists.
+    fn open_readings_dir(&self, attempts: u32) -> bool {
+        let mut delay = STORAGE_RETRY_DELAY;
+        for attempt in 1..=attempts {
+            if self.fs.read_dir(&self.path("ct_readings")).is_ok()
+                || self.fs.create_dir(&self.path("ct_readings")).is_ok()
+            {
+                return true;
+            }
+            warn!(
+                "Can't create the readings directory (attempt {} of {}).",
+                attempt, attempts
+            );
+            if attempt < attempts {
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+        false
+    }
+
+    /// What to do at boot when the newest shard ends with an incomplete record, see ShardRecovery.
+    /// Defaults to SHARD_RECOVERY.
+    ///
+    /// Takes effect on the next find_newest_readings_shard_num.
+    pub(crate) fn set_shard_recovery(&mut self, recovery: ShardRecovery) {
+        self.shard_recovery = recovery;
+    }
+
+    // A power loss in the middle of an append leaves an incomplete record at the end of the newest
+    // shard, which would misalign every record appended after it.
+    fn recover_newest_shard(&mut self) {
+        let shard_id = self.readings_shard_counter;
+        let path = self.shard_path(shard_id);
+        let size = match self.fs.file_size(&path) {
+            Ok(size) => size as usize,
+            Err(_) => return,
+        };
+        let (records, trailing) = self.shard_records(size as u64);
+        if trailing == 0 || self.shard_recovery == ShardRecovery::Off {
+            return;
+        }
+        warn!(
+            "Shard {} ends with {} bytes of an incomplete record.",
+            shard_id, trailing
+        );
+        if self.shard_recovery == ShardRecovery::Truncate {
+            let kept = size - trailing;
+            let truncated = self.fs.read(&path).and_then(|data| {
+                self.fs
+                    .write_atomic(&path, &data[..usize::min(kept, data.len())])
+            });
+            self.cache.get_mut().remove(shard_id);
+            match truncated {
+                Ok(()) => {
+                    info!(
+                        "Salvaged {} records of shard {}, cut off {} bytes.",
+                        records, shard_id, trailing
+                    );
+                    self.boot.truncated_bytes += trailing;
+                    return;
+                }
+                Err(err) => warn!("Can't truncate shard {}: {}", shard_id, err),
+            }
+        }
+        let next = shard_id + 1;
+        if let Err(err) = self.create_shard(next) {
+            warn!(
+                "Can't create shard {}, appending to shard {}: {}",
+                next, shard_id, err
+            );
+            return;
+        }
+        self.readings_shards.insert(next);
+        self.readings_shard_counter = next;
+        self.boot.rolled_shard = true;
+        info!(
+            "Kept {} records of shard {} as they are, rolled to shard {}.",
+            records, shard_id, next
+        );
+    }
+
+    /// What to do when the filesystem is nearly full, see LowSpacePolicy. Defaults to
+    /// LOW_SPACE_POLICY, Off.
+    ///
+    /// Meant for a device that is offline for longer than expected with nothing pruning its
+    /// shards. Coarsening keeps the readings of up to LOW_SPACE_SAVE_INTERVAL in RAM, which are
+    /// lost on a power cut. With the Ring policy of set_unsynced_limit the oldest shards make room
+    /// already, so then the saves are never coarsened.
+    pub(crate) fn set_low_space_policy(&mut self, policy: LowSpacePolicy) {
+        self.low_space_policy = policy;
+        self.check_low_space();
+    }
+
+    /// Whether saves are coarsened because the filesystem is nearly full, see LowSpacePolicy.
+    pub(crate) fn is_low_space(&self) -> bool {
+        self.low_space
+    }
+
+    // Update low_space from the space of the filesystem, logging every change. Unknown space
+    // counts as enough.
+    fn check_low_space(&mut self) {
+        let ring = matches!(self.unsynced_limit, Some((_, UnsyncedPolicy::Ring)));
+        let low_space = match self.fs.space(&self.root) {
+            Ok((total, used)) if self.low_space_policy == LowSpacePolicy::Coarsen && !ring => {
+                total > 0 && used as f32 / total as f32 > LOW_SPACE_USED
+            }
+            _ => false,
+        };
+        if low_space != self.low_space {
+            if low_space {
+                warn!(
+                    "Storage nearly full, saving at most every {:?}.",
+                    LOW_SPACE_SAVE_INTERVAL
+                );
+            } else {
+                info!("Storage space is back, saving as usual.");
+            }
+            self.low_space = low_space;
+        }
+    }
+
+    /// What to do with clipped readings, see ClippedPolicy. Defaults to Store.
+    pub(crate) fn set_clipped_policy(&mut self, policy: ClippedPolicy) {
+        self.clipped_policy = policy;
+    }
+
+    /// Whether save_to_storage leaves out the reading of `ct`, see ClippedPolicy::Skip. Start the
+    /// next period of the CT with CT::discard_interval then, so its kWh is left out of the energy
+    /// total too.
+    pub(crate) fn skips(&self, ct: &CT) -> bool {
+        ct.reading.clipped && self.clipped_policy == ClippedPolicy::Skip
+    }
+
+    /// When to sync the shard after a save, see SyncPolicy for the trade off. Defaults to Never.
+    pub(crate) fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_policy = policy;
+        self.saves_since_sync = 0;
+    }
+
+    /// Minimum time between two saves that are written to flash.
+    ///
+    /// Protects the flash from wearing out if saves are requested too often. Defaults to
+    /// MIN_SAVE_INTERVAL, Duration::ZERO writes every save.
+    pub(crate) fn set_min_save_interval(&mut self, interval: std::time::Duration) {
+        self.min_save_interval = interval;
+    }
+
+    /// Keep saves in RAM until they add up to `block_size` bytes, then write them together. None,
+    /// the default WRITE_BATCH, writes every save right away.
+    ///
+    /// Flash is erased a block at a time, and littlefs rewrites the block at the end of a shard
+    /// for every append, so small saves each cost a program of that block. A batch is appended to
+    /// the newest shard with one write and one sync instead of one per save, split only where the
+    /// shard fills up, see MAX_SHARD_SIZE. Set it to the erase block size of the flash, 4096 bytes
+    /// on the ESP32, and make the shards at least as large for the batch to take a single write.
+    /// The lifetime statistics, the time of use totals and the sequence number of the shards only
+    /// count a save once it is written. The catch is the window it opens: up to a block of saves
+    /// is only in RAM and lost on a power cut, and doesn't show up in readings read from the
+    /// shards until written. They are written early once MAX_BUFFERED_SAVES saves are waiting, an
+    /// hour of saves of a single phase device at the default save period. shutdown and dropping
+    /// the storage write them out.
+    pub(crate) fn set_write_batching(&mut self, block_size: Option<usize>) {
+        self.write_batch = block_size;
+    }
+
+    // Whether the saves kept in RAM are due to be written, see set_write_batching.
+    fn is_batch_due(&self) -> bool {
+        match self.write_batch {
+            Some(block_size) => {
+                self.buffered.len() >= MAX_BUFFERED_SAVES
+                    || self
+                        .buffered
+                        .iter()
+                        .map(|save| save.records.len())
+                        .sum::<usize>()
+                        >= block_size
+            }
+            None => true,
+        }
+    }
+
+    /// Whether readings are written to flash.
+    ///
+    /// While the filesystem is unavailable (not mounted, read-only, ...) the device keeps measuring
+    /// and save_to_storage keeps the last MAX_BUFFERED_SAVES saves in RAM instead. Every save
+    /// checks whether the filesystem is back and then writes the buffered saves first.
+    pub(crate) fn storage_available(&self) -> bool {
+        self.available
+    }
+
+    // Check once whether the filesystem is back and pick up where it left off.
+    fn recover_storage(&mut self) -> anyhow::Result<()> {
+        if !self.open_readings_dir(1) {
+            return Ok(());
+        }
+        self.find_newest_readings_shard_num()?;
+        if self.available {
+            // Saves made while degraded already used sequence numbers past the stored one.
+            let sequence = self.sequence;
+            self.load_persisted_state()?;
+            self.sequence = u32::max(self.sequence, sequence);
+            info!("Storage is available again.");
+        }
+        Ok(())
+    }
+
+    /// Load the state kept on the storage, once it is available: the time, the sequence number,
+    /// the lifetime statistics and the time of use totals. Then log the power loss.
+    ///
+    /// Call it at boot after find_newest_readings_shard_num. Saves do it themselves when the
+    /// storage comes back after being unavailable at boot.
+    pub(crate) fn load_persisted_state(&mut self) -> anyhow::Result<()> {
+        self.update_system_time()?;
+        self.load_sequence()?;
+        if let Err(err) = self.load_lifetime_stats() {
+            warn!(
+                "Can't load the lifetime statistics, starting from zero: {}",
+                err
+            );
+            self.stats = LifetimeStats::default();
+            self.stats_sequence = self.sequence;
+        }
+        if let Err(err) = self.load_tou_totals() {
+            warn!(
+                "Can't load the time of use totals, starting from zero: {}",
+                err
+            );
+            self.tou_totals = TouTotals::default();
+            self.tou_sequence = self.sequence;
+        }
+        self.log_powerloss()
+    }
+
+    /// Save sensor readings to storage.
+    ///
+    /// Only one save runs at a time on a CTStorage. A call while another save or write to
+    /// the storage files is running fails right away with an io::Error of kind WouldBlock, see
+    /// is_save_busy, and leaves the storage alone, retry it later. shutdown, recompute_energy,
+    /// import_state and reset_storage are exclusive the same way. The other state of CTStorage is
+    /// not synchronized, share it behind a mutex.
+    /// under "<root>/ct_readings" files are saved with a number as their filename.
+    /// newer files have a higher number as their filename.
+    /// While the storage is unavailable the readings are buffered in RAM, see storage_available.
+    /// Saves that come sooner than the minimum save interval after the previous one are not
+    /// written, their readings are averaged into the next save instead, see set_min_save_interval.
+    /// While back-pressured the readings are not saved at all, see set_unsynced_limit.
+    pub(crate) fn save_to_storage(&mut self, cts: &[CT; AC_PHASE]) -> anyhow::Result<()> {
+        let _saving = SaveGuard::acquire(&self.saving)?;
+        self.save(cts)
+    }
+
+    // save_to_storage, for a caller that holds the SaveGuard.
+    fn save(&mut self, cts: &[CT; AC_PHASE]) -> anyhow::Result<()> {
+        if self.is_back_pressured() {
+            anyhow::bail!(
+                "Storage paused, {} bytes not synced yet, dropped the readings.",
+                self.unsynced_bytes()
+            );
+        }
+        for ct in cts {
+            if ct.paused && ct.reading.quality.is_none() {
+                debug!("CT {}: paused all period, nothing to save", ct.id);
+                continue;
+            }
+            if self.skips(ct) {
+                warn!("CT {}: skipped clipped reading {:?}", ct.id, ct.reading);
+                continue;
+            }
+            match self.coalesced.iter_mut().find(|(id, _)| *id == ct.id) {
+                // A period without measurements, e.g. at shutdown, adds nothing to the saves held
+                // back.
+                Some(_) if ct.reading.quality.is_none() => {}
+                Some((_, accumulator)) => accumulator.push(&ct.reading),
+                None => {
+                    let mut accumulator = Accumulator::default();
+                    accumulator.aggregations = ct.accumulator.aggregations;
+                    accumulator.push(&ct.reading);
+                    self.coalesced.push((ct.id, accumulator));
+                }
+            }
+        }
+        // Every CT paused or skipped: no save, so no sequence number either.
+        if self.coalesced.is_empty() {
+            debug!("No CT to save, keeping sequence {}", self.sequence);
+            return Ok(());
+        }
+        self.check_low_space();
+        let min_save_interval = if self.low_space {
+            std::time::Duration::max(self.min_save_interval, LOW_SPACE_SAVE_INTERVAL)
+        } else {
+            self.min_save_interval
+        };
+        if let Some(last_save) = self.last_save {
+            if last_save.elapsed() < min_save_interval {
+                debug!(
+                    "Coalescing save, the last one was {:?} ago.",
+                    last_save.elapsed()
+                );
+                return Ok(());
+            }
+        }
+        self.last_save = Some(std::time::Instant::now());
+        let readings: Vec<(u16, CTReading)> = std::mem::take(&mut self.coalesced)
+            .into_iter()
+            .map(|(id, accumulator)| (id, accumulator.finalize()))
+            .collect();
+
+        if !self.available {
+            // The save is kept in RAM until the storage recovers, see write_buffered.
+            if let Err(err) = self.recover_storage() {
+                warn!(
+                    "Can't recover the storage, keeping the save in RAM: {}",
+                    err
+                );
+                self.available = false;
+            }
+        }
+
+        // Every save gets the next sequence number. It is stored with the records, see
+        // load_sequence.
+        let sequence = self.sequence + 1;
+        self.sequence = sequence;
+
+        let mut buf = Vec::with_capacity(self.record_size() * AC_PHASE);
+        for (id, reading) in &readings {
+            if reading.clipped && self.clipped_policy == ClippedPolicy::Sentinel {
+                let mut reading = reading.clone();
+                reading.i_rms = CLIPPED_SENTINEL;
+                reading.apparent_power = CLIPPED_SENTINEL;
+                self.encode(*id, &reading, sequence, &mut buf)?;
+            } else {
+                self.encode(*id, reading, sequence, &mut buf)?;
+            }
+        }
+        if self.buffered.len() >= MAX_BUFFERED_SAVES {
+            self.buffered.pop_front();
+            warn!("Storage buffer full, dropped the oldest save.");
+        }
+        self.buffered.push_back(PendingSave {
+            sequence,
+            records: buf,
+            readings: readings.into_iter().map(|(_, reading)| reading).collect(),
+        });
+
+        if self.is_batch_due() {
+            self.write_buffered();
+        }
+        if let Some((limit, UnsyncedPolicy::Ring)) = self.unsynced_limit {
+            self.drop_unsynced_over(limit)?;
+        }
+        Ok(())
+    }
+
+    // Write the buffered saves, oldest first, until a write fails. The saves that fit into the
+    // newest shard go in one append, see append_saves.
+    fn write_buffered(&mut self) {
+        while self.available && !self.buffered.is_empty() {
+            match self.append_saves() {
+                Ok(written) => {
+                    let saves: Vec<PendingSave> = self.buffered.drain(..written).collect();
+                    for save in &saves {
+                        self.count_written(save);
+                    }
+                }
+                Err(err) => {
+                    warn!("Can't write readings, buffering them in RAM: {}", err);
+                    self.available = false;
+                }
+            }
+        }
+        self.store_stats(false);
+        if !self.available {
+            info!("{} saves buffered in RAM.", self.buffered.len());
+        }
+    }
+
+    // Count a written save into the lifetime statistics and the time of use totals.
+    fn count_written(&mut self, save: &PendingSave) {
+        for reading in &save.readings {
+            self.stats.add(reading);
+            self.tou_totals.add(reading, &self.tou_schedule);
+        }
+        self.stats_sequence = save.sequence;
+        self.tou_sequence = save.sequence;
+        self.unstored_stats += 1;
+    }
+
+    // Store the lifetime statistics and the time of use totals once STATS_STORE_INTERVAL written
+    // saves were counted since the last time, or after any if `force`. Saves counted but not
+    // stored yet are counted again from the shards at boot, see load_lifetime_stats and
+    // load_tou_totals.
+    fn store_stats(&mut self, force: bool) {
+        if self.unstored_stats == 0 || (!force && self.unstored_stats < STATS_STORE_INTERVAL) {
+            return;
+        }
+        match self
+            .save_lifetime_stats()
+            .and_then(|()| self.save_tou_totals())
+        {
+            Ok(()) => self.unstored_stats = 0,
+            Err(err) => warn!("Can't store the statistics and time of use totals: {}", err),
+        }
+    }
+
+    /// Persist everything that is only in RAM, before a controlled restart such as an OTA update.
+    ///
+    /// Saves the readings of the running save period right away, regardless of
+    /// set_min_save_interval, together with any coalesced ones, and syncs them. Then writes the
+    /// saves buffered while the storage was unavailable, the energy totals including the running
+    /// period, the lifetime statistics with the peak demand and the time. Without it a restart
+    /// loses up to a whole save period. Call it last, right before the restart: the totals already
+    /// count the running period, so measuring on and resetting the CTs afterwards would count it
+    /// twice. Saves that still can't be written are lost and reported in the error.
+    pub(crate) fn shutdown(&mut self, cts: &[CT; AC_PHASE]) -> anyhow::Result<()> {
+        let _saving = SaveGuard::acquire(&self.saving)?;
+        let sync_policy = self.sync_policy;
+        self.sync_policy = SyncPolicy::EverySave;
+        let measured = cts.iter().any(|ct| ct.reading.quality.is_some());
+        let res = if measured || !self.coalesced.is_empty() {
+            self.last_save = None;
+            let res = self.save(cts);
+            // Also the saves kept for a batch, see set_write_batching.
+            self.write_buffered();
+            res
+        } else {
+            let res = if self.available {
+                Ok(())
+            } else {
+                self.recover_storage()
+            };
+            self.write_buffered();
+            res
+        };
+        self.sync_policy = sync_policy;
+        self.store_stats(true);
+        res?;
+        let totals: Vec<(u16, f64, f64)> = cts
+            .iter()
+            .map(|ct| {
+                let kwh = if self.skips(ct) { 0.0 } else { ct.reading.kwh };
+                (ct.id, ct.energy_total_kwh + kwh as f64, ct.exported_kwh)
+            })
+            .collect();
+        self.write_energy_totals(totals.into_iter())?;
+        self.store_time(now().as_millis() as u64)?;
+        if !self.buffered.is_empty() {
+            anyhow::bail!(
+                "{} saves could not be written and are lost on restart",
+                self.buffered.len()
+            );
+        }
+        info!("Persisted all readings for shutdown.");
+        Ok(())
+    }
+
+    // Append the oldest buffered saves to the newest shard with a single write and sync, as many
+    // as fit into it, and return how many. A full shard is rolled over first. If the write fails
+    // none of them count as written, the shard is cut back to its size before and they stay
+    // buffered.
+    fn append_saves(&mut self) -> anyhow::Result<usize> {
+        let record_size = self.record_size();
+        let has_room = |size: u64| (MAX_SHARD_SIZE as i64 - size as i64) >= record_size as i64;
+        // check whether the selected shard has enough size. if it doesn't create a new shard
+        let mut shard_size = self
+            .fs
+            .file_size(&self.shard_path(self.readings_shard_counter))?;
+        let first = match self.buffered.iter().find(|save| !save.records.is_empty()) {
+            Some(save) => save.sequence,
+            None => return Ok(self.buffered.len()),
+        };
+        if !has_room(shard_size) {
+            // The sequence numbers in the full shard are no longer the newest ones, see
+            // load_sequence.
+            self.fs
+                .write_atomic(&self.path("sequence"), &first.to_le_bytes())?;
+            self.readings_shard_counter += 1;
+            self.readings_shards.insert(self.readings_shard_counter);
+            shard_size = 0;
+        }
+        let shard_id = self.readings_shard_counter;
+
+        let mut buf = Vec::new();
+        if shard_size == 0 {
+            buf.extend_from_slice(&CTStorage::shard_header(self.byte_order, self.schema));
+        }
+        let mut size = u64::max(shard_size, SHARD_HEADER_SIZE as u64);
+        let mut entries = Vec::new();
+        let mut count = 0;
+        for save in &self.buffered {
+            if !save.records.is_empty() {
+                if !entries.is_empty() && !has_room(size) {
+                    break;
+                }
+                let offset = size - SHARD_HEADER_SIZE as u64;
+                entries.extend_from_slice(&Self::index_entry(save.sequence, shard_id, offset));
+                buf.extend_from_slice(&save.records);
+                size += save.records.len() as u64;
+            }
+            count += 1;
+        }
+
+        self.cache.get_mut().remove(shard_id);
+        let path = self.shard_path(shard_id);
+        let sync = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EverySave => true,
+            SyncPolicy::EveryNSaves(n) => self.saves_since_sync + count as u32 >= n,
+        };
+        if let Err(err) = Self::append_to(self.fs.as_ref(), &path, &buf, sync) {
+            // Whole records of the batch may have landed, and the saves stay buffered to be
+            // appended again. Cut them off, so no sequence number ends up in the shard twice.
+            if let Err(cut_err) = self.cut_shard(&path, shard_size) {
+                error!(
+                    "Can't cut {} back to {} bytes: {}",
+                    path, shard_size, cut_err
+                );
+            }
+            return Err(err);
+        }
+        self.saves_since_sync = if sync {
+            0
+        } else {
+            self.saves_since_sync + count as u32
+        };
+        info!(
+            "Flushed {} saves to storage and shard size is {}",
+            count, size
+        );
+        if let Err(err) = self.append_index_entries(&entries) {
+            // A torn entry would shift every entry after it, so the index is written anew.
+            warn!(
+                "Can't index saves from {}, rebuilding the index: {}",
+                first, err
+            );
+            if let Err(err) = self.rebuild_sequence_index() {
+                warn!("Can't rebuild the sequence index, removing it: {}", err);
+                let _ = self.fs.remove_file(&self.path("sequence_index"));
+            }
+        }
+        Ok(count)
+    }
+
+    // Append `buf` to the file at `path` of `fs`, and sync it if `sync`.
+    fn append_to(fs: &dyn Filesystem, path: &str, buf: &[u8], sync: bool) -> anyhow::Result<()> {
+        let mut file = fs.open(path, OpenMode::Append)?;
+        info!("Opened {} for writing.", path);
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(buf)?;
+        file.flush()?;
+        if sync {
+            file.sync()?;
+        }
+        Ok(())
+    }
+
+    // Cut the shard at `path` back to its first `len` bytes by writing it anew.
+    fn cut_shard(&self, path: &str, len: u64) -> anyhow::Result<()> {
+        let mut kept = self.fs.read(path)?;
+        kept.truncate(len as usize);
+        self.fs.write_atomic(path, &kept)?;
+        Ok(())
+    }
+
+    // The entry of a save in the sequence index: its sequence number, shard id and offset after
+    // the shard header, each 4 bytes little endian.
+    fn index_entry(sequence: u32, shard_id: i32, offset: u64) -> [u8; SEQUENCE_INDEX_ENTRY_SIZE] {
+        let mut entry = [0_u8; SEQUENCE_INDEX_ENTRY_SIZE];
+        entry[0..4].copy_from_slice(&sequence.to_le_bytes());
+        entry[4..8].copy_from_slice(&shard_id.to_le_bytes());
+        entry[8..12].copy_from_slice(&(offset as u32).to_le_bytes());
+        entry
+    }
+
+    // Add the entries of written saves to "<root>/sequence_index", see index_entry. Entries are
+    // in sequence order. Past SEQUENCE_INDEX_MAX_ENTRIES the index is thinned, see
+    // thin_sequence_index.
+    fn append_index_entries(&mut self, entries: &[u8]) -> anyhow::Result<()> {
+        let mut file = self
+            .fs
+            .open(&self.path("sequence_index"), OpenMode::Append)?;
+        if file.size()? % SEQUENCE_INDEX_ENTRY_SIZE as u64 != 0 {
+            anyhow::bail!("torn entry at the end of the sequence index");
+        }
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(entries)?;
+        file.flush()?;
+        let size = file.size()?;
+        drop(file);
+        if size > (SEQUENCE_INDEX_MAX_ENTRIES * SEQUENCE_INDEX_ENTRY_SIZE) as u64 {
+            let buf = self.fs.read(&self.path("sequence_index"))?;
+            self.fs
+                .write_atomic(&self.path("sequence_index"), &thin_sequence_index(&buf))?;
+        }
+        Ok(())
+    }
+
+    // Write the sequence index anew from the shards, with an entry for the first record of every
+    // save.
+    fn rebuild_sequence_index(&mut self) -> anyhow::Result<()> {
+        let mut shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
+        shard_ids.sort_unstable();
+        let mut buf = Vec::new();
+        let mut last_sequence = None;
+        for shard_id in shard_ids {
+            for (n, (_, reading)) in self.read_shard(shard_id)?.iter().enumerate() {
+                if last_sequence != Some(reading.sequence) {
+                    last_sequence = Some(reading.sequence);
+                    let offset = (n * self.record_size()) as u64;
+                    buf.extend_from_slice(&Self::index_entry(reading.sequence, shard_id, offset));
+                }
+            }
+        }
+        self.fs
+            .write_atomic(&self.path("sequence_index"), &thin_sequence_index(&buf))?;
+        info!(
+            "Rebuilt the sequence index, {} entries.",
+            buf.len() / SEQUENCE_INDEX_ENTRY_SIZE
+        );
+        Ok(())
+    }
+
+    // Where the last indexed save with a sequence number of at most `seq` starts, as
+    // (shard id, offset), found by a binary search over the index. None if there is none, or no
+    // index.
+    fn index_lookup(&self, seq: u32) -> anyhow::Result<Option<(i32, u64)>> {
+        let mut file = match self.fs.open(&self.path("sequence_index"), OpenMode::Read) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        let size = file.size()?;
+        if size % SEQUENCE_INDEX_ENTRY_SIZE as u64 != 0 {
+            // A torn entry, the shards are searched instead until the index is rebuilt.
+            return Ok(None);
+        }
+        let entries = size / SEQUENCE_INDEX_ENTRY_SIZE as u64;
+        let mut entry = [0_u8; SEQUENCE_INDEX_ENTRY_SIZE];
+        let mut read_entry = |n: u64| -> anyhow::Result<(u32, i32, u64)> {
+            file.seek(SeekFrom::Start(n * SEQUENCE_INDEX_ENTRY_SIZE as u64))?;
+            file.read_exact(&mut entry)?;
+            let field = |i: usize| [entry[i], entry[i + 1], entry[i + 2], entry[i + 3]];
+            Ok((
+                u32::from_le_bytes(field(0)),
+                i32::from_le_bytes(field(4)),
+                u32::from_le_bytes(field(8)) as u64,
+            ))
+        };
+        // The first entry above `seq` is in lo..hi.
+        let (mut lo, mut hi) = (0, entries);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if read_entry(mid)?.0 <= seq {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if lo == 0 {
+            return Ok(None);
+        }
+        let (_, shard_id, offset) = read_entry(lo - 1)?;
+        Ok(Some((shard_id, offset)))
+    }
+
+    // Drop the index entries of shards that no longer exist, after shards were deleted.
+    fn prune_sequence_index(&mut self) -> anyhow::Result<()> {
+        let buf = match self.fs.read(&self.path("sequence_index")) {
+            Ok(buf) => buf,
+            Err(_) => return Ok(()),
+        };
+        let mut kept = Vec::with_capacity(buf.len());
+        for entry in buf.chunks_exact(SEQUENCE_INDEX_ENTRY_SIZE) {
+            let shard_id = i32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            if self.readings_shards.contains(&shard_id) {
+                kept.extend_from_slice(entry);
+            }
+        }
+        if kept.len() != buf.len() {
+            self.fs.write_atomic(&self.path("sequence_index"), &kept)?;
+        }
+        Ok(())
+    }
+
+    // Retrieve the latest time from storage and update RTC. The clock never goes back, a later
+    // time set since boot stays.
+    pub(crate) fn update_system_time(&mut self) -> anyhow::Result<()> {
+        let mut file = self.fs.open(&self.path("time"), OpenMode::ReadWrite)?;
+        if file
+            .seek(std::io::SeekFrom::End(-(std::mem::size_of::<u64>() as i64)))
+            .is_ok()
+        {
+            let mut time_buf = [12_u8; 8];
+            if file.read_exact(&mut time_buf).is_ok() {
+                let time = u64::from_le_bytes(time_buf);
+                println!("Found time from storage: {}", time);
+                if u128::from(time) > now().as_millis() {
+                    set_system_time(time)?;
+                    set_clock_restored();
+                    self.boot.time_restored = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Store the given time to storage
+    pub(crate) fn store_time(&mut self, time: u64) -> anyhow::Result<()> {
+        let mut file = if (MAX_TIME_STORAGE_SIZE as i64
+            - self.fs.file_size(&self.path("time"))? as i64)
+            < std::mem::size_of::<u64>() as i64
+        {
+            // If the file is full, create a new one overwriting the previous file.
+            self.fs.open(&self.path("time"), OpenMode::Truncate)?
+        } else {
+            self.fs.open(&self.path("time"), OpenMode::Append)?
+        };
+
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&time.to_le_bytes())?;
+        file.flush()?;
+        info!("Wrote time {} to storage.", time);
+        info!("Time file size {}", file.size()?);
+        Ok(())
+    }
+
+    /// Store the fault captures the CTs took since the last call, see CT::set_fault_capture.
+    ///
+    /// Each goes to "fault_capture_<id>" in the format of CT::capture_waveform, replacing the one
+    /// before. A capture that can't be stored is logged and dropped, the CT captures the next fault
+    /// after set_fault_capture again.
+    pub(crate) fn store_fault_captures(&self, cts: &mut [CT; AC_PHASE]) {
+        for ct in cts.iter_mut() {
+            let samples = match ct.take_fault_capture() {
+                Some(samples) => samples,
+                None => continue,
+            };
+            let path = self.path(&format!("fault_capture_{}", ct.id));
+            let mut buf = Vec::with_capacity(samples.len() * 4);
+            for (sample_i, sample_v) in &samples {
+                buf.extend_from_slice(&sample_i.to_le_bytes());
+                buf.extend_from_slice(&sample_v.to_le_bytes());
+            }
+            match self.fs.write_atomic(&path, &buf) {
+                Ok(()) => info!(
+                    "CT {}: stored {} samples of the fault capture to {}",
+                    ct.id,
+                    samples.len(),
+                    path
+                ),
+                Err(err) => warn!("CT {}: can't store the fault capture: {}", ct.id, err),
+            }
+        }
+    }
+
+    // Retrieve the latest token from storage
+    pub(crate) fn retrieve_token(&mut self) -> anyhow::Result<[u8; ACCESS_TOKEN_SIZE]> {
+        let mut file = self.fs.open(&self.path("token"), OpenMode::Read)?;
+        let mut token = [0_u8; ACCESS_TOKEN_SIZE];
+        file.read_exact(&mut token)?;
+        Ok(token)
+    }
+
+    // Store the given token to storage
+    pub(crate) fn store_token(&mut self, token: &[u8]) -> anyhow::Result<()> {
+        let mut file = self.fs.open(&self.path("token"), OpenMode::Truncate)?;
+        file.write_all(token)?;
+        log::info!(
+            "Stored toke: {} to storage.",
+            String::from_utf8(token.to_vec())?
+        );
+        Ok(())
+    }
+
+    /// Store the text of the settings, see settings::parse. Apply them only once they are stored.
+    pub(crate) fn store_settings(&mut self, settings: &str) -> anyhow::Result<()> {
+        self.fs
+            .write_atomic(&self.path("settings"), settings.as_bytes())?;
+        info!("Stored settings to storage.");
+        Ok(())
+    }
+
+    /// The stored text of the settings, empty if none are stored.
+    pub(crate) fn settings(&self) -> anyhow::Result<String> {
+        match self.read_retrying(&self.path("settings")) {
+            Ok(buf) => Ok(String::from_utf8(buf)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Capture `samples` samples of `ct` to the storage, see CT::capture_waveform. Returns the name
+    /// of the file for replay_waveform, which is replaced by the next capture of the CT.
+    pub(crate) fn capture_waveform(
+        &self,
+        ct: &mut CT,
+        sampler: &mut Sampler,
+        samples: usize,
+    ) -> anyhow::Result<String> {
+        let name = format!("waveform_{}", ct.id);
+        ct.capture_waveform(sampler, samples, &*self.fs, &self.path(&name))?;
+        Ok(name)
+    }
+
+    /// Replay the waveform in the file `name` of the storage through `ct`, see
+    /// ct::replay_waveform. The dc offsets are reset after, as the replay moved them to those of
+    /// the captured waveform.
+    pub(crate) fn replay_waveform(&self, ct: &mut CT, name: &str) -> anyhow::Result<CTReading> {
+        if name.is_empty() || name.contains('/') {
+            anyhow::bail!("Invalid waveform file {:?}", name);
+        }
+        let reading = crate::ct::replay_waveform(&*self.fs, &self.path(name), ct);
+        ct.reset_offsets();
+        reading
+    }
+
+    // Send reading shards one by one into this writer, each with its header, see shard_header_size.
+    // before deleting a shard, we make sure that he have flushed thr writer.
+    pub(crate) fn send_readings_shards(
+        &mut self,
+        writer: &mut EspHttpResponseWrite,
+    ) -> anyhow::Result<()> {
+        let mut sorted_shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
+        sorted_shard_ids.sort();
+        // a fixed size buffer to avoid stack overflow
+        let mut buf = [0_u8; CT_READING_SIZE];
+        let buf = &mut buf[..self.record_size()];
+        for shard_id in sorted_shard_ids {
+            if let Ok(mut file) = self
+                .fs
+                .open(&self.shard_path(shard_id), OpenMode::ReadWrite)
+            {
+                let mut header = [0_u8; SHARD_HEADER_SIZE];
+                if !read_record(&mut file, &mut header)? {
+                    continue;
+                }
+                writer.write(&header)?;
+                while read_record(&mut file, buf)? {
+                    writer.write(buf)?;
+                }
+                writer.flush()?;
+                info!("Sent shard {}", self.shard_path(shard_id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the calibration of every CT.
+    ///
+    /// The calibration is loaded at boot, so the file is replaced atomically. A power loss in the
+    /// middle of a save leaves the previous calibration in place instead of a corrupt file. The
+    /// file is the CALIBRATION_VERSION byte, then per CT its id, vcal, ical, phase_cal and
+    /// voltage transformer ratio, and a crc16 of everything before it, all little endian.
+    pub(crate) fn save_calibration(&mut self, cts: &[CT; AC_PHASE]) -> anyhow::Result<()> {
+        let mut buf = [0_u8; 3 + CALIBRATION_SIZE * AC_PHASE];
+        buf[0] = CALIBRATION_VERSION;
+        let mut pos = 1;
+        for ct in cts {
+            let cal = ct.calibration();
+            pos += add_u16_to_buf(&ct.id, &mut buf, &pos)?;
+            pos += add_f32_to_buf(&cal.vcal, &mut buf, &pos)?;
+            pos += add_f32_to_buf(&cal.ical, &mut buf, &pos)?;
+            pos += add_f32_to_buf(&cal.phase_cal, &mut buf, &pos)?;
+            pos += add_f32_to_buf(&ct.vt_ratio(), &mut buf, &pos)?;
+        }
+        let crc = crc16(&buf[..pos]);
+        add_u16_to_buf(&crc, &mut buf, &pos)?;
+        self.fs.write_atomic(&self.path("calibration"), &buf)?;
+        info!("Stored calibration to storage.");
+        Ok(())
+    }
+
+    /// Load the stored calibration into the CTs, see BootReport::calibration for the outcome.
+    ///
+    /// CTs without a stored calibration keep their compiled defaults. A read that fails is tried
+    /// STORAGE_RETRIES times. Files from before version 1 hold the records only, without version
+    /// and crc, and files of version 1 have no voltage transformer ratio. Both are loaded, keeping
+    /// the ratio of the CTs, and stored again in the current format. A file that still
+    /// can't be read, or has an unknown version, a bad crc or invalid values, is not loaded at all:
+    /// every CT keeps its defaults and the file is moved to "<root>/calibration.bad" for
+    /// diagnostics, where it stays until the next bad file replaces it.
+    pub(crate) fn load_calibration(&mut self, cts: &mut [CT; AC_PHASE]) -> anyhow::Result<()> {
+        let buf = match self.read_retrying(&self.path("calibration")) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                info!("No stored calibration, using defaults.");
+                return Ok(());
+            }
+            Err(err) => {
+                self.set_aside_calibration(&format!("can't be read: {}", err));
+                return Ok(());
+            }
+        };
+        // The second byte tells the formats apart: in a versioned file it is the low byte of the
+        // id of the first CT, never 0, in the records of an unversioned one the high byte, always
+        // 0. So a damaged versioned file is never taken for an unversioned one.
+        let versioned = buf.len() >= 2 && buf[1] != 0;
+        let (records, record_size) = if !versioned {
+            if buf.is_empty() || buf.len() % LEGACY_CALIBRATION_SIZE != 0 {
+                self.set_aside_calibration(&format!("is {} bytes", buf.len()));
+                return Ok(());
+            }
+            (&buf[..], LEGACY_CALIBRATION_SIZE)
+        } else if buf[0] != CALIBRATION_VERSION && buf[0] != 1 {
+            self.set_aside_calibration(&format!("has unknown version {}", buf[0]));
+            return Ok(());
+        } else {
+            let record_size = if buf[0] == 1 {
+                LEGACY_CALIBRATION_SIZE
+            } else {
+                CALIBRATION_SIZE
+            };
+            if buf.len() < 3 || (buf.len() - 3) % record_size != 0 {
+                self.set_aside_calibration(&format!("is {} bytes", buf.len()));
+                return Ok(());
+            }
+            let mut pos = buf.len() - 2;
+            if read_u16_from_buf(&buf, &mut pos)? != crc16(&buf[..buf.len() - 2]) {
+                self.set_aside_calibration("has a bad crc");
+                return Ok(());
+            }
+            (&buf[1..buf.len() - 2], record_size)
+        };
+        let migrate = record_size != CALIBRATION_SIZE;
+        let mut cals = Vec::with_capacity(records.len() / record_size);
+        let mut pos = 0;
+        while pos + record_size <= records.len() {
+            let id = read_u16_from_buf(records, &mut pos)?;
+            let cal = Calibration {
+                vcal: read_f32_from_buf(records, &mut pos)?,
+                ical: read_f32_from_buf(records, &mut pos)?,
+                phase_cal: read_f32_from_buf(records, &mut pos)?,
+            };
+            let vt_ratio = if migrate {
+                None
+            } else {
+                Some(read_f32_from_buf(records, &mut pos)?)
+            };
+            if !cal.is_valid() {
+                self.set_aside_calibration(&format!("of CT {} is invalid: {:?}", id, cal));
+                return Ok(());
+            }
+            if let Some(ratio) = vt_ratio.filter(|ratio| !(*ratio > 0.0 && ratio.is_finite())) {
+                self.set_aside_calibration(&format!(
+                    "of CT {} has an invalid voltage transformer ratio {}",
+                    id, ratio
+                ));
+                return Ok(());
+            }
+            cals.push((id, cal, vt_ratio));
+        }
+        for (id, cal, vt_ratio) in cals {
+            if let Some(ct) = cts.iter_mut().find(|ct| ct.id == id) {
+                ct.set_calibration(cal);
+                if let Some(ratio) = vt_ratio {
+                    ct.set_vt_ratio(ratio);
+                }
+                self.boot.calibrations_loaded += 1;
+                info!(
+                    "Loaded calibration of CT {}: {:?}, voltage transformer ratio {}",
+                    id,
+                    cal,
+                    ct.vt_ratio()
+                );
+            }
+        }
+        self.boot.calibration = CalibrationLoad::Loaded;
+        if migrate {
+            info!(
+                "Calibration is of the format before version {}, migrating it.",
+                CALIBRATION_VERSION
+            );
+            self.save_calibration(cts)?;
+            self.boot.calibration = CalibrationLoad::Migrated;
+        }
+        Ok(())
+    }
+
+    // Read a file, trying up to STORAGE_RETRIES times with a doubling delay in between like
+    // open_readings_dir. A missing file is not retried.
+    fn read_retrying(&self, path: &str) -> std::io::Result<Vec<u8>> {
+        let mut delay = STORAGE_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            match self.fs.read(path) {
+                Err(err)
+                    if err.kind() != std::io::ErrorKind::NotFound && attempt < STORAGE_RETRIES =>
+                {
+                    warn!(
+                        "Can't read {} (attempt {} of {}): {}",
+                        path, attempt, STORAGE_RETRIES, err
+                    );
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Fall back to the compiled calibration and keep the file that can't be loaded, see
+    // load_calibration.
+    fn set_aside_calibration(&mut self, reason: &str) {
+        warn!(
+            "Stored calibration {}, using defaults and moving it to {}/calibration.bad.",
+            reason, self.root
+        );
+        if let Err(err) = self
+            .fs
+            .rename(&self.path("calibration"), &self.path("calibration.bad"))
+        {
+            warn!("Can't move the calibration aside: {}", err);
+        }
+        self.boot.calibration = CalibrationLoad::Corrupt;
+    }
+
+    /// Store the total energy and the exported kWh of every CT.
+    ///
+    /// Uses the same atomic replace as save_calibration so a power loss can't lose the totals.
+    pub(crate) fn save_energy_totals(&mut self, cts: &[CT; AC_PHASE]) -> anyhow::Result<()> {
+        self.write_energy_totals(
+            cts.iter()
+                .map(|ct| (ct.id, ct.energy_total_kwh, ct.exported_kwh)),
+        )
+    }
+
+    // Store the given (CT id, total kWh, exported kWh) triples, see save_energy_totals.
+    fn write_energy_totals(
+        &mut self,
+        totals: impl Iterator<Item = (u16, f64, f64)>,
+    ) -> anyhow::Result<()> {
+        let mut buf = [0_u8; ENERGY_TOTAL_SIZE * AC_PHASE];
+        let mut pos = 0;
+        for (id, total, exported) in totals.take(AC_PHASE) {
+            pos += add_u16_to_buf(&id, &mut buf, &pos)?;
+            pos += add_f64_to_buf(&total, &mut buf, &pos)?;
+            pos += add_f64_to_buf(&exported, &mut buf, &pos)?;
+        }
+        self.fs.write_atomic(&self.path("energy_totals"), &buf)?;
+        info!("Stored energy totals to storage.");
+        Ok(())
+    }
+
+    // Load the stored energy totals into the CTs. Files from before the exported kWh leave them
+    // at 0.
+    pub(crate) fn load_energy_totals(&mut self, cts: &mut [CT; AC_PHASE]) -> anyhow::Result<()> {
+        let buf = match self.fs.read(&self.path("energy_totals")) {
+            Ok(buf) => buf,
+            Err(_) => {
+                info!("No stored energy totals.");
+                return Ok(());
+            }
+        };
+        let legacy = buf.len() == LEGACY_ENERGY_TOTAL_SIZE * AC_PHASE;
+        let size = if legacy {
+            LEGACY_ENERGY_TOTAL_SIZE
+        } else {
+            ENERGY_TOTAL_SIZE
+        };
+        let mut pos = 0;
+        while pos + size <= buf.len() {
+            let id = read_u16_from_buf(&buf, &mut pos)?;
+            let total = read_f64_from_buf(&buf, &mut pos)?;
+            let exported = if legacy {
+                0.0
+            } else {
+                read_f64_from_buf(&buf, &mut pos)?
+            };
+            if let Some(ct) = cts.iter_mut().find(|ct| ct.id == id) {
+                ct.energy_total_kwh = total;
+                ct.exported_kwh = exported;
+                self.boot.energy_totals_loaded += 1;
+                info!(
+                    "Loaded energy total of CT {}: {} kWh, {} kWh exported",
+                    id, total, exported
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Count, sum and sum of squares of every metric, and the peak demand, over all records ever
+    /// written.
+    ///
+    /// Saves count once they are written to the shards, not while they wait in RAM. Stored in
+    /// "<root>/lifetime_stats" every STATS_STORE_INTERVAL written saves, by shutdown and on
+    /// drop, so the all-time mean and standard deviation are available without scanning the
+    /// shards, which only hold the records that were not uploaded or pruned yet.
+    pub fn lifetime_stats(&self) -> LifetimeStats {
+        self.stats
+    }
+
+    // Store the lifetime statistics and the sequence number of the last save they count,
+    // replacing the file atomically like save_calibration.
+    fn save_lifetime_stats(&mut self) -> anyhow::Result<()> {
+        let mut buf = [0_u8; LIFETIME_STATS_SIZE];
+        let mut pos = 0;
+        for metric in self.stats.metrics() {
+            pos += add_u64_to_buf(&metric.count, &mut buf, &pos)?;
+            pos += add_f64_to_buf(&metric.sum, &mut buf, &pos)?;
+            pos += add_f64_to_buf(&metric.sum_sq, &mut buf, &pos)?;
+        }
+        pos += add_u32_to_buf(&self.stats_sequence, &mut buf, &pos)?;
+        pos += add_f32_to_buf(&self.stats.peak_demand, &mut buf, &pos)?;
+        add_u64_to_buf(&self.stats.peak_demand_at, &mut buf, &pos)?;
+        self.fs.write_atomic(&self.path("lifetime_stats"), &buf)?;
+        Ok(())
+    }
+
+    /// Load the lifetime statistics from storage, call it after load_sequence.
+    ///
+    /// The saves written after the stored statistics were, at most STATS_STORE_INTERVAL of them,
+    /// are counted from the shards. If there are no statistics yet, or the file is damaged, they
+    /// are rebuilt from the records still in the shards, which is the best that can be recovered.
+    pub fn load_lifetime_stats(&mut self) -> anyhow::Result<()> {
+        if let Ok(buf) = self.fs.read(&self.path("lifetime_stats")) {
+            // Files without the sequence number were stored on every save, they count them all.
+            // Files without the peak demand start it over from the records after them.
+            let without_peak = LIFETIME_STATS_SIZE - PEAK_DEMAND_SIZE;
+            let without_sequence = without_peak - std::mem::size_of::<u32>();
+            if [LIFETIME_STATS_SIZE, without_peak, without_sequence].contains(&buf.len()) {
+                let mut stats = LifetimeStats::default();
+                let mut pos = 0;
+                for metric in stats.metrics_mut() {
+                    metric.count = read_u64_from_buf(&buf, &mut pos)?;
+                    metric.sum = read_f64_from_buf(&buf, &mut pos)?;
+                    metric.sum_sq = read_f64_from_buf(&buf, &mut pos)?;
+                }
+                self.stats_sequence = if buf.len() == without_sequence {
+                    self.sequence
+                } else {
+                    read_u32_from_buf(&buf, &mut pos)?
+                };
+                if buf.len() == LIFETIME_STATS_SIZE {
+                    stats.peak_demand = read_f32_from_buf(&buf, &mut pos)?;
+                    stats.peak_demand_at = read_u64_from_buf(&buf, &mut pos)?;
+                }
+                self.stats = stats;
+                let missed = self.records_after(self.stats_sequence)?;
+                for reading in &missed {
+                    self.stats.add(reading);
+                    self.stats_sequence = reading.sequence;
+                }
+                info!(
+                    "Loaded lifetime statistics, counted {} records written after them: {:?}",
+                    missed.len(),
+                    self.stats
+                );
+                if !missed.is_empty() {
+                    self.save_lifetime_stats()?;
+                }
+                return Ok(());
+            }
+        }
+
+        warn!("No lifetime statistics, rebuilding them from the shards.");
+        self.boot.lifetime_stats_rebuilt = true;
+        let mut stats = LifetimeStats::default();
+        let mut sorted_shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
+        sorted_shard_ids.sort();
+        for shard_id in sorted_shard_ids {
+            for (_, reading) in self.read_shard(shard_id)? {
+                stats.add(&reading);
+            }
+        }
+        self.stats = stats;
+        self.stats_sequence = self.sequence;
+        self.save_lifetime_stats()
+    }
+
+    // The records of the saves after `sequence`, oldest first. Only reads the newest shards, back
+    // to the first one that holds an older record.
+    fn records_after(&self, sequence: u32) -> anyhow::Result<Vec<CTReading>> {
+        let mut shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
+        shard_ids.sort_unstable_by(|a, b| b.cmp(a));
+        let mut newer = Vec::new();
+        for shard_id in shard_ids {
+            let records = self.read_shard(shard_id)?;
+            let reached = records
+                .iter()
+                .any(|(_, reading)| reading.sequence <= sequence);
+            newer.extend(
+                records
+                    .into_iter()
+                    .rev()
+                    .map(|(_, reading)| reading)
+                    .filter(|reading| reading.sequence > sequence),
+            );
+            if reached {
+                break;
+            }
+        }
+        newer.reverse();
+        Ok(newer)
+    }
+
+    /// kWh of all CTs saved so far, split by the time of use period of their timestamps.
+    ///
+    /// Saves count once they are written to the shards, and the totals are stored in
+    /// "<root>/tou_totals" together with the lifetime statistics, see lifetime_stats.
+    pub fn tou_totals(&self) -> TouTotals {
+        self.tou_totals
+    }
+
+    /// Split the kWh of future saves by `schedule`. Defaults to TouSchedule::default.
+    ///
+    /// Set it before load_tou_totals, which uses it to rebuild missing totals. A change only
+    /// applies to the saves after it, the totals so far stay as they were split.
+    pub(crate) fn set_tou_schedule(&mut self, schedule: TouSchedule) {
+        self.tou_schedule = schedule;
+    }
+
+    // Store the time of use totals and the sequence number of the last save they count,
+    // replacing the file atomically like save_calibration.
+    fn save_tou_totals(&mut self) -> anyhow::Result<()> {
+        let mut buf = [0_u8; TOU_TOTALS_SIZE];
+        let mut pos = 0;
+        for total in self.tou_totals.totals() {
+            pos += add_f64_to_buf(total, &mut buf, &pos)?;
+        }
+        add_u32_to_buf(&self.tou_sequence, &mut buf, &pos)?;
+        self.fs.write_atomic(&self.path("tou_totals"), &buf)?;
+        Ok(())
+    }
+
+    /// Load the time of use totals from storage, call it after load_sequence.
+    ///
+    /// Like load_lifetime_stats, the saves written after the stored totals are counted from the
+    /// shards, and missing or damaged totals are rebuilt from them.
+    pub fn load_tou_totals(&mut self) -> anyhow::Result<()> {
+        if let Ok(buf) = self.fs.read(&self.path("tou_totals")) {
+            // Files without the sequence number were stored on every save, they count them all.
+            let without_sequence = TOU_TOTALS_SIZE - std::mem::size_of::<u32>();
+            if buf.len() == TOU_TOTALS_SIZE || buf.len() == without_sequence {
+                let mut totals = TouTotals::default();
+                let mut pos = 0;
+                for total in totals.totals_mut() {
+                    *total = read_f64_from_buf(&buf, &mut pos)?;
+                }
+                self.tou_totals = totals;
+                self.tou_sequence = if buf.len() == TOU_TOTALS_SIZE {
+                    read_u32_from_buf(&buf, &mut pos)?
+                } else {
+                    self.sequence
+                };
+                let missed = self.records_after(self.tou_sequence)?;
+                for reading in &missed {
+                    self.tou_totals.add(reading, &self.tou_schedule);
+                    self.tou_sequence = reading.sequence;
+                }
+                info!(
+                    "Loaded time of use totals, counted {} records written after them: {:?}",
+                    missed.len(),
+                    self.tou_totals
+                );
+                if !missed.is_empty() {
+                    self.save_tou_totals()?;
+                }
+                return Ok(());
+            }
+        }
+
+        warn!("No time of use totals, rebuilding them from the shards.");
+        self.boot.tou_totals_rebuilt = true;
+        let mut totals = TouTotals::default();
+        for &shard_id in &self.readings_shards {
+            for (_, reading) in self.read_shard(shard_id)? {
+                totals.add(&reading, &self.tou_schedule);
+            }
+        }
+        self.tou_totals = totals;
+        self.tou_sequence = self.sequence;
+        self.save_tou_totals()
+    }
+
+    /// The settings and counters of the device in one blob, for a backup or to provision another
+    /// device with import_state.
+    ///
+    /// Holds the calibration, voltage transformer ratio and energy total of every CT, the sequence
+    /// number, the time of use totals and the peak demand, not the shards. The layout is the
+    /// STATE_VERSION byte, the number of phases, the sequence number, the time of use totals, the
+    /// peak demand and its timestamp, then per CT its calibration and voltage transformer ratio as
+    /// in "<root>/calibration" followed by its energy total, all little endian, and a crc16 of
+    /// everything before it.
+    pub(crate) fn export_state(&self, cts: &[CT; AC_PHASE]) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0_u8; STATE_SIZE];
+        buf[0] = STATE_VERSION;
+        buf[1] = AC_PHASE as u8;
+        let mut pos = 2;
+        pos += add_u32_to_buf(&self.sequence, &mut buf, &pos)?;
+        for total in self.tou_totals.totals() {
+            pos += add_f64_to_buf(total, &mut buf, &pos)?;
+        }
+        pos += add_f32_to_buf(&self.stats.peak_demand, &mut buf, &pos)?;
+        pos += add_u64_to_buf(&self.stats.peak_demand_at, &mut buf, &pos)?;
+        for ct in cts {
+            let cal = ct.calibration();
+            pos += add_u16_to_buf(&ct.id, &mut buf, &pos)?;
+            pos += add_f32_to_buf(&cal.vcal, &mut buf, &pos)?;
+            pos += add_f32_to_buf(&cal.ical, &mut buf, &pos)?;
+            pos += add_f32_to_buf(&cal.phase_cal, &mut buf, &pos)?;
+            pos += add_f32_to_buf(&ct.vt_ratio(), &mut buf, &pos)?;
+            pos += add_f64_to_buf(&ct.energy_total_kwh, &mut buf, &pos)?;
+        }
+        let crc = crc16(&buf[..pos]);
+        add_u16_to_buf(&crc, &mut buf, &pos)?;
+        Ok(buf)
+    }
+
+    /// Restore a blob of export_state into the CTs and storage.
+    ///
+    /// The whole blob is checked before anything changes: its size, crc and version, the number
+    /// of phases, the CT ids, which must be the ones of `cts`, and the values. The restored state
+    /// is stored right away. The sequence number only ever moves forward, so the saves already on
+    /// this device keep numbers of their own, see mark_synced. Blobs of version 1, from before the
+    /// peak demand and the voltage transformer ratio, leave both as they are.
+    pub(crate) fn import_state(
+        &mut self,
+        cts: &mut [CT; AC_PHASE],
+        bytes: &[u8],
+    ) -> anyhow::Result<()> {
+        let _writing = SaveGuard::acquire(&self.saving)?;
+        let v1 = bytes.first() == Some(&1);
+        let size = if v1 {
+            STATE_SIZE - PEAK_DEMAND_SIZE - std::mem::size_of::<f32>() * AC_PHASE
+        } else {
+            STATE_SIZE
+        };
+        if bytes.len() != size {
+            anyhow::bail!("State is {} bytes, expected {}", bytes.len(), size);
+        }
+        let mut pos = size - 2;
+        if read_u16_from_buf(bytes, &mut pos)? != crc16(&bytes[..size - 2]) {
+            anyhow::bail!("State has a bad crc");
+        }
+        if bytes[0] != STATE_VERSION && !v1 {
+            anyhow::bail!(
+                "State version {} isn't supported, expected {}",
+                bytes[0],
+                STATE_VERSION
+            );
+        }
+        if bytes[1] as usize != AC_PHASE {
+            anyhow::bail!(
+                "State is of {} phases, this device has {}",
+                bytes[1],
+                AC_PHASE
+            );
+        }
+
+        let mut pos = 2;
+        let sequence = read_u32_from_buf(bytes, &mut pos)?;
+        let mut tou_totals = TouTotals::default();
+        for total in tou_totals.totals_mut() {
+            *total = read_f64_from_buf(bytes, &mut pos)?;
+        }
+        let peak_demand = if v1 {
+            None
+        } else {
+            Some((
+                read_f32_from_buf(bytes, &mut pos)?,
+                read_u64_from_buf(bytes, &mut pos)?,
+            ))
+        };
+        let mut restored = Vec::with_capacity(AC_PHASE);
+        for ct in cts.iter() {
+            let id = read_u16_from_buf(bytes, &mut pos)?;
+            let cal = Calibration {
+                vcal: read_f32_from_buf(bytes, &mut pos)?,
+                ical: read_f32_from_buf(bytes, &mut pos)?,
+                phase_cal: read_f32_from_buf(bytes, &mut pos)?,
+            };
+            let vt_ratio = if v1 {
+                ct.vt_ratio()
+            } else {
+                read_f32_from_buf(bytes, &mut pos)?
+            };
+            let total = read_f64_from_buf(bytes, &mut pos)?;
+            if id != ct.id {
+                anyhow::bail!("State has CT {} where this device has CT {}", id, ct.id);
+            }
+            if !cal.is_valid() {
+                anyhow::bail!("State has an invalid calibration of CT {}: {:?}", id, cal);
+            }
+            if !(vt_ratio > 0.0 && vt_ratio.is_finite()) {
+                anyhow::bail!(
+                    "State has an invalid voltage transformer ratio of CT {}: {}",
+                    id,
+                    vt_ratio
+                );
+            }
+            if !total.is_finite() {
+                anyhow::bail!("State has an invalid energy total of CT {}: {}", id, total);
+            }
+            restored.push((cal, vt_ratio, total));
+        }
+        if tou_totals.totals().iter().any(|total| !total.is_finite()) {
+            anyhow::bail!("State has invalid time of use totals: {:?}", tou_totals);
+        }
+        if let Some((peak, _)) = peak_demand.filter(|(peak, _)| !peak.is_finite()) {
+            anyhow::bail!("State has an invalid peak demand: {}", peak);
+        }
+
+        for (ct, &(cal, vt_ratio, total)) in cts.iter_mut().zip(&restored) {
+            ct.set_calibration(cal);
+            ct.set_vt_ratio(vt_ratio);
+            ct.energy_total_kwh = total;
+        }
+        self.tou_totals = tou_totals;
+        if let Some((peak, at)) = peak_demand {
+            self.stats.peak_demand = peak;
+            self.stats.peak_demand_at = at;
+        }
+        if sequence > self.sequence {
+            self.sequence = sequence;
+            self.fs
+                .write_atomic(&self.path("sequence"), &self.sequence.to_le_bytes())?;
+        }
+        self.tou_sequence = self.sequence;
+        self.save_calibration(cts)?;
+        self.save_energy_totals(cts)?;
+        self.save_tou_totals()?;
+        self.save_lifetime_stats()?;
+        info!("Imported state, last sequence number is {}", self.sequence);
+        Ok(())
+    }
+
+    /// What was loaded at boot and what defaulted, together with the shards as they are now.
+    ///
+    /// Call it after the loads of the boot (find_newest_readings_shard_num, load_persisted_state,
+    /// load_calibration, ...), which record what they find. Counts the records of every shard,
+    /// which only reads their sizes.
+    pub(crate) fn boot_report(&self) -> anyhow::Result<BootReport> {
+        let mut report = BootReport {
+            storage_available: self.available,
+            newest_shard: self.readings_shard_counter,
+            shards: self.readings_shards.len(),
+            sequence: self.sequence,
+            ..self.boot
+        };
+        if self.available {
+            // One shard that can't be read doesn't hide the state of the others.
+            let mut unreadable = HashSet::new();
+            for &shard_id in &self.readings_shards {
+                let size = match self.fs.file_size(&self.shard_path(shard_id)) {
+                    Ok(size) => size,
+                    Err(err) => {
+                        warn!(
+                            "Can't read the size of shard {}, skipping it: {}",
+                            shard_id, err
+                        );
+                        unreadable.insert(shard_id);
+                        continue;
+                    }
+                };
+                let (records, trailing) = self.shard_records(size);
+                report.records += records;
+                if trailing != 0 {
+                    report.damaged_shards += 1;
+                }
+            }
+            if self.integrity_scan {
+                let mut corrupt = 0;
+                for &shard_id in &self.readings_shards {
+                    if unreadable.contains(&shard_id) {
+                        continue;
+                    }
+                    match self.count_corrupt_records(shard_id) {
+                        Ok(count) => corrupt += count,
+                        Err(err) => {
+                            warn!("Can't scan shard {}, skipping it: {}", shard_id, err);
+                            unreadable.insert(shard_id);
+                        }
+                    }
+                }
+                report.corrupt_records = Some(corrupt);
+            }
+            report.unreadable_shards = unreadable.len();
+        }
+        Ok(report)
+    }
+
+    /// Read every record of every shard in boot_report and count the ones that don't make sense in
+    /// corrupt_records. Defaults to INTEGRITY_SCAN.
+    ///
+    /// Records carry no checksum, so a record counts as corrupt if it can't be decoded, belongs to
+    /// no CT of this device, has a value that is not finite, or a sequence number after the last
+    /// save. Catches flash corruption at boot instead of at the next upload, at the cost of
+    /// reading all of the storage, which can take seconds on a full device.
+    pub(crate) fn set_integrity_scan(&mut self, enabled: bool) {
+        self.integrity_scan = enabled;
+    }
+
+    // Count the corrupt records of a shard, see set_integrity_scan. Bypasses the shard cache, which
+    // a scan of every shard would only flush.
+    fn count_corrupt_records(&self, shard_id: i32) -> anyhow::Result<usize> {
+        let mut file = self.fs.open(&self.shard_path(shard_id), OpenMode::Read)?;
+        file.seek(SeekFrom::Start(SHARD_HEADER_SIZE as u64))?;
+        let mut buf = [0_u8; CT_READING_SIZE];
+        let buf = &mut buf[..self.record_size()];
+        let mut corrupt = 0;
+        let mut index = 0;
+        while read_record(&mut file, buf)? {
+            let sound = match self.decode(buf) {
+                Ok((id, reading)) => {
+                    (1..=AC_PHASE as u16).contains(&id)
+                        && reading.values().iter().all(|(_, value)| value.is_finite())
+                        && reading.sequence <= self.sequence
+                }
+                Err(_) => false,
+            };
+            if !sound {
+                warn!("Shard {}: record {} is corrupt", shard_id, index);
+                corrupt += 1;
+            }
+            index += 1;
+        }
+        Ok(corrupt)
+    }
+
+    /// Sequence number of the last saved records.
+    ///
+    /// Unlike the timestamps, which jump when the clock is corrected, sequence numbers only ever
+    /// increase, also across reboots and storage resets. Records can be ordered and deduplicated by
+    /// (sequence, id).
+    pub(crate) fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// All records with a sequence number above `seq`, oldest first.
+    ///
+    /// For incremental sync: a backend that has seen everything up to sequence `seq` gets the rest.
+    /// The last save at or below `seq` is looked up in the sequence index, which every save adds
+    /// an entry to, with a binary search, and the records are read on from there. So the cost
+    /// doesn't grow with the number of shards before it. A save missing from the index only makes
+    /// the read start earlier, as it does for the older saves once the index is thinned to
+    /// SEQUENCE_INDEX_MAX_ENTRIES. Without an entry at or below `seq`, e.g. before the index
+    /// existed, the shards are looked at from the newest back, and only as far as the first one
+    /// that starts at or below `seq`, since sequence numbers only increase. Records of a save that
+    /// was dropped from the RAM buffer are missing, a gap in the sequence numbers shows that.
+    pub(crate) fn readings_since(&self, seq: u32) -> anyhow::Result<Vec<(u16, CTReading)>> {
+        if let Some((start_shard, offset)) = self.index_lookup(seq)? {
+            if self.readings_shards.contains(&start_shard) {
+                let mut shard_ids = self
+                    .readings_shards
+                    .iter()
+                    .copied()
+                    .filter(|&shard_id| shard_id >= start_shard)
+                    .collect::<Vec<i32>>();
+                shard_ids.sort_unstable();
+                let mut readings = Vec::new();
+                for shard_id in shard_ids {
+                    let skip = if shard_id == start_shard {
+                        offset as usize / self.record_size()
+                    } else {
+                        0
+                    };
+                    readings.extend(
+                        self.read_shard(shard_id)?
+                            .into_iter()
+                            .skip(skip)
+                            .filter(|(_, reading)| reading.sequence > seq),
+                    );
+                }
+                return Ok(readings);
+            }
+        }
+
+        let mut sorted_shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
+        sorted_shard_ids.sort_unstable_by(|a, b| b.cmp(a));
+        let mut shard_ids = Vec::new();
+        for shard_id in sorted_shard_ids {
+            if self.record_count(shard_id)? == 0 {
+                continue;
+            }
+            shard_ids.push(shard_id);
+            let (_, first) = self.read_record(shard_id, 0)?;
+            if first.sequence <= seq {
+                break;
+            }
+        }
+
+        let mut readings = Vec::new();
+        for shard_id in shard_ids.into_iter().rev() {
+            readings.extend(
+                self.read_shard(shard_id)?
+                    .into_iter()
+                    .filter(|(_, reading)| reading.sequence > seq),
+            );
+        }
+        Ok(readings)
+    }
+
+    // Retrieve the sequence number of the last save from storage, and of the last synced one.
+    // "<root>/sequence" is only stored when a shard fills up, the saves since then are in the
+    // newest shard that has records. The last of its records has the sequence number of the last
+    // save.
+    pub(crate) fn load_sequence(&mut self) -> anyhow::Result<()> {
+        let mut bytes = [0_u8; std::mem::size_of::<u32>()];
+        if let Ok(buf) = self.fs.read(&self.path("sequence")) {
+            if buf.len() == bytes.len() {
+                bytes.copy_from_slice(&buf);
+                self.sequence = u32::max(self.sequence, u32::from_le_bytes(bytes));
+            }
+        }
+        let mut shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
+        shard_ids.sort_unstable();
+        let newest =
+            shard_ids
+                .into_iter()
+                .rev()
+                .find_map(|shard_id| match self.record_count(shard_id) {
+                    Ok(count) if count > 0 => Some((shard_id, count)),
+                    _ => None,
+                });
+        if let Some((shard_id, count)) = newest {
+            if let Ok((_, last)) = self.read_record(shard_id, count - 1) {
+                self.sequence = u32::max(self.sequence, last.sequence);
+            }
+        }
+        if let Ok(buf) = self.fs.read(&self.path("synced")) {
+            if buf.len() == bytes.len() {
+                bytes.copy_from_slice(&buf);
+                self.synced = u32::from_le_bytes(bytes);
+            }
+        }
+        info!(
+            "Last sequence number is {}, synced up to {}",
+            self.sequence, self.synced
+        );
+        Ok(())
+    }
+
+    /// Record that a backend has synced every save up to sequence number `seq`.
+    ///
+    /// The high-water mark of unsynced_bytes, stored in "<root>/synced". It only moves forward.
+    /// A backend acknowledges what it got from /telemetry by posting the sequence number of the
+    /// last record to /synced, 4 bytes little endian.
+    pub(crate) fn mark_synced(&mut self, seq: u32) -> anyhow::Result<()> {
+        let seq = u32::min(seq, self.sequence);
+        if seq > self.synced {
+            self.synced = seq;
+            self.fs
+                .write_atomic(&self.path("synced"), &self.synced.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Size in bytes of the records of the saves after the synced high-water mark, see mark_synced.
+    ///
+    /// Counted from the sequence numbers, so it includes saves still buffered in RAM, and saves
+    /// that were dropped from the RAM buffer until the mark passes them.
+    pub(crate) fn unsynced_bytes(&self) -> u64 {
+        let saves = self.sequence.saturating_sub(self.synced) as u64;
+        saves * (AC_PHASE * self.record_size()) as u64
+    }
+
+    /// Act on `policy` once unsynced_bytes exceeds `limit`, None lifts the limit. No limit by
+    /// default.
+    ///
+    /// For a device whose backend is out of reach for a while: in Ring mode every save deletes the
+    /// oldest shards until the rest fits within the limit again, which moves the synced mark past
+    /// them since they can't be synced any more. The shard being appended to is kept, so the
+    /// limit is rounded up to whole shards. With BackPressure save_to_storage fails and drops the
+    /// readings until mark_synced brings the unsynced bytes back within the limit. Either way up
+    /// to `limit` bytes are lost if the device dies before the next sync.
+    pub(crate) fn set_unsynced_limit(&mut self, limit: Option<u64>, policy: UnsyncedPolicy) {
+        self.unsynced_limit = limit.map(|limit| (limit, policy));
+    }
+
+    /// Whether save_to_storage is paused until the backend catches up, see set_unsynced_limit.
+    pub(crate) fn is_back_pressured(&self) -> bool {
+        match self.unsynced_limit {
+            Some((limit, UnsyncedPolicy::BackPressure)) => self.unsynced_bytes() >= limit,
+            _ => false,
+        }
+    }
+
+    // Delete the oldest shards until the unsynced bytes are within `limit`, keeping the current
+    // one, and move the synced mark past their records.
+    fn drop_unsynced_over(&mut self, limit: u64) -> anyhow::Result<()> {
+        let mut deleted = false;
+        while self.unsynced_bytes() > limit {
+            let oldest = match self.readings_shards.iter().copied().min() {
+                Some(oldest) if oldest != self.readings_shard_counter => oldest,
+                _ => break,
+            };
+            let count = self.record_count(oldest)?;
+            let last = if count > 0 {
+                Some(self.read_record(oldest, count - 1)?.1.sequence)
+            } else {
+                None
+            };
+            self.fs.remove_file(&self.shard_path(oldest))?;
+            self.cache.get_mut().remove(oldest);
+            self.readings_shards.remove(&oldest);
+            deleted = true;
+            warn!(
+                "Unsynced data over {} bytes, deleted shard {}",
+                limit, oldest
+            );
+            if let Some(last) = last {
+                self.mark_synced(last)?;
+            }
+        }
+        if deleted {
+            self.prune_sequence_index()?;
+        }
+        Ok(())
+    }
+
+    /// Keep up to `budget` bytes of recently read shards in RAM, 0, the default, turns it off.
+    ///
+    /// For dashboards that run several queries over the same recent shards: read_shard,
+    /// read_record, shard_summary, shard_cost_with, validate_shard_ordering and content_hash read a
+    /// cached shard from RAM instead of flash. The least recently used shards are evicted first,
+    /// shards larger than the budget are never cached. A shard is dropped from the cache whenever
+    /// it is written or deleted. While on, the readers hold whole shards in RAM even if they
+    /// otherwise stream them.
+    pub(crate) fn set_shard_cache(&mut self, budget: usize) {
+        self.cache.get_mut().set_budget(budget);
+    }
+
+    /// Hits, misses and evictions of the shard cache, see set_shard_cache.
+    pub(crate) fn shard_cache_stats(&self) -> CacheStats {
+        self.cache.borrow().stats()
+    }
+
+    // Open a shard for reading at its first record, through the cache if it is on, see
+    // set_shard_cache.
+    fn open_shard(&self, shard_id: i32) -> anyhow::Result<Box<dyn StorageFile>> {
+        let path = self.shard_path(shard_id);
+        let mut cache = self.cache.borrow_mut();
+        let mut file: Box<dyn StorageFile> = if !cache.is_enabled() {
+            self.fs.open(&path, OpenMode::Read)?
+        } else if let Some(data) = cache.get(shard_id) {
+            Box::new(CachedFile::new(data))
+        } else {
+            let data: Arc<[u8]> = self.fs.read(&path)?.into();
+            cache.insert(shard_id, data.clone());
+            Box::new(CachedFile::new(data))
+        };
+        file.seek(SeekFrom::Start(SHARD_HEADER_SIZE as u64))?;
+        Ok(file)
+    }
+
+    // Complete records and the bytes of an incomplete one in a shard of `size` bytes, counted
+    // after its header. A shard cut off within its header has only those bytes.
+    fn shard_records(&self, size: u64) -> (usize, usize) {
+        let size = size as usize;
+        if size < SHARD_HEADER_SIZE {
+            return (0, size);
+        }
+        let records = size - SHARD_HEADER_SIZE;
+        (records / self.record_size(), records % self.record_size())
+    }
+
+    /// Read all the records of a shard.
+    pub(crate) fn read_shard(&self, shard_id: i32) -> anyhow::Result<Vec<(u16, CTReading)>> {
+        let mut file = self.open_shard(shard_id)?;
+        let mut buf = [0_u8; CT_READING_SIZE];
+        let buf = &mut buf[..self.record_size()];
+        let mut readings = Vec::new();
+        while read_record(&mut file, buf)? {
+            readings.push(self.decode(buf)?);
+        }
+        Ok(readings)
+    }
+
+    /// At most `target` records of each CT of a shard, for a preview over a slow link.
+    ///
+    /// The records of each CT are split into `target` runs of consecutive records, as even as
+    /// they divide, and each run is combined into one record like the measurements of a save
+    /// period, see Accumulator. The kWh of a run are summed, so the energy of the shard is kept.
+    /// A combined record has the timestamp and sequence number of the last record of its run. A
+    /// CT with no more than `target` records keeps all of them. Ordered by sequence number.
+    pub(crate) fn decimate_shard(
+        &self,
+        shard_id: i32,
+        target: usize,
+    ) -> anyhow::Result<Vec<(u16, CTReading)>> {
+        let records = self.read_shard(shard_id)?;
+        let mut ids: Vec<u16> = Vec::new();
+        for (id, _) in &records {
+            if !ids.contains(id) {
+                ids.push(*id);
+            }
+        }
+
+        let mut decimated = Vec::new();
+        for id in ids {
+            let readings: Vec<&CTReading> = records
+                .iter()
+                .filter(|(record_id, _)| *record_id == id)
+                .map(|(_, reading)| reading)
+                .collect();
+            if readings.len() <= target {
+                decimated.extend(readings.into_iter().map(|reading| (id, reading.clone())));
+                continue;
+            }
+            for run in 0..target {
+                let start = run * readings.len() / target;
+                let end = (run + 1) * readings.len() / target;
+                let mut accumulator = Accumulator::default();
+                for reading in &readings[start..end] {
+                    accumulator.push(reading);
+                }
+                let mut reading = accumulator.finalize();
+                reading.sequence = readings[end - 1].sequence;
+                decimated.push((id, reading));
+            }
+        }
+        decimated.sort_by_key(|(_, reading)| reading.sequence);
+        Ok(decimated)
+    }
+
+    /// Byte offset of the record at `index` in a shard. Records are fixed size, so this is just
+    /// the shard header and `index` times the record size of the schema, whether the shard has
+    /// that many records is up to read_record.
+    pub(crate) fn record_offset(&self, index: usize) -> u64 {
+        (SHARD_HEADER_SIZE + index * self.record_size()) as u64
+    }
+
+    /// Number of complete records in a shard.
+    pub(crate) fn record_count(&self, shard_id: i32) -> anyhow::Result<usize> {
+        let size = self.fs.file_size(&self.shard_path(shard_id))?;
+        Ok(self.shard_records(size).0)
+    }
+
+    /// Read the record at `index` of a shard with a seek, without reading the records before it.
+    ///
+    /// For paging through large shards, together with record_count. Fails if the shard has no
+    /// complete record at `index`.
+    pub(crate) fn read_record(
+        &self,
+        shard_id: i32,
+        index: usize,
+    ) -> anyhow::Result<(u16, CTReading)> {
+        let mut file = self.open_shard(shard_id)?;
+        let offset = self.record_offset(index);
+        let size = file.size()?;
+        if offset + self.record_size() as u64 > size {
+            anyhow::bail!(
+                "Shard {} has {} records, no record {}",
+                shard_id,
+                self.shard_records(size).0,
+                index
+            );
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = [0_u8; CT_READING_SIZE];
+        let buf = &mut buf[..self.record_size()];
+        file.read_exact(buf)?;
+        self.decode(buf)
+    }
+
+    /// Cost of the energy of all records of a shard at a flat `rate_per_kwh`, see
+    /// CTReading::estimated_cost.
+    pub(crate) fn shard_cost(&self, shard_id: i32, rate_per_kwh: f32) -> anyhow::Result<f32> {
+        self.shard_cost_with(shard_id, &rate_per_kwh)
+    }
+
+    /// Cost of the energy of all records of a shard under `tariff`, each at the rate of its
+    /// timestamp. Reads the shard a record at a time.
+    pub(crate) fn shard_cost_with(
+        &self,
+        shard_id: i32,
+        tariff: &dyn Tariff,
+    ) -> anyhow::Result<f32> {
+        let mut file = self.open_shard(shard_id)?;
+        let mut buf = [0_u8; CT_READING_SIZE];
+        let buf = &mut buf[..self.record_size()];
+        let mut cost = 0.0;
+        while read_record(&mut file, buf)? {
+            let (_, reading) = self.decode(buf)?;
+            cost += reading.cost(tariff);
+        }
+        Ok(cost)
+    }
+
+    /// Summarize the records of a shard in a single pass, without loading them all into memory.
+    ///
+    /// An empty shard gives a summary with 0 records. A shard whose size is not a multiple of
+    /// the record size is summarized up to the last complete record and the rest is reported in
+    /// trailing_bytes.
+    pub(crate) fn shard_summary(&self, shard_id: i32) -> anyhow::Result<ShardSummary> {
+        let mut file = self.open_shard(shard_id)?;
+        let mut summary = ShardSummary {
+            trailing_bytes: self.shard_records(file.size()?).1,
+            ..Default::default()
+        };
+        let mut buf = [0_u8; CT_READING_SIZE];
+        let buf = &mut buf[..self.record_size()];
+        while read_record(&mut file, buf)? {
+            let (_, reading) = self.decode(buf)?;
+            summary.records += 1;
+            summary.real_power.add(reading.real_power, summary.records);
+            summary.i_rms.add(reading.i_rms, summary.records);
+            summary.v_rms.add(reading.v_rms, summary.records);
+            summary.total_kwh += reading.kwh as f64;
+        }
+        if summary.trailing_bytes != 0 {
+            warn!(
+                "Shard {} ends with {} bytes of an incomplete record",
+                shard_id, summary.trailing_bytes
+            );
+        }
+        Ok(summary)
+    }
+
+    /// The shards in the readings directory, sorted by id.
+    ///
+    /// Lists the directory again instead of relying on what find_newest_readings_shard_num found,
+    /// and only reads the sizes of the shards, so it is cheap enough for a status page. Files whose
+    /// name is not a shard id are logged and left out.
+    pub(crate) fn list_shards(&self) -> anyhow::Result<Vec<ShardInfo>> {
+        let mut shards = Vec::new();
+        for name in self.fs.read_dir(&self.path("ct_readings"))? {
+            let id: i32 = match name.parse() {
+                Ok(id) => id,
+                Err(_) => {
+                    warn!("Skipping {:?} in the readings directory, not a shard", name);
+                    continue;
+                }
+            };
+            let size = self
+                .fs
+                .file_size(&format!("{}/ct_readings/{}", self.root, name))?;
+            shards.push(ShardInfo {
+                id,
+                size,
+                records: self.shard_records(size).0,
+                active: self.available && id == self.readings_shard_counter,
+            });
+        }
+        shards.sort_by_key(|shard| shard.id);
+        Ok(shards)
+    }
+
+    /// Whether the timestamps of the records of a shard never decrease, streaming it like
+    /// shard_summary.
+    ///
+    /// Time range queries expect them to, a clock that jumped back or a corrupt record breaks
+    /// that. The first record that is older than the one before it is logged. Pair it with
+    /// shard_summary, whose trailing_bytes show a cut off shard, and content_hash.
+    pub(crate) fn validate_shard_ordering(&self, shard_id: i32) -> anyhow::Result<bool> {
+        let mut file = self.open_shard(shard_id)?;
+        let mut buf = [0_u8; CT_READING_SIZE];
+        let buf = &mut buf[..self.record_size()];
+        let mut previous = 0;
+        let mut index = 0;
+        while read_record(&mut file, buf)? {
+            let (_, reading) = self.decode(buf)?;
+            if reading.timestamp < previous {
+                warn!(
+                    "Shard {}: record {} at {} is older than the one before it at {}",
+                    shard_id, index, reading.timestamp, previous
+                );
+                return Ok(false);
+            }
+            previous = reading.timestamp;
+            index += 1;
+        }
+        Ok(true)
+    }
+
+    /// Hash of the records stored in the shards `from_shard` to `to_shard`, both included.
+    ///
+    /// 64 bit FNV-1a over the bytes of every complete record, shard after shard in ascending order,
+    /// exactly as send_readings_shards sends them without the shard headers. A backend that runs
+    /// the same hash over the data it received can tell whether it is in sync with the device
+    /// without downloading it again.
+    pub(crate) fn content_hash(&self, from_shard: i32, to_shard: i32) -> anyhow::Result<u64> {
+        let mut sorted_shard_ids = self
+            .readings_shards
+            .iter()
+            .copied()
+            .filter(|id| (from_shard..=to_shard).contains(id))
+            .collect::<Vec<i32>>();
+        sorted_shard_ids.sort();
+        let mut hash = FNV1A_64_INIT;
+        let mut buf = [0_u8; CT_READING_SIZE];
+        let buf = &mut buf[..self.record_size()];
+        for shard_id in sorted_shard_ids {
+            let mut file = self.open_shard(shard_id)?;
+            while read_record(&mut file, buf)? {
+                hash = fnv1a_64(hash, buf);
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Delete the shards whose records are all older than `max_age` at `now_ms`.
+    ///
+    /// A shard is only deleted if every record in it is outside the retention window, so no
+    /// reading younger than `max_age` is ever lost. The shard currently being appended to is
+    /// always kept. Returns the number of deleted shards.
+    pub(crate) fn prune_older_than(
+        &mut self,
+        max_age: std::time::Duration,
+        now_ms: u64,
+    ) -> anyhow::Result<usize> {
+        let cutoff = now_ms.saturating_sub(max_age.as_millis() as u64);
+        let mut sorted_shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
+        sorted_shard_ids.sort();
+        let mut count = 0;
+        for shard_id in sorted_shard_ids {
+            if shard_id == self.readings_shard_counter {
+                continue;
+            }
+            let readings = self.read_shard(shard_id)?;
+            if readings.is_empty()
+                || readings
+                    .iter()
+                    .any(|(_, reading)| reading.timestamp >= cutoff)
+            {
+                continue;
+            }
+            self.fs.remove_file(&self.shard_path(shard_id))?;
+            self.cache.get_mut().remove(shard_id);
+            self.readings_shards.remove(&shard_id);
+            info!("Pruned shard {}", shard_id);
+            count += 1;
+        }
+        if count > 0 {
+            self.prune_sequence_index()?;
+        }
+        Ok(count)
+    }
+
+    /// Recompute the kWh of every stored record as real_power over `actual_period`.
+    ///
+    /// Records saved with the old kWh formula, which divided by SAVE_PERIOD_TIMEOUT instead of
+    /// converting to hours, can be fixed with this. `actual_period` is the save period the records
+    /// were stored with. The kWh keep the sign they were stored with, so whatever the power
+    /// convention of the real power and NegativeEnergy did to them stays: only the magnitude comes
+    /// from the real power, and kWh dropped to 0 stay 0. Each shard is rewritten with write_atomic,
+    /// so a power loss leaves every shard either fully old or fully recomputed.
+    /// Returns the number of records that were rewritten.
+    pub(crate) fn recompute_energy(
+        &mut self,
+        actual_period: std::time::Duration,
+    ) -> anyhow::Result<usize> {
+        let _writing = SaveGuard::acquire(&self.saving)?;
+        let hours = actual_period.as_secs_f32() / 3600.0;
+        let mut sorted_shard_ids = self.readings_shards.iter().copied().collect::<Vec<i32>>();
+        sorted_shard_ids.sort();
+        let mut count = 0;
+        for shard_id in sorted_shard_ids {
+            let readings = self.read_shard(shard_id)?;
+            let mut buf = CTStorage::shard_header(self.byte_order, self.schema).to_vec();
+            for (id, mut reading) in readings {
+                let kwh = f32::abs(reading.real_power) / 1000.0 * hours;
+                reading.kwh = if reading.kwh == 0.0 {
+                    0.0
+                } else {
+                    kwh.copysign(reading.kwh)
+                };
+                self.encode(id, &reading, reading.sequence, &mut buf)?;
+                count += 1;
+            }
+            self.fs.write_atomic(&self.shard_path(shard_id), &buf)?;
+            self.cache.get_mut().remove(shard_id);
+            info!("Recomputed energy of shard {}", shard_id);
+        }
+        Ok(count)
+    }
+
+    pub(crate) fn ct_reading_to_le_bytes(
+        id: u16,
+        reading: &CTReading,
+        sequence: u32,
+    ) -> anyhow::Result<[u8; CT_READING_SIZE]> {
+        CTStorage::ct_reading_to_bytes(id, reading, sequence, ByteOrder::Little)
+    }
+
+    pub(crate) fn ct_reading_to_bytes(
+        id: u16,
+        reading: &CTReading,
+        sequence: u32,
+        order: ByteOrder,
+    ) -> anyhow::Result<[u8; CT_READING_SIZE]> {
+        let mut buf = [0_u8; CT_READING_SIZE];
+        CTStorage::encode_record(
+            id,
+            reading,
+            sequence,
+            order,
+            RecordSchema::default(),
+            &mut buf,
+        )?;
+        Ok(buf)
+    }
+
+    // Write the record of `reading` with the metrics of `schema` to the start of `buf`, which must
+    // hold schema.record_size() bytes. Returns the size of the record.
+    fn encode_record(
+        id: u16,
+        reading: &CTReading,
+        sequence: u32,
+        order: ByteOrder,
+        schema: RecordSchema,
+        buf: &mut [u8],
+    ) -> anyhow::Result<usize> {
+        let (add_u16, add_u32, add_u64, add_f32): (AddFn<u16>, AddFn<u32>, AddFn<u64>, AddFn<f32>) =
+            match order {
+                ByteOrder::Little => (
+                    add_u16_to_buf,
+                    add_u32_to_buf,
+                    add_u64_to_buf,
+                    add_f32_to_buf,
+                ),
+                ByteOrder::Big => (
+                    add_u16_to_buf_be,
+                    add_u32_to_buf_be,
+                    add_u64_to_buf_be,
+                    add_f32_to_buf_be,
+                ),
+            };
+        let mut pos = 0;
+        pos += add_u16(&id, buf, &pos)?;
+        for ((_, value), stored) in reading.values().iter().zip(schema.fields()) {
+            if stored {
+                pos += add_f32(value, buf, &pos)?;
+            }
+        }
+        pos += add_u64(&reading.timestamp, buf, &pos)?;
+        if schema.uptime {
+            pos += add_u64(&reading.uptime, buf, &pos)?;
+        }
+        pos += add_u32(&sequence, buf, &pos)?;
+        if schema.flags {
+            match buf.get_mut(pos) {
+                Some(byte) => *byte = reading.flags().bits(),
+                None => anyhow::bail!("Buffer too small for the flags at {}", pos),
+            }
+            pos += 1;
+        }
+        if schema.quality {
+            match buf.get_mut(pos) {
+                Some(byte) => *byte = reading.quality.unwrap_or(NO_QUALITY),
+                None => anyhow::bail!("Buffer too small for the quality at {}", pos),
+            }
+            pos += 1;
+        }
+        Ok(pos)
+    }
+
+    // Read a record with the metrics of `schema` from the start of `buf`.
+    fn decode_record(
+        buf: &[u8],
+        order: ByteOrder,
+        schema: RecordSchema,
+    ) -> anyhow::Result<(u16, CTReading)> {
+        let (read_u16, read_u32, read_u64, read_f32): (
+            ReadFn<u16>,
+            ReadFn<u32>,
+            ReadFn<u64>,
+            ReadFn<f32>,
+        ) = match order {
+            ByteOrder::Little => (
+                read_u16_from_buf,
+                read_u32_from_buf,
+                read_u64_from_buf,
+                read_f32_from_buf,
+            ),
+            ByteOrder::Big => (
+                read_u16_from_buf_be,
+                read_u32_from_buf_be,
+                read_u64_from_buf_be,
+                read_f32_from_buf_be,
+            ),
+        };
+        let mut pos = 0;
+        let id = read_u16(buf, &mut pos)?;
+        let mut metrics = [0.0_f32; 5];
+        for (metric, stored) in metrics.iter_mut().zip(schema.fields()) {
+            if stored {
+                *metric = read_f32(buf, &mut pos)?;
+            }
+        }
+        let [real_power, apparent_power, i_rms, v_rms, kwh] = metrics;
+        let timestamp = read_u64(buf, &mut pos)?;
+        let uptime = if schema.uptime {
+            read_u64(buf, &mut pos)?
+        } else {
+            0
+        };
+        let sequence = read_u32(buf, &mut pos)?;
+        let mut reading = CTReading::default();
+        reading.real_power = real_power;
+        reading.apparent_power = apparent_power;
+        reading.i_rms = i_rms;
+        reading.v_rms = v_rms;
+        reading.kwh = kwh;
+        reading.timestamp = timestamp;
+        reading.uptime = uptime;
+        reading.sequence = sequence;
+        if schema.flags {
+            match buf.get(pos) {
+                Some(&bits) => reading.set_flags(ReadingFlags::from_bits(bits)),
+                None => anyhow::bail!("Buffer too small for the flags at {}", pos),
+            }
+            pos += 1;
+        }
+        if schema.quality {
+            match buf.get(pos) {
+                Some(&NO_QUALITY) => {}
+                Some(&quality) => reading.quality = Some(quality),
+                None => anyhow::bail!("Buffer too small for the quality at {}", pos),
+            }
+        }
+        Ok((id, reading))
+    }
+}
+
+/// Best effort flush of the saves still buffered in RAM, also those kept for a batch.
+///
+/// Drop can't report errors, so whatever can't be written is logged and lost. Call
+/// CTStorage::shutdown instead where the end is known, it also persists the running readings and
+/// reports failures.
+impl Drop for CTStorage {
+    fn drop(&mut self) {
+        let _writing = SaveGuard::wait(&self.saving);
+        if self.buffered.is_empty() {
+            self.store_stats(true);
+            return;
+        }
+        if !self.available {
+            if let Err(err) = self.recover_storage() {
+                error!("Can't recover the storage on drop: {}", err);
+            }
+        }
+        self.write_buffered();
+        self.store_stats(true);
+        if !self.buffered.is_empty() {
+            error!(
+                "Dropped storage with {} unwritten saves, they are lost.",
+                self.buffered.len()
+            );
+        }
+    }
+}
+
+// Every other entry of the sequence index in `buf`, starting with the first, until there are at
+// most SEQUENCE_INDEX_MAX_ENTRIES. The saves in between start a read at the entry before them,
+// so the oldest saves keep an entry and the index stays bounded as the shards grow.
+fn thin_sequence_index(buf: &[u8]) -> Vec<u8> {
+    let mut entries: Vec<&[u8]> = buf.chunks_exact(SEQUENCE_INDEX_ENTRY_SIZE).collect();
+    while entries.len() > SEQUENCE_INDEX_MAX_ENTRIES {
+        entries = entries.into_iter().step_by(2).collect();
+    }
+    entries.concat()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ct::tests::{centred_ct, mock_sine_ct, reading, sine_samples, test_cts};
+    use crate::ct::{replay_waveform, NegativeEnergy};
+    use crate::sampling::tests::test_sampler;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    struct MemFsInner {
+        files: HashMap<String, Vec<u8>>,
+        // Every directory, starting with the one the filesystem is mounted at.
+        dirs: HashSet<String>,
+    }
+
+    impl MemFsInner {
+        // Files and directories can only be created in a directory that exists, as on littlefs.
+        fn check_parent(&self, path: &str) -> io::Result<()> {
+            let dir = parent_dir(path);
+            if self.dirs.contains(dir) {
+                Ok(())
+            } else {
+                Err(not_found(dir))
+            }
+        }
+    }
+
+    /// A filesystem that only lives in RAM.
+    ///
+    /// Lets the storage logic (shard rollover, sequence numbers, recovery) run off-device, e.g.
+    /// on a host. Clones share the same files, so a clone can be kept to inspect what CTStorage
+    /// wrote. Paths outside the directory it is mounted at don't exist, like those of a partition
+    /// that isn't mounted.
+    #[derive(Clone)]
+    pub(crate) struct MemFs {
+        inner: Arc<Mutex<MemFsInner>>,
+    }
+
+    impl MemFs {
+        /// Mounted at the root of the first of STORAGE_MOUNTS.
+        pub(crate) fn new() -> Self {
+            MemFs::mounted_at(STORAGE_MOUNTS[0].root)
+        }
+
+        /// Mounted at `root`, e.g. "/spiffs" to stand in for a board that mounts its partition
+        /// there.
+        pub(crate) fn mounted_at(root: &str) -> Self {
+            let mut dirs = HashSet::new();
+            dirs.insert(root.trim_end_matches('/').to_string());
+            MemFs {
+                inner: Arc::new(Mutex::new(MemFsInner {
+                    files: HashMap::new(),
+                    dirs,
+                })),
+            }
+        }
+
+        fn inner(&self) -> std::sync::MutexGuard<'_, MemFsInner> {
+            match self.inner.lock() {
+                Ok(gaurd) => gaurd,
+                Err(poisoned) => poisoned.into_inner(),
+            }
+        }
+    }
+
+    fn not_found(path: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, path.to_string())
+    }
+
+    // Directory part of a path, "" for a file in the root.
+    fn parent_dir(path: &str) -> &str {
+        path.rsplit_once('/').map_or("", |(dir, _)| dir)
+    }
+
+    impl Filesystem for MemFs {
+        fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+            let mut inner = self.inner();
+            inner.check_parent(path)?;
+            if inner.dirs.contains(path) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{} is a directory", path),
+                ));
+            }
+            match mode {
+                OpenMode::Read if !inner.files.contains_key(path) => return Err(not_found(path)),
+                OpenMode::Truncate => {
+                    inner.files.insert(path.to_string(), Vec::new());
+                }
+                _ => {
+                    inner.files.entry(path.to_string()).or_default();
+                }
+            }
+            Ok(Box::new(MemFile {
+                fs: self.clone(),
+                path: path.to_string(),
+                pos: 0,
+                append: mode == OpenMode::Append,
+            }))
+        }
+
+        fn file_size(&self, path: &str) -> io::Result<u64> {
+            self.inner()
+                .files
+                .get(path)
+                .map(|data| data.len() as u64)
+                .ok_or_else(|| not_found(path))
+        }
+
+        fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+            let inner = self.inner();
+            if !inner.dirs.contains(path) {
+                return Err(not_found(path));
+            }
+            Ok(inner
+                .files
+                .keys()
+                .chain(inner.dirs.iter())
+                .filter(|entry| parent_dir(entry) == path)
+                .map(|entry| entry[path.len() + 1..].to_string())
+                .collect())
+        }
+
+        fn create_dir(&self, path: &str) -> io::Result<()> {
+            let mut inner = self.inner();
+            inner.check_parent(path)?;
+            if inner.files.contains_key(path) || !inner.dirs.insert(path.to_string()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    path.to_string(),
+                ));
+            }
+            Ok(())
+        }
+
+        fn remove_file(&self, path: &str) -> io::Result<()> {
+            self.inner()
+                .files
+                .remove(path)
+                .map(|_| ())
+                .ok_or_else(|| not_found(path))
+        }
+
+        fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+            let mut inner = self.inner();
+            if !inner.dirs.remove(path) {
+                return Err(not_found(path));
+            }
+            let prefix = format!("{}/", path);
+            inner.files.retain(|file, _| !file.starts_with(&prefix));
+            inner.dirs.retain(|dir| !dir.starts_with(&prefix));
+            Ok(())
+        }
+
+        fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            let mut inner = self.inner();
+            inner.check_parent(to)?;
+            let data = inner.files.remove(from).ok_or_else(|| not_found(from))?;
+            inner.files.insert(to.to_string(), data);
+            Ok(())
+        }
+    }
+
+    /// An open file of a MemFs.
+    struct MemFile {
+        fs: MemFs,
+        path: String,
+        pos: u64,
+        append: bool,
+    }
+
+    impl MemFile {
+        fn with_data<T>(&self, f: impl FnOnce(&mut Vec<u8>) -> T) -> io::Result<T> {
+            let mut inner = self.fs.inner();
+            let data = inner
+                .files
+                .get_mut(&self.path)
+                .ok_or_else(|| not_found(&self.path))?;
+            Ok(f(data))
+        }
+    }
+
+    impl Read for MemFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let pos = self.pos as usize;
+            let n = self.with_data(|data| {
+                let available = data.get(pos..).unwrap_or(&[]);
+                let n = usize::min(available.len(), buf.len());
+                buf[..n].copy_from_slice(&available[..n]);
+                n
+            })?;
+            self.pos += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl Write for MemFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let append = self.append;
+            let pos = self.pos as usize;
+            self.pos = self.with_data(|data| {
+                let start = if append { data.len() } else { pos };
+                if data.len() < start + buf.len() {
+                    data.resize(start + buf.len(), 0);
+                }
+                data[start..start + buf.len()].copy_from_slice(buf);
+                (start + buf.len()) as u64
+            })?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for MemFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            let len = self.size()? as i64;
+            let new_pos = match pos {
+                SeekFrom::Start(offset) => offset as i64,
+                SeekFrom::End(offset) => len + offset,
+                SeekFrom::Current(offset) => self.pos as i64 + offset,
+            };
+            if new_pos < 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "seek before the start of the file",
+                ));
+            }
+            self.pos = new_pos as u64;
+            Ok(self.pos)
+        }
+    }
+
+    impl StorageFile for MemFile {
+        fn size(&self) -> io::Result<u64> {
+            self.with_data(|data| data.len() as u64)
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Power {
+        // Bytes that can still be written before the power is cut.
+        budget: usize,
+        cut: bool,
+    }
+
+    fn power_lost() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "power lost")
+    }
+
+    /// A MemFs that loses power after `budget` more bytes are written: the write that crosses it
+    /// is cut short and everything after it fails, as on a device that lost power. The MemFs keeps
+    /// what was written, like the flash after a reboot.
+    #[derive(Clone)]
+    pub(crate) struct PowerCutFs {
+        fs: MemFs,
+        power: Arc<Mutex<Power>>,
+    }
+
+    impl PowerCutFs {
+        pub(crate) fn new(fs: &MemFs, budget: usize) -> Self {
+            PowerCutFs {
+                fs: fs.clone(),
+                power: Arc::new(Mutex::new(Power { budget, cut: false })),
+            }
+        }
+
+        pub(crate) fn is_cut(&self) -> bool {
+            self.power.lock().unwrap().cut
+        }
+
+        fn check(&self) -> io::Result<()> {
+            if self.is_cut() {
+                return Err(power_lost());
+            }
+            Ok(())
+        }
+    }
+
+    impl Filesystem for PowerCutFs {
+        fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+            self.check()?;
+            Ok(Box::new(PowerCutFile {
+                file: self.fs.open(path, mode)?,
+                power: self.power.clone(),
+            }))
+        }
+
+        fn file_size(&self, path: &str) -> io::Result<u64> {
+            self.check()?;
+            self.fs.file_size(path)
+        }
+
+        fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+            self.check()?;
+            self.fs.read_dir(path)
+        }
+
+        fn create_dir(&self, path: &str) -> io::Result<()> {
+            self.check()?;
+            self.fs.create_dir(path)
+        }
+
+        fn remove_file(&self, path: &str) -> io::Result<()> {
+            self.check()?;
+            self.fs.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+            self.check()?;
+            self.fs.remove_dir_all(path)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.check()?;
+            self.fs.rename(from, to)
+        }
+    }
+
+    /// A MemFs that reports (total, used) bytes of `space` as its space, which can be changed
+    /// while it is in use.
+    #[derive(Clone)]
+    pub(crate) struct SpaceFs {
+        fs: MemFs,
+        pub(crate) space: Arc<Mutex<(u64, u64)>>,
+    }
+
+    impl SpaceFs {
+        pub(crate) fn new(fs: &MemFs, total: u64, used: u64) -> Self {
+            SpaceFs {
+                fs: fs.clone(),
+                space: Arc::new(Mutex::new((total, used))),
+            }
+        }
+    }
+
+    impl Filesystem for SpaceFs {
+        fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+            self.fs.open(path, mode)
+        }
+
+        fn file_size(&self, path: &str) -> io::Result<u64> {
+            self.fs.file_size(path)
+        }
+
+        fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+            self.fs.read_dir(path)
+        }
+
+        fn create_dir(&self, path: &str) -> io::Result<()> {
+            self.fs.create_dir(path)
+        }
+
+        fn remove_file(&self, path: &str) -> io::Result<()> {
+            self.fs.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+            self.fs.remove_dir_all(path)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.fs.rename(from, to)
+        }
+
+        fn space(&self, _root: &str) -> io::Result<(u64, u64)> {
+            Ok(*self.space.lock().unwrap())
+        }
+    }
+
+    /// A MemFs whose file at `path` fails to read past its first `readable` bytes, like flash
+    /// with a bad block. Everything else goes through.
+    #[derive(Clone)]
+    pub(crate) struct UnreadableFs {
+        fs: MemFs,
+        path: String,
+        readable: u64,
+    }
+
+    impl UnreadableFs {
+        pub(crate) fn new(fs: &MemFs, path: &str, readable: u64) -> Self {
+            UnreadableFs {
+                fs: fs.clone(),
+                path: path.to_string(),
+                readable,
+            }
+        }
+    }
+
+    impl Filesystem for UnreadableFs {
+        fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+            let file = self.fs.open(path, mode)?;
+            if path != self.path {
+                return Ok(file);
+            }
+            Ok(Box::new(UnreadableFile {
+                file,
+                readable: self.readable,
+            }))
+        }
+
+        fn file_size(&self, path: &str) -> io::Result<u64> {
+            self.fs.file_size(path)
+        }
+
+        fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+            self.fs.read_dir(path)
+        }
+
+        fn create_dir(&self, path: &str) -> io::Result<()> {
+            self.fs.create_dir(path)
+        }
+
+        fn remove_file(&self, path: &str) -> io::Result<()> {
+            self.fs.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+            self.fs.remove_dir_all(path)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.fs.rename(from, to)
+        }
+    }
+
+    /// A MemFs whose files under `root` can be read but not written, like a partition mounted
+    /// read only. Everything else goes through.
+    #[derive(Clone)]
+    pub(crate) struct ReadOnlyFs {
+        fs: MemFs,
+        root: String,
+    }
+
+    impl ReadOnlyFs {
+        pub(crate) fn new(fs: &MemFs, root: &str) -> Self {
+            ReadOnlyFs {
+                fs: fs.clone(),
+                root: root.to_string(),
+            }
+        }
+
+        fn check(&self, path: &str) -> io::Result<()> {
+            if path.starts_with(&self.root) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} is read only", self.root),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    impl Filesystem for ReadOnlyFs {
+        fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+            if mode != OpenMode::Read {
+                self.check(path)?;
+            }
+            self.fs.open(path, mode)
+        }
+
+        fn file_size(&self, path: &str) -> io::Result<u64> {
+            self.fs.file_size(path)
+        }
+
+        fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+            self.fs.read_dir(path)
+        }
+
+        fn create_dir(&self, path: &str) -> io::Result<()> {
+            self.check(path)?;
+            self.fs.create_dir(path)
+        }
+
+        fn remove_file(&self, path: &str) -> io::Result<()> {
+            self.check(path)?;
+            self.fs.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+            self.check(path)?;
+            self.fs.remove_dir_all(path)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.check(from)?;
+            self.check(to)?;
+            self.fs.rename(from, to)
+        }
+    }
+
+    struct UnreadableFile {
+        file: Box<dyn StorageFile>,
+        readable: u64,
+    }
+
+    impl Read for UnreadableFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let pos = self.file.stream_position()?;
+            if pos >= self.readable {
+                return Err(io::Error::new(io::ErrorKind::Other, "flash read failed"));
+            }
+            let n = usize::min(buf.len(), (self.readable - pos) as usize);
+            self.file.read(&mut buf[..n])
+        }
+    }
+
+    impl Write for UnreadableFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.file.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Seek for UnreadableFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl StorageFile for UnreadableFile {
+        fn size(&self) -> io::Result<u64> {
+            self.file.size()
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.file.sync()
+        }
+    }
+
+    /// A MemFs that counts the writes and syncs of the files whose path contains `pattern`.
+    #[derive(Clone)]
+    pub(crate) struct CountingFs {
+        fs: MemFs,
+        pattern: String,
+        /// (writes, syncs) so far.
+        pub(crate) counts: Arc<Mutex<(usize, usize)>>,
+    }
+
+    impl CountingFs {
+        pub(crate) fn new(fs: &MemFs, pattern: &str) -> Self {
+            CountingFs {
+                fs: fs.clone(),
+                pattern: pattern.to_string(),
+                counts: Arc::new(Mutex::new((0, 0))),
+            }
+        }
+    }
+
+    impl Filesystem for CountingFs {
+        fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+            let file = self.fs.open(path, mode)?;
+            if !path.contains(&self.pattern) {
+                return Ok(file);
+            }
+            Ok(Box::new(CountingFile {
+                file,
+                counts: self.counts.clone(),
+            }))
+        }
+
+        fn file_size(&self, path: &str) -> io::Result<u64> {
+            self.fs.file_size(path)
+        }
+
+        fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+            self.fs.read_dir(path)
+        }
+
+        fn create_dir(&self, path: &str) -> io::Result<()> {
+            self.fs.create_dir(path)
+        }
+
+        fn remove_file(&self, path: &str) -> io::Result<()> {
+            self.fs.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+            self.fs.remove_dir_all(path)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.fs.rename(from, to)
+        }
+    }
+
+    struct CountingFile {
+        file: Box<dyn StorageFile>,
+        counts: Arc<Mutex<(usize, usize)>>,
+    }
+
+    impl Read for CountingFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.file.read(buf)
+        }
+    }
+
+    impl Write for CountingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.counts.lock().unwrap().0 += 1;
+            self.file.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Seek for CountingFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl StorageFile for CountingFile {
+        fn size(&self) -> io::Result<u64> {
+            self.file.size()
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.counts.lock().unwrap().1 += 1;
+            self.file.sync()
+        }
+    }
+
+    /// A MemFs where a write to a file whose path contains `pattern` fails once after `budget`
+    /// more bytes, like a flash write that errors halfway. The bytes up to it are kept, and later
+    /// writes go through.
+    #[derive(Clone)]
+    pub(crate) struct ShortWriteFs {
+        fs: MemFs,
+        pattern: String,
+        budget: Arc<Mutex<Option<usize>>>,
+    }
+
+    impl ShortWriteFs {
+        pub(crate) fn new(fs: &MemFs, pattern: &str, budget: usize) -> Self {
+            ShortWriteFs {
+                fs: fs.clone(),
+                pattern: pattern.to_string(),
+                budget: Arc::new(Mutex::new(Some(budget))),
+            }
+        }
+    }
+
+    impl Filesystem for ShortWriteFs {
+        fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+            let file = self.fs.open(path, mode)?;
+            if !path.contains(&self.pattern) {
+                return Ok(file);
+            }
+            Ok(Box::new(ShortWriteFile {
+                file,
+                budget: self.budget.clone(),
+            }))
+        }
+
+        fn file_size(&self, path: &str) -> io::Result<u64> {
+            self.fs.file_size(path)
+        }
+
+        fn read_dir(&self, path: &str) -> io::Result<Vec<String>> {
+            self.fs.read_dir(path)
+        }
+
+        fn create_dir(&self, path: &str) -> io::Result<()> {
+            self.fs.create_dir(path)
+        }
+
+        fn remove_file(&self, path: &str) -> io::Result<()> {
+            self.fs.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+            self.fs.remove_dir_all(path)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+            self.fs.rename(from, to)
+        }
+    }
+
+    struct ShortWriteFile {
+        file: Box<dyn StorageFile>,
+        budget: Arc<Mutex<Option<usize>>>,
+    }
+
+    impl Read for ShortWriteFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.file.read(buf)
+        }
+    }
+
+    impl Write for ShortWriteFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut budget = self.budget.lock().unwrap();
+            match *budget {
+                None => self.file.write(buf),
+                Some(0) => {
+                    *budget = None;
+                    Err(io::Error::new(io::ErrorKind::Other, "write failed"))
+                }
+                Some(left) => {
+                    let n = self.file.write(&buf[..usize::min(buf.len(), left)])?;
+                    *budget = Some(left - n);
+                    Ok(n)
+                }
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Seek for ShortWriteFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl StorageFile for ShortWriteFile {
+        fn size(&self) -> io::Result<u64> {
+            self.file.size()
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.file.sync()
+        }
+    }
+
+    struct PowerCutFile {
+        file: Box<dyn StorageFile>,
+        power: Arc<Mutex<Power>>,
+    }
+
+    impl Read for PowerCutFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.file.read(buf)
+        }
+    }
+
+    impl Write for PowerCutFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut power = self.power.lock().unwrap();
+            if power.cut {
+                return Err(power_lost());
+            }
+            let n = usize::min(buf.len(), power.budget);
+            power.budget -= n;
+            if n < buf.len() {
+                power.cut = true;
+            }
+            if n == 0 {
+                return Err(power_lost());
+            }
+            self.file.write(&buf[..n])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl Seek for PowerCutFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl StorageFile for PowerCutFile {
+        fn size(&self) -> io::Result<u64> {
+            self.file.size()
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            if self.power.lock().unwrap().cut {
+                return Err(power_lost());
+            }
+            self.file.sync()
+        }
+    }
+
+    #[test]
+    fn memfs_needs_the_parent_dir() {
+        let fs = MemFs::new();
+        assert!(fs.open("/littlefs/a", OpenMode::Truncate).is_ok());
+        let err = fs
+            .open("/littlefs/data/a", OpenMode::Truncate)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            fs.create_dir("/littlefs/data/shards").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        fs.create_dir("/littlefs/data").unwrap();
+        assert!(fs.open("/littlefs/data/a", OpenMode::Truncate).is_ok());
+        assert_eq!(
+            fs.create_dir("/littlefs/data").unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert!(fs.open("/littlefs/data", OpenMode::Read).is_err());
+    }
+
+    #[test]
+    fn memfs_paths_outside_the_mount_dont_exist() {
+        let fs = MemFs::mounted_at("/spiffs");
+        assert!(fs.open("/littlefs/a", OpenMode::Truncate).is_err());
+        assert!(fs.create_dir("/littlefs").is_err());
+        assert!(fs.write_atomic("/spiffs/a", b"data").is_ok());
+        assert_eq!(fs.read("/spiffs/a").unwrap(), b"data");
+    }
+
+    #[test]
+    fn memfs_read_dir_lists_files_and_dirs() {
+        let fs = MemFs::new();
+        fs.create_dir("/littlefs/data").unwrap();
+        fs.write_atomic("/littlefs/a", b"").unwrap();
+        fs.write_atomic("/littlefs/data/b", b"").unwrap();
+        let mut entries = fs.read_dir("/littlefs").unwrap();
+        entries.sort();
+        assert_eq!(entries, ["a", "data"]);
+        assert_eq!(fs.read_dir("/littlefs/data").unwrap(), ["b"]);
+        assert!(fs.read_dir("/littlefs/other").is_err());
+    }
+
+    #[test]
+    fn memfs_write_modes() {
+        let fs = MemFs::new();
+        fs.write_atomic("/littlefs/a", b"hello").unwrap();
+        fs.open("/littlefs/a", OpenMode::Append)
+            .unwrap()
+            .write_all(b" world")
+            .unwrap();
+        assert_eq!(fs.read("/littlefs/a").unwrap(), b"hello world");
+
+        let mut file = fs.open("/littlefs/a", OpenMode::ReadWrite).unwrap();
+        file.seek(SeekFrom::Start(6)).unwrap();
+        file.write_all(b"there").unwrap();
+        assert_eq!(fs.read("/littlefs/a").unwrap(), b"hello there");
+
+        fs.write_atomic("/littlefs/a", b"bye").unwrap();
+        assert_eq!(fs.read("/littlefs/a").unwrap(), b"bye");
+        assert!(fs.file_size("/littlefs/a.tmp").is_err());
+        fs.open("/littlefs/a", OpenMode::Truncate).unwrap();
+        assert_eq!(fs.file_size("/littlefs/a").unwrap(), 0);
+    }
+
+    #[test]
+    fn memfs_rename_and_remove() {
+        let fs = MemFs::new();
+        fs.create_dir("/littlefs/data").unwrap();
+        fs.write_atomic("/littlefs/data/a", b"1").unwrap();
+        assert!(fs.rename("/littlefs/data/a", "/littlefs/other/a").is_err());
+        assert!(fs.read("/littlefs/data/a").is_ok());
+        fs.rename("/littlefs/data/a", "/littlefs/data/b").unwrap();
+        assert_eq!(fs.read("/littlefs/data/b").unwrap(), b"1");
+
+        fs.create_dir("/littlefs/data/sub").unwrap();
+        fs.write_atomic("/littlefs/data/sub/c", b"2").unwrap();
+        fs.remove_dir_all("/littlefs/data").unwrap();
+        assert!(fs.read("/littlefs/data/b").is_err());
+        assert!(fs.read_dir("/littlefs/data/sub").is_err());
+        assert!(fs.read_dir("/littlefs").unwrap().is_empty());
+    }
+
+    #[test]
+    fn memfs_clones_share_files() {
+        let fs = MemFs::new();
+        let clone = fs.clone();
+        fs.write_atomic("/littlefs/a", b"shared").unwrap();
+        assert_eq!(clone.read("/littlefs/a").unwrap(), b"shared");
+    }
+
+    #[test]
+    fn shard_cache_evicts_least_recently_used() {
+        let mut cache = ShardCache::default();
+        cache.set_budget(8);
+        cache.insert(1, Arc::from(&[0u8; 4][..]));
+        cache.insert(2, Arc::from(&[0u8; 4][..]));
+        assert!(cache.get(1).is_some());
+        cache.insert(3, Arc::from(&[0u8; 4][..]));
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(1).is_some());
+        cache.insert(4, Arc::from(&[0u8; 16][..]));
+        assert!(cache.get(4).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 2, 1));
+        assert_eq!(stats.bytes, 8);
+        cache.set_budget(4);
+        assert_eq!(cache.stats().bytes, 4);
+    }
+
+    #[test]
+    fn power_cut_stops_writes_midway() {
+        let fs = MemFs::new();
+        let cut = PowerCutFs::new(&fs, 3);
+        assert!(cut.write_atomic("/littlefs/a", b"hello").is_err());
+        assert!(cut.is_cut());
+        assert_eq!(fs.read("/littlefs/a.tmp").unwrap(), b"hel");
+        assert!(fs.read("/littlefs/a").is_err());
+        assert!(cut.read("/littlefs/a.tmp").is_err());
+    }
+
+    struct BrokenRead;
+
+    impl Read for BrokenRead {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "flash read failed"))
+        }
+    }
+
+    #[test]
+    fn read_record_tells_the_end_from_a_failed_read() {
+        let mut buf = [0_u8; 4];
+        let mut file = io::Cursor::new(vec![1, 2, 3, 4, 5, 6]);
+        assert!(read_record(&mut file, &mut buf).unwrap());
+        assert_eq!(buf, [1, 2, 3, 4]);
+        // The incomplete record at the end is the end.
+        assert!(!read_record(&mut file, &mut buf).unwrap());
+        assert!(!read_record(&mut file, &mut buf).unwrap());
+
+        let err = read_record(&mut BrokenRead, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    // A storage on `fs` that writes every save right away.
+    pub(crate) fn storage(fs: &MemFs) -> CTStorage {
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.set_min_save_interval(Duration::ZERO);
+        storage.set_write_batching(None);
+        storage.find_newest_readings_shard_num().unwrap();
+        storage
+    }
+
+    // Give every CT a reading for `timestamp` and save it.
+    pub(crate) fn save(storage: &mut CTStorage, cts: &mut [CT; AC_PHASE], timestamp: u64) {
+        for ct in cts.iter_mut() {
+            ct.reading = reading(100.0 * ct.id as f32, timestamp);
+        }
+        storage.save_to_storage(cts).unwrap();
+    }
+
+    // Every stored record, oldest shard first.
+    pub(crate) fn stored(storage: &CTStorage) -> Vec<(u16, CTReading)> {
+        let mut shards: Vec<i32> = storage.readings_shards.iter().copied().collect();
+        shards.sort_unstable();
+        shards
+            .into_iter()
+            .flat_map(|shard| storage.read_shard(shard).unwrap())
+            .collect()
+    }
+
+    // The reading of a little endian record with every metric, as framed for the serial link.
+    pub(crate) fn decode_le_record(buf: &[u8]) -> anyhow::Result<(u16, CTReading)> {
+        CTStorage::decode_record(buf, ByteOrder::Little, RecordSchema::default())
+    }
+
+    #[test]
+    fn first_save_goes_to_shard_1() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        let mut cts = test_cts();
+        save(&mut storage, &mut cts, 1_000);
+
+        let records = storage.read_shard(1).unwrap();
+        assert_eq!(records.len(), AC_PHASE);
+        for ((id, stored), ct) in records.iter().zip(&cts) {
+            assert_eq!(*id, ct.id);
+            assert_eq!(stored.real_power, ct.reading.real_power);
+            assert_eq!(stored.apparent_power, ct.reading.apparent_power);
+            assert_eq!(stored.i_rms, ct.reading.i_rms);
+            assert_eq!(stored.v_rms, ct.reading.v_rms);
+            assert_eq!(stored.kwh, ct.reading.kwh);
+            assert_eq!(stored.timestamp, 1_000);
+            assert_eq!(stored.sequence, 1);
+        }
+    }
+
+    #[test]
+    fn saves_roll_over_to_new_shards_and_survive_a_reboot() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        {
+            let mut storage = storage(&fs);
+            for i in 0..5 {
+                save(&mut storage, &mut cts, 1_000 * (i + 1));
+            }
+            assert!(storage.readings_shards.len() > 1);
+            for shard in &storage.readings_shards {
+                let size = fs.file_size(&storage.shard_path(*shard)).unwrap();
+                let save = (SHARD_HEADER_SIZE + record_size() * AC_PHASE) as u64;
+                assert!(size <= u64::max(MAX_SHARD_SIZE, save));
+            }
+        }
+
+        let mut storage = storage(&fs);
+        storage.load_sequence().unwrap();
+        assert_eq!(storage.sequence(), 5);
+        let newest = storage.readings_shard_counter;
+        assert_eq!(Some(&newest), storage.readings_shards.iter().max());
+        save(&mut storage, &mut cts, 6_000);
+
+        let records = stored(&storage);
+        assert_eq!(records.len(), 6 * AC_PHASE);
+        let sequences: Vec<u32> = records.iter().map(|(_, r)| r.sequence).collect();
+        assert!(sequences.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(records.last().unwrap().1.timestamp, 6_000);
+    }
+
+    #[test]
+    fn prune_keeps_recent_and_current_shards() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        let mut cts = test_cts();
+        for i in 0..4 {
+            save(&mut storage, &mut cts, 1_000 * (i + 1));
+        }
+        let shards = storage.readings_shards.len();
+        let pruned = storage
+            .prune_older_than(Duration::from_millis(500), 3_100)
+            .unwrap();
+        assert!(pruned > 0);
+        assert_eq!(storage.readings_shards.len(), shards - pruned);
+        assert!(stored(&storage)
+            .iter()
+            .any(|(_, reading)| reading.timestamp == 4_000));
+        assert!(stored(&storage)
+            .iter()
+            .all(|(_, reading)| reading.timestamp >= 3_000));
+    }
+
+    #[test]
+    fn truncated_record_is_cut_off_at_boot() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let shard = {
+            let mut storage = storage(&fs);
+            save(&mut storage, &mut cts, 1_000);
+            storage.readings_shard_counter
+        };
+        let path = format!("{}/ct_readings/{}", STORAGE_MOUNTS[0].root, shard);
+        let mut file = fs.open(&path, OpenMode::Append).unwrap();
+        file.write_all(&[0xab; 7]).unwrap();
+        drop(file);
+
+        let mut untouched = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        untouched.set_shard_recovery(ShardRecovery::Off);
+        untouched.find_newest_readings_shard_num().unwrap();
+        let summary = untouched.shard_summary(shard).unwrap();
+        assert_eq!(summary.trailing_bytes, 7);
+        assert_eq!(summary.records, AC_PHASE);
+
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.set_shard_recovery(ShardRecovery::Truncate);
+        storage.set_min_save_interval(Duration::ZERO);
+        storage.find_newest_readings_shard_num().unwrap();
+        assert_eq!(storage.boot.truncated_bytes, 7);
+        let size = fs.file_size(&path).unwrap() as usize;
+        assert_eq!((size - SHARD_HEADER_SIZE) % record_size(), 0);
+        storage.load_sequence().unwrap();
+        save(&mut storage, &mut cts, 2_000);
+        let records = stored(&storage);
+        assert_eq!(records.len(), 2 * AC_PHASE);
+        assert_eq!(records.last().unwrap().1.timestamp, 2_000);
+    }
+
+    #[test]
+    fn corrupt_shard_keeps_its_complete_records() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        let mut cts = test_cts();
+        save(&mut storage, &mut cts, 1_000);
+        let path = storage.shard_path(1);
+        let mut data = fs.read(&path).unwrap();
+        data.truncate(data.len() - 1);
+        fs.write_atomic(&path, &data).unwrap();
+
+        let summary = storage.shard_summary(1).unwrap();
+        assert_eq!(summary.records, AC_PHASE - 1);
+        assert_eq!(summary.trailing_bytes, record_size() - 1);
+        assert_eq!(storage.read_shard(1).unwrap().len(), AC_PHASE - 1);
+    }
+
+    #[test]
+    fn storage_on_an_unmounted_root_buffers_in_ram() {
+        let fs = MemFs::mounted_at("/spiffs");
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.set_min_save_interval(Duration::ZERO);
+        storage.find_newest_readings_shard_num().unwrap();
+        assert!(!storage.storage_available());
+        let mut cts = test_cts();
+        save(&mut storage, &mut cts, 1_000);
+        assert_eq!(storage.buffered.len(), 1);
+        assert!(fs.read_dir("/spiffs").unwrap().is_empty());
+        storage.buffered.clear();
+    }
+
+    fn set_ical(cts: &mut [CT; AC_PHASE], ical: f32) {
+        for ct in cts.iter_mut() {
+            ct.set_calibration(Calibration {
+                ical,
+                ..ct.calibration()
+            });
+        }
+    }
+
+    #[test]
+    fn interrupted_calibration_save_keeps_the_previous_one() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        set_ical(&mut cts, 50.0);
+        CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little)
+            .save_calibration(&cts)
+            .unwrap();
+        let size = fs.file_size("/littlefs/calibration").unwrap() as usize;
+
+        set_ical(&mut cts, 60.0);
+        for budget in 0..size {
+            let cut = PowerCutFs::new(&fs, budget);
+            let mut storage = CTStorage::with_fs(Box::new(cut.clone()), ByteOrder::Little);
+            assert!(storage.save_calibration(&cts).is_err());
+            assert!(cut.is_cut());
+
+            let mut loaded = test_cts();
+            let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+            storage.load_calibration(&mut loaded).unwrap();
+            assert_eq!(storage.boot.calibration, CalibrationLoad::Loaded);
+            assert!(loaded.iter().all(|ct| ct.calibration().ical == 50.0));
+        }
+
+        let mut storage =
+            CTStorage::with_fs(Box::new(PowerCutFs::new(&fs, size)), ByteOrder::Little);
+        storage.save_calibration(&cts).unwrap();
+        let mut loaded = test_cts();
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.load_calibration(&mut loaded).unwrap();
+        assert!(loaded.iter().all(|ct| ct.calibration().ical == 60.0));
+    }
+
+    #[test]
+    fn interrupted_energy_totals_save_keeps_the_previous_ones() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        cts.iter_mut().for_each(|ct| ct.energy_total_kwh = 12.5);
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.save_energy_totals(&cts).unwrap();
+        let size = fs.file_size("/littlefs/energy_totals").unwrap() as usize;
+
+        cts.iter_mut().for_each(|ct| ct.energy_total_kwh = 20.0);
+        for budget in 0..size {
+            let mut storage =
+                CTStorage::with_fs(Box::new(PowerCutFs::new(&fs, budget)), ByteOrder::Little);
+            assert!(storage.save_energy_totals(&cts).is_err());
+            let mut loaded = test_cts();
+            let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+            storage.load_energy_totals(&mut loaded).unwrap();
+            assert!(loaded.iter().all(|ct| ct.lifetime_kwh() == 12.5));
+        }
+    }
+
+    fn read_sequence_file(fs: &MemFs) -> Option<u32> {
+        let buf = fs.read("/littlefs/sequence").ok()?;
+        Some(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]))
+    }
+
+    #[test]
+    fn sequence_is_stored_on_rollover_only() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        {
+            let mut storage = storage(&fs);
+            save(&mut storage, &mut cts, 1_000);
+            assert_eq!(read_sequence_file(&fs), None);
+            while storage.readings_shard_counter == 1 {
+                save(&mut storage, &mut cts, 2_000);
+            }
+            assert_eq!(read_sequence_file(&fs), Some(storage.sequence()));
+            save(&mut storage, &mut cts, 3_000);
+        }
+        let records = {
+            let storage = storage(&fs);
+            stored(&storage)
+        };
+        // The newest shard has the last sequence number.
+        fs.remove_file("/littlefs/sequence").unwrap();
+
+        let mut storage = storage(&fs);
+        storage.load_sequence().unwrap();
+        assert_eq!(storage.sequence(), records.last().unwrap().1.sequence);
+        save(&mut storage, &mut cts, 4_000);
+        assert_eq!(
+            stored(&storage).last().unwrap().1.sequence,
+            records.last().unwrap().1.sequence + 1
+        );
+    }
+
+    #[test]
+    fn sequence_survives_a_storage_reset() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        {
+            let mut storage = storage(&fs);
+            storage.log_powerloss().unwrap();
+            save(&mut storage, &mut cts, 1_000);
+            storage.reset_storage().unwrap();
+        }
+        let mut storage = storage(&fs);
+        storage.load_sequence().unwrap();
+        assert_eq!(storage.sequence(), 1);
+    }
+
+    // A shard of records as stored before the format file, `size` bytes each.
+    fn legacy_shard(readings: &[(u16, f32, u64, u32)], size: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for &(id, real_power, timestamp, sequence) in readings {
+            buf.extend_from_slice(&id.to_le_bytes());
+            for value in [real_power, 2.0, 3.0, 230.0, 0.5].iter() {
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+            buf.extend_from_slice(&timestamp.to_le_bytes());
+            if size > LEGACY_RECORD_SIZE {
+                buf.extend_from_slice(&sequence.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    fn legacy_storage(size: usize) -> MemFs {
+        let fs = MemFs::new();
+        fs.create_dir("/littlefs/ct_readings").unwrap();
+        for shard in 1..=3_u32 {
+            let readings: Vec<_> = (1..=AC_PHASE as u16)
+                .map(|id| (id, 100.0 * shard as f32, 1_000 * shard as u64, shard + 10))
+                .collect();
+            let path = format!("/littlefs/ct_readings/{}", shard);
+            fs.write_atomic(&path, &legacy_shard(&readings, size))
+                .unwrap();
+        }
+        fs
+    }
+
+    fn assert_migrated(fs: &MemFs, sequences: [u32; 3]) {
+        let mut storage = storage(fs);
+        storage.load_sequence().unwrap();
+        assert!(fs.read("/littlefs/format").is_err());
+        let shard = fs.read("/littlefs/ct_readings/1").unwrap();
+        assert_eq!(shard[..2], [SHARD_MAGIC, SHARD_FORMAT_VERSION]);
+        let records = stored(&storage);
+        assert_eq!(records.len(), 3 * AC_PHASE);
+        for (i, (id, reading)) in records.iter().enumerate() {
+            let shard = i / AC_PHASE;
+            assert_eq!(*id as usize, i - shard * AC_PHASE + 1);
+            assert_eq!(reading.real_power, 100.0 * (shard + 1) as f32);
+            assert_eq!(reading.v_rms, 230.0);
+            assert_eq!(reading.timestamp, 1_000 * (shard + 1) as u64);
+            assert_eq!(reading.sequence, sequences[shard]);
+        }
+        assert_eq!(storage.sequence(), sequences[2]);
+    }
+
+    #[test]
+    fn shards_from_before_sequence_numbers_are_migrated() {
+        let fs = legacy_storage(LEGACY_RECORD_SIZE);
+        assert_migrated(&fs, [1, 2, 3]);
+    }
+
+    #[test]
+    fn shards_from_before_the_format_file_are_migrated() {
+        let fs = legacy_storage(LEGACY_RECORD_SIZE + 4);
+        assert_migrated(&fs, [11, 12, 13]);
+        // Only once.
+        assert_migrated(&fs, [11, 12, 13]);
+    }
+
+    #[test]
+    fn interrupted_migration_is_finished_at_the_next_boot() {
+        let fs = legacy_storage(LEGACY_RECORD_SIZE);
+        let mut budget = 0;
+        loop {
+            let cut = PowerCutFs::new(&fs, budget);
+            let mut storage = CTStorage::with_fs(Box::new(cut.clone()), ByteOrder::Little);
+            let res = storage.migrate_legacy_shards();
+            if !cut.is_cut() {
+                res.unwrap();
+                break;
+            }
+            budget += 16;
+        }
+        assert_migrated(&fs, [1, 2, 3]);
+    }
+
+    #[test]
+    fn recompute_energy_keeps_the_stored_sign() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        let mut cts = test_cts();
+        let stored_kwh = [
+            (1200.0, 0.01),
+            (-600.0, 0.005),
+            (-600.0, 0.0),
+            (300.0, -0.0025),
+        ];
+        for (i, &(real_power, kwh)) in stored_kwh.iter().enumerate() {
+            for ct in cts.iter_mut() {
+                ct.reading = reading(real_power, i as u64);
+                ct.reading.kwh = kwh;
+            }
+            storage.save_to_storage(&cts).unwrap();
+        }
+
+        let count = storage.recompute_energy(Duration::from_secs(60)).unwrap();
+        assert_eq!(count, stored_kwh.len() * AC_PHASE);
+        let expected = [0.02, 0.01, 0.0, -0.005];
+        for (i, (_, reading)) in stored(&storage).iter().enumerate() {
+            assert!(
+                (reading.kwh - expected[i / AC_PHASE]).abs() < 1e-6,
+                "{:?}",
+                reading
+            );
+        }
+    }
+
+    #[test]
+    fn storage_coming_back_loads_what_the_boot_missed() {
+        // The partition isn't there at boot.
+        let fs = MemFs::mounted_at("/");
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.set_min_save_interval(Duration::ZERO);
+        storage.find_newest_readings_shard_num().unwrap();
+        assert!(!storage.storage_available());
+        let mut cts = test_cts();
+        save(&mut storage, &mut cts, 1_000);
+
+        fs.create_dir("/littlefs").unwrap();
+        fs.write_atomic("/littlefs/time", &5_000_000_u64.to_le_bytes())
+            .unwrap();
+        fs.write_atomic("/littlefs/powerloss_log", &[]).unwrap();
+        save(&mut storage, &mut cts, 2_000);
+        assert!(storage.storage_available());
+        assert!(storage.boot.time_restored);
+        assert_eq!(fs.file_size("/littlefs/powerloss_log").unwrap(), 16);
+        assert!(fs.file_size("/littlefs/lifetime_stats").is_ok());
+        assert!(fs.file_size("/littlefs/tou_totals").is_ok());
+        let timestamps: Vec<u64> = stored(&storage).iter().map(|(_, r)| r.timestamp).collect();
+        assert_eq!(timestamps.len(), 2 * AC_PHASE);
+        assert!(timestamps[..AC_PHASE].iter().all(|&t| t == 1_000));
+        assert!(timestamps[AC_PHASE..].iter().all(|&t| t == 2_000));
+    }
+
+    #[test]
+    fn storage_reset_goes_back_to_the_configured_format() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let old_schema = RecordSchema::from_bits(0b0_0011);
+        {
+            let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Big);
+            storage.set_min_save_interval(Duration::ZERO);
+            storage.set_record_schema(old_schema);
+            storage.find_newest_readings_shard_num().unwrap();
+            save(&mut storage, &mut cts, 1_000);
+        }
+
+        let mut storage = storage(&fs);
+        assert_eq!(storage.byte_order(), ByteOrder::Big);
+        assert_eq!(storage.record_schema(), old_schema);
+        storage.log_powerloss().unwrap();
+        storage.reset_storage().unwrap();
+        assert_eq!(storage.byte_order(), ByteOrder::Little);
+        assert_eq!(storage.record_schema(), RecordSchema::default());
+
+        save(&mut storage, &mut cts, 2_000);
+        let records = stored(&storage);
+        assert_eq!(records.len(), AC_PHASE);
+        assert_eq!(records[0].1.real_power, 100.0);
+        assert_eq!(records[0].1.timestamp, 2_000);
+    }
+
+    #[test]
+    fn records_round_trip_in_both_byte_orders() {
+        for &byte_order in &[ByteOrder::Little, ByteOrder::Big] {
+            let fs = MemFs::new();
+            let mut storage = CTStorage::with_fs(Box::new(fs.clone()), byte_order);
+            storage.set_min_save_interval(Duration::ZERO);
+            storage.find_newest_readings_shard_num().unwrap();
+            let mut cts = test_cts();
+            save(&mut storage, &mut cts, 1_000);
+            let shard = fs.read("/littlefs/ct_readings/1").unwrap();
+            assert_eq!(shard[2], byte_order as u8);
+            // Another configured order doesn't change how the stored records read.
+            let mut storage = CTStorage::with_fs(Box::new(fs), ByteOrder::Little);
+            storage.find_newest_readings_shard_num().unwrap();
+            let (id, reading) = stored(&storage).remove(0);
+            assert_eq!(id, cts[0].id);
+            assert_eq!(reading.real_power, 100.0 * f32::from(cts[0].id));
+            assert_eq!(reading.timestamp, 1_000);
+            assert_eq!(reading.quality, Some(100));
+        }
+    }
+
+    #[test]
+    fn quality_is_stored_with_the_record() {
+        let full = RecordSchema::default();
+        let before_quality = RecordSchema::from_bits(0b111_1111);
+        assert_eq!(full.record_size(), before_quality.record_size() + 1);
+        for &(quality, schema, expected) in &[
+            (Some(42), full, Some(42)),
+            (None, full, None),
+            (Some(42), before_quality, None),
+        ] {
+            let mut reading = reading(100.0, 1_000);
+            reading.quality = quality;
+            let mut buf = [0_u8; CT_READING_SIZE];
+            let size =
+                CTStorage::encode_record(1, &reading, 7, ByteOrder::Little, schema, &mut buf)
+                    .unwrap();
+            assert_eq!(size, schema.record_size());
+            let (_, decoded) =
+                CTStorage::decode_record(&buf[..size], ByteOrder::Little, schema).unwrap();
+            assert_eq!(decoded.quality, expected);
+            assert_eq!(decoded.sequence, 7);
+        }
+    }
+
+    #[test]
+    fn lifetime_stats_count_written_saves_only() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        storage.set_write_batching(Some(4096));
+        let mut cts = test_cts();
+        save(&mut storage, &mut cts, 1_000);
+        save(&mut storage, &mut cts, 2_000);
+        assert_eq!(storage.lifetime_stats().real_power.count, 0);
+
+        storage.write_buffered();
+        assert_eq!(
+            storage.lifetime_stats().real_power.count,
+            2 * AC_PHASE as u64
+        );
+    }
+
+    #[test]
+    fn lifetime_stats_are_stored_every_few_saves_and_caught_up_at_boot() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let saves = STATS_STORE_INTERVAL as u64 + 3;
+        let mut before = storage(&fs);
+        save(&mut before, &mut cts, 1_000);
+        assert!(fs.file_size("/littlefs/lifetime_stats").is_err());
+        for n in 2..=saves {
+            save(&mut before, &mut cts, n * 1_000);
+        }
+        assert_eq!(before.unstored_stats, 3);
+        // A power cut, no chance to store the last saves.
+        std::mem::forget(before);
+
+        let mut storage = storage(&fs);
+        storage.load_sequence().unwrap();
+        storage.load_lifetime_stats().unwrap();
+        assert!(!storage.boot.lifetime_stats_rebuilt);
+        let stats = storage.lifetime_stats();
+        assert_eq!(stats.real_power.count, saves * AC_PHASE as u64);
+        assert_eq!(storage.stats_sequence, saves as u32);
+    }
+
+    #[test]
+    fn peak_demand_is_stored_with_the_lifetime_stats() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let mut before = storage(&fs);
+        for (timestamp, real_power) in [(1_000, 800.0), (2_000, 2_500.0), (3_000, -4_000.0)] {
+            for ct in cts.iter_mut() {
+                ct.reading = reading(real_power, timestamp);
+            }
+            before.save_to_storage(&cts).unwrap();
+        }
+        let stats = before.lifetime_stats();
+        assert_eq!((stats.peak_demand, stats.peak_demand_at), (2_500.0, 2_000));
+        // Dropping the storage stores the stats.
+        drop(before);
+
+        let mut storage = storage(&fs);
+        storage.load_sequence().unwrap();
+        storage.load_lifetime_stats().unwrap();
+        assert!(!storage.boot.lifetime_stats_rebuilt);
+        let stats = storage.lifetime_stats();
+        assert_eq!((stats.peak_demand, stats.peak_demand_at), (2_500.0, 2_000));
+
+        // Stats stored without the peak demand still load, the peak starts over.
+        let mut old = fs.read("/littlefs/lifetime_stats").unwrap();
+        old.truncate(LIFETIME_STATS_SIZE - PEAK_DEMAND_SIZE);
+        fs.write_atomic("/littlefs/lifetime_stats", &old).unwrap();
+        storage.load_lifetime_stats().unwrap();
+        assert!(!storage.boot.lifetime_stats_rebuilt);
+        assert_eq!(storage.lifetime_stats().peak_demand, 0.0);
+        assert_eq!(
+            storage.lifetime_stats().real_power.count,
+            3 * AC_PHASE as u64
+        );
+    }
+
+    #[test]
+    fn tou_totals_count_written_saves_and_are_caught_up_at_boot() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let saves = STATS_STORE_INTERVAL as u64 + 3;
+        let mut before = storage(&fs);
+        before.set_write_batching(Some(4096));
+        save(&mut before, &mut cts, 1_000);
+        assert_eq!(before.tou_totals().off_peak, 0.0);
+        before.write_buffered();
+        let per_save = before.tou_totals().off_peak;
+        assert!(per_save > 0.0);
+        before.set_write_batching(None);
+        for n in 2..=saves {
+            save(&mut before, &mut cts, n * 1_000);
+        }
+        assert!(fs.file_size("/littlefs/tou_totals").is_ok());
+        assert_eq!(before.unstored_stats, 3);
+        // A power cut, no chance to store the last saves.
+        std::mem::forget(before);
+
+        let mut storage = storage(&fs);
+        storage.load_sequence().unwrap();
+        storage.load_tou_totals().unwrap();
+        assert!(!storage.boot.tou_totals_rebuilt);
+        let expected = per_save * saves as f64;
+        assert!((storage.tou_totals().off_peak - expected).abs() < 1e-6 * expected);
+        assert_eq!(storage.tou_sequence, saves as u32);
+    }
+
+    #[test]
+    fn damaged_tou_totals_dont_stop_the_boot() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        {
+            let mut before = storage(&fs);
+            save(&mut before, &mut cts, 1_000);
+        }
+        // Damaged, and the rebuilt totals can't be stored either.
+        fs.write_atomic("/littlefs/tou_totals", b"damaged").unwrap();
+        fs.create_dir("/littlefs/tou_totals.tmp").unwrap();
+
+        let mut storage = storage(&fs);
+        storage.load_persisted_state().unwrap();
+        assert_eq!(storage.tou_totals().off_peak, 0.0);
+        assert_eq!(storage.tou_sequence, storage.sequence);
+        assert!(storage.lifetime_stats().real_power.count > 0);
+    }
+
+    // Sequence numbers of the records of readings_since(`seq`).
+    fn sequences_since(storage: &CTStorage, seq: u32) -> Vec<u32> {
+        storage
+            .readings_since(seq)
+            .unwrap()
+            .iter()
+            .map(|(_, reading)| reading.sequence)
+            .collect()
+    }
+
+    #[test]
+    fn readings_since_crosses_shard_boundaries() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        let mut cts = test_cts();
+        for n in 1..=5 {
+            save(&mut storage, &mut cts, n * 1_000);
+        }
+        // Every save is past MAX_SHARD_SIZE and starts a shard of its own.
+        assert_eq!(storage.readings_shards.len(), 5);
+        let since = |seq: u32| -> Vec<u32> {
+            (seq + 1..=5)
+                .flat_map(|n| std::iter::repeat_n(n, AC_PHASE))
+                .collect()
+        };
+        for seq in 0..=5 {
+            assert_eq!(sequences_since(&storage, seq), since(seq), "since {}", seq);
+        }
+
+        // Without the sequence index the shards are searched from the newest back.
+        fs.remove_file("/littlefs/sequence_index").unwrap();
+        for seq in 0..=5 {
+            assert_eq!(sequences_since(&storage, seq), since(seq), "since {}", seq);
+        }
+    }
+
+    #[test]
+    fn shard_header_holds_the_format_until_reset() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let schema = RecordSchema::from_bits(0b0_0011);
+        {
+            let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Big);
+            storage.set_min_save_interval(Duration::ZERO);
+            storage.set_record_schema(schema);
+            storage.find_newest_readings_shard_num().unwrap();
+            save(&mut storage, &mut cts, 1_000);
+        }
+        let shard = fs.read("/littlefs/ct_readings/1").unwrap();
+        assert_eq!(shard[..SHARD_HEADER_SIZE], [b'S', 1, 1, schema.to_bits()]);
+        assert_eq!(
+            shard.len(),
+            SHARD_HEADER_SIZE + schema.record_size() * AC_PHASE
+        );
+
+        let mut restored = storage(&fs);
+        assert_eq!(restored.byte_order(), ByteOrder::Big);
+        assert_eq!(restored.record_schema(), schema);
+        assert_eq!(restored.record_count(1).unwrap(), AC_PHASE);
+        assert_eq!(restored.read_record(1, 0).unwrap().1.timestamp, 1_000);
+
+        // The new shards after a reset get the configured format, not the stale one.
+        restored.log_powerloss().unwrap();
+        restored.reset_storage().unwrap();
+        assert_eq!(restored.record_schema(), RecordSchema::default());
+        save(&mut restored, &mut cts, 2_000);
+        let shard = fs.read("/littlefs/ct_readings/1").unwrap();
+        let header = [b'S', 1, 0, RecordSchema::default().to_bits()];
+        assert_eq!(shard[..SHARD_HEADER_SIZE], header);
+        drop(restored);
+        let rebooted = storage(&fs);
+        assert_eq!(rebooted.record_schema(), RecordSchema::default());
+        assert_eq!(stored(&rebooted)[0].1.timestamp, 2_000);
+    }
+
+    #[test]
+    fn shards_of_the_format_file_get_a_header() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let schema = RecordSchema::from_bits(0b0_0111);
+        {
+            let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Big);
+            storage.set_min_save_interval(Duration::ZERO);
+            storage.set_record_schema(schema);
+            storage.find_newest_readings_shard_num().unwrap();
+            for i in 1..=3 {
+                save(&mut storage, &mut cts, 1_000 * i);
+            }
+        }
+        // As stored before the headers: the records only, their format in the format file.
+        for name in fs.read_dir("/littlefs/ct_readings").unwrap() {
+            let path = format!("/littlefs/ct_readings/{}", name);
+            let shard = fs.read(&path).unwrap();
+            fs.write_atomic(&path, &shard[SHARD_HEADER_SIZE..]).unwrap();
+        }
+        fs.write_atomic("/littlefs/format", &[1, schema.to_bits()])
+            .unwrap();
+
+        let storage = storage(&fs);
+        assert!(fs.read("/littlefs/format").is_err());
+        assert_eq!(storage.byte_order(), ByteOrder::Big);
+        assert_eq!(storage.record_schema(), schema);
+        let timestamps: Vec<u64> = stored(&storage).iter().map(|(_, r)| r.timestamp).collect();
+        let expected: Vec<u64> = (1..=3)
+            .flat_map(|i| std::iter::repeat_n(1_000 * i, AC_PHASE))
+            .collect();
+        assert_eq!(timestamps, expected);
+    }
+
+    #[test]
+    fn shard_of_an_unknown_header_version_is_refused() {
+        let fs = MemFs::new();
+        fs.create_dir("/littlefs/ct_readings").unwrap();
+        fs.write_atomic("/littlefs/ct_readings/1", &[b'S', 2, 0, 0b1_1111, 0])
+            .unwrap();
+        let mut storage = CTStorage::with_fs(Box::new(fs), ByteOrder::Little);
+        assert!(storage.find_newest_readings_shard_num().is_err());
+    }
+
+    // Sequence numbers of the stored records, one per save.
+    fn stored_saves(storage: &CTStorage) -> Vec<u32> {
+        let mut saves: Vec<u32> = stored(storage).iter().map(|(_, r)| r.sequence).collect();
+        saves.dedup();
+        saves
+    }
+
+    #[test]
+    fn ring_policy_deletes_the_oldest_unsynced_shards() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let mut storage = storage(&fs);
+        let save_size = (record_size() * AC_PHASE) as u64;
+        storage.set_unsynced_limit(Some(2 * save_size), UnsyncedPolicy::Ring);
+        for i in 1..=5 {
+            save(&mut storage, &mut cts, 1_000 * i);
+            assert!(storage.unsynced_bytes() <= 2 * save_size);
+        }
+        assert_eq!(stored_saves(&storage), [4, 5]);
+        // The deleted saves can't be synced any more.
+        assert_eq!(fs.read("/littlefs/synced").unwrap(), 3_u32.to_le_bytes());
+
+        // An acknowledged sync frees the room for the next saves.
+        storage.mark_synced(5).unwrap();
+        assert_eq!(storage.unsynced_bytes(), 0);
+        save(&mut storage, &mut cts, 6_000);
+        save(&mut storage, &mut cts, 7_000);
+        assert_eq!(stored_saves(&storage), [4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn back_pressure_policy_pauses_saving_until_synced() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let mut storage = storage(&fs);
+        let save_size = (record_size() * AC_PHASE) as u64;
+        storage.set_unsynced_limit(Some(2 * save_size), UnsyncedPolicy::BackPressure);
+        save(&mut storage, &mut cts, 1_000);
+        assert!(!storage.is_back_pressured());
+        save(&mut storage, &mut cts, 2_000);
+        assert!(storage.is_back_pressured());
+        assert!(storage.save_to_storage(&cts).is_err());
+        assert_eq!(stored_saves(&storage), [1, 2]);
+
+        storage.mark_synced(1).unwrap();
+        assert!(!storage.is_back_pressured());
+        save(&mut storage, &mut cts, 3_000);
+        assert_eq!(stored_saves(&storage), [1, 2, 3]);
+        assert!(storage.is_back_pressured());
+        // Marks beyond the last save don't count.
+        storage.mark_synced(100).unwrap();
+        assert_eq!(storage.unsynced_bytes(), 0);
+        assert_eq!(fs.read("/littlefs/synced").unwrap(), 3_u32.to_le_bytes());
+    }
+
+    // Golden readings of the waveforms of replayed_waveforms_match_their_golden_readings: path,
+    // lag of the current, and i_rms, v_rms, real_power and apparent_power of the reading.
+    const GOLDEN_WAVEFORMS: [(&str, f32, [f32; 4]); 2] = [
+        (
+            "/littlefs/resistive",
+            0.0,
+            [34.2481, 175.0349, 5994.61, 5994.61],
+        ),
+        (
+            "/littlefs/inductive",
+            0.6,
+            [34.2399, 175.0349, 4946.75, 5993.18],
+        ),
+    ];
+
+    #[test]
+    fn replayed_waveforms_match_their_golden_readings() {
+        let fs = MemFs::new();
+        for (path, lag, golden) in GOLDEN_WAVEFORMS {
+            let buf: Vec<u8> = sine_samples(20, 800.0, 400.0, lag)
+                .iter()
+                .flat_map(|(i, v)| [i.to_le_bytes(), v.to_le_bytes()].concat())
+                .collect();
+            fs.write_atomic(path, &buf).unwrap();
+            // The calibration of the goldens, the defaults differ between the phase features.
+            let mut ct = centred_ct();
+            ct.set_calibration(Calibration {
+                vcal: 230.0,
+                ical: 90.0,
+                phase_cal: 1.0,
+            });
+            let replayed = replay_waveform(&fs, path, &mut ct).unwrap();
+            let values = [
+                replayed.i_rms,
+                replayed.v_rms,
+                replayed.real_power,
+                replayed.apparent_power,
+            ];
+            for (value, golden) in values.iter().zip(golden) {
+                assert!(
+                    (value - golden).abs() <= golden * 1e-5,
+                    "{}: {:?} instead of {:?}",
+                    path,
+                    values,
+                    GOLDEN_WAVEFORMS
+                );
+            }
+        }
+        assert!(replay_waveform(&fs, "/littlefs/missing", &mut centred_ct()).is_err());
+    }
+
+    #[test]
+    fn skipped_clipped_readings_count_nowhere() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        storage.set_clipped_policy(ClippedPolicy::Skip);
+        let mut cts = test_cts();
+        for ct in cts.iter_mut() {
+            ct.reading = reading(1200.0, 1_000);
+        }
+        cts[0].reading.clipped = true;
+        let clipped_id = cts[0].id;
+        assert!(storage.skips(&cts[0]));
+        storage.save_to_storage(&cts).unwrap();
+        for ct in cts.iter_mut() {
+            if storage.skips(ct) {
+                ct.discard_interval();
+            } else {
+                ct.reset_interval();
+            }
+        }
+
+        let records = stored(&storage);
+        assert_eq!(records.len(), AC_PHASE - 1);
+        assert!(records.iter().all(|(id, _)| *id != clipped_id));
+        assert_eq!(cts[0].lifetime_kwh(), 0.0);
+        let kwh = reading(1200.0, 1_000).kwh as f64;
+        for ct in &cts[1..] {
+            assert_eq!(ct.lifetime_kwh(), kwh);
+        }
+        let stats = storage.lifetime_stats();
+        assert_eq!(stats.real_power.count, AC_PHASE as u64 - 1);
+        let tou = storage.tou_totals();
+        let tou_kwh = tou.peak + tou.shoulder + tou.off_peak;
+        assert!((tou_kwh - kwh * (AC_PHASE - 1) as f64).abs() < 1e-9);
+
+        // The energy totals stored by shutdown leave it out too.
+        cts[0].reading = reading(1200.0, 2_000);
+        cts[0].reading.clipped = true;
+        fs.write_atomic("/littlefs/time", &[]).unwrap();
+        storage.shutdown(&cts).unwrap();
+        let mut restored = test_cts();
+        storage.load_energy_totals(&mut restored).unwrap();
+        assert_eq!(restored[0].lifetime_kwh(), 0.0);
+    }
+
+    #[test]
+    fn clipped_currents_are_left_out_of_the_lifetime_stats() {
+        let fs = MemFs::new();
+        let mut live = storage(&fs);
+        live.set_clipped_policy(ClippedPolicy::Sentinel);
+        let mut cts = test_cts();
+        save(&mut live, &mut cts, 1_000);
+        for ct in cts.iter_mut() {
+            ct.reading = reading(5000.0, 2_000);
+            ct.reading.clipped = true;
+        }
+        live.save_to_storage(&cts).unwrap();
+        let records = stored(&live);
+        assert!(records[AC_PHASE..]
+            .iter()
+            .all(|(_, r)| r.i_rms == CLIPPED_SENTINEL && r.apparent_power == CLIPPED_SENTINEL));
+
+        // Neither the measured values nor the sentinels, live or rebuilt from the records.
+        let stats = live.lifetime_stats();
+        let unclipped = save_i_rms(&records[..AC_PHASE]);
+        assert_eq!(stats.real_power.count, 2 * AC_PHASE as u64);
+        assert_eq!(stats.i_rms.count, AC_PHASE as u64);
+        assert!((stats.i_rms.sum - unclipped).abs() < 1e-3);
+        // Not stored yet, see STATS_STORE_INTERVAL, so a reboot rebuilds them from the records.
+        assert!(fs.file_size("/littlefs/lifetime_stats").is_err());
+        let mut rebuilt = storage(&fs);
+        rebuilt.load_lifetime_stats().unwrap();
+        assert_eq!(rebuilt.lifetime_stats().i_rms.count, AC_PHASE as u64);
+        assert!((rebuilt.lifetime_stats().i_rms.sum - unclipped).abs() < 1e-3);
+    }
+
+    fn save_i_rms(records: &[(u16, CTReading)]) -> f64 {
+        records.iter().map(|(_, r)| r.i_rms as f64).sum()
+    }
+
+    #[test]
+    fn shards_get_padded_names_at_boot() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let mut unpadded = storage(&fs);
+        for i in 1..=3 {
+            save(&mut unpadded, &mut cts, 1_000 * i);
+        }
+        let before = stored(&unpadded);
+        drop(unpadded);
+
+        let mut padded = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        padded.set_min_save_interval(Duration::ZERO);
+        padded.set_write_batching(None);
+        padded.set_pad_shard_names(true);
+        padded.find_newest_readings_shard_num().unwrap();
+        assert_eq!(padded.migrate_shard_names().unwrap(), 3);
+        assert_eq!(padded.migrate_shard_names().unwrap(), 0);
+        let mut names = fs.read_dir("/littlefs/ct_readings").unwrap();
+        names.sort();
+        assert_eq!(names, ["0000000001", "0000000002", "0000000003"]);
+        assert_eq!(format!("{:?}", stored(&padded)), format!("{:?}", before));
+
+        save(&mut padded, &mut cts, 4_000);
+        assert!(fs.file_size("/littlefs/ct_readings/0000000004").is_ok());
+        assert_eq!(stored(&padded).len(), 4 * AC_PHASE);
+    }
+
+    // The sequence numbers of the entries of the sequence index.
+    fn index_sequences(fs: &MemFs) -> Vec<u32> {
+        fs.read("/littlefs/sequence_index")
+            .unwrap()
+            .chunks_exact(SEQUENCE_INDEX_ENTRY_SIZE)
+            .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]))
+            .collect()
+    }
+
+    #[test]
+    fn sequence_index_is_thinned_to_its_bound() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        let mut cts = test_cts();
+        let saves = SEQUENCE_INDEX_MAX_ENTRIES as u32 + 10;
+        for n in 1..=saves {
+            save(&mut storage, &mut cts, n as u64 * 1_000);
+        }
+        let entries = index_sequences(&fs);
+        assert!(entries.len() <= SEQUENCE_INDEX_MAX_ENTRIES);
+        assert!(entries.len() > SEQUENCE_INDEX_MAX_ENTRIES / 2);
+        assert_eq!(entries[0], 1);
+        assert!(entries.windows(2).all(|pair| pair[0] < pair[1]));
+        for seq in [0, 1, 500, saves - 1, saves] {
+            let expected: Vec<u32> = (seq + 1..=saves)
+                .flat_map(|n| std::iter::repeat_n(n, AC_PHASE))
+                .collect();
+            assert_eq!(sequences_since(&storage, seq), expected, "since {}", seq);
+        }
+    }
+
+    #[test]
+    fn torn_sequence_index_is_rebuilt() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        let mut cts = test_cts();
+        for n in 1..=3 {
+            save(&mut storage, &mut cts, n * 1_000);
+        }
+        // A power cut in the middle of an entry.
+        let mut torn = fs.read("/littlefs/sequence_index").unwrap();
+        torn.extend_from_slice(&[4, 0, 0]);
+        fs.write_atomic("/littlefs/sequence_index", &torn).unwrap();
+        assert_eq!(sequences_since(&storage, 2).len(), AC_PHASE);
+
+        save(&mut storage, &mut cts, 4_000);
+        assert_eq!(index_sequences(&fs), [1, 2, 3, 4]);
+        assert_eq!(
+            fs.file_size("/littlefs/sequence_index").unwrap(),
+            4 * SEQUENCE_INDEX_ENTRY_SIZE as u64
+        );
+        assert_eq!(sequences_since(&storage, 3), vec![4; AC_PHASE]);
+    }
+
+    #[test]
+    fn coarsened_saves_are_averaged_evenly() {
+        let fs = MemFs::new();
+        let space = SpaceFs::new(&fs, 100, 50);
+        let mut coarse = CTStorage::with_fs(Box::new(space.clone()), ByteOrder::Little);
+        coarse.set_min_save_interval(Duration::ZERO);
+        coarse.set_write_batching(None);
+        coarse.find_newest_readings_shard_num().unwrap();
+        assert!(!coarse.is_low_space());
+        coarse.set_low_space_policy(LowSpacePolicy::Coarsen);
+        let mut cts = test_cts();
+        save(&mut coarse, &mut cts, 1_000);
+
+        // Nearly full: the next saves are held back and combined.
+        *space.space.lock().unwrap() = (100, 95);
+        for (timestamp, power) in [(2_000, 200.0), (3_000, 300.0), (4_000, 700.0)] {
+            for ct in cts.iter_mut() {
+                ct.reading = reading(power, timestamp);
+            }
+            coarse.save_to_storage(&cts).unwrap();
+            assert!(coarse.is_low_space());
+            cts.iter_mut().for_each(CT::reset_interval);
+        }
+        assert_eq!(stored(&coarse).len(), AC_PHASE);
+        fs.write_atomic("/littlefs/time", &[]).unwrap();
+        coarse.shutdown(&cts).unwrap();
+
+        let records = stored(&coarse);
+        assert_eq!(records.len(), 2 * AC_PHASE);
+        let kwh: f32 = [200.0, 300.0, 700.0]
+            .iter()
+            .map(|&power| reading(power, 0).kwh)
+            .sum();
+        for (_, coarsened) in &records[AC_PHASE..] {
+            assert!((coarsened.real_power - 400.0).abs() < 1e-3);
+            assert!((coarsened.kwh - kwh).abs() < 1e-6);
+            assert_eq!(coarsened.timestamp, 4_000);
+        }
+    }
+
+    #[test]
+    fn state_round_trips_to_another_device() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let mut source = storage(&fs);
+        for (timestamp, real_power) in [(1_000, 800.0), (2_000, 2_500.0)] {
+            for ct in cts.iter_mut() {
+                ct.reading = reading(real_power, timestamp);
+            }
+            source.save_to_storage(&cts).unwrap();
+        }
+        for (i, ct) in cts.iter_mut().enumerate() {
+            ct.set_calibration(Calibration {
+                vcal: 230.0 + i as f32,
+                ical: 90.0 + i as f32,
+                phase_cal: 1.5,
+            });
+            ct.set_vt_ratio(25.5);
+            ct.energy_total_kwh = 12.5 * (i + 1) as f64;
+        }
+        source.tou_totals = TouTotals {
+            peak: 1.0,
+            shoulder: 2.0,
+            off_peak: 3.0,
+        };
+        let state = source.export_state(&cts).unwrap();
+        assert_eq!(state.len(), STATE_SIZE);
+
+        let target_fs = MemFs::new();
+        let mut target_cts = test_cts();
+        let mut target = storage(&target_fs);
+        target.import_state(&mut target_cts, &state).unwrap();
+        assert_eq!(target.export_state(&target_cts).unwrap(), state);
+        let stats = target.lifetime_stats();
+        assert_eq!((stats.peak_demand, stats.peak_demand_at), (2_500.0, 2_000));
+        for (ct, target_ct) in cts.iter().zip(&target_cts) {
+            assert_eq!(target_ct.calibration(), ct.calibration());
+            assert_eq!(target_ct.vt_ratio(), 25.5);
+            assert_eq!(target_ct.energy_total_kwh, ct.energy_total_kwh);
+        }
+
+        // The import was stored, a reboot loads the same state.
+        let mut booted_cts = test_cts();
+        let mut booted = storage(&target_fs);
+        booted.load_sequence().unwrap();
+        booted.load_calibration(&mut booted_cts).unwrap();
+        booted.load_energy_totals(&mut booted_cts).unwrap();
+        booted.load_lifetime_stats().unwrap();
+        booted.load_tou_totals().unwrap();
+        assert_eq!(booted.sequence, source.sequence);
+        assert_eq!(booted.lifetime_stats().peak_demand, 2_500.0);
+        assert_eq!(booted.tou_totals().off_peak, 3.0);
+        for (ct, booted_ct) in cts.iter().zip(&booted_cts) {
+            assert_eq!(booted_ct.calibration(), ct.calibration());
+            assert_eq!(booted_ct.vt_ratio(), 25.5);
+            assert_eq!(booted_ct.energy_total_kwh, ct.energy_total_kwh);
+        }
+    }
+
+    #[test]
+    fn damaged_or_foreign_state_changes_nothing() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let mut target = storage(&fs);
+        let before = target.export_state(&cts).unwrap();
+        let state = before.clone();
+
+        let mut bad_crc = state.clone();
+        bad_crc[2] ^= 0xff;
+        let mut other_ct = state.clone();
+        let ct_pos = 2 + 4 + 3 * 8 + PEAK_DEMAND_SIZE;
+        other_ct[ct_pos] = 99;
+        let crc = crc16(&other_ct[..STATE_SIZE - 2]);
+        other_ct[STATE_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+        let mut newer = state.clone();
+        newer[0] = STATE_VERSION + 1;
+        let crc = crc16(&newer[..STATE_SIZE - 2]);
+        newer[STATE_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+        for bytes in [&state[..STATE_SIZE - 1], &bad_crc, &other_ct, &newer] {
+            assert!(target.import_state(&mut cts, bytes).is_err());
+        }
+        assert_eq!(target.export_state(&cts).unwrap(), before);
+        assert!(fs.read("/littlefs/calibration").is_err());
+    }
+
+    #[test]
+    fn version_1_state_keeps_the_peak_demand_and_ratio() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        for ct in cts.iter_mut() {
+            ct.set_vt_ratio(25.5);
+        }
+        let mut target = storage(&fs);
+        target.stats.peak_demand = 1_000.0;
+
+        // A blob of version 1: no peak demand and no ratio.
+        let mut state = vec![1, AC_PHASE as u8];
+        state.extend_from_slice(&7_u32.to_le_bytes());
+        for total in [1.0_f64, 2.0, 3.0] {
+            state.extend_from_slice(&total.to_le_bytes());
+        }
+        for ct in &cts {
+            state.extend_from_slice(&ct.id.to_le_bytes());
+            for value in [240.0_f32, 95.0, 1.2] {
+                state.extend_from_slice(&value.to_le_bytes());
+            }
+            state.extend_from_slice(&4.5_f64.to_le_bytes());
+        }
+        let crc = crc16(&state);
+        state.extend_from_slice(&crc.to_le_bytes());
+
+        target.import_state(&mut cts, &state).unwrap();
+        assert_eq!(target.lifetime_stats().peak_demand, 1_000.0);
+        assert_eq!(target.tou_totals().shoulder, 2.0);
+        for ct in &cts {
+            assert_eq!(ct.vt_ratio(), 25.5);
+            assert_eq!(ct.calibration().vcal, 240.0);
+            assert_eq!(ct.energy_total_kwh, 4.5);
+        }
+    }
+
+    #[test]
+    fn exported_energy_is_stored_with_the_energy_totals() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        for ct in cts.iter_mut() {
+            ct.set_warmup_readings(0);
+            ct.set_negative_energy(NegativeEnergy::Export);
+            let mut exported = reading(-750.0, 0);
+            exported.kwh = -0.75;
+            ct.add_reading(exported);
+            ct.reset_interval();
+        }
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.save_energy_totals(&cts).unwrap();
+
+        let mut loaded = test_cts();
+        storage.load_energy_totals(&mut loaded).unwrap();
+        assert!(loaded.iter().all(|ct| ct.exported_kwh() == 0.75));
+
+        // Totals stored before the exported kWh still load, without any.
+        let mut legacy = Vec::new();
+        for ct in &cts {
+            legacy.extend_from_slice(&ct.id.to_le_bytes());
+            legacy.extend_from_slice(&12.5_f64.to_le_bytes());
+        }
+        assert_eq!(legacy.len(), LEGACY_ENERGY_TOTAL_SIZE * AC_PHASE);
+        fs.write_atomic("/littlefs/energy_totals", &legacy).unwrap();
+        let mut loaded = test_cts();
+        storage.load_energy_totals(&mut loaded).unwrap();
+        assert!(loaded
+            .iter()
+            .all(|ct| ct.lifetime_kwh() == 12.5 && ct.exported_kwh() == 0.0));
+    }
+
+    #[test]
+    fn writers_back_off_while_another_save_runs() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let mut live = storage(&fs);
+        save(&mut live, &mut cts, 1_000);
+        let state = live.export_state(&cts).unwrap();
+        let before = stored(&live);
+
+        let saving = SaveGuard::acquire(&live.saving).unwrap();
+        let results = [
+            live.save_to_storage(&cts),
+            live.shutdown(&cts),
+            live.import_state(&mut cts, &state),
+            live.recompute_energy(Duration::from_secs(60)).map(|_| ()),
+            live.reset_storage(),
+        ];
+        for res in results {
+            assert!(is_save_busy(&res.unwrap_err()));
+        }
+        assert_eq!(stored(&live).len(), before.len());
+        // Another storage has its own saves.
+        save(&mut storage(&fs), &mut test_cts(), 1_500);
+        drop(saving);
+
+        save(&mut live, &mut cts, 2_000);
+        assert_eq!(stored(&live).len(), before.len() + 2 * AC_PHASE);
+    }
+
+    // Store a reading with just `flag` set and read it back from the shard and from a serial
+    // frame, both must have that flag only.
+    fn assert_flag_round_trips(flag: ReadingFlags) {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let mut live = storage(&fs);
+        for ct in cts.iter_mut() {
+            ct.reading = reading(100.0, 1_000);
+            ct.reading.set_flags(flag);
+        }
+        live.save_to_storage(&cts).unwrap();
+        let records = stored(&live);
+        assert_eq!(records.len(), AC_PHASE);
+        for (_, record) in &records {
+            assert_eq!(record.flags(), flag);
+        }
+
+        let frame = cts[0].reading.reading_to_frame(cts[0].id);
+        let (parsed, _) = crate::serial::tests::parse_frame(&frame);
+        assert_eq!(parsed.unwrap().1.flags(), flag);
+    }
+
+    #[test]
+    fn clipped_flag_round_trips() {
+        assert_flag_round_trips(ReadingFlags::CLIPPED);
+    }
+
+    #[test]
+    fn under_sampled_flag_round_trips() {
+        assert_flag_round_trips(ReadingFlags::UNDER_SAMPLED);
+    }
+
+    #[test]
+    fn unsynced_time_flag_round_trips() {
+        assert_flag_round_trips(ReadingFlags::UNSYNCED_TIME);
+    }
+
+    #[test]
+    fn apparent_only_flag_round_trips() {
+        assert_flag_round_trips(ReadingFlags::APPARENT_ONLY);
+    }
+
+    #[test]
+    fn stuck_flag_round_trips() {
+        assert_flag_round_trips(ReadingFlags::STUCK);
+    }
+
+    #[test]
+    fn reduced_precision_flag_round_trips() {
+        assert_flag_round_trips(ReadingFlags::REDUCED_PRECISION);
+    }
+
+    #[test]
+    fn implausible_voltage_flag_round_trips() {
+        assert_flag_round_trips(ReadingFlags::IMPLAUSIBLE_VOLTAGE);
+    }
+
+    #[test]
+    fn shard_cache_evicts_within_its_budget_and_is_cleared_on_reset() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let mut live = storage(&fs);
+        live.log_powerloss().unwrap();
+        // A shard per save.
+        for timestamp in 1..=4 {
+            save(&mut live, &mut cts, timestamp * 1_000);
+        }
+        let mut ids: Vec<i32> = live.readings_shards.iter().copied().collect();
+        ids.sort();
+        assert_eq!(ids.len(), 4);
+        let shard_size = fs.file_size(&live.shard_path(ids[0])).unwrap() as usize;
+        let read = |live: &CTStorage, id: i32| -> Vec<(u16, u64, f32)> {
+            let records = live.read_shard(id).unwrap();
+            records
+                .iter()
+                .map(|(ct_id, record)| (*ct_id, record.timestamp, record.real_power))
+                .collect()
+        };
+        let uncached: Vec<_> = ids.iter().map(|&id| read(&live, id)).collect();
+
+        live.set_shard_cache(2 * shard_size);
+        for &id in &ids {
+            live.read_shard(id).unwrap();
+        }
+        let stats = live.shard_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (0, ids.len() as u64));
+        assert_eq!(stats.evictions, ids.len() as u64 - 2);
+        assert_eq!(stats.bytes, 2 * shard_size);
+
+        // The two read last are cached, the first one was evicted.
+        for (i, &id) in ids.iter().enumerate().rev().take(2) {
+            assert_eq!(read(&live, id), uncached[i]);
+        }
+        assert_eq!(live.shard_cache_stats().hits, 2);
+        assert_eq!(read(&live, ids[0]), uncached[0]);
+        assert_eq!(live.shard_cache_stats().misses, ids.len() as u64 + 1);
+
+        // A reset rewrites the shards, none of the cached contents survive it.
+        live.reset_storage().unwrap();
+        assert_eq!(live.shard_cache_stats().bytes, 0);
+        save(&mut live, &mut cts, 3_000);
+        let newest = *live.readings_shards.iter().max().unwrap();
+        let records = live.read_shard(newest).unwrap();
+        assert!(records.iter().all(|(_, record)| record.timestamp == 3_000));
+    }
+
+    #[test]
+    fn truncated_shard_is_kept_and_rolled_over() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let shard = {
+            let mut storage = storage(&fs);
+            save(&mut storage, &mut cts, 1_000);
+            storage.readings_shard_counter
+        };
+        let path = format!("{}/ct_readings/{}", STORAGE_MOUNTS[0].root, shard);
+        let mut data = fs.read(&path).unwrap();
+        data.truncate(data.len() - 3);
+        fs.write_atomic(&path, &data).unwrap();
+
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.set_shard_recovery(ShardRecovery::Roll);
+        storage.set_min_save_interval(Duration::ZERO);
+        storage.find_newest_readings_shard_num().unwrap();
+        assert!(storage.boot.rolled_shard);
+        assert_eq!(storage.boot.truncated_bytes, 0);
+        assert_eq!(storage.readings_shard_counter, shard + 1);
+        assert_eq!(fs.read(&path).unwrap(), data);
+        assert_eq!(storage.read_shard(shard).unwrap().len(), AC_PHASE - 1);
+
+        storage.load_sequence().unwrap();
+        save(&mut storage, &mut cts, 2_000);
+        let size = fs.file_size(&storage.shard_path(shard + 1)).unwrap() as usize;
+        assert_eq!((size - SHARD_HEADER_SIZE) % record_size(), 0);
+        let records = stored(&storage);
+        assert_eq!(records.len(), 2 * AC_PHASE - 1);
+        assert_eq!(records.last().unwrap().1.timestamp, 2_000);
+    }
+
+    #[test]
+    fn list_shards_skips_stray_files() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        let mut cts = test_cts();
+        for i in 0..3 {
+            save(&mut storage, &mut cts, 1_000 * (i + 1));
+        }
+        let dir = format!("{}/ct_readings", STORAGE_MOUNTS[0].root);
+        fs.write_atomic(&format!("{}/notes.txt", dir), b"not a shard")
+            .unwrap();
+
+        let shards = storage.list_shards().unwrap();
+        assert_eq!(shards.len(), storage.readings_shards.len());
+        assert!(shards.windows(2).all(|pair| pair[0].id < pair[1].id));
+        for shard in &shards {
+            let size = fs.file_size(&storage.shard_path(shard.id)).unwrap();
+            assert_eq!(shard.size, size);
+            assert_eq!(shard.records, storage.shard_records(size).0);
+            assert_eq!(shard.active, shard.id == storage.readings_shard_counter);
+        }
+        let records: usize = shards.iter().map(|shard| shard.records).sum();
+        assert_eq!(records, 3 * AC_PHASE);
+        assert_eq!(shards.iter().filter(|shard| shard.active).count(), 1);
+    }
+
+    #[test]
+    fn vt_ratio_is_stored_with_the_calibration() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        set_ical(&mut cts, 50.0);
+        for ct in cts.iter_mut() {
+            ct.set_vt_ratio(25.5);
+        }
+        CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little)
+            .save_calibration(&cts)
+            .unwrap();
+        let size = fs.file_size("/littlefs/calibration").unwrap() as usize;
+        assert_eq!(size, 3 + CALIBRATION_SIZE * AC_PHASE);
+
+        let mut loaded = test_cts();
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.load_calibration(&mut loaded).unwrap();
+        assert_eq!(storage.boot.calibration, CalibrationLoad::Loaded);
+        assert!(loaded.iter().all(|ct| ct.vt_ratio() == 25.5));
+        assert!(loaded.iter().all(|ct| ct.calibration().ical == 50.0));
+    }
+
+    #[test]
+    fn version_1_calibration_keeps_the_ratio_and_is_migrated() {
+        let fs = MemFs::new();
+        let cts = test_cts();
+        let mut buf = vec![1_u8];
+        for ct in &cts {
+            buf.extend_from_slice(&ct.id.to_le_bytes());
+            for value in [40.0_f32, 50.0, 1.5] {
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        let crc = crc16(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        fs.write_atomic("/littlefs/calibration", &buf).unwrap();
+
+        let mut loaded = test_cts();
+        for ct in loaded.iter_mut() {
+            ct.set_vt_ratio(12.0);
+        }
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.load_calibration(&mut loaded).unwrap();
+        assert_eq!(storage.boot.calibration, CalibrationLoad::Migrated);
+        let expected = Calibration {
+            vcal: 40.0,
+            ical: 50.0,
+            phase_cal: 1.5,
+        };
+        assert!(loaded.iter().all(|ct| ct.calibration() == expected));
+        assert!(loaded.iter().all(|ct| ct.vt_ratio() == 12.0));
+        let size = fs.file_size("/littlefs/calibration").unwrap() as usize;
+        assert_eq!(size, 3 + CALIBRATION_SIZE * AC_PHASE);
+
+        let mut reloaded = test_cts();
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.load_calibration(&mut reloaded).unwrap();
+        assert_eq!(storage.boot.calibration, CalibrationLoad::Loaded);
+        assert!(reloaded.iter().all(|ct| ct.vt_ratio() == 12.0));
+    }
+
+    #[test]
+    fn boot_report_skips_unreadable_shards() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        let mut shards = {
+            let mut storage = storage(&fs);
+            for i in 0..3 {
+                save(&mut storage, &mut cts, 1_000 * (i + 1));
+            }
+            storage.readings_shards.iter().copied().collect::<Vec<_>>()
+        };
+        shards.sort();
+        assert_eq!(shards.len(), 3);
+
+        // The second shard fails to read after its first record.
+        let path = format!("{}/ct_readings/{}", STORAGE_MOUNTS[0].root, shards[1]);
+        let readable = (SHARD_HEADER_SIZE + record_size()) as u64;
+        let unreadable = UnreadableFs::new(&fs, &path, readable);
+        let mut storage = CTStorage::with_fs(Box::new(unreadable), ByteOrder::Little);
+        storage.set_integrity_scan(true);
+        storage.find_newest_readings_shard_num().unwrap();
+        storage.load_sequence().unwrap();
+        let report = storage.boot_report().unwrap();
+        assert_eq!(report.unreadable_shards, 1);
+        assert_eq!(report.corrupt_records, Some(0));
+        assert_eq!(report.records, 3 * AC_PHASE);
+
+        // A shard that is gone is skipped too.
+        fs.remove_file(&format!(
+            "{}/ct_readings/{}",
+            STORAGE_MOUNTS[0].root, shards[0]
+        ))
+        .unwrap();
+        let report = storage.boot_report().unwrap();
+        assert_eq!(report.unreadable_shards, 2);
+        assert_eq!(report.corrupt_records, Some(0));
+        assert_eq!(report.records, 2 * AC_PHASE);
+    }
+
+    // Measure `real_power` on every CT of `cts`, then save and start a new period like main.
+    fn measure_and_save(storage: &mut CTStorage, cts: &mut [CT; AC_PHASE], real_power: f32) {
+        for ct in cts.iter_mut() {
+            ct.add_reading(reading(real_power, 1_000));
+        }
+        storage.save_to_storage(cts).unwrap();
+        for ct in cts.iter_mut() {
+            ct.reset_interval();
+        }
+    }
+
+    #[test]
+    fn pausing_leaves_the_totals_unchanged() {
+        let fs = MemFs::new();
+        let mut storage = storage(&fs);
+        let mut cts = test_cts();
+        for ct in cts.iter_mut() {
+            ct.set_warmup_readings(0);
+        }
+        measure_and_save(&mut storage, &mut cts, 500.0);
+        let sequence = storage.sequence();
+        let records = stored(&storage).len();
+        let tou = storage.tou_totals();
+        let energy: Vec<f64> = cts.iter().map(|ct| ct.lifetime_kwh()).collect();
+        assert!(energy.iter().all(|kwh| *kwh > 0.0));
+
+        for ct in cts.iter_mut() {
+            ct.pause();
+        }
+        let shown = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = shown.clone();
+        cts[0].on_reading(Box::new(move |_, _| counter.set(counter.get() + 1)));
+        measure_and_save(&mut storage, &mut cts, 2_000.0);
+        assert_eq!(shown.get(), 1);
+        assert_eq!(storage.sequence(), sequence);
+        assert_eq!(stored(&storage).len(), records);
+        assert_eq!(storage.tou_totals().totals(), tou.totals());
+        for (ct, kwh) in cts.iter().zip(&energy) {
+            assert_eq!(ct.lifetime_kwh(), *kwh);
+        }
+
+        for ct in cts.iter_mut() {
+            ct.resume();
+        }
+        measure_and_save(&mut storage, &mut cts, 500.0);
+        assert_eq!(storage.sequence(), sequence + 1);
+        assert_eq!(stored(&storage).len(), records + AC_PHASE);
+        assert!(cts
+            .iter()
+            .zip(&energy)
+            .all(|(ct, kwh)| ct.lifetime_kwh() > *kwh));
+    }
+
+    #[test]
+    fn fault_capture_is_stored_under_the_storage_root() {
+        let fs = MemFs::mounted_at("/spiffs");
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.set_root("/spiffs");
+        let mut cts = test_cts();
+        cts[0] = mock_sine_ct(None);
+        cts[0].set_fault_capture(16, Some(0.001));
+        storage.store_fault_captures(&mut cts);
+        assert!(fs.read_dir("/spiffs").unwrap().is_empty());
+
+        let timeout = Duration::from_secs(5);
+        cts[0]
+            .measure_once(&mut test_sampler(), 40, timeout)
+            .unwrap();
+        storage.store_fault_captures(&mut cts);
+        let path = format!("/spiffs/fault_capture_{}", cts[0].id);
+        // The current is over the limit from the start, so there is little before the fault.
+        let size = fs.file_size(&path).unwrap();
+        assert!(
+            size > 0 && size <= 16 * 4 && size % 4 == 0,
+            "{} bytes",
+            size
+        );
+
+        // Only the first fault is captured, and stored once.
+        fs.remove_file(&path).unwrap();
+        cts[0]
+            .measure_once(&mut test_sampler(), 40, timeout)
+            .unwrap();
+        storage.store_fault_captures(&mut cts);
+        assert!(fs.read_dir("/spiffs").unwrap().is_empty());
+    }
+
+    // Load `bytes` as the stored calibration into default CTs.
+    fn load_calibration_file(bytes: &[u8]) -> (CalibrationLoad, [CT; AC_PHASE], MemFs) {
+        let fs = MemFs::new();
+        fs.write_atomic("/littlefs/calibration", bytes).unwrap();
+        let mut cts = test_cts();
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.load_calibration(&mut cts).unwrap();
+        (storage.boot.calibration, cts, fs)
+    }
+
+    // `file` with its crc made to match again.
+    fn with_crc(mut file: Vec<u8>) -> Vec<u8> {
+        let end = file.len() - 2;
+        let crc = crc16(&file[..end]);
+        file[end..].copy_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn damaged_calibration_is_set_aside() {
+        let fs = MemFs::new();
+        let mut cts = test_cts();
+        set_ical(&mut cts, 50.0);
+        CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little)
+            .save_calibration(&cts)
+            .unwrap();
+        let file = fs.read("/littlefs/calibration").unwrap();
+        let (load, loaded, _) = load_calibration_file(&file);
+        assert_eq!(load, CalibrationLoad::Loaded);
+        assert!(loaded.iter().all(|ct| ct.calibration().ical == 50.0));
+
+        // Cut off at a multiple of the size of the unversioned records, which it isn't.
+        let cut = file.len() / LEGACY_CALIBRATION_SIZE * LEGACY_CALIBRATION_SIZE;
+        let mut unknown_version = file.clone();
+        unknown_version[0] = CALIBRATION_VERSION + 1;
+        let mut bad_crc = file.clone();
+        bad_crc[4] ^= 0xff;
+        let mut invalid = file.clone();
+        invalid[3..7].copy_from_slice(&(-1.0_f32).to_le_bytes());
+        let mut invalid_ratio = file.clone();
+        invalid_ratio[15..19].copy_from_slice(&f32::NAN.to_le_bytes());
+        let damaged = [
+            file[..cut].to_vec(),
+            file[..file.len() - 1].to_vec(),
+            file[..2].to_vec(),
+            unknown_version,
+            bad_crc,
+            with_crc(invalid),
+            with_crc(invalid_ratio),
+            vec![1, 0, 0],
+            vec![0; LEGACY_CALIBRATION_SIZE - 1],
+            Vec::new(),
+        ];
+        let defaults = test_cts();
+        for bytes in damaged {
+            let (load, loaded, fs) = load_calibration_file(&bytes);
+            assert_eq!(load, CalibrationLoad::Corrupt, "{:?}", bytes);
+            for (ct, default) in loaded.iter().zip(&defaults) {
+                assert_eq!(ct.calibration(), default.calibration());
+                assert_eq!(ct.vt_ratio(), default.vt_ratio());
+            }
+            assert!(fs.file_size("/littlefs/calibration").is_err());
+            assert_eq!(fs.read("/littlefs/calibration.bad").unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn unreadable_calibration_is_set_aside() {
+        let fs = MemFs::new();
+        let cts = test_cts();
+        CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little)
+            .save_calibration(&cts)
+            .unwrap();
+        let unreadable = UnreadableFs::new(&fs, "/littlefs/calibration", 0);
+        let mut loaded = test_cts();
+        let mut storage = CTStorage::with_fs(Box::new(unreadable), ByteOrder::Little);
+        storage.load_calibration(&mut loaded).unwrap();
+        assert_eq!(storage.boot.calibration, CalibrationLoad::Corrupt);
+        assert_eq!(storage.boot.calibrations_loaded, 0);
+        assert!(fs.file_size("/littlefs/calibration.bad").is_ok());
+
+        // Without a file the defaults are used and nothing is set aside.
+        let fs = MemFs::new();
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.load_calibration(&mut loaded).unwrap();
+        assert_eq!(storage.boot.calibration, CalibrationLoad::Missing);
+        assert!(fs.file_size("/littlefs/calibration.bad").is_err());
+    }
+
+    #[test]
+    fn unversioned_calibration_is_migrated() {
+        let mut file = Vec::new();
+        for ct in test_cts().iter() {
+            file.extend_from_slice(&ct.id.to_le_bytes());
+            for value in [40.0_f32, 50.0, 1.5] {
+                file.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        let (load, loaded, fs) = load_calibration_file(&file);
+        assert_eq!(load, CalibrationLoad::Migrated);
+        assert!(loaded.iter().all(|ct| ct.calibration().ical == 50.0));
+        let migrated = fs.read("/littlefs/calibration").unwrap();
+        assert_eq!(migrated[0], CALIBRATION_VERSION);
+        assert_eq!(migrated.len(), 3 + CALIBRATION_SIZE * AC_PHASE);
+    }
+
+    #[test]
+    fn batched_saves_are_written_with_one_write_per_shard() {
+        let fs = MemFs::new();
+        let counting = CountingFs::new(&fs, "ct_readings");
+        let mut storage = CTStorage::with_fs(Box::new(counting.clone()), ByteOrder::Little);
+        storage.set_min_save_interval(Duration::ZERO);
+        storage.set_sync_policy(SyncPolicy::EverySave);
+        storage.find_newest_readings_shard_num().unwrap();
+        storage.set_write_batching(Some(3 * AC_PHASE * record_size()));
+        *counting.counts.lock().unwrap() = (0, 0);
+        let mut cts = test_cts();
+
+        save(&mut storage, &mut cts, 1_000);
+        save(&mut storage, &mut cts, 2_000);
+        assert_eq!(*counting.counts.lock().unwrap(), (0, 0));
+        assert!(stored(&storage).is_empty());
+        assert_eq!(storage.lifetime_stats().real_power.count, 0);
+        assert_eq!(storage.tou_totals().off_peak, 0.0);
+
+        save(&mut storage, &mut cts, 3_000);
+        assert!(storage.buffered.is_empty());
+        // Each save fills a shard of MAX_SHARD_SIZE, header and records go in one write.
+        let shards = storage.readings_shards.len();
+        assert_eq!(shards, 3);
+        assert_eq!(*counting.counts.lock().unwrap(), (shards, shards));
+        assert_eq!(stored(&storage).len(), 3 * AC_PHASE);
+        assert_eq!(storage.readings_since(1).unwrap().len(), 2 * AC_PHASE);
+        assert_eq!(
+            storage.lifetime_stats().real_power.count,
+            3 * AC_PHASE as u64
+        );
+        assert!(storage.tou_totals().off_peak > 0.0);
+    }
+
+    #[test]
+    fn detect_root_uses_the_first_writable_root() {
+        let fs = MemFs::mounted_at("/spiffs");
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.detect_root(&["/littlefs", "/spiffs/"]).unwrap();
+        assert_eq!(storage.root(), "/spiffs");
+        assert!(fs.read_dir("/spiffs").unwrap().is_empty());
+
+        storage.set_min_save_interval(Duration::ZERO);
+        storage.find_newest_readings_shard_num().unwrap();
+        assert!(storage.storage_available());
+        let mut cts = test_cts();
+        save(&mut storage, &mut cts, 1_000);
+        assert!(fs.file_size("/spiffs/ct_readings/1").is_ok());
+        assert_eq!(stored(&storage).len(), AC_PHASE);
+    }
+
+    #[test]
+    fn detect_root_skips_a_read_only_root_with_files() {
+        let fs = MemFs::mounted_at("/");
+        fs.create_dir("/littlefs").unwrap();
+        fs.write_atomic("/littlefs/time", &[]).unwrap();
+        fs.create_dir("/spiffs").unwrap();
+        let read_only = ReadOnlyFs::new(&fs, "/littlefs");
+        let mut storage = CTStorage::with_fs(Box::new(read_only), ByteOrder::Little);
+        storage.detect_root(&["/littlefs", "/spiffs"]).unwrap();
+        assert_eq!(storage.root(), "/spiffs");
+        assert_eq!(fs.read_dir("/littlefs").unwrap(), ["time"]);
+        assert!(fs.read_dir("/spiffs").unwrap().is_empty());
+    }
+
+    #[test]
+    fn detect_root_without_a_writable_root_keeps_the_default() {
+        let fs = MemFs::mounted_at("/fat");
+        let mut storage = CTStorage::with_fs(Box::new(fs), ByteOrder::Little);
+        let err = storage.detect_root(&["/littlefs", "/spiffs"]).unwrap_err();
+        assert!(err.to_string().contains("\"/spiffs\""), "{}", err);
+        assert_eq!(storage.root(), STORAGE_MOUNTS[0].root);
+    }
+
+    #[test]
+    fn failed_storage_recovery_keeps_the_save_in_ram() {
+        let fs = MemFs::mounted_at("/");
+        let mut storage = CTStorage::with_fs(Box::new(fs.clone()), ByteOrder::Little);
+        storage.set_min_save_interval(Duration::ZERO);
+        storage.find_newest_readings_shard_num().unwrap();
+        let mut cts = test_cts();
+        save(&mut storage, &mut cts, 1_000);
+
+        // The partition is back, but the time can't be loaded.
+        fs.create_dir("/littlefs").unwrap();
+        fs.create_dir("/littlefs/time").unwrap();
+        save(&mut storage, &mut cts, 2_000);
+        assert!(!storage.storage_available());
+        assert_eq!(storage.buffered.len(), 2);
+
+        fs.remove_dir_all("/littlefs/time").unwrap();
+        save(&mut storage, &mut cts, 3_000);
+        assert!(storage.storage_available());
+        let timestamps: Vec<u64> = stored(&storage).iter().map(|(_, r)| r.timestamp).collect();
+        assert_eq!(timestamps.len(), 3 * AC_PHASE);
+        assert!(timestamps[AC_PHASE..2 * AC_PHASE]
+            .iter()
+            .all(|&t| t == 2_000));
+    }
+
+    #[test]
+    fn failed_write_leaves_no_records_of_the_save_behind() {
+        let fs = MemFs::new();
+        // Fails halfway through the last record of the first save.
+        let budget = SHARD_HEADER_SIZE + record_size() * (2 * AC_PHASE - 1) / 2;
+        let short = ShortWriteFs::new(&fs, "ct_readings", budget);
+        let mut storage = CTStorage::with_fs(Box::new(short), ByteOrder::Little);
+        storage.set_min_save_interval(Duration::ZERO);
+        storage.set_write_batching(None);
+        storage.find_newest_readings_shard_num().unwrap();
+        let mut cts = test_cts();
+        for ct in cts.iter_mut() {
+            ct.reading = reading(100.0, 1_000);
+        }
+        let _ = storage.save_to_storage(&cts);
+        assert_eq!(storage.buffered.len(), 1);
+        assert_eq!(storage.read_shard(1).unwrap().len(), 0);
+
+        save(&mut storage, &mut cts, 2_000);
+        assert!(storage.buffered.is_empty());
+        let sequences: Vec<u32> = stored(&storage).iter().map(|(_, r)| r.sequence).collect();
+        assert_eq!(sequences.len(), 2 * AC_PHASE);
+        assert!(sequences[..AC_PHASE].iter().all(|&seq| seq == 1));
+        assert!(sequences[AC_PHASE..].iter().all(|&seq| seq == 2));
+    }
+}
@@ -1,3 +1,12 @@
+/// Byte order of the stored readings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// The native order of the ESP32.
+    Little,
+    /// Network byte order.
+    Big,
+}
+
 pub(crate) fn add_u16_to_buf(val: &u16, buf: &mut [u8], offset: &usize) -> anyhow::Result<usize> {
     let bytes = val.to_le_bytes();
     let n = bytes.len();
@@ -5,6 +14,13 @@ pub(crate) fn add_u16_to_buf(val: &u16, buf: &mut [u8], offset: &usize) -> anyho
     Ok(n)
 }
 
+pub(crate) fn add_u32_to_buf(val: &u32, buf: &mut [u8], offset: &usize) -> anyhow::Result<usize> {
+    let bytes = val.to_le_bytes();
+    let n = bytes.len();
+    buf[*offset..(n + (*offset))].copy_from_slice(&bytes);
+    Ok(n)
+}
+
 pub(crate) fn add_f32_to_buf(val: &f32, buf: &mut [u8], offset: &usize) -> anyhow::Result<usize> {
     let bytes = val.to_le_bytes();
     let n = bytes.len();
@@ -18,3 +34,205 @@ pub(crate) fn add_u64_to_buf(val: &u64, buf: &mut [u8], offset: &usize) -> anyho
     buf[*offset..(n + (*offset))].copy_from_slice(&bytes);
     Ok(n)
 }
+
+pub(crate) fn add_f64_to_buf(val: &f64, buf: &mut [u8], offset: &usize) -> anyhow::Result<usize> {
+    let bytes = val.to_le_bytes();
+    let n = bytes.len();
+    buf[*offset..(n + (*offset))].copy_from_slice(&bytes);
+    Ok(n)
+}
+
+pub(crate) fn add_u16_to_buf_be(
+    val: &u16,
+    buf: &mut [u8],
+    offset: &usize,
+) -> anyhow::Result<usize> {
+    let bytes = val.to_be_bytes();
+    let n = bytes.len();
+    buf[*offset..(n + (*offset))].copy_from_slice(&bytes);
+    Ok(n)
+}
+
+pub(crate) fn add_u32_to_buf_be(
+    val: &u32,
+    buf: &mut [u8],
+    offset: &usize,
+) -> anyhow::Result<usize> {
+    let bytes = val.to_be_bytes();
+    let n = bytes.len();
+    buf[*offset..(n + (*offset))].copy_from_slice(&bytes);
+    Ok(n)
+}
+
+pub(crate) fn add_u64_to_buf_be(
+    val: &u64,
+    buf: &mut [u8],
+    offset: &usize,
+) -> anyhow::Result<usize> {
+    let bytes = val.to_be_bytes();
+    let n = bytes.len();
+    buf[*offset..(n + (*offset))].copy_from_slice(&bytes);
+    Ok(n)
+}
+
+pub(crate) fn add_f32_to_buf_be(
+    val: &f32,
+    buf: &mut [u8],
+    offset: &usize,
+) -> anyhow::Result<usize> {
+    let bytes = val.to_be_bytes();
+    let n = bytes.len();
+    buf[*offset..(n + (*offset))].copy_from_slice(&bytes);
+    Ok(n)
+}
+
+pub(crate) fn read_u16_from_buf(buf: &[u8], offset: &mut usize) -> anyhow::Result<u16> {
+    let mut bytes = [0_u8; std::mem::size_of::<u16>()];
+    let n = bytes.len();
+    bytes.copy_from_slice(read_bytes_from_buf(buf, offset, n)?);
+    Ok(u16::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_u32_from_buf(buf: &[u8], offset: &mut usize) -> anyhow::Result<u32> {
+    let mut bytes = [0_u8; std::mem::size_of::<u32>()];
+    let n = bytes.len();
+    bytes.copy_from_slice(read_bytes_from_buf(buf, offset, n)?);
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_u64_from_buf(buf: &[u8], offset: &mut usize) -> anyhow::Result<u64> {
+    let mut bytes = [0_u8; std::mem::size_of::<u64>()];
+    let n = bytes.len();
+    bytes.copy_from_slice(read_bytes_from_buf(buf, offset, n)?);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_f32_from_buf(buf: &[u8], offset: &mut usize) -> anyhow::Result<f32> {
+    let mut bytes = [0_u8; std::mem::size_of::<f32>()];
+    let n = bytes.len();
+    bytes.copy_from_slice(read_bytes_from_buf(buf, offset, n)?);
+    Ok(f32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_f64_from_buf(buf: &[u8], offset: &mut usize) -> anyhow::Result<f64> {
+    let mut bytes = [0_u8; std::mem::size_of::<f64>()];
+    let n = bytes.len();
+    bytes.copy_from_slice(read_bytes_from_buf(buf, offset, n)?);
+    Ok(f64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_u16_from_buf_be(buf: &[u8], offset: &mut usize) -> anyhow::Result<u16> {
+    let mut bytes = [0_u8; std::mem::size_of::<u16>()];
+    let n = bytes.len();
+    bytes.copy_from_slice(read_bytes_from_buf(buf, offset, n)?);
+    Ok(u16::from_be_bytes(bytes))
+}
+
+pub(crate) fn read_u32_from_buf_be(buf: &[u8], offset: &mut usize) -> anyhow::Result<u32> {
+    let mut bytes = [0_u8; std::mem::size_of::<u32>()];
+    let n = bytes.len();
+    bytes.copy_from_slice(read_bytes_from_buf(buf, offset, n)?);
+    Ok(u32::from_be_bytes(bytes))
+}
+
+pub(crate) fn read_u64_from_buf_be(buf: &[u8], offset: &mut usize) -> anyhow::Result<u64> {
+    let mut bytes = [0_u8; std::mem::size_of::<u64>()];
+    let n = bytes.len();
+    bytes.copy_from_slice(read_bytes_from_buf(buf, offset, n)?);
+    Ok(u64::from_be_bytes(bytes))
+}
+
+pub(crate) fn read_f32_from_buf_be(buf: &[u8], offset: &mut usize) -> anyhow::Result<f32> {
+    let mut bytes = [0_u8; std::mem::size_of::<f32>()];
+    let n = bytes.len();
+    bytes.copy_from_slice(read_bytes_from_buf(buf, offset, n)?);
+    Ok(f32::from_be_bytes(bytes))
+}
+
+fn read_bytes_from_buf<'a>(
+    buf: &'a [u8],
+    offset: &mut usize,
+    n: usize,
+) -> anyhow::Result<&'a [u8]> {
+    let bytes = buf
+        .get(*offset..(n + (*offset)))
+        .ok_or_else(|| anyhow::anyhow!("buffer too short to read {} bytes at {}", n, offset))?;
+    *offset += n;
+    Ok(bytes)
+}
+
+/// CRC-16/CCITT-FALSE of `data`.
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// FNV-1a offset basis, the hash of no data.
+pub(crate) const FNV1A_64_INIT: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Continue the 64 bit FNV-1a hash `hash` over `data`.
+pub(crate) fn fnv1a_64(mut hash: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Run `future` to completion on the current thread, yielding the thread while it is pending.
+///
+/// The firmware has no async executor, this is enough for futures that only wait by returning
+/// Pending, like the ones of YieldNow, and don't rely on their waker.
+#[cfg(feature = "async")]
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    fn raw_waker() -> RawWaker {
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(|_| raw_waker(), |_| {}, |_| {}, |_| {});
+
+    // Polling goes on regardless of wakes, so the waker has nothing to do.
+    let waker = unsafe { Waker::from_raw(raw_waker()) };
+    let mut context = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::yield_now(),
+        }
+    }
+}
+
+/// A future that is pending once before it is ready, so the executor runs other tasks in between.
+#[cfg(feature = "async")]
+#[derive(Default)]
+pub(crate) struct YieldNow(bool);
+
+#[cfg(feature = "async")]
+impl std::future::Future for YieldNow {
+    type Output = ();
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<()> {
+        if self.0 {
+            std::task::Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        }
+    }
+}